@@ -0,0 +1,149 @@
+use anchor_lang::error::ErrorCode;
+use anchor_lang::{AccountDeserialize, AnchorDeserialize, Discriminator, Result};
+use enumflags2::BitFlags;
+use fermi_dex::state::EventFlag;
+use solana_program::pubkey::Pubkey;
+
+use crate::{
+    AllowList, Asks, Bids, Event, EventQueue, MarginAccount, Market, OpenOrders, Order, Registry,
+    Side, TriggerOrders,
+};
+
+pub fn market(data: &[u8]) -> Result<Market> {
+    Market::try_deserialize(&mut &data[..])
+}
+
+pub fn registry(data: &[u8]) -> Result<Registry> {
+    Registry::try_deserialize(&mut &data[..])
+}
+
+pub fn allow_list(data: &[u8]) -> Result<AllowList> {
+    AllowList::try_deserialize(&mut &data[..])
+}
+
+pub fn bids(data: &[u8]) -> Result<Bids> {
+    Bids::try_deserialize(&mut &data[..])
+}
+
+pub fn asks(data: &[u8]) -> Result<Asks> {
+    Asks::try_deserialize(&mut &data[..])
+}
+
+pub fn open_orders(data: &[u8]) -> Result<OpenOrders> {
+    OpenOrders::try_deserialize(&mut &data[..])
+}
+
+pub fn trigger_orders(data: &[u8]) -> Result<TriggerOrders> {
+    TriggerOrders::try_deserialize(&mut &data[..])
+}
+
+pub fn margin_account(data: &[u8]) -> Result<MarginAccount> {
+    MarginAccount::try_deserialize(&mut &data[..])
+}
+
+/// Copies the zero-copy event queue out of raw account data.
+pub fn event_queue(data: &[u8]) -> Result<EventQueue> {
+    if data.len() < 8 || data[..8] != EventQueue::discriminator() {
+        return Err(ErrorCode::AccountDiscriminatorMismatch.into());
+    }
+    let len = std::mem::size_of::<EventQueue>();
+    let body = data
+        .get(8..8 + len)
+        .ok_or(ErrorCode::AccountDidNotDeserialize)?;
+    bytemuck::try_from_bytes::<EventQueue>(body)
+        .map(|queue| *queue)
+        .map_err(|_| ErrorCode::AccountDidNotDeserialize.into())
+}
+
+/// Decodes the return data of one of the `view_*` instructions.
+pub fn view<T: AnchorDeserialize>(return_data: &[u8]) -> Result<T> {
+    T::try_from_slice(return_data).map_err(|_| ErrorCode::InstructionDidNotDeserialize.into())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Fill,
+    Out,
+}
+
+/// The `finalised` byte of an event.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Settlement {
+    Pending,
+    Finalised,
+    CancelledWithPenalty,
+    Settled,
+    Unknown(u8),
+}
+
+impl From<u8> for Settlement {
+    fn from(finalised: u8) -> Self {
+        match finalised {
+            0 => Settlement::Pending,
+            1 => Settlement::Finalised,
+            2 => Settlement::CancelledWithPenalty,
+            3 => Settlement::Settled,
+            other => Settlement::Unknown(other),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DecodedEvent {
+    pub slot: usize,
+    pub kind: EventKind,
+    pub side: Side,
+    pub maker: bool,
+    pub release_funds: bool,
+    pub native_qty_released: u64,
+    pub native_qty_paid: u64,
+    pub order_id: u128,
+    pub order_id_second: u128,
+    pub owner: Pubkey,
+    pub owner_slot: u8,
+    pub settlement: Settlement,
+    pub timestamp: u64,
+}
+
+impl DecodedEvent {
+    pub fn price(&self) -> u64 {
+        Order::price_from_order_id(self.order_id)
+    }
+}
+
+/// Returns `None` for slots that were never written.
+pub fn event(slot: usize, event: &Event) -> Option<DecodedEvent> {
+    let flags = BitFlags::<EventFlag>::from_bits(event.event_flags).ok()?;
+    let kind = if flags.contains(EventFlag::Fill) {
+        EventKind::Fill
+    } else if flags.contains(EventFlag::Out) {
+        EventKind::Out
+    } else {
+        return None;
+    };
+
+    Some(DecodedEvent {
+        slot,
+        kind,
+        side: EventFlag::flags_to_side(flags),
+        maker: flags.contains(EventFlag::Maker),
+        release_funds: flags.contains(EventFlag::ReleaseFunds),
+        native_qty_released: event.native_qty_released,
+        native_qty_paid: event.native_qty_paid,
+        order_id: event.order_id,
+        order_id_second: event.order_id_second,
+        owner: event.owner,
+        owner_slot: event.owner_slot,
+        settlement: Settlement::from(event.finalised),
+        timestamp: event.timestamp,
+    })
+}
+
+/// All written events, in slot order.
+pub fn events(queue: &EventQueue) -> Vec<DecodedEvent> {
+    let buf = queue.buf;
+    buf.iter()
+        .enumerate()
+        .filter_map(|(slot, e)| event(slot, e))
+        .collect()
+}
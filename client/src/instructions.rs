@@ -0,0 +1,977 @@
+use anchor_lang::{InstructionData, ToAccountMetas};
+use fermi_dex::token_interface::{associated_address, is_native_mint};
+use fermi_dex::{accounts, instruction};
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program::pubkey::Pubkey;
+use solana_program::{system_program, sysvar};
+
+use crate::pda::{self, MarketAddresses};
+use crate::{LiquidatedFill, OrderType, Peg, Side, TriggerParams, PROGRAM_ID};
+
+fn build(accounts: impl ToAccountMetas, data: impl InstructionData) -> Instruction {
+    Instruction {
+        program_id: PROGRAM_ID,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+pub fn init_registry(authority: Pubkey, listing_fee: u64) -> Instruction {
+    build(
+        accounts::InitRegistry {
+            registry: pda::registry().0,
+            authority,
+            system_program: system_program::ID,
+        },
+        instruction::InitRegistry { listing_fee },
+    )
+}
+
+pub fn set_listing_fee(authority: Pubkey, listing_fee: u64) -> Instruction {
+    build(
+        accounts::SetListingFee {
+            registry: pda::registry().0,
+            authority,
+        },
+        instruction::SetListingFee { listing_fee },
+    )
+}
+
+/// Lists the market at `m`, whose nonce sets it apart from others of the
+/// pair. `authority` pays the listing fee to `registry_authority`.
+pub fn initialize_market(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    registry_authority: Pubkey,
+    coin_lot_size: u64,
+    pc_lot_size: u64,
+) -> Instruction {
+    build(
+        accounts::InitializeMarket {
+            market: m.market,
+            coin_vault: m.coin_vault,
+            pc_vault: m.pc_vault,
+            coin_mint: m.coin_mint,
+            pc_mint: m.pc_mint,
+            bids: m.bids,
+            asks: m.asks,
+            req_q: m.req_q,
+            event_q: m.event_q,
+            registry: pda::registry().0,
+            listing_fee_receiver: registry_authority,
+            authority,
+            system_program: system_program::ID,
+            coin_token_program: m.coin_token_program,
+            pc_token_program: m.pc_token_program,
+            associated_token_program: anchor_spl::associated_token::ID,
+            rent: sysvar::rent::ID,
+        },
+        instruction::InitializeMarket {
+            coin_lot_size,
+            pc_lot_size,
+            nonce: m.nonce,
+        },
+    )
+}
+
+/// Signed by the market's authority or the registry's.
+pub fn delist_market(m: &MarketAddresses, authority: Pubkey) -> Instruction {
+    build(
+        accounts::DelistMarket {
+            registry: pda::registry().0,
+            market: m.market,
+            bids: m.bids,
+            asks: m.asks,
+            authority,
+        },
+        instruction::DelistMarket {},
+    )
+}
+
+/// `payer` is the authority's coin wallet for asks and pc wallet for bids. On
+/// the native side of a SOL market, passing `authority` pays in lamports and
+/// makes the order fully funded.
+#[allow(clippy::too_many_arguments)]
+pub fn new_order(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    payer: Pubkey,
+    side: Side,
+    limit_price: u64,
+    max_coin_qty: u64,
+    max_native_pc_qty: u64,
+    order_type: OrderType,
+    fully_funded: bool,
+) -> Instruction {
+    new_order_for(
+        m,
+        authority,
+        authority,
+        authority,
+        payer,
+        side,
+        limit_price,
+        max_coin_qty,
+        max_native_pc_qty,
+        order_type,
+        fully_funded,
+    )
+}
+
+/// Places an order on `owner`'s account. `authority` is the owner or its
+/// delegate, and `rent_payer` funds the open orders account if it is new,
+/// which lets a PDA owner trade through CPI.
+#[allow(clippy::too_many_arguments)]
+pub fn new_order_for(
+    m: &MarketAddresses,
+    owner: Pubkey,
+    authority: Pubkey,
+    rent_payer: Pubkey,
+    payer: Pubkey,
+    side: Side,
+    limit_price: u64,
+    max_coin_qty: u64,
+    max_native_pc_qty: u64,
+    order_type: OrderType,
+    fully_funded: bool,
+) -> Instruction {
+    build(
+        accounts::NewOrder {
+            open_orders: m.open_orders(&owner),
+            market: m.market,
+            coin_vault: m.coin_vault,
+            pc_vault: m.pc_vault,
+            coin_mint: m.coin_mint,
+            pc_mint: m.pc_mint,
+            payer,
+            bids: m.bids,
+            asks: m.asks,
+            req_q: m.req_q,
+            event_q: m.event_q,
+            owner,
+            authority,
+            rent_payer,
+            system_program: system_program::ID,
+            token_program: m.payer_mint(side).1,
+            associated_token_program: anchor_spl::associated_token::ID,
+            rent: sysvar::rent::ID,
+        },
+        instruction::NewOrder {
+            side,
+            limit_price,
+            max_coin_qty,
+            max_native_pc_qty,
+            order_type,
+            fully_funded,
+        },
+    )
+}
+
+/// Rests an order priced off `peg`, trading no worse than `limit_price`.
+/// Use `with_peg_oracle` on this and on takers' orders for oracle pegs.
+#[allow(clippy::too_many_arguments)]
+pub fn new_pegged_order(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    payer: Pubkey,
+    side: Side,
+    limit_price: u64,
+    max_coin_qty: u64,
+    max_native_pc_qty: u64,
+    peg: Peg,
+    fully_funded: bool,
+) -> Instruction {
+    let order = new_order(
+        m,
+        authority,
+        payer,
+        side,
+        limit_price,
+        max_coin_qty,
+        max_native_pc_qty,
+        OrderType::PostOnly,
+        fully_funded,
+    );
+    Instruction {
+        data: instruction::NewPeggedOrder {
+            side,
+            limit_price,
+            max_coin_qty,
+            max_native_pc_qty,
+            peg,
+            fully_funded,
+        }
+        .data(),
+        ..order
+    }
+}
+
+/// Rests an order showing at most `display_qty` lots of `max_coin_qty`.
+#[allow(clippy::too_many_arguments)]
+pub fn new_iceberg_order(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    payer: Pubkey,
+    side: Side,
+    limit_price: u64,
+    max_coin_qty: u64,
+    max_native_pc_qty: u64,
+    display_qty: u64,
+    order_type: OrderType,
+    fully_funded: bool,
+) -> Instruction {
+    let order = new_order(
+        m,
+        authority,
+        payer,
+        side,
+        limit_price,
+        max_coin_qty,
+        max_native_pc_qty,
+        order_type,
+        fully_funded,
+    );
+    Instruction {
+        data: instruction::NewIcebergOrder {
+            side,
+            limit_price,
+            max_coin_qty,
+            max_native_pc_qty,
+            display_qty,
+            order_type,
+            fully_funded,
+        }
+        .data(),
+        ..order
+    }
+}
+
+/// Passes the market's peg oracle so the order is matched at a fresh oracle price.
+pub fn with_peg_oracle(mut ix: Instruction, oracle: Pubkey) -> Instruction {
+    ix.accounts.push(AccountMeta::new_readonly(oracle, false));
+    ix
+}
+
+/// Has the admission authority co-sign the order that opens an account on a
+/// permissioned market.
+pub fn with_admission_authority(mut ix: Instruction, admission_authority: Pubkey) -> Instruction {
+    ix.accounts
+        .push(AccountMeta::new_readonly(admission_authority, true));
+    ix
+}
+
+/// Passes the market's allow list for the order that opens an account on a
+/// permissioned market.
+pub fn with_allow_list(mut ix: Instruction, m: &MarketAddresses) -> Instruction {
+    ix.accounts.push(AccountMeta::new_readonly(
+        pda::allow_list(&m.market).0,
+        false,
+    ));
+    ix
+}
+
+/// Passes the open orders of the makers a fully funded order may fill, so
+/// fills against their fully funded orders settle without finalise_matches_*.
+/// `owners` are the makers' authorities.
+pub fn with_makers(mut ix: Instruction, m: &MarketAddresses, owners: &[Pubkey]) -> Instruction {
+    ix.accounts.extend(
+        owners
+            .iter()
+            .map(|owner| AccountMeta::new(m.open_orders(owner), false)),
+    );
+    ix
+}
+
+/// `payer` funds whatever part of the collateral the free balance doesn't cover.
+pub fn place_trigger_order(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    payer: Pubkey,
+    params: TriggerParams,
+) -> Instruction {
+    let (mint, token_program) = m.payer_mint(params.side);
+    build(
+        accounts::PlaceTriggerOrder {
+            open_orders: m.open_orders(&authority),
+            trigger_orders: m.trigger_orders,
+            market: m.market,
+            vault: m.payer_vault(params.side),
+            mint,
+            payer,
+            req_q: m.req_q,
+            authority,
+            system_program: system_program::ID,
+            token_program,
+        },
+        instruction::PlaceTriggerOrder { params },
+    )
+}
+
+pub fn cancel_trigger_order(m: &MarketAddresses, authority: Pubkey, order_id: u128) -> Instruction {
+    build(
+        accounts::CancelTriggerOrder {
+            open_orders: m.open_orders(&authority),
+            trigger_orders: m.trigger_orders,
+            market: m.market,
+            authority,
+        },
+        instruction::CancelTriggerOrder { order_id },
+    )
+}
+
+/// `owners` are the authorities whose triggered orders should be placed.
+pub fn execute_triggers(m: &MarketAddresses, owners: &[Pubkey], max_orders: u8) -> Instruction {
+    let mut ix = build(
+        accounts::ExecuteTriggers {
+            trigger_orders: m.trigger_orders,
+            market: m.market,
+            bids: m.bids,
+            asks: m.asks,
+            req_q: m.req_q,
+            event_q: m.event_q,
+        },
+        instruction::ExecuteTriggers { max_orders },
+    );
+    ix.accounts.extend(
+        owners
+            .iter()
+            .map(|owner| AccountMeta::new(m.open_orders(owner), false)),
+    );
+    ix
+}
+
+fn cancel_accounts(m: &MarketAddresses, owner: Pubkey, authority: Pubkey) -> accounts::CancelOrder {
+    accounts::CancelOrder {
+        open_orders: m.open_orders(&owner),
+        market: m.market,
+        bids: m.bids,
+        asks: m.asks,
+        event_q: m.event_q,
+        owner,
+        authority,
+    }
+}
+
+pub fn cancel_bid(m: &MarketAddresses, authority: Pubkey, order_id: u128) -> Instruction {
+    cancel_bid_for(m, authority, authority, order_id)
+}
+
+pub fn cancel_ask(m: &MarketAddresses, authority: Pubkey, order_id: u128) -> Instruction {
+    cancel_ask_for(m, authority, authority, order_id)
+}
+
+/// Cancels one of `owner`'s bids, signed by the owner or its delegate.
+pub fn cancel_bid_for(
+    m: &MarketAddresses,
+    owner: Pubkey,
+    authority: Pubkey,
+    order_id: u128,
+) -> Instruction {
+    build(
+        cancel_accounts(m, owner, authority),
+        instruction::CancelBid {
+            order_id,
+            expected_owner: owner,
+        },
+    )
+}
+
+/// Cancels one of `owner`'s asks, signed by the owner or its delegate.
+pub fn cancel_ask_for(
+    m: &MarketAddresses,
+    owner: Pubkey,
+    authority: Pubkey,
+    order_id: u128,
+) -> Instruction {
+    build(
+        cancel_accounts(m, owner, authority),
+        instruction::CancelAsk {
+            order_id,
+            expected_owner: owner,
+        },
+    )
+}
+
+/// Deposits `amount` of `mint`, either of the market's, from the authority's
+/// associated token account into its free balance.
+pub fn deposit(m: &MarketAddresses, authority: Pubkey, mint: Pubkey, amount: u64) -> Instruction {
+    let side = m.mint_side(&mint);
+    let token_program = m.payer_mint(side).1;
+    build(
+        accounts::Deposit {
+            market: m.market,
+            mint,
+            vault: m.payer_vault(side),
+            payer: associated_address(&authority, &mint, &token_program),
+            open_orders: m.open_orders(&authority),
+            authority,
+            token_program,
+        },
+        instruction::Deposit { amount },
+    )
+}
+
+/// Wraps `lamports` into the SOL side of the market, credited as free balance.
+pub fn deposit_native(m: &MarketAddresses, authority: Pubkey, lamports: u64) -> Instruction {
+    let side = native_side(m);
+    build(
+        accounts::DepositNative {
+            market: m.market,
+            vault: m.payer_vault(side),
+            open_orders: m.open_orders(&authority),
+            authority,
+            system_program: system_program::ID,
+            token_program: m.payer_mint(side).1,
+        },
+        instruction::DepositNative { lamports },
+    )
+}
+
+pub fn withdraw_native(m: &MarketAddresses, authority: Pubkey, lamports: u64) -> Instruction {
+    let side = native_side(m);
+    let (mint, token_program) = m.payer_mint(side);
+    let open_orders = m.open_orders(&authority);
+    build(
+        accounts::WithdrawNative {
+            market: m.market,
+            mint,
+            vault: m.payer_vault(side),
+            open_orders,
+            wsol: pda::wsol(&open_orders).0,
+            authority,
+            system_program: system_program::ID,
+            token_program,
+        },
+        instruction::WithdrawNative { lamports },
+    )
+}
+
+/// The side locking wrapped SOL. Panics if neither mint is native.
+fn native_side(m: &MarketAddresses) -> Side {
+    if is_native_mint(&m.pc_mint) {
+        Side::Bid
+    } else if is_native_mint(&m.coin_mint) {
+        Side::Ask
+    } else {
+        panic!("market has no native SOL side")
+    }
+}
+
+/// Withdraws `amount` of `mint` from the authority's free balance to its
+/// associated token account.
+pub fn withdraw(m: &MarketAddresses, authority: Pubkey, mint: Pubkey, amount: u64) -> Instruction {
+    let token_program = m.payer_mint(m.mint_side(&mint)).1;
+    let destination = associated_address(&authority, &mint, &token_program);
+    withdraw_to(m, authority, mint, destination, amount)
+}
+
+/// As `withdraw`, paying into `destination`, a token account of a whitelisted wallet.
+pub fn withdraw_to(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    mint: Pubkey,
+    destination: Pubkey,
+    amount: u64,
+) -> Instruction {
+    let side = m.mint_side(&mint);
+    build(
+        accounts::Withdraw {
+            market: m.market,
+            mint,
+            vault: m.payer_vault(side),
+            destination,
+            open_orders: m.open_orders(&authority),
+            authority,
+            token_program: m.payer_mint(side).1,
+        },
+        instruction::Withdraw { amount },
+    )
+}
+
+/// Needs no signature from `owner`, any crank can send it. `payer` fronts the
+/// rent of the temporary account a native SOL payout goes through.
+pub fn settle_funds(m: &MarketAddresses, owner: Pubkey, payer: Pubkey) -> Instruction {
+    let open_orders = m.open_orders(&owner);
+    build(
+        accounts::SettleFunds {
+            open_orders,
+            market: m.market,
+            coin_mint: m.coin_mint,
+            pc_mint: m.pc_mint,
+            coin_vault: m.coin_vault,
+            pc_vault: m.pc_vault,
+            owner,
+            owner_coin_account: m.coin_wallet(&owner),
+            owner_pc_account: m.pc_wallet(&owner),
+            wsol: pda::wsol(&open_orders).0,
+            payer,
+            coin_token_program: m.coin_token_program,
+            pc_token_program: m.pc_token_program,
+            system_program: system_program::ID,
+            associated_token_program: anchor_spl::associated_token::ID,
+        },
+        instruction::SettleFunds {},
+    )
+}
+
+pub fn set_requeue_on_default(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    enabled: bool,
+) -> Instruction {
+    build(
+        accounts::SetRequeueOnDefault {
+            open_orders: m.open_orders(&authority),
+            market: m.market,
+            authority,
+        },
+        instruction::SetRequeueOnDefault { enabled },
+    )
+}
+
+/// Signed by the market authority. `owner` is the program the feed account
+/// must belong to. `Pubkey::default()` disables oracle pegs.
+pub fn set_peg_oracle(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    oracle: Pubkey,
+    owner: Pubkey,
+) -> Instruction {
+    build(
+        accounts::SetPegOracle {
+            market: m.market,
+            authority,
+        },
+        instruction::SetPegOracle { oracle, owner },
+    )
+}
+
+/// `Pubkey::default()` makes the market open to everyone.
+pub fn set_admission_authority(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    admission_authority: Pubkey,
+) -> Instruction {
+    build(
+        accounts::SetAdmissionAuthority {
+            market: m.market,
+            authority,
+        },
+        instruction::SetAdmissionAuthority {
+            admission_authority,
+        },
+    )
+}
+
+/// Creates the allow list on first use, paid for by `admission_authority`.
+pub fn set_allowed(
+    m: &MarketAddresses,
+    admission_authority: Pubkey,
+    wallet: Pubkey,
+    allowed: bool,
+) -> Instruction {
+    build(
+        accounts::SetAllowed {
+            allow_list: pda::allow_list(&m.market).0,
+            market: m.market,
+            admission_authority,
+            system_program: system_program::ID,
+        },
+        instruction::SetAllowed { wallet, allowed },
+    )
+}
+
+pub fn freeze_open_orders(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    owner: Pubkey,
+    frozen: bool,
+) -> Instruction {
+    build(
+        accounts::FreezeOpenOrders {
+            open_orders: m.open_orders(&owner),
+            market: m.market,
+            authority,
+        },
+        instruction::FreezeOpenOrders { frozen },
+    )
+}
+
+/// `Pubkey::default()` revokes the current delegate.
+pub fn set_delegate(m: &MarketAddresses, authority: Pubkey, delegate: Pubkey) -> Instruction {
+    build(
+        accounts::SetDelegate {
+            open_orders: m.open_orders(&authority),
+            market: m.market,
+            authority,
+        },
+        instruction::SetDelegate { delegate },
+    )
+}
+
+/// `Pubkey::default()` revokes the session key.
+pub fn set_session_key(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    session_key: Pubkey,
+    expires_at: u64,
+) -> Instruction {
+    build(
+        accounts::SetSessionKey {
+            open_orders: m.open_orders(&authority),
+            market: m.market,
+            authority,
+        },
+        instruction::SetSessionKey {
+            session_key,
+            expires_at,
+        },
+    )
+}
+
+/// An empty list allows withdrawals to the authority's own accounts only.
+pub fn set_withdraw_whitelist(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    destinations: Vec<Pubkey>,
+) -> Instruction {
+    build(
+        accounts::SetWithdrawWhitelist {
+            open_orders: m.open_orders(&authority),
+            market: m.market,
+            authority,
+        },
+        instruction::SetWithdrawWhitelist { destinations },
+    )
+}
+
+/// Returns the rent of `authority`'s emptied open orders account to `recipient`.
+pub fn close_open_orders(m: &MarketAddresses, authority: Pubkey, recipient: Pubkey) -> Instruction {
+    build(
+        accounts::CloseOpenOrders {
+            open_orders: m.open_orders(&authority),
+            market: m.market,
+            event_q: m.event_q,
+            authority,
+            recipient,
+        },
+        instruction::CloseOpenOrders {},
+    )
+}
+
+pub fn init_margin_account(owner: Pubkey) -> Instruction {
+    build(
+        accounts::InitMarginAccount {
+            margin_account: pda::margin_account(&owner).0,
+            owner,
+            system_program: system_program::ID,
+        },
+        instruction::InitMarginAccount {},
+    )
+}
+
+/// Deposits from `owner`'s associated token account for `mint`.
+pub fn deposit_margin(
+    owner: Pubkey,
+    mint: Pubkey,
+    token_program: Pubkey,
+    amount: u64,
+) -> Instruction {
+    build(
+        accounts::DepositMargin {
+            margin_account: pda::margin_account(&owner).0,
+            vault: pda::margin_vault(&owner, &mint, &token_program),
+            mint,
+            payer: associated_address(&owner, &mint, &token_program),
+            owner,
+            system_program: system_program::ID,
+            token_program,
+            associated_token_program: anchor_spl::associated_token::ID,
+        },
+        instruction::DepositMargin { amount },
+    )
+}
+
+/// Withdraws to `owner`'s associated token account for `mint`.
+pub fn withdraw_margin(
+    owner: Pubkey,
+    mint: Pubkey,
+    token_program: Pubkey,
+    amount: u64,
+) -> Instruction {
+    build(
+        accounts::WithdrawMargin {
+            margin_account: pda::margin_account(&owner).0,
+            vault: pda::margin_vault(&owner, &mint, &token_program),
+            mint,
+            destination: associated_address(&owner, &mint, &token_program),
+            owner,
+            token_program,
+        },
+        instruction::WithdrawMargin { amount },
+    )
+}
+
+/// Creates `authority`'s open orders account on `m` if it doesn't exist yet.
+pub fn link_margin_account(m: &MarketAddresses, authority: Pubkey) -> Instruction {
+    build(
+        accounts::LinkMarginAccount {
+            open_orders: m.open_orders(&authority),
+            market: m.market,
+            margin_account: pda::margin_account(&authority).0,
+            authority,
+            system_program: system_program::ID,
+        },
+        instruction::LinkMarginAccount {},
+    )
+}
+
+/// Passes `owner`'s margin account and its vault for the mint locked on
+/// `side`. Needed on JIT orders, cancels and finalises of accounts linked to
+/// a margin account, where `side` is the linked party's side.
+pub fn with_margin_account(
+    mut ix: Instruction,
+    m: &MarketAddresses,
+    owner: Pubkey,
+    side: Side,
+) -> Instruction {
+    let (mint, token_program) = m.payer_mint(side);
+    ix.accounts
+        .push(AccountMeta::new(pda::margin_account(&owner).0, false));
+    ix.accounts.push(AccountMeta::new(
+        pda::margin_vault(&owner, &mint, &token_program),
+        false,
+    ));
+    ix
+}
+
+/// `side` is the side of the defaulting party.
+pub fn cancel_with_penalty(
+    m: &MarketAddresses,
+    bidder: Pubkey,
+    asker: Pubkey,
+    side: Side,
+    event_slot1: u8,
+    event_slot2: u8,
+) -> Instruction {
+    build(
+        accounts::CancelWithPenalty {
+            open_orders_bidder: m.open_orders(&bidder),
+            open_orders_asker: m.open_orders(&asker),
+            event_q: m.event_q,
+            market: m.market,
+            bids: m.bids,
+            asks: m.asks,
+        },
+        instruction::CancelWithPenalty {
+            side,
+            event_slot1,
+            event_slot2,
+        },
+    )
+}
+
+/// Liquidates `owner` on behalf of `liquidator`, whose open orders account has
+/// to exist already. `counterparties` are the authorities on the other side of
+/// `fills`, except the liquidator.
+pub fn liquidate(
+    m: &MarketAddresses,
+    owner: Pubkey,
+    liquidator: Pubkey,
+    fills: Vec<LiquidatedFill>,
+    counterparties: &[Pubkey],
+) -> Instruction {
+    let mut ix = build(
+        accounts::Liquidate {
+            open_orders: m.open_orders(&owner),
+            liquidator_open_orders: m.open_orders(&liquidator),
+            market: m.market,
+            bids: m.bids,
+            asks: m.asks,
+            event_q: m.event_q,
+            liquidator,
+        },
+        instruction::Liquidate { fills },
+    );
+    ix.accounts.extend(
+        counterparties
+            .iter()
+            .map(|owner| AccountMeta::new(m.open_orders(owner), false)),
+    );
+    ix
+}
+
+/// `authority` owns the event in `event1_slot`, `authority_second` the one in `event2_slot`.
+/// `bidder` is whichever of the two placed the bid; their pc wallet is debited.
+pub fn finalise_matches_bid(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    authority_second: Pubkey,
+    bidder: Pubkey,
+    event1_slot: u8,
+    event2_slot: u8,
+) -> Instruction {
+    finalise_matches_bid_for(
+        m,
+        authority,
+        authority,
+        authority_second,
+        bidder,
+        event1_slot,
+        event2_slot,
+    )
+}
+
+/// As `finalise_matches_bid`, signed by `authority` trading for `owner`.
+pub fn finalise_matches_bid_for(
+    m: &MarketAddresses,
+    owner: Pubkey,
+    authority: Pubkey,
+    authority_second: Pubkey,
+    bidder: Pubkey,
+    event1_slot: u8,
+    event2_slot: u8,
+) -> Instruction {
+    build(
+        accounts::NewMatch {
+            open_orders_owner: m.open_orders(&owner),
+            open_orders_counterparty: m.open_orders(&authority_second),
+            market: m.market,
+            pc_vault: m.pc_vault,
+            coin_mint: m.coin_mint,
+            pc_mint: m.pc_mint,
+            req_q: m.req_q,
+            event_q: m.event_q,
+            owner,
+            authority,
+            authority_second,
+            pcpayer: m.pc_wallet(&bidder),
+            system_program: system_program::ID,
+            token_program: m.pc_token_program,
+            associated_token_program: anchor_spl::associated_token::ID,
+            rent: sysvar::rent::ID,
+            clock: sysvar::clock::ID,
+        },
+        instruction::FinaliseMatchesBid {
+            event1_slot,
+            event2_slot,
+        },
+    )
+}
+
+/// `authority` owns the event in `event1_slot`, `authority_second` the one in `event2_slot`.
+/// `asker` is whichever of the two placed the ask; their coin wallet is debited.
+pub fn finalise_matches_ask(
+    m: &MarketAddresses,
+    authority: Pubkey,
+    authority_second: Pubkey,
+    asker: Pubkey,
+    event1_slot: u8,
+    event2_slot: u8,
+) -> Instruction {
+    finalise_matches_ask_for(
+        m,
+        authority,
+        authority,
+        authority_second,
+        asker,
+        event1_slot,
+        event2_slot,
+    )
+}
+
+/// As `finalise_matches_ask`, signed by `authority` trading for `owner`.
+pub fn finalise_matches_ask_for(
+    m: &MarketAddresses,
+    owner: Pubkey,
+    authority: Pubkey,
+    authority_second: Pubkey,
+    asker: Pubkey,
+    event1_slot: u8,
+    event2_slot: u8,
+) -> Instruction {
+    build(
+        accounts::NewMatchAsk {
+            open_orders_owner: m.open_orders(&owner),
+            open_orders_counterparty: m.open_orders(&authority_second),
+            market: m.market,
+            coin_vault: m.coin_vault,
+            coin_mint: m.coin_mint,
+            pc_mint: m.pc_mint,
+            req_q: m.req_q,
+            event_q: m.event_q,
+            owner,
+            authority,
+            authority_second,
+            coinpayer: m.coin_wallet(&asker),
+            system_program: system_program::ID,
+            token_program: m.coin_token_program,
+            associated_token_program: anchor_spl::associated_token::ID,
+            rent: sysvar::rent::ID,
+        },
+        instruction::FinaliseMatchesAsk {
+            event1_slot,
+            event2_slot,
+        },
+    )
+}
+
+fn view_book_accounts(m: &MarketAddresses) -> accounts::ViewBook {
+    accounts::ViewBook {
+        market: m.market,
+        bids: m.bids,
+        asks: m.asks,
+    }
+}
+
+fn view_open_orders_accounts(m: &MarketAddresses, owner: Pubkey) -> accounts::ViewOpenOrders {
+    accounts::ViewOpenOrders {
+        market: m.market,
+        open_orders: m.open_orders(&owner),
+        bids: m.bids,
+        asks: m.asks,
+        event_q: m.event_q,
+    }
+}
+
+/// Read the result with `decode::view::<Bbo>` on the transaction's return data.
+pub fn view_bbo(m: &MarketAddresses) -> Instruction {
+    build(view_book_accounts(m), instruction::ViewBbo {})
+}
+
+/// Read the result with `decode::view::<PriceView>`.
+pub fn view_price(m: &MarketAddresses) -> Instruction {
+    build(view_book_accounts(m), instruction::ViewPrice {})
+}
+
+/// Read the result with `decode::view::<Vec<PriceLevel>>`.
+pub fn view_depth(m: &MarketAddresses, side: Side, levels: u8) -> Instruction {
+    build(
+        view_book_accounts(m),
+        instruction::ViewDepth { side, levels },
+    )
+}
+
+/// Read the result with `decode::view::<Vec<OpenOrderView>>`.
+pub fn view_open_orders(m: &MarketAddresses, owner: Pubkey) -> Instruction {
+    build(
+        view_open_orders_accounts(m, owner),
+        instruction::ViewOpenOrders {},
+    )
+}
+
+/// Read the result with `decode::view::<Vec<PendingObligation>>`.
+pub fn view_pending_obligations(m: &MarketAddresses, owner: Pubkey) -> Instruction {
+    build(
+        view_open_orders_accounts(m, owner),
+        instruction::ViewPendingObligations {},
+    )
+}
+
+/// Read the result with `decode::view::<i128>`.
+pub fn view_health(m: &MarketAddresses, owner: Pubkey) -> Instruction {
+    build(
+        view_open_orders_accounts(m, owner),
+        instruction::ViewHealth {},
+    )
+}
@@ -0,0 +1,19 @@
+//! Rust client for the fermi-dex program.
+//!
+//! Derives the program's PDAs, builds every instruction with its account list
+//! and decodes program accounts into the on-chain types, so bots and keepers
+//! can be written without hand-rolled layouts.
+
+pub mod decode;
+pub mod instructions;
+pub mod pda;
+pub mod sim;
+
+pub use fermi_dex::state::{
+    AllowList, Asks, Bbo, Bids, Collateral, Event, EventQueue, LiquidatedFill, MarginAccount,
+    Market, MarketListing, OpenOrderView, OpenOrders, Order, OrderType, Orders, Peg, PegReference,
+    PendingObligation, PriceLevel, PriceView, Registry, Side, TriggerKind, TriggerOrder,
+    TriggerOrders, TriggerParams, TriggerReference,
+};
+pub use fermi_dex::ID as PROGRAM_ID;
+pub use pda::MarketAddresses;
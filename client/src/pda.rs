@@ -0,0 +1,169 @@
+use fermi_dex::token_interface::associated_address;
+use solana_program::pubkey::Pubkey;
+
+use crate::{Side, PROGRAM_ID};
+
+pub fn registry() -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"registry"], &PROGRAM_ID)
+}
+
+pub fn market(coin_mint: &Pubkey, pc_mint: &Pubkey, nonce: u64) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            b"market",
+            coin_mint.as_ref(),
+            pc_mint.as_ref(),
+            &nonce.to_le_bytes(),
+        ],
+        &PROGRAM_ID,
+    )
+}
+
+pub fn bids(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"bids", market.as_ref()], &PROGRAM_ID)
+}
+
+pub fn asks(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"asks", market.as_ref()], &PROGRAM_ID)
+}
+
+pub fn req_q(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"req-q", market.as_ref()], &PROGRAM_ID)
+}
+
+pub fn event_q(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"event-q", market.as_ref()], &PROGRAM_ID)
+}
+
+pub fn trigger_orders(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"trigger-orders", market.as_ref()], &PROGRAM_ID)
+}
+
+pub fn open_orders(market: &Pubkey, authority: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[b"open-orders", market.as_ref(), authority.as_ref()],
+        &PROGRAM_ID,
+    )
+}
+
+/// Wallets admitted to a permissioned market.
+pub fn allow_list(market: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"allow-list", market.as_ref()], &PROGRAM_ID)
+}
+
+/// Temporary wrapped SOL account used while paying out lamports.
+pub fn wsol(open_orders: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"wsol", open_orders.as_ref()], &PROGRAM_ID)
+}
+
+/// A user's collateral account, shared by all of their markets.
+pub fn margin_account(owner: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"margin", owner.as_ref()], &PROGRAM_ID)
+}
+
+/// The margin account's associated token account holding `mint`.
+pub fn margin_vault(owner: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Pubkey {
+    associated_address(&margin_account(owner).0, mint, token_program)
+}
+
+/// Every market-level address, derived from the two mints and the nonce.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MarketAddresses {
+    pub market: Pubkey,
+    pub coin_mint: Pubkey,
+    pub pc_mint: Pubkey,
+    pub nonce: u64,
+    pub coin_token_program: Pubkey,
+    pub pc_token_program: Pubkey,
+    pub coin_vault: Pubkey,
+    pub pc_vault: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub req_q: Pubkey,
+    pub event_q: Pubkey,
+    pub trigger_orders: Pubkey,
+}
+
+impl MarketAddresses {
+    /// The pair's first market, for mints that both belong to the legacy token program.
+    pub fn derive(coin_mint: Pubkey, pc_mint: Pubkey) -> Self {
+        Self::derive_with_nonce(coin_mint, pc_mint, 0)
+    }
+
+    pub fn derive_with_nonce(coin_mint: Pubkey, pc_mint: Pubkey, nonce: u64) -> Self {
+        Self::derive_with_programs(
+            coin_mint,
+            pc_mint,
+            nonce,
+            anchor_spl::token::ID,
+            anchor_spl::token::ID,
+        )
+    }
+
+    /// `coin_token_program` and `pc_token_program` are the programs owning
+    /// each mint, either the legacy token program or Token-2022.
+    pub fn derive_with_programs(
+        coin_mint: Pubkey,
+        pc_mint: Pubkey,
+        nonce: u64,
+        coin_token_program: Pubkey,
+        pc_token_program: Pubkey,
+    ) -> Self {
+        let (market, _) = market(&coin_mint, &pc_mint, nonce);
+        MarketAddresses {
+            market,
+            coin_mint,
+            pc_mint,
+            nonce,
+            coin_token_program,
+            pc_token_program,
+            coin_vault: associated_address(&market, &coin_mint, &coin_token_program),
+            pc_vault: associated_address(&market, &pc_mint, &pc_token_program),
+            bids: bids(&market).0,
+            asks: asks(&market).0,
+            req_q: req_q(&market).0,
+            event_q: event_q(&market).0,
+            trigger_orders: trigger_orders(&market).0,
+        }
+    }
+
+    pub fn open_orders(&self, authority: &Pubkey) -> Pubkey {
+        open_orders(&self.market, authority).0
+    }
+
+    /// The associated token account of `owner` for this market's coin mint.
+    pub fn coin_wallet(&self, owner: &Pubkey) -> Pubkey {
+        associated_address(owner, &self.coin_mint, &self.coin_token_program)
+    }
+
+    /// The associated token account of `owner` for this market's pc mint.
+    pub fn pc_wallet(&self, owner: &Pubkey) -> Pubkey {
+        associated_address(owner, &self.pc_mint, &self.pc_token_program)
+    }
+
+    /// The mint orders on `side` lock and the program owning it.
+    pub fn payer_mint(&self, side: Side) -> (Pubkey, Pubkey) {
+        match side {
+            Side::Bid => (self.pc_mint, self.pc_token_program),
+            Side::Ask => (self.coin_mint, self.coin_token_program),
+        }
+    }
+
+    /// The side whose orders lock `mint`. Panics if it isn't one of the market's.
+    pub fn mint_side(&self, mint: &Pubkey) -> Side {
+        if *mint == self.pc_mint {
+            Side::Bid
+        } else if *mint == self.coin_mint {
+            Side::Ask
+        } else {
+            panic!("{} is not a mint of this market", mint)
+        }
+    }
+
+    pub fn payer_vault(&self, side: Side) -> Pubkey {
+        match side {
+            Side::Bid => self.pc_vault,
+            Side::Ask => self.coin_vault,
+        }
+    }
+}
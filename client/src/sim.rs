@@ -0,0 +1,219 @@
+//! Off-chain replica of a market that runs the program's own matching code.
+
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+
+use anchor_lang::prelude::*;
+use bytemuck::Zeroable;
+use fermi_dex::errors::ErrorCodeCustom;
+use fermi_dex::state::{OrderBook, RequestProceeds, RequestQueue, RequestView};
+
+use crate::decode::{self, DecodedEvent};
+use crate::{pda, Asks, Bids, EventQueue, Market, OpenOrders, OrderType, Side};
+
+/// The arguments of one `new_order` call, plus the block time it lands at.
+#[derive(Copy, Clone)]
+pub struct OrderRequest {
+    pub authority: Pubkey,
+    pub side: Side,
+    pub limit_price: u64,
+    pub max_coin_qty: u64,
+    pub max_native_pc_qty: u64,
+    pub order_type: OrderType,
+    pub fully_funded: bool,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct PlacedOrder {
+    pub order_id: u128,
+    pub owner: Pubkey,
+    pub owner_slot: u8,
+    /// Events written by this request, in queue order.
+    pub events: Vec<DecodedEvent>,
+
+    pub coin_unlocked: u64,
+    pub native_pc_unlocked: u64,
+    pub coin_credit: u64,
+    pub native_pc_credit: u64,
+    pub coin_debit: u64,
+    pub native_pc_debit: u64,
+}
+
+pub struct Simulator {
+    pub market_key: Pubkey,
+    pub market: Market,
+    pub bids: Bids,
+    pub asks: Asks,
+    pub req_q: RequestQueue,
+    pub event_q: Box<EventQueue>,
+    /// Keyed by open orders PDA, like event owners.
+    pub open_orders: HashMap<Pubkey, OpenOrders>,
+}
+
+impl Simulator {
+    /// An empty book for `market`.
+    pub fn new(market_key: Pubkey, market: Market) -> Self {
+        Simulator {
+            market_key,
+            market,
+            bids: Bids::default(),
+            asks: Asks::default(),
+            req_q: RequestQueue::default(),
+            event_q: Box::new(EventQueue::zeroed()),
+            open_orders: HashMap::new(),
+        }
+    }
+
+    /// A replica seeded from decoded on-chain accounts.
+    pub fn from_chain(
+        market_key: Pubkey,
+        market: Market,
+        bids: Bids,
+        asks: Asks,
+        req_q: RequestQueue,
+        event_q: EventQueue,
+    ) -> Self {
+        Simulator {
+            market_key,
+            market,
+            bids,
+            asks,
+            req_q,
+            event_q: Box::new(event_q),
+            open_orders: HashMap::new(),
+        }
+    }
+
+    /// Seeds the slot bookkeeping of an owner, e.g. from `decode::open_orders`.
+    pub fn set_open_orders(&mut self, authority: &Pubkey, open_orders: OpenOrders) {
+        let key = pda::open_orders(&self.market_key, authority).0;
+        self.open_orders.insert(key, open_orders);
+    }
+
+    /// Empties the event queue so long backtests don't run out of slots.
+    pub fn clear_events(&mut self) {
+        *self.event_q = EventQueue::zeroed();
+    }
+
+    /// Runs one `new_order` through the matching engine. Like a failed
+    /// transaction, a request that errors or overruns the event queue leaves
+    /// the replica untouched.
+    pub fn place(&mut self, request: &OrderRequest) -> Result<PlacedOrder> {
+        let owner = pda::open_orders(&self.market_key, &request.authority).0;
+
+        let native_pc_qty_locked = match request.side {
+            Side::Bid => Some(
+                request
+                    .max_native_pc_qty
+                    .checked_mul(self.market.pc_lot_size)
+                    .ok_or(error!(ErrorCodeCustom::InsufficientFunds))?,
+            ),
+            Side::Ask => {
+                request
+                    .max_coin_qty
+                    .checked_mul(self.market.coin_lot_size)
+                    .ok_or(error!(ErrorCodeCustom::InsufficientFunds))?;
+                None
+            }
+        };
+
+        let market = self.market.clone();
+        let bids = self.bids.clone();
+        let asks = self.asks.clone();
+        let req_q = self.req_q.clone();
+        let event_q = *self.event_q;
+        let open_orders = self.open_orders.get(&owner).cloned();
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.process(request, owner, native_pc_qty_locked)
+        }))
+        .unwrap_or_else(|_| Err(error!(ErrorCodeCustom::QueueAlreadyFull)));
+
+        if result.is_err() {
+            self.market = market;
+            self.bids = bids;
+            self.asks = asks;
+            self.req_q = req_q;
+            *self.event_q = event_q;
+            match open_orders {
+                Some(open_orders) => self.open_orders.insert(owner, open_orders),
+                None => self.open_orders.remove(&owner),
+            };
+        }
+        result
+    }
+
+    /// Places every request in order, stopping at the first failure.
+    pub fn replay<I>(&mut self, requests: I) -> Result<Vec<PlacedOrder>>
+    where
+        I: IntoIterator<Item = OrderRequest>,
+    {
+        requests
+            .into_iter()
+            .map(|request| self.place(&request))
+            .collect()
+    }
+
+    fn process(
+        &mut self,
+        request: &OrderRequest,
+        owner: Pubkey,
+        native_pc_qty_locked: Option<u64>,
+    ) -> Result<PlacedOrder> {
+        let market_key = self.market_key;
+        let open_orders = self.open_orders.entry(owner).or_default();
+        if !open_orders.is_initialized {
+            open_orders.init(market_key, request.authority)?;
+        }
+
+        let order_id = self.req_q.gen_order_id(request.limit_price, request.side);
+        let owner_slot = open_orders.add_order(order_id, request.side)?;
+        let view = RequestView::NewOrder {
+            side: request.side,
+            order_type: request.order_type,
+            order_id,
+            owner,
+            owner_slot,
+            max_coin_qty: request.max_coin_qty,
+            native_pc_qty_locked,
+            fully_funded: request.fully_funded,
+        };
+        let mut proceeds = RequestProceeds {
+            coin_unlocked: 0,
+            native_pc_unlocked: 0,
+            coin_credit: 0,
+            native_pc_credit: 0,
+            coin_debit: 0,
+            native_pc_debit: 0,
+            jit_data: vec![],
+        };
+
+        let head_before = self.event_q.head;
+        let mut order_book = OrderBook {
+            bids: &mut self.bids,
+            asks: &mut self.asks,
+            market: &mut self.market,
+        };
+        order_book.process_request(&view, &mut self.event_q, &mut proceeds, request.timestamp)?;
+        let head_after = self.event_q.head;
+
+        let buf = self.event_q.buf;
+        let events = (head_before + 1..=head_after)
+            .filter_map(|slot| decode::event(slot as usize, &buf[slot as usize]))
+            .collect();
+
+        Ok(PlacedOrder {
+            order_id,
+            owner,
+            owner_slot,
+            events,
+            coin_unlocked: proceeds.coin_unlocked,
+            native_pc_unlocked: proceeds.native_pc_unlocked,
+            coin_credit: proceeds.coin_credit,
+            native_pc_credit: proceeds.native_pc_credit,
+            coin_debit: proceeds.coin_debit,
+            native_pc_debit: proceeds.native_pc_debit,
+        })
+    }
+}
@@ -0,0 +1,78 @@
+//! Closing an open orders account once it has no orders or balances left,
+//! with its rent paid out to another account.
+
+mod common;
+
+use common::*;
+use fermi_dex_client::{decode, instructions, MarketAddresses, OrderType, Side};
+use solana_sdk::signature::{Keypair, Signer};
+
+#[tokio::test]
+async fn emptied_open_orders_is_closed() {
+    let mut ctx = program_test().start_with_context().await;
+    create_registry(&mut ctx).await;
+
+    let coin_mint = create_mint(&mut ctx).await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive(coin_mint, pc_mint);
+    let authority = ctx.payer.pubkey();
+    send(
+        &mut ctx,
+        &[instructions::initialize_market(
+            &m,
+            authority,
+            authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let carol = create_user(&mut ctx, &m, 0, 1_000).await;
+    let open_orders = m.open_orders(&carol.pubkey());
+    let recipient = Keypair::new().pubkey();
+    let close = instructions::close_open_orders(&m, carol.pubkey(), recipient);
+
+    let ix = instructions::new_order(
+        &m,
+        carol.pubkey(),
+        m.pc_wallet(&carol.pubkey()),
+        Side::Bid,
+        1,
+        1,
+        1,
+        OrderType::Limit,
+        false,
+    );
+    send(&mut ctx, &[ix], &[&carol]).await.unwrap();
+    let order_id = decode::bids(&account_data(&mut ctx, m.bids).await)
+        .unwrap()
+        .sorted[0]
+        .order_id;
+
+    // a resting order keeps the account open
+    assert!(send(&mut ctx, &[close.clone()], &[&carol]).await.is_err());
+
+    // and so does a free balance
+    let ix = instructions::cancel_bid(&m, carol.pubkey(), order_id);
+    let deposit = instructions::deposit(&m, carol.pubkey(), m.pc_mint, 100);
+    send(&mut ctx, &[ix, deposit], &[&carol]).await.unwrap();
+    let withdraw = instructions::withdraw(&m, carol.pubkey(), m.pc_mint, 100);
+    assert!(
+        send(&mut ctx, &[close.clone(), withdraw.clone()], &[&carol])
+            .await
+            .is_err()
+    );
+
+    let rent = ctx.banks_client.get_balance(open_orders).await.unwrap();
+    send(&mut ctx, &[withdraw, close], &[&carol]).await.unwrap();
+    assert_eq!(ctx.banks_client.get_balance(recipient).await.unwrap(), rent);
+    assert!(ctx
+        .banks_client
+        .get_account(open_orders)
+        .await
+        .unwrap()
+        .is_none());
+}
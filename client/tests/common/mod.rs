@@ -0,0 +1,164 @@
+//! Helpers shared by the `solana-program-test` suites.
+
+#![allow(dead_code)]
+
+use anchor_lang::prelude::{AccountInfo, Pubkey};
+use fermi_dex::token_interface::associated_address;
+use fermi_dex_client::{instructions, MarketAddresses, Side, PROGRAM_ID};
+use solana_program::entrypoint::ProgramResult;
+use solana_program::instruction::Instruction;
+use solana_program::program_pack::Pack;
+use solana_program::system_instruction;
+use solana_program_test::{processor, ProgramTest, ProgramTestContext};
+use solana_sdk::signature::{Keypair, Signer};
+use solana_sdk::transaction::Transaction;
+use spl_token_2022::extension::StateWithExtensions;
+
+pub const COIN_LOT_SIZE: u64 = 1;
+pub const PC_LOT_SIZE: u64 = 1;
+
+// anchor's entry ties the account slice to the account lifetime, which the
+// builtin processor signature does not.
+pub fn process_instruction(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    data: &[u8],
+) -> ProgramResult {
+    let accounts = Box::leak(Box::new(accounts.to_vec()));
+    fermi_dex::entry(program_id, accounts, data)
+}
+
+/// The program plus in-process Token-2022 and associated token programs. The
+/// associated token program bundled with solana-program-test predates
+/// Token-2022, which `initialize_market` needs to create vaults under.
+pub fn program_test() -> ProgramTest {
+    let mut test = ProgramTest::new("fermi_dex", PROGRAM_ID, processor!(process_instruction));
+    test.add_program(
+        "spl_token_2022",
+        spl_token_2022::id(),
+        processor!(spl_token_2022::processor::Processor::process),
+    );
+    test.add_program(
+        "spl_associated_token_account",
+        spl_associated_token_account::id(),
+        processor!(spl_associated_token_account::processor::process_instruction),
+    );
+    test
+}
+
+pub async fn send(
+    ctx: &mut ProgramTestContext,
+    ixs: &[Instruction],
+    signers: &[&Keypair],
+) -> Result<(), String> {
+    let blockhash = ctx.banks_client.get_latest_blockhash().await.unwrap();
+    let mut all_signers = vec![&ctx.payer];
+    all_signers.extend_from_slice(signers);
+    let tx =
+        Transaction::new_signed_with_payer(ixs, Some(&ctx.payer.pubkey()), &all_signers, blockhash);
+    ctx.banks_client
+        .process_transaction(tx)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+pub async fn account_data(ctx: &mut ProgramTestContext, key: Pubkey) -> Vec<u8> {
+    ctx.banks_client
+        .get_account(key)
+        .await
+        .unwrap()
+        .unwrap_or_else(|| panic!("missing account {}", key))
+        .data
+}
+
+/// Unpacks legacy and Token-2022 accounts alike, ignoring any extensions.
+pub async fn token(ctx: &mut ProgramTestContext, key: Pubkey) -> spl_token_2022::state::Account {
+    StateWithExtensions::<spl_token_2022::state::Account>::unpack(&account_data(ctx, key).await)
+        .unwrap()
+        .base
+}
+
+pub async fn balance(ctx: &mut ProgramTestContext, key: Pubkey) -> u64 {
+    token(ctx, key).await.amount
+}
+/// The registry, owned by the test payer and free to list on.
+pub async fn create_registry(ctx: &mut ProgramTestContext) {
+    let authority = ctx.payer.pubkey();
+    send(ctx, &[instructions::init_registry(authority, 0)], &[])
+        .await
+        .unwrap();
+}
+
+pub async fn create_mint(ctx: &mut ProgramTestContext) -> Pubkey {
+    let mint = Keypair::new();
+    let rent = ctx.banks_client.get_rent().await.unwrap();
+    let payer = ctx.payer.pubkey();
+    let ixs = [
+        system_instruction::create_account(
+            &payer,
+            &mint.pubkey(),
+            rent.minimum_balance(spl_token::state::Mint::LEN),
+            spl_token::state::Mint::LEN as u64,
+            &spl_token::id(),
+        ),
+        spl_token::instruction::initialize_mint(&spl_token::id(), &mint.pubkey(), &payer, None, 0)
+            .unwrap(),
+    ];
+    send(ctx, &ixs, &[&mint]).await.unwrap();
+    mint.pubkey()
+}
+
+/// Creates `owner`'s associated token account for `mint` under
+/// `token_program`. The 1.0 builder always names the legacy token program.
+pub fn create_associated_account(
+    payer: &Pubkey,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Instruction {
+    let mut ix = spl_associated_token_account::instruction::create_associated_token_account(
+        payer, owner, mint,
+    );
+    ix.accounts[1].pubkey = associated_address(owner, mint, token_program);
+    ix.accounts[5].pubkey = *token_program;
+    ix
+}
+
+/// A funded user with coin and pc wallets holding the given amounts.
+pub async fn create_user(
+    ctx: &mut ProgramTestContext,
+    m: &MarketAddresses,
+    coin: u64,
+    pc: u64,
+) -> Keypair {
+    let user = Keypair::new();
+    let payer = ctx.payer.pubkey();
+    let mut ixs = vec![system_instruction::transfer(
+        &payer,
+        &user.pubkey(),
+        1_000_000_000,
+    )];
+    for (side, amount) in [(Side::Ask, coin), (Side::Bid, pc)] {
+        let (mint, token_program) = m.payer_mint(side);
+        ixs.push(create_associated_account(
+            &payer,
+            &user.pubkey(),
+            &mint,
+            &token_program,
+        ));
+        let wallet = associated_address(&user.pubkey(), &mint, &token_program);
+        ixs.push(
+            spl_token_2022::instruction::mint_to(
+                &token_program,
+                &mint,
+                &wallet,
+                &payer,
+                &[],
+                amount,
+            )
+            .unwrap(),
+        );
+    }
+    send(ctx, &ixs, &[]).await.unwrap();
+    user
+}
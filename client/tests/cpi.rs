@@ -0,0 +1,199 @@
+//! A vault program whose PDA owns an `OpenOrders` account, trading through
+//! CPI with a separate rent payer, plus a keeper delegated to trade (but not
+//! withdraw) on the vault's behalf.
+
+mod common;
+
+use anchor_lang::prelude::{AccountInfo, Pubkey};
+use common::*;
+use fermi_dex_client::{decode, instructions, MarketAddresses, OrderType, Side, PROGRAM_ID};
+use solana_program::entrypoint::ProgramResult;
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program::program::invoke_signed;
+use solana_program_test::{processor, ProgramTestContext};
+use solana_sdk::signature::Signer;
+
+const VAULT_SEED: &[u8] = b"vault";
+
+/// Mock caller: forwards its instruction data to the program in the first
+/// account, signing for its vault PDA.
+fn process_vault(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+    let (vault, bump) = Pubkey::find_program_address(&[VAULT_SEED], program_id);
+    let ix = Instruction {
+        program_id: *accounts[0].key,
+        accounts: accounts[1..]
+            .iter()
+            .map(|info| AccountMeta {
+                pubkey: *info.key,
+                is_signer: info.is_signer || *info.key == vault,
+                is_writable: info.is_writable,
+            })
+            .collect(),
+        data: data.to_vec(),
+    };
+    invoke_signed(&ix, accounts, &[&[VAULT_SEED, &[bump]]])
+}
+
+/// Wraps a fermi-dex instruction so the vault program signs it for `vault`.
+fn via_vault(vault_program: &Pubkey, vault: &Pubkey, ix: Instruction) -> Instruction {
+    let mut accounts = vec![AccountMeta::new_readonly(PROGRAM_ID, false)];
+    accounts.extend(ix.accounts.into_iter().map(|mut meta| {
+        if meta.pubkey == *vault {
+            meta.is_signer = false;
+        }
+        meta
+    }));
+    Instruction {
+        program_id: *vault_program,
+        accounts,
+        data: ix.data,
+    }
+}
+
+fn bid_for(
+    m: &MarketAddresses,
+    owner: Pubkey,
+    authority: Pubkey,
+    rent_payer: Pubkey,
+    limit_price: u64,
+    max_coin_qty: u64,
+    max_native_pc_qty: u64,
+) -> Instruction {
+    instructions::new_order_for(
+        m,
+        owner,
+        authority,
+        rent_payer,
+        m.pc_wallet(&authority),
+        Side::Bid,
+        limit_price,
+        max_coin_qty,
+        max_native_pc_qty,
+        OrderType::Limit,
+        true,
+    )
+}
+
+async fn vault_open_orders(
+    ctx: &mut ProgramTestContext,
+    m: &MarketAddresses,
+    vault: &Pubkey,
+) -> fermi_dex_client::OpenOrders {
+    decode::open_orders(&account_data(ctx, m.open_orders(vault)).await).unwrap()
+}
+
+#[tokio::test]
+async fn pda_owner_and_delegate() {
+    let vault_program = Pubkey::new_unique();
+    let mut test = program_test();
+    test.add_program("mock_vault", vault_program, processor!(process_vault));
+    let mut ctx = test.start_with_context().await;
+
+    let coin_mint = create_mint(&mut ctx).await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive(coin_mint, pc_mint);
+    let market_authority = ctx.payer.pubkey();
+    create_registry(&mut ctx).await;
+    send(
+        &mut ctx,
+        &[instructions::initialize_market(
+            &m,
+            market_authority,
+            market_authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let (vault, _) = Pubkey::find_program_address(&[VAULT_SEED], &vault_program);
+    let vault_pc = m.pc_wallet(&vault);
+    let payer = ctx.payer.pubkey();
+    let ixs = [
+        spl_associated_token_account::create_associated_token_account(&payer, &vault, &coin_mint),
+        spl_associated_token_account::create_associated_token_account(&payer, &vault, &pc_mint),
+        spl_token::instruction::mint_to(&spl_token::id(), &pc_mint, &vault_pc, &payer, &[], 10_000)
+            .unwrap(),
+    ];
+    send(&mut ctx, &ixs, &[]).await.unwrap();
+
+    let keeper = create_user(&mut ctx, &m, 0, 10_000).await;
+    let stranger = create_user(&mut ctx, &m, 0, 0).await;
+
+    // the PDA can't fund its own OpenOrders, the transaction fee payer does
+    let ix = bid_for(&m, vault, vault, payer, 10, 100, 1_000);
+    send(&mut ctx, &[via_vault(&vault_program, &vault, ix)], &[])
+        .await
+        .unwrap();
+
+    let oo = vault_open_orders(&mut ctx, &m, &vault).await;
+    assert_eq!(oo.authority, vault);
+    assert_eq!(oo.delegate, Pubkey::default());
+    assert_eq!((oo.native_pc_free, oo.native_pc_total), (0, 1_000));
+    assert_eq!(balance(&mut ctx, vault_pc).await, 9_000);
+    assert_eq!(balance(&mut ctx, m.pc_vault).await, 1_000);
+    let first_bid = oo.orders[0];
+
+    let ix = instructions::set_delegate(&m, vault, keeper.pubkey());
+    send(&mut ctx, &[via_vault(&vault_program, &vault, ix)], &[])
+        .await
+        .unwrap();
+    let oo = vault_open_orders(&mut ctx, &m, &vault).await;
+    assert_eq!(oo.delegate, keeper.pubkey());
+
+    // only the owner and its delegate may cancel
+    let ix = instructions::cancel_bid_for(&m, vault, stranger.pubkey(), first_bid);
+    assert!(send(&mut ctx, &[ix], &[&stranger]).await.is_err());
+
+    let ix = instructions::cancel_bid_for(&m, vault, keeper.pubkey(), first_bid);
+    send(&mut ctx, &[ix], &[&keeper]).await.unwrap();
+    let oo = vault_open_orders(&mut ctx, &m, &vault).await;
+    assert_eq!((oo.native_pc_free, oo.native_pc_total), (1_000, 1_000));
+
+    // the delegate trades the vault's free balance
+    let ix = bid_for(&m, vault, keeper.pubkey(), keeper.pubkey(), 10, 50, 500);
+    send(&mut ctx, &[ix], &[&keeper]).await.unwrap();
+    let oo = vault_open_orders(&mut ctx, &m, &vault).await;
+    assert_eq!((oo.native_pc_free, oo.native_pc_total), (500, 1_000));
+    assert_eq!(
+        balance(&mut ctx, m.pc_wallet(&keeper.pubkey())).await,
+        10_000
+    );
+    let second_bid = oo.orders[0];
+
+    // but can't top it up from its own wallet
+    let ix = bid_for(&m, vault, keeper.pubkey(), keeper.pubkey(), 10, 60, 600);
+    assert!(send(&mut ctx, &[ix], &[&keeper]).await.is_err());
+
+    // nor withdraw it
+    let mut ix = instructions::withdraw(&m, vault, m.pc_mint, 500);
+    for meta in ix.accounts.iter_mut().filter(|meta| meta.pubkey == vault) {
+        *meta = AccountMeta::new_readonly(keeper.pubkey(), true);
+    }
+    assert!(send(&mut ctx, &[ix], &[&keeper]).await.is_err());
+
+    // revoking the delegate locks the keeper out
+    let ix = instructions::set_delegate(&m, vault, Pubkey::default());
+    send(&mut ctx, &[via_vault(&vault_program, &vault, ix)], &[])
+        .await
+        .unwrap();
+    let ix = instructions::cancel_bid_for(&m, vault, keeper.pubkey(), second_bid);
+    assert!(send(&mut ctx, &[ix], &[&keeper]).await.is_err());
+
+    // the vault cancels and withdraws through CPI
+    let ix = instructions::cancel_bid_for(&m, vault, vault, second_bid);
+    send(&mut ctx, &[via_vault(&vault_program, &vault, ix)], &[])
+        .await
+        .unwrap();
+    let ix = instructions::withdraw(&m, vault, m.pc_mint, 1_000);
+    send(&mut ctx, &[via_vault(&vault_program, &vault, ix)], &[])
+        .await
+        .unwrap();
+
+    let oo = vault_open_orders(&mut ctx, &m, &vault).await;
+    assert_eq!(oo.native_pc_free, 0);
+    assert_eq!(balance(&mut ctx, vault_pc).await, 10_000);
+    assert_eq!(balance(&mut ctx, m.pc_vault).await, 0);
+}
@@ -0,0 +1,304 @@
+//! Failures carry the error that names what went wrong, rather than a
+//! generic one or a panic.
+
+mod common;
+
+use common::*;
+use fermi_dex::errors::ErrorCodeCustom;
+use fermi_dex::state::PRICE_FEED_LEN;
+use fermi_dex_client::decode::{self, EventKind, Settlement};
+use fermi_dex_client::{instructions, MarketAddresses, OrderType, Peg, PegReference, Side};
+use solana_program::pubkey::Pubkey;
+use solana_program::system_instruction;
+use solana_sdk::signature::{Keypair, Signer};
+
+fn assert_error(result: Result<(), String>, expected: ErrorCodeCustom) {
+    let code = format!("custom program error: {:#x}", u32::from(expected));
+    let err = result.unwrap_err();
+    assert!(err.contains(&code), "expected {}, got {}", code, err);
+}
+
+#[tokio::test]
+async fn each_failure_has_its_own_error() {
+    let mut ctx = program_test().start_with_context().await;
+    create_registry(&mut ctx).await;
+
+    let coin_mint = create_mint(&mut ctx).await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive(coin_mint, pc_mint);
+    let authority = ctx.payer.pubkey();
+
+    let ix = instructions::initialize_market(&m, authority, authority, 0, PC_LOT_SIZE);
+    assert_error(
+        send(&mut ctx, &[ix], &[]).await,
+        ErrorCodeCustom::InvalidLotSize,
+    );
+    send(
+        &mut ctx,
+        &[instructions::initialize_market(
+            &m,
+            authority,
+            authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let alice = create_user(&mut ctx, &m, 0, 10_000).await;
+    let bob = create_user(&mut ctx, &m, 10_000, 0).await;
+
+    let ix = instructions::new_order(
+        &m,
+        alice.pubkey(),
+        m.pc_wallet(&alice.pubkey()),
+        Side::Bid,
+        10,
+        100,
+        1_000,
+        OrderType::Limit,
+        false,
+    );
+    send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+    let order_id = decode::bids(&account_data(&mut ctx, m.bids).await)
+        .unwrap()
+        .sorted[0]
+        .order_id;
+
+    // bob can't cancel alice's order
+    let ix = instructions::cancel_bid_for(&m, alice.pubkey(), bob.pubkey(), order_id);
+    assert_error(
+        send(&mut ctx, &[ix], &[&bob]).await,
+        ErrorCodeCustom::WrongAuthority,
+    );
+
+    // a pegged order needs a limit price to be sized by
+    let peg = Peg {
+        reference: PegReference::BestBid,
+        offset_ticks: 0,
+        offset_bps: 0,
+    };
+    let ix = instructions::new_pegged_order(
+        &m,
+        bob.pubkey(),
+        m.coin_wallet(&bob.pubkey()),
+        Side::Ask,
+        0,
+        10,
+        0,
+        peg,
+        false,
+    );
+    assert_error(
+        send(&mut ctx, &[ix], &[&bob]).await,
+        ErrorCodeCustom::InvalidPrice,
+    );
+
+    // the peg oracle is only read from an account of the configured program
+    let oracle_program = Pubkey::new_unique();
+    let feed = Keypair::new();
+    let rent = ctx.banks_client.get_rent().await.unwrap();
+    let ixs = [
+        system_instruction::create_account(
+            &authority,
+            &feed.pubkey(),
+            rent.minimum_balance(PRICE_FEED_LEN),
+            PRICE_FEED_LEN as u64,
+            &Pubkey::new_unique(),
+        ),
+        instructions::set_peg_oracle(&m, authority, feed.pubkey(), oracle_program),
+    ];
+    send(&mut ctx, &ixs, &[&feed]).await.unwrap();
+    let ix = instructions::with_peg_oracle(
+        instructions::new_pegged_order(
+            &m,
+            bob.pubkey(),
+            m.coin_wallet(&bob.pubkey()),
+            Side::Ask,
+            10,
+            10,
+            0,
+            peg,
+            false,
+        ),
+        feed.pubkey(),
+    );
+    assert_error(
+        send(&mut ctx, &[ix], &[&bob]).await,
+        ErrorCodeCustom::InvalidOracle,
+    );
+
+    let ix = instructions::new_order(
+        &m,
+        bob.pubkey(),
+        m.coin_wallet(&bob.pubkey()),
+        Side::Ask,
+        10,
+        100,
+        0,
+        OrderType::Limit,
+        false,
+    );
+    // orders only go to the market's own book
+    let mut swapped = ix.clone();
+    for meta in swapped.accounts.iter_mut() {
+        if meta.pubkey == m.bids {
+            meta.pubkey = m.asks;
+        } else if meta.pubkey == m.asks {
+            meta.pubkey = m.bids;
+        }
+    }
+    assert_error(
+        send(&mut ctx, &[swapped], &[&bob]).await,
+        ErrorCodeCustom::WrongMarket,
+    );
+    send(&mut ctx, &[ix], &[&bob]).await.unwrap();
+    let queue = decode::event_queue(&account_data(&mut ctx, m.event_q).await).unwrap();
+    let fills: Vec<_> = decode::events(&queue)
+        .into_iter()
+        .filter(|event| event.kind == EventKind::Fill)
+        .collect();
+    let alice_fill = fills
+        .iter()
+        .find(|event| event.owner == m.open_orders(&alice.pubkey()))
+        .unwrap();
+    let bob_fill = fills
+        .iter()
+        .find(|event| event.order_id == alice_fill.order_id_second)
+        .unwrap();
+    let (alice_slot, bob_slot) = (alice_fill.slot as u8, bob_fill.slot as u8);
+    let unused_slot = (queue.buf.len() - 1) as u8;
+
+    let finalise = |event1_slot, event2_slot| {
+        instructions::finalise_matches_bid(
+            &m,
+            alice.pubkey(),
+            bob.pubkey(),
+            alice.pubkey(),
+            event1_slot,
+            event2_slot,
+        )
+    };
+    assert_error(
+        send(&mut ctx, &[finalise(alice_slot, alice_slot)], &[&alice]).await,
+        ErrorCodeCustom::EventsNotMatched,
+    );
+    assert_error(
+        send(&mut ctx, &[finalise(alice_slot, unused_slot)], &[&alice]).await,
+        ErrorCodeCustom::EventEvicted,
+    );
+
+    // nobody has defaulted while the finalise window is still open
+    let ix = instructions::cancel_with_penalty(
+        &m,
+        alice.pubkey(),
+        bob.pubkey(),
+        Side::Bid,
+        alice_slot,
+        bob_slot,
+    );
+    assert_error(
+        send(&mut ctx, &[ix], &[]).await,
+        ErrorCodeCustom::SettlementWindowOpen,
+    );
+
+    // carol has resting JIT orders, so the market is a delegate on both of her wallets
+    let carol = create_user(&mut ctx, &m, 1_000, 1_000).await;
+    for (side, price, payer) in [
+        (Side::Bid, 5, m.pc_wallet(&carol.pubkey())),
+        (Side::Ask, 50, m.coin_wallet(&carol.pubkey())),
+    ] {
+        let ix = instructions::new_order(
+            &m,
+            carol.pubkey(),
+            payer,
+            side,
+            price,
+            10,
+            500,
+            OrderType::Limit,
+            false,
+        );
+        send(&mut ctx, &[ix], &[&carol]).await.unwrap();
+    }
+
+    // her account isn't the counterparty recorded on the fill
+    let ix = instructions::finalise_matches_bid(
+        &m,
+        alice.pubkey(),
+        carol.pubkey(),
+        alice.pubkey(),
+        alice_slot,
+        bob_slot,
+    );
+    assert_error(
+        send(&mut ctx, &[ix], &[&alice]).await,
+        ErrorCodeCustom::InvalidAuthority,
+    );
+    let ix = instructions::finalise_matches_ask(
+        &m,
+        alice.pubkey(),
+        carol.pubkey(),
+        bob.pubkey(),
+        alice_slot,
+        bob_slot,
+    );
+    assert_error(
+        send(&mut ctx, &[ix], &[&alice]).await,
+        ErrorCodeCustom::InvalidAuthority,
+    );
+
+    // nor can her delegated wallets be made to fund either side of it
+    let ix = instructions::finalise_matches_bid(
+        &m,
+        alice.pubkey(),
+        bob.pubkey(),
+        carol.pubkey(),
+        alice_slot,
+        bob_slot,
+    );
+    assert_error(
+        send(&mut ctx, &[ix], &[&alice]).await,
+        ErrorCodeCustom::InvalidAuthority,
+    );
+    let ix = instructions::finalise_matches_ask(
+        &m,
+        alice.pubkey(),
+        bob.pubkey(),
+        carol.pubkey(),
+        alice_slot,
+        bob_slot,
+    );
+    assert_error(
+        send(&mut ctx, &[ix], &[&alice]).await,
+        ErrorCodeCustom::InvalidAuthority,
+    );
+
+    // and she can't sign for alice's account
+    let ix = instructions::finalise_matches_bid_for(
+        &m,
+        alice.pubkey(),
+        carol.pubkey(),
+        bob.pubkey(),
+        alice.pubkey(),
+        alice_slot,
+        bob_slot,
+    );
+    assert_error(
+        send(&mut ctx, &[ix], &[&carol]).await,
+        ErrorCodeCustom::InvalidAuthority,
+    );
+
+    let queue = decode::event_queue(&account_data(&mut ctx, m.event_q).await).unwrap();
+    for slot in [alice_slot, bob_slot] {
+        let event = decode::event(slot.into(), &queue.buf[usize::from(slot)]).unwrap();
+        assert_eq!(event.settlement, Settlement::Pending);
+    }
+    assert_eq!(balance(&mut ctx, m.pc_wallet(&carol.pubkey())).await, 995);
+    assert_eq!(
+        balance(&mut ctx, m.coin_wallet(&carol.pubkey())).await,
+        1_000
+    );
+}
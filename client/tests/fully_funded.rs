@@ -0,0 +1,171 @@
+//! Fully funded orders lock the whole order when placed and give back what
+//! neither fills nor rests.
+
+mod common;
+
+use common::*;
+use fermi_dex_client::decode::{self, EventKind, Settlement};
+use fermi_dex_client::{instructions, MarketAddresses, OrderType, Side};
+use solana_sdk::signature::Signer;
+
+#[tokio::test]
+async fn fully_funded_ioc_releases_what_did_not_fill() {
+    let mut ctx = program_test().start_with_context().await;
+    create_registry(&mut ctx).await;
+
+    let coin_mint = create_mint(&mut ctx).await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive(coin_mint, pc_mint);
+    let authority = ctx.payer.pubkey();
+    send(
+        &mut ctx,
+        &[instructions::initialize_market(
+            &m,
+            authority,
+            authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let alice = create_user(&mut ctx, &m, 0, 10_000).await;
+    let bob = create_user(&mut ctx, &m, 10_000, 0).await;
+
+    // bob rests 4 @ 200
+    let ix = instructions::new_order(
+        &m,
+        bob.pubkey(),
+        m.coin_wallet(&bob.pubkey()),
+        Side::Ask,
+        200,
+        4,
+        0,
+        OrderType::Limit,
+        false,
+    );
+    send(&mut ctx, &[ix], &[&bob]).await.unwrap();
+
+    // alice's IOC for 10 @ 300 locks 3000, fills 4 @ 200 and frees the other 2200
+    let ix = instructions::new_order(
+        &m,
+        alice.pubkey(),
+        m.pc_wallet(&alice.pubkey()),
+        Side::Bid,
+        300,
+        10,
+        3_000,
+        OrderType::ImmediateOrCancel,
+        true,
+    );
+    send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+    assert_eq!(balance(&mut ctx, m.pc_wallet(&alice.pubkey())).await, 7_000);
+    let oo =
+        decode::open_orders(&account_data(&mut ctx, m.open_orders(&alice.pubkey())).await).unwrap();
+    assert_eq!((oo.native_pc_free, oo.native_pc_total), (2_200, 3_000));
+    assert!(decode::bids(&account_data(&mut ctx, m.bids).await)
+        .unwrap()
+        .sorted
+        .is_empty());
+
+    // cancelling the spent order leaves the 800 backing the fill locked
+    let ix = instructions::cancel_bid(&m, alice.pubkey(), oo.orders[0]);
+    send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+    let oo =
+        decode::open_orders(&account_data(&mut ctx, m.open_orders(&alice.pubkey())).await).unwrap();
+    assert_eq!((oo.native_pc_free, oo.native_pc_total), (2_200, 3_000));
+    assert_eq!(oo.free_slot_bits, u8::MAX);
+}
+
+#[tokio::test]
+async fn fully_funded_fills_settle_as_they_match() {
+    let mut ctx = program_test().start_with_context().await;
+    create_registry(&mut ctx).await;
+
+    let coin_mint = create_mint(&mut ctx).await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive(coin_mint, pc_mint);
+    let authority = ctx.payer.pubkey();
+    send(
+        &mut ctx,
+        &[instructions::initialize_market(
+            &m,
+            authority,
+            authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let alice = create_user(&mut ctx, &m, 0, 10_000).await;
+    let bob = create_user(&mut ctx, &m, 10_000, 0).await;
+
+    // bob's fully funded ask pays its 4 coin into the vault and says so on the book
+    let ix = instructions::new_order(
+        &m,
+        bob.pubkey(),
+        m.coin_wallet(&bob.pubkey()),
+        Side::Ask,
+        200,
+        4,
+        0,
+        OrderType::Limit,
+        true,
+    );
+    send(&mut ctx, &[ix], &[&bob]).await.unwrap();
+    assert!(
+        decode::asks(&account_data(&mut ctx, m.asks).await)
+            .unwrap()
+            .sorted[0]
+            .fully_funded
+    );
+
+    // alice's fully funded IOC fills it, and both sides are paid out at once
+    let ix = instructions::with_makers(
+        instructions::new_order(
+            &m,
+            alice.pubkey(),
+            m.pc_wallet(&alice.pubkey()),
+            Side::Bid,
+            300,
+            10,
+            3_000,
+            OrderType::ImmediateOrCancel,
+            true,
+        ),
+        &m,
+        &[bob.pubkey()],
+    );
+    send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+
+    let alice_oo =
+        decode::open_orders(&account_data(&mut ctx, m.open_orders(&alice.pubkey())).await).unwrap();
+    assert_eq!(
+        (alice_oo.native_pc_free, alice_oo.native_pc_total),
+        (2_200, 2_200)
+    );
+    assert_eq!(
+        (alice_oo.native_coin_free, alice_oo.native_coin_total),
+        (4, 4)
+    );
+    let bob_oo =
+        decode::open_orders(&account_data(&mut ctx, m.open_orders(&bob.pubkey())).await).unwrap();
+    assert_eq!((bob_oo.native_pc_free, bob_oo.native_pc_total), (800, 800));
+    assert_eq!((bob_oo.native_coin_free, bob_oo.native_coin_total), (0, 0));
+
+    // nothing is left for finalise_matches_*
+    let queue = decode::event_queue(&account_data(&mut ctx, m.event_q).await).unwrap();
+    let fills: Vec<_> = decode::events(&queue)
+        .into_iter()
+        .filter(|event| event.kind == EventKind::Fill && event.native_qty_paid > 0)
+        .collect();
+    assert_eq!(fills.len(), 2);
+    assert!(fills
+        .iter()
+        .all(|event| event.settlement == Settlement::Settled));
+}
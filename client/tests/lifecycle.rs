@@ -0,0 +1,372 @@
+//! Full JIT lifecycle against the program running in-process under
+//! `solana-program-test`: market setup, crossing orders, both finalise
+//! handlers, the `cancel_with_penalty` timeout path, cancels and the
+//! deposit/withdraw round trip. Vault, wallet and `OpenOrders` balances are
+//! checked after every step.
+
+mod common;
+
+use common::*;
+use fermi_dex_client::decode::{self, Settlement};
+use fermi_dex_client::{instructions, MarketAddresses, OpenOrders, OrderType, Side};
+use solana_program::sysvar::clock::Clock;
+use solana_program_test::ProgramTestContext;
+use solana_sdk::signature::{Keypair, Signer};
+
+async fn open_orders(
+    ctx: &mut ProgramTestContext,
+    m: &MarketAddresses,
+    owner: &Keypair,
+) -> OpenOrders {
+    decode::open_orders(&account_data(ctx, m.open_orders(&owner.pubkey())).await).unwrap()
+}
+
+/// (native_coin_free, native_coin_total, native_pc_free, native_pc_total)
+fn balances(oo: &OpenOrders) -> (u64, u64, u64, u64) {
+    (
+        oo.native_coin_free,
+        oo.native_coin_total,
+        oo.native_pc_free,
+        oo.native_pc_total,
+    )
+}
+
+async fn place(
+    ctx: &mut ProgramTestContext,
+    m: &MarketAddresses,
+    user: &Keypair,
+    side: Side,
+    limit_price: u64,
+    max_coin_qty: u64,
+    max_native_pc_qty: u64,
+) {
+    let payer = match side {
+        Side::Bid => m.pc_wallet(&user.pubkey()),
+        Side::Ask => m.coin_wallet(&user.pubkey()),
+    };
+    let ix = instructions::new_order(
+        m,
+        user.pubkey(),
+        payer,
+        side,
+        limit_price,
+        max_coin_qty,
+        max_native_pc_qty,
+        OrderType::Limit,
+        false,
+    );
+    send(ctx, &[ix], &[user]).await.unwrap();
+}
+
+#[tokio::test]
+async fn jit_lifecycle() {
+    let mut ctx = program_test().start_with_context().await;
+
+    // initialize_market
+    let coin_mint = create_mint(&mut ctx).await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive(coin_mint, pc_mint);
+    let authority = ctx.payer.pubkey();
+    create_registry(&mut ctx).await;
+    send(
+        &mut ctx,
+        &[instructions::initialize_market(
+            &m,
+            authority,
+            authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let market = decode::market(&account_data(&mut ctx, m.market).await).unwrap();
+    assert_eq!(market.coin_vault, m.coin_vault);
+    assert_eq!(market.pc_vault, m.pc_vault);
+    assert_eq!(market.event_q, m.event_q);
+    assert_eq!(market.coin_lot_size, COIN_LOT_SIZE);
+    assert_eq!(market.pc_lot_size, PC_LOT_SIZE);
+    assert_eq!(market.authority, authority);
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 0);
+    assert_eq!(balance(&mut ctx, m.pc_vault).await, 0);
+
+    let alice = create_user(&mut ctx, &m, 0, 10_000).await;
+    let bob = create_user(&mut ctx, &m, 10_000, 0).await;
+    let alice_pc = m.pc_wallet(&alice.pubkey());
+    let bob_coin = m.coin_wallet(&bob.pubkey());
+
+    // alice rests a bid of 100 @ 10: the market is approved for the full
+    // 1000 pc and 1% of it moves to the vault as margin
+    place(&mut ctx, &m, &alice, Side::Bid, 10, 100, 1_000).await;
+    assert_eq!(balance(&mut ctx, alice_pc).await, 9_990);
+    assert_eq!(balance(&mut ctx, m.pc_vault).await, 10);
+    // only the margin that reached the vault counts towards the deposit totals
+    let market = decode::market(&account_data(&mut ctx, m.market).await).unwrap();
+    assert_eq!(market.pc_deposits_total, 10);
+    let wallet = token(&mut ctx, alice_pc).await;
+    assert_eq!(wallet.delegate, Some(m.market).into());
+    assert_eq!(wallet.delegated_amount, 1_000);
+    assert_eq!(
+        balances(&open_orders(&mut ctx, &m, &alice).await),
+        (0, 0, 0, 10)
+    );
+    let bids = decode::bids(&account_data(&mut ctx, m.bids).await).unwrap();
+    assert_eq!(bids.sorted.len(), 1);
+    assert_eq!(bids.sorted[0].qty, 100);
+
+    // bob crosses with an ask of 100 @ 10
+    place(&mut ctx, &m, &bob, Side::Ask, 10, 100, 0).await;
+    assert_eq!(balance(&mut ctx, bob_coin).await, 9_999);
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 1);
+    assert_eq!(
+        balances(&open_orders(&mut ctx, &m, &bob).await),
+        (0, 1, 0, 0)
+    );
+    assert!(decode::bids(&account_data(&mut ctx, m.bids).await)
+        .unwrap()
+        .sorted
+        .is_empty());
+    assert!(decode::asks(&account_data(&mut ctx, m.asks).await)
+        .unwrap()
+        .sorted
+        .is_empty());
+    let market = decode::market(&account_data(&mut ctx, m.market).await).unwrap();
+    assert_eq!(market.last_trade_price, 10);
+    assert_eq!((market.best_bid, market.best_ask), (0, 0));
+
+    // slot 1 is alice's bid out, then maker fill, maker out, taker fill, taker out
+    let queue = decode::event_queue(&account_data(&mut ctx, m.event_q).await).unwrap();
+    let maker_fill = decode::event(2, &queue.buf[2]).unwrap();
+    let taker_fill = decode::event(4, &queue.buf[4]).unwrap();
+    assert_eq!(maker_fill.owner, m.open_orders(&alice.pubkey()));
+    assert_eq!(maker_fill.side, Side::Bid);
+    assert!(maker_fill.maker);
+    assert_eq!(maker_fill.native_qty_paid, 1_000);
+    assert_eq!(maker_fill.native_qty_released, 100);
+    assert_eq!(maker_fill.order_id_second, taker_fill.order_id);
+    assert_eq!(taker_fill.owner, m.open_orders(&bob.pubkey()));
+    assert_eq!(taker_fill.side, Side::Ask);
+    assert_eq!(taker_fill.native_qty_paid, 100);
+    assert_eq!(taker_fill.native_qty_released, 1_000);
+    assert_eq!(taker_fill.settlement, Settlement::Pending);
+
+    // finalise_matches_bid pulls qty_pc / (10 * pc_lot_size) from alice's
+    // wallet through the delegation
+    let ix =
+        instructions::finalise_matches_bid(&m, alice.pubkey(), bob.pubkey(), alice.pubkey(), 2, 4);
+    send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+    assert_eq!(balance(&mut ctx, alice_pc).await, 9_890);
+    assert_eq!(balance(&mut ctx, m.pc_vault).await, 110);
+    let market = decode::market(&account_data(&mut ctx, m.market).await).unwrap();
+    assert_eq!(market.pc_deposits_total, 110);
+    assert_eq!(token(&mut ctx, alice_pc).await.delegated_amount, 900);
+    assert_eq!(
+        balances(&open_orders(&mut ctx, &m, &alice).await),
+        (0, 0, 100, 1_110)
+    );
+    let queue = decode::event_queue(&account_data(&mut ctx, m.event_q).await).unwrap();
+    assert_eq!(
+        decode::event(2, &queue.buf[2]).unwrap().settlement,
+        Settlement::Finalised
+    );
+    assert_eq!(
+        decode::event(4, &queue.buf[4]).unwrap().settlement,
+        Settlement::Pending
+    );
+
+    // finalise_matches_ask pulls bob's coin and settles the pair
+    let ix =
+        instructions::finalise_matches_ask(&m, alice.pubkey(), bob.pubkey(), bob.pubkey(), 2, 4);
+    send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+    assert_eq!(balance(&mut ctx, bob_coin).await, 9_899);
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 101);
+    assert_eq!(token(&mut ctx, bob_coin).await.delegated_amount, 0);
+    let market = decode::market(&account_data(&mut ctx, m.market).await).unwrap();
+    assert_eq!(market.coin_deposits_total, 101);
+    assert_eq!(
+        balances(&open_orders(&mut ctx, &m, &alice).await),
+        (100, 0, 100, 110)
+    );
+    assert_eq!(
+        balances(&open_orders(&mut ctx, &m, &bob).await),
+        (0, 1, 1_000, 0)
+    );
+    let queue = decode::event_queue(&account_data(&mut ctx, m.event_q).await).unwrap();
+    assert_eq!(
+        decode::event(2, &queue.buf[2]).unwrap().settlement,
+        Settlement::Settled
+    );
+    assert_eq!(
+        decode::event(4, &queue.buf[4]).unwrap().settlement,
+        Settlement::Settled
+    );
+
+    // a settled pair cannot be finalised again
+    let ix =
+        instructions::finalise_matches_ask(&m, alice.pubkey(), bob.pubkey(), bob.pubkey(), 2, 4);
+    assert!(send(&mut ctx, &[ix], &[&alice]).await.is_err());
+
+    // second match, 50 @ 10; alice's 100 free pc cover part of the bid so only
+    // 400 is approved and 4 taken as margin, bob's 0.5 margin rounds to zero
+    place(&mut ctx, &m, &alice, Side::Bid, 10, 50, 500).await;
+    assert_eq!(balance(&mut ctx, alice_pc).await, 9_886);
+    assert_eq!(balance(&mut ctx, m.pc_vault).await, 114);
+    assert_eq!(token(&mut ctx, alice_pc).await.delegated_amount, 400);
+    assert_eq!(
+        balances(&open_orders(&mut ctx, &m, &alice).await),
+        (100, 0, 100, 114)
+    );
+
+    place(&mut ctx, &m, &bob, Side::Ask, 10, 50, 0).await;
+    assert_eq!(balance(&mut ctx, bob_coin).await, 9_899);
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 101);
+    assert_eq!(token(&mut ctx, bob_coin).await.delegated_amount, 50);
+    assert_eq!(
+        balances(&open_orders(&mut ctx, &m, &bob).await),
+        (0, 1, 1_000, 0)
+    );
+
+    // alice never funds; the penalty is only claimable after 60 seconds
+    let ix = instructions::cancel_with_penalty(&m, alice.pubkey(), bob.pubkey(), Side::Bid, 7, 9);
+    assert!(send(&mut ctx, &[ix.clone()], &[]).await.is_err());
+
+    let slot = ctx.banks_client.get_root_slot().await.unwrap();
+    ctx.warp_to_slot(slot + 10).unwrap();
+    let mut clock: Clock = ctx.banks_client.get_sysvar().await.unwrap();
+    clock.unix_timestamp += 120;
+    ctx.set_sysvar(&clock);
+
+    send(&mut ctx, &[ix], &[]).await.unwrap();
+    // 1% of alice's 500 pc goes to bob, bob's coin margin (0) is released
+    assert_eq!(
+        balances(&open_orders(&mut ctx, &m, &alice).await),
+        (100, 0, 100, 109)
+    );
+    assert_eq!(
+        balances(&open_orders(&mut ctx, &m, &bob).await),
+        (0, 1, 1_005, 5)
+    );
+    assert_eq!(balance(&mut ctx, m.pc_vault).await, 114);
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 101);
+    let queue = decode::event_queue(&account_data(&mut ctx, m.event_q).await).unwrap();
+    assert_eq!(
+        decode::event(7, &queue.buf[7]).unwrap().settlement,
+        Settlement::CancelledWithPenalty
+    );
+    assert_eq!(
+        decode::event(9, &queue.buf[9]).unwrap().settlement,
+        Settlement::CancelledWithPenalty
+    );
+
+    // cancel_bid / cancel_ask on orders that never matched
+    let carol = create_user(&mut ctx, &m, 100, 1_000).await;
+    let dave = create_user(&mut ctx, &m, 100, 0).await;
+
+    place(&mut ctx, &m, &carol, Side::Bid, 5, 10, 50).await;
+    place(&mut ctx, &m, &dave, Side::Ask, 50, 10, 0).await;
+    let carol_order = open_orders(&mut ctx, &m, &carol).await.orders[0];
+    let dave_order = open_orders(&mut ctx, &m, &dave).await.orders[0];
+    assert_eq!(
+        decode::bids(&account_data(&mut ctx, m.bids).await)
+            .unwrap()
+            .sorted[0]
+            .order_id,
+        carol_order
+    );
+    assert_eq!(
+        decode::asks(&account_data(&mut ctx, m.asks).await)
+            .unwrap()
+            .sorted[0]
+            .order_id,
+        dave_order
+    );
+
+    let ix = instructions::cancel_bid(&m, carol.pubkey(), carol_order);
+    send(&mut ctx, &[ix], &[&carol]).await.unwrap();
+    let ix = instructions::cancel_ask(&m, dave.pubkey(), dave_order);
+    send(&mut ctx, &[ix], &[&dave]).await.unwrap();
+
+    assert!(decode::bids(&account_data(&mut ctx, m.bids).await)
+        .unwrap()
+        .sorted
+        .is_empty());
+    assert!(decode::asks(&account_data(&mut ctx, m.asks).await)
+        .unwrap()
+        .sorted
+        .is_empty());
+    for user in [&carol, &dave] {
+        let oo = open_orders(&mut ctx, &m, user).await;
+        assert_eq!(oo.free_slot_bits, u8::MAX);
+        assert_eq!(oo.orders[0], 0);
+        assert_eq!(balances(&oo), (0, 0, 0, 0));
+    }
+    // margins under one token round to zero, so nothing left the wallets
+    assert_eq!(balance(&mut ctx, m.pc_wallet(&carol.pubkey())).await, 1_000);
+    assert_eq!(balance(&mut ctx, m.coin_wallet(&dave.pubkey())).await, 100);
+
+    // deposit / withdraw, which keep the market's deposit totals in step
+    let carol_pc = m.pc_wallet(&carol.pubkey());
+    let carol_coin = m.coin_wallet(&carol.pubkey());
+    let market = decode::market(&account_data(&mut ctx, m.market).await).unwrap();
+    let (coin_deposits, pc_deposits) = (market.coin_deposits_total, market.pc_deposits_total);
+
+    let ix = instructions::deposit(&m, carol.pubkey(), m.pc_mint, 500);
+    send(&mut ctx, &[ix], &[&carol]).await.unwrap();
+    assert_eq!(balance(&mut ctx, carol_pc).await, 500);
+    assert_eq!(balance(&mut ctx, m.pc_vault).await, 614);
+    assert_eq!(
+        balances(&open_orders(&mut ctx, &m, &carol).await),
+        (0, 0, 500, 500)
+    );
+
+    let ix = instructions::withdraw(&m, carol.pubkey(), m.pc_mint, 200);
+    send(&mut ctx, &[ix], &[&carol]).await.unwrap();
+    assert_eq!(balance(&mut ctx, carol_pc).await, 700);
+    assert_eq!(balance(&mut ctx, m.pc_vault).await, 414);
+    assert_eq!(
+        balances(&open_orders(&mut ctx, &m, &carol).await),
+        (0, 0, 300, 300)
+    );
+
+    let ix = instructions::deposit(&m, carol.pubkey(), m.coin_mint, 50);
+    send(&mut ctx, &[ix], &[&carol]).await.unwrap();
+    assert_eq!(balance(&mut ctx, carol_coin).await, 50);
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 151);
+    assert_eq!(
+        balances(&open_orders(&mut ctx, &m, &carol).await),
+        (50, 50, 300, 300)
+    );
+
+    let ix = instructions::withdraw(&m, carol.pubkey(), m.coin_mint, 20);
+    send(&mut ctx, &[ix], &[&carol]).await.unwrap();
+    assert_eq!(balance(&mut ctx, carol_coin).await, 70);
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 131);
+    assert_eq!(
+        balances(&open_orders(&mut ctx, &m, &carol).await),
+        (30, 30, 300, 300)
+    );
+    let market = decode::market(&account_data(&mut ctx, m.market).await).unwrap();
+    assert_eq!(market.coin_deposits_total, coin_deposits + 30);
+    assert_eq!(market.pc_deposits_total, pc_deposits + 300);
+
+    // only into the vault of the mint deposited
+    let mut ix = instructions::deposit(&m, carol.pubkey(), m.pc_mint, 100);
+    for meta in ix
+        .accounts
+        .iter_mut()
+        .filter(|meta| meta.pubkey == m.pc_vault)
+    {
+        meta.pubkey = m.coin_vault;
+    }
+    assert!(send(&mut ctx, &[ix], &[&carol]).await.is_err());
+
+    // more than the free balance cannot be withdrawn
+    let ix = instructions::withdraw(&m, carol.pubkey(), m.coin_mint, 31);
+    assert!(send(&mut ctx, &[ix], &[&carol]).await.is_err());
+
+    // and alice's locked margin stays where it is
+    let ix = instructions::withdraw(&m, alice.pubkey(), m.pc_mint, 101);
+    assert!(send(&mut ctx, &[ix], &[&alice]).await.is_err());
+}
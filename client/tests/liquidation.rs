@@ -0,0 +1,136 @@
+//! Liquidating a JIT bidder whose locked margin no longer covers its pending
+//! fill: the counterparty, acting as liquidator, collects the penalty and the
+//! bounty and both events end up cancelled with penalty.
+
+mod common;
+
+use common::*;
+use fermi_dex_client::decode::{self, EventKind, Settlement};
+use fermi_dex_client::{
+    instructions, LiquidatedFill, MarketAddresses, OpenOrders, OrderType, Side,
+};
+use solana_program_test::ProgramTestContext;
+use solana_sdk::signature::{Keypair, Signer};
+
+async fn open_orders(
+    ctx: &mut ProgramTestContext,
+    m: &MarketAddresses,
+    owner: &Keypair,
+) -> OpenOrders {
+    decode::open_orders(&account_data(ctx, m.open_orders(&owner.pubkey())).await).unwrap()
+}
+
+#[tokio::test]
+async fn under_margined_bid_is_liquidated() {
+    let mut ctx = program_test().start_with_context().await;
+    create_registry(&mut ctx).await;
+
+    let coin_mint = create_mint(&mut ctx).await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive(coin_mint, pc_mint);
+    let authority = ctx.payer.pubkey();
+    send(
+        &mut ctx,
+        &[instructions::initialize_market(
+            &m,
+            authority,
+            authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let carol = create_user(&mut ctx, &m, 0, 1_000).await;
+    let dave = create_user(&mut ctx, &m, 1_000, 0).await;
+    let carol_pc = m.pc_wallet(&carol.pubkey());
+    let bid = |limit_price, max_coin_qty| {
+        instructions::new_order(
+            &m,
+            carol.pubkey(),
+            carol_pc,
+            Side::Bid,
+            limit_price,
+            max_coin_qty,
+            limit_price * max_coin_qty,
+            OrderType::Limit,
+            false,
+        )
+    };
+
+    // a cancelled 1 @ 1 bid opens carol's account without leaving a balance
+    send(&mut ctx, &[bid(1, 1)], &[&carol]).await.unwrap();
+    let order_id = decode::bids(&account_data(&mut ctx, m.bids).await)
+        .unwrap()
+        .sorted[0]
+        .order_id;
+    let ix = instructions::cancel_bid(&m, carol.pubkey(), order_id);
+    send(&mut ctx, &[ix], &[&carol]).await.unwrap();
+
+    // 500 free pc cover half of a bid of 100 @ 10, so only 5 pc of margin is
+    // locked while the free balance stays withdrawable
+    let ix = instructions::deposit(&m, carol.pubkey(), m.pc_mint, 500);
+    send(&mut ctx, &[ix], &[&carol]).await.unwrap();
+    send(&mut ctx, &[bid(10, 100)], &[&carol]).await.unwrap();
+
+    let ix = instructions::new_order(
+        &m,
+        dave.pubkey(),
+        m.coin_wallet(&dave.pubkey()),
+        Side::Ask,
+        10,
+        100,
+        0,
+        OrderType::Limit,
+        false,
+    );
+    send(&mut ctx, &[ix], &[&dave]).await.unwrap();
+
+    let queue = decode::event_queue(&account_data(&mut ctx, m.event_q).await).unwrap();
+    let fills: Vec<_> = decode::events(&queue)
+        .into_iter()
+        .filter(|event| event.kind == EventKind::Fill)
+        .collect();
+    let carol_fill = fills
+        .iter()
+        .find(|event| event.owner == m.open_orders(&carol.pubkey()))
+        .unwrap();
+    let dave_fill = fills
+        .iter()
+        .find(|event| event.order_id == carol_fill.order_id_second)
+        .unwrap();
+    let fill = LiquidatedFill {
+        event_slot: carol_fill.slot as u8,
+        counterparty_slot: dave_fill.slot as u8,
+    };
+
+    // every pending fill has to be covered
+    let liquidate = |fills| instructions::liquidate(&m, carol.pubkey(), dave.pubkey(), fills, &[]);
+    assert!(send(&mut ctx, &[liquidate(vec![])], &[&dave])
+        .await
+        .is_err());
+
+    // dave, the counterparty, liquidates: 1% of the 1000 owed as the penalty
+    // plus 0.5% as the bounty, and dave's coin margin is released
+    send(&mut ctx, &[liquidate(vec![fill])], &[&dave])
+        .await
+        .unwrap();
+    let carol_oo = open_orders(&mut ctx, &m, &carol).await;
+    assert_eq!(carol_oo.native_pc_free, 490);
+    let dave_oo = open_orders(&mut ctx, &m, &dave).await;
+    assert_eq!((dave_oo.native_coin_free, dave_oo.native_pc_free), (1, 15));
+
+    let queue = decode::event_queue(&account_data(&mut ctx, m.event_q).await).unwrap();
+    for slot in [carol_fill.slot, dave_fill.slot] {
+        assert_eq!(
+            decode::event(slot, &queue.buf[slot]).unwrap().settlement,
+            Settlement::CancelledWithPenalty
+        );
+    }
+
+    // and the account is healthy again
+    let ix = instructions::liquidate(&m, carol.pubkey(), dave.pubkey(), vec![fill, fill], &[]);
+    assert!(send(&mut ctx, &[ix], &[&dave]).await.is_err());
+}
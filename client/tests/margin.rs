@@ -0,0 +1,156 @@
+//! One margin account backing JIT bids on two markets quoted in the same pc
+//! mint: the health check across both, finalise pulling from the margin
+//! vault, cancels releasing reservations and withdrawals kept healthy.
+
+mod common;
+
+use common::*;
+use fermi_dex_client::{decode, instructions, pda, Collateral, MarketAddresses, OrderType, Side};
+use solana_program::pubkey::Pubkey;
+use solana_program_test::ProgramTestContext;
+use solana_sdk::signature::{Keypair, Signer};
+
+async fn collateral(ctx: &mut ProgramTestContext, owner: &Keypair, mint: Pubkey) -> Collateral {
+    let data = account_data(ctx, pda::margin_account(&owner.pubkey()).0).await;
+    let margin_account = decode::margin_account(&data).unwrap();
+    *margin_account
+        .collateral
+        .iter()
+        .find(|c| c.mint == mint)
+        .unwrap()
+}
+
+/// A JIT bid backed by `user`'s margin account.
+async fn margin_bid(
+    ctx: &mut ProgramTestContext,
+    m: &MarketAddresses,
+    user: &Keypair,
+    limit_price: u64,
+    max_coin_qty: u64,
+) -> Result<(), String> {
+    let ix = instructions::new_order(
+        m,
+        user.pubkey(),
+        m.pc_wallet(&user.pubkey()),
+        Side::Bid,
+        limit_price,
+        max_coin_qty,
+        limit_price * max_coin_qty,
+        OrderType::Limit,
+        false,
+    );
+    let ix = instructions::with_margin_account(ix, m, user.pubkey(), Side::Bid);
+    send(ctx, &[ix], &[user]).await
+}
+
+#[tokio::test]
+async fn margin_account_backs_two_markets() {
+    let mut ctx = program_test().start_with_context().await;
+    create_registry(&mut ctx).await;
+
+    let pc_mint = create_mint(&mut ctx).await;
+    let authority = ctx.payer.pubkey();
+    let mut markets = vec![];
+    for _ in 0..2 {
+        let coin_mint = create_mint(&mut ctx).await;
+        let m = MarketAddresses::derive(coin_mint, pc_mint);
+        send(
+            &mut ctx,
+            &[instructions::initialize_market(
+                &m,
+                authority,
+                authority,
+                COIN_LOT_SIZE,
+                PC_LOT_SIZE,
+            )],
+            &[],
+        )
+        .await
+        .unwrap();
+        markets.push(m);
+    }
+    let (m1, m2) = (markets[0], markets[1]);
+
+    let alice = create_user(&mut ctx, &m1, 0, 10_000).await;
+    let bob = create_user(&mut ctx, &m1, 10_000, 0).await;
+    let alice_pc = m1.pc_wallet(&alice.pubkey());
+    let vault = pda::margin_vault(&alice.pubkey(), &pc_mint, &spl_token::id());
+
+    send(
+        &mut ctx,
+        &[
+            instructions::init_margin_account(alice.pubkey()),
+            instructions::deposit_margin(alice.pubkey(), pc_mint, spl_token::id(), 400),
+            instructions::link_margin_account(&m1, alice.pubkey()),
+            instructions::link_margin_account(&m2, alice.pubkey()),
+        ],
+        &[&alice],
+    )
+    .await
+    .unwrap();
+    assert_eq!(balance(&mut ctx, alice_pc).await, 9_600);
+    assert_eq!(balance(&mut ctx, vault).await, 400);
+    let oo = decode::open_orders(&account_data(&mut ctx, m2.open_orders(&alice.pubkey())).await)
+        .unwrap();
+    assert_eq!(oo.margin_account, pda::margin_account(&alice.pubkey()).0);
+
+    // a bid of 100 @ 10 reserves its 1000 pc and pays the 1% margin out of
+    // the margin vault, leaving the wallet without a delegation
+    margin_bid(&mut ctx, &m1, &alice, 10, 100).await.unwrap();
+    assert_eq!(balance(&mut ctx, vault).await, 390);
+    assert_eq!(balance(&mut ctx, m1.pc_vault).await, 10);
+    let wallet = token(&mut ctx, alice_pc).await;
+    assert_eq!((wallet.amount, wallet.delegated_amount), (9_600, 0));
+    let c = collateral(&mut ctx, &alice, pc_mint).await;
+    assert_eq!((c.deposited, c.reserved), (390, 990));
+
+    // the same collateral backs a second market
+    margin_bid(&mut ctx, &m2, &alice, 10, 50).await.unwrap();
+    let c = collateral(&mut ctx, &alice, pc_mint).await;
+    assert_eq!((c.deposited, c.reserved), (385, 1_485));
+    let order_id = decode::bids(&account_data(&mut ctx, m2.bids).await)
+        .unwrap()
+        .sorted[0]
+        .order_id;
+
+    // but 20% of everything reserved has to stay covered
+    assert!(margin_bid(&mut ctx, &m2, &alice, 11, 50).await.is_err());
+    let withdraw =
+        |amount| instructions::withdraw_margin(alice.pubkey(), pc_mint, spl_token::id(), amount);
+    assert!(send(&mut ctx, &[withdraw(200)], &[&alice]).await.is_err());
+    send(&mut ctx, &[withdraw(50)], &[&alice]).await.unwrap();
+    assert_eq!(balance(&mut ctx, alice_pc).await, 9_650);
+    let c = collateral(&mut ctx, &alice, pc_mint).await;
+    assert_eq!((c.deposited, c.reserved), (335, 1_485));
+
+    // bob fills the first bid and finalising pulls alice's side from the margin vault
+    let ix = instructions::new_order(
+        &m1,
+        bob.pubkey(),
+        m1.coin_wallet(&bob.pubkey()),
+        Side::Ask,
+        10,
+        100,
+        0,
+        OrderType::Limit,
+        false,
+    );
+    send(&mut ctx, &[ix], &[&bob]).await.unwrap();
+    let ix =
+        instructions::finalise_matches_bid(&m1, alice.pubkey(), bob.pubkey(), alice.pubkey(), 2, 4);
+    let ix = instructions::with_margin_account(ix, &m1, alice.pubkey(), Side::Bid);
+    send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+    assert_eq!(balance(&mut ctx, vault).await, 235);
+    assert_eq!(balance(&mut ctx, m1.pc_vault).await, 110);
+    assert_eq!(balance(&mut ctx, alice_pc).await, 9_650);
+    let c = collateral(&mut ctx, &alice, pc_mint).await;
+    assert_eq!((c.deposited, c.reserved), (235, 1_385));
+
+    // cancelling the second bid needs the margin account, which it releases
+    let cancel = instructions::cancel_bid(&m2, alice.pubkey(), order_id);
+    assert!(send(&mut ctx, &[cancel.clone()], &[&alice]).await.is_err());
+    let cancel = instructions::with_margin_account(cancel, &m2, alice.pubkey(), Side::Bid);
+    send(&mut ctx, &[cancel], &[&alice]).await.unwrap();
+    let c = collateral(&mut ctx, &alice, pc_mint).await;
+    assert_eq!((c.deposited, c.reserved), (235, 885));
+}
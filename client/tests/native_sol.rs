@@ -0,0 +1,154 @@
+//! A SOL-based market: orders paid in lamports, native deposits and
+//! withdrawals, and settlement unwrapping back to lamports.
+
+mod common;
+
+use common::*;
+use fermi_dex_client::{decode, instructions, pda, MarketAddresses, OrderType, Side};
+use solana_program::program_pack::Pack;
+use solana_program::pubkey::Pubkey;
+use solana_program::rent::Rent;
+use solana_program::system_instruction;
+use solana_program_test::ProgramTestContext;
+use solana_sdk::account::Account;
+use solana_sdk::signature::{Keypair, Signer};
+use spl_token::native_mint;
+
+/// solana-program-test doesn't seed the native mint, so it is added by hand.
+fn native_mint_account() -> Account {
+    let mut data = vec![0; spl_token::state::Mint::LEN];
+    let mint = spl_token::state::Mint {
+        decimals: native_mint::DECIMALS,
+        is_initialized: true,
+        ..Default::default()
+    };
+    spl_token::state::Mint::pack(mint, &mut data).unwrap();
+    Account {
+        lamports: 1_000_000_000,
+        data,
+        owner: spl_token::id(),
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+async fn lamports(ctx: &mut ProgramTestContext, key: Pubkey) -> u64 {
+    ctx.banks_client.get_balance(key).await.unwrap()
+}
+
+async fn coin_free(ctx: &mut ProgramTestContext, m: &MarketAddresses, owner: &Keypair) -> u64 {
+    decode::open_orders(&account_data(ctx, m.open_orders(&owner.pubkey())).await)
+        .unwrap()
+        .native_coin_free
+}
+
+#[tokio::test]
+async fn sol_market_wraps_and_unwraps() {
+    let mut test = program_test();
+    test.add_account(native_mint::id(), native_mint_account());
+    let mut ctx = test.start_with_context().await;
+
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive(native_mint::id(), pc_mint);
+    let authority = ctx.payer.pubkey();
+    create_registry(&mut ctx).await;
+    send(
+        &mut ctx,
+        &[instructions::initialize_market(
+            &m,
+            authority,
+            authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let bob = Keypair::new();
+    let fund = system_instruction::transfer(&authority, &bob.pubkey(), 1_000_000_000);
+    send(&mut ctx, &[fund], &[]).await.unwrap();
+
+    // an ask paid in lamports is wrapped into the vault and fully funded
+    let before = lamports(&mut ctx, bob.pubkey()).await;
+    let ix = instructions::new_order(
+        &m,
+        bob.pubkey(),
+        bob.pubkey(),
+        Side::Ask,
+        10,
+        1_000,
+        0,
+        OrderType::Limit,
+        false,
+    );
+    send(&mut ctx, &[ix], &[&bob]).await.unwrap();
+    let open_orders_rent = lamports(&mut ctx, m.open_orders(&bob.pubkey())).await;
+    assert_eq!(
+        lamports(&mut ctx, bob.pubkey()).await,
+        before - 1_000 - open_orders_rent
+    );
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 1_000);
+    let asks = decode::asks(&account_data(&mut ctx, m.asks).await).unwrap();
+    assert!(asks.sorted[0].fully_funded);
+    let order_id = asks.sorted[0].order_id;
+
+    // native deposits and withdrawals move lamports in and out of free balance
+    let before = lamports(&mut ctx, bob.pubkey()).await;
+    send(
+        &mut ctx,
+        &[instructions::deposit_native(&m, bob.pubkey(), 500_000)],
+        &[&bob],
+    )
+    .await
+    .unwrap();
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 501_000);
+    assert_eq!(coin_free(&mut ctx, &m, &bob).await, 500_000);
+
+    send(
+        &mut ctx,
+        &[instructions::withdraw_native(&m, bob.pubkey(), 200_000)],
+        &[&bob],
+    )
+    .await
+    .unwrap();
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 301_000);
+    assert_eq!(coin_free(&mut ctx, &m, &bob).await, 300_000);
+    assert_eq!(
+        lamports(&mut ctx, bob.pubkey()).await,
+        before - 500_000 + 200_000
+    );
+
+    // settling after a cancel pays everything out as lamports, and the
+    // temporary wSOL account is gone again
+    send(
+        &mut ctx,
+        &[instructions::cancel_ask(&m, bob.pubkey(), order_id)],
+        &[&bob],
+    )
+    .await
+    .unwrap();
+    assert_eq!(coin_free(&mut ctx, &m, &bob).await, 301_000);
+
+    // lamports sent to the wSOL address beforehand don't block the unwrap
+    let wsol = pda::wsol(&m.open_orders(&bob.pubkey())).0;
+    let prefund =
+        system_instruction::transfer(&authority, &wsol, Rent::default().minimum_balance(0));
+    send(&mut ctx, &[prefund], &[]).await.unwrap();
+
+    let before = lamports(&mut ctx, bob.pubkey()).await;
+    send(
+        &mut ctx,
+        &[instructions::settle_funds(&m, bob.pubkey(), authority)],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(lamports(&mut ctx, bob.pubkey()).await, before + 301_000);
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 0);
+    assert_eq!(coin_free(&mut ctx, &m, &bob).await, 0);
+    let market = decode::market(&account_data(&mut ctx, m.market).await).unwrap();
+    assert_eq!(market.coin_deposits_total, 0);
+    assert!(ctx.banks_client.get_account(wsol).await.unwrap().is_none());
+}
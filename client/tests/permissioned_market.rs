@@ -0,0 +1,146 @@
+//! Markets restricted to admitted wallets, and accounts frozen by the market
+//! authority down to cancelling.
+
+mod common;
+
+use common::*;
+use fermi_dex_client::{decode, instructions, pda, MarketAddresses, OrderType, Side};
+use solana_program::instruction::Instruction;
+use solana_program::pubkey::Pubkey;
+use solana_program_test::ProgramTestContext;
+use solana_sdk::signature::Signer;
+
+async fn create_market(ctx: &mut ProgramTestContext) -> MarketAddresses {
+    create_registry(ctx).await;
+    let coin_mint = create_mint(ctx).await;
+    let pc_mint = create_mint(ctx).await;
+    let m = MarketAddresses::derive(coin_mint, pc_mint);
+    let authority = ctx.payer.pubkey();
+    send(
+        ctx,
+        &[instructions::initialize_market(
+            &m,
+            authority,
+            authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+    m
+}
+
+fn bid(m: &MarketAddresses, owner: Pubkey, limit_price: u64) -> Instruction {
+    instructions::new_order(
+        m,
+        owner,
+        m.pc_wallet(&owner),
+        Side::Bid,
+        limit_price,
+        1,
+        limit_price,
+        OrderType::Limit,
+        false,
+    )
+}
+
+#[tokio::test]
+async fn only_admitted_wallets_open_accounts() {
+    let mut ctx = program_test().start_with_context().await;
+    let m = create_market(&mut ctx).await;
+    let authority = ctx.payer.pubkey();
+
+    let gatekeeper = create_user(&mut ctx, &m, 0, 0).await;
+    let alice = create_user(&mut ctx, &m, 0, 1_000).await;
+    let bob = create_user(&mut ctx, &m, 0, 1_000).await;
+    let carol = create_user(&mut ctx, &m, 0, 1_000).await;
+
+    // only the market authority restricts the market
+    let ix = instructions::set_admission_authority(&m, alice.pubkey(), alice.pubkey());
+    assert!(send(&mut ctx, &[ix], &[&alice]).await.is_err());
+    let ix = instructions::set_admission_authority(&m, authority, gatekeeper.pubkey());
+    send(&mut ctx, &[ix], &[]).await.unwrap();
+
+    // alice gets in with the gatekeeper's co-signature
+    assert!(send(&mut ctx, &[bid(&m, alice.pubkey(), 1)], &[&alice])
+        .await
+        .is_err());
+    let ix =
+        instructions::with_admission_authority(bid(&m, alice.pubkey(), 1), gatekeeper.pubkey());
+    send(&mut ctx, &[ix], &[&alice, &gatekeeper]).await.unwrap();
+    // and trades on without it once the account exists
+    send(&mut ctx, &[bid(&m, alice.pubkey(), 2)], &[&alice])
+        .await
+        .unwrap();
+
+    // bob through the allow list, once listed
+    let ix = instructions::with_allow_list(bid(&m, bob.pubkey(), 1), &m);
+    assert!(send(&mut ctx, &[ix.clone()], &[&bob]).await.is_err());
+    let allow = instructions::set_allowed(&m, gatekeeper.pubkey(), bob.pubkey(), true);
+    send(&mut ctx, &[allow, ix], &[&gatekeeper, &bob])
+        .await
+        .unwrap();
+    let allow_list =
+        decode::allow_list(&account_data(&mut ctx, pda::allow_list(&m.market).0).await).unwrap();
+    assert_eq!(allow_list.wallets, vec![bob.pubkey()]);
+
+    // carol isn't listed
+    let ix = instructions::with_allow_list(bid(&m, carol.pubkey(), 1), &m);
+    assert!(send(&mut ctx, &[ix], &[&carol]).await.is_err());
+
+    // until the market is opened up again
+    let ix = instructions::set_admission_authority(&m, authority, Pubkey::default());
+    send(&mut ctx, &[ix, bid(&m, carol.pubkey(), 1)], &[&carol])
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn frozen_account_can_only_cancel() {
+    let mut ctx = program_test().start_with_context().await;
+    let m = create_market(&mut ctx).await;
+    let authority = ctx.payer.pubkey();
+
+    let alice = create_user(&mut ctx, &m, 0, 1_000).await;
+    let deposit = instructions::deposit(&m, alice.pubkey(), m.pc_mint, 100);
+    send(&mut ctx, &[bid(&m, alice.pubkey(), 1), deposit], &[&alice])
+        .await
+        .unwrap();
+    let order_id = decode::bids(&account_data(&mut ctx, m.bids).await)
+        .unwrap()
+        .sorted[0]
+        .order_id;
+
+    // only the market authority freezes
+    let ix = instructions::freeze_open_orders(&m, alice.pubkey(), alice.pubkey(), true);
+    assert!(send(&mut ctx, &[ix], &[&alice]).await.is_err());
+    let ix = instructions::freeze_open_orders(&m, authority, alice.pubkey(), true);
+    send(&mut ctx, &[ix], &[]).await.unwrap();
+
+    assert!(send(&mut ctx, &[bid(&m, alice.pubkey(), 2)], &[&alice])
+        .await
+        .is_err());
+    let deposit = instructions::deposit(&m, alice.pubkey(), m.pc_mint, 10);
+    assert!(send(&mut ctx, &[deposit], &[&alice]).await.is_err());
+    let withdraw = instructions::withdraw(&m, alice.pubkey(), m.pc_mint, 10);
+    assert!(send(&mut ctx, &[withdraw], &[&alice]).await.is_err());
+
+    let cancel = instructions::cancel_bid(&m, alice.pubkey(), order_id);
+    send(&mut ctx, &[cancel], &[&alice]).await.unwrap();
+    assert!(decode::bids(&account_data(&mut ctx, m.bids).await)
+        .unwrap()
+        .sorted
+        .is_empty());
+
+    // thawed, alice can withdraw again
+    let ix = instructions::freeze_open_orders(&m, authority, alice.pubkey(), false);
+    let withdraw = instructions::withdraw(&m, alice.pubkey(), m.pc_mint, 20);
+    let before = balance(&mut ctx, m.pc_wallet(&alice.pubkey())).await;
+    send(&mut ctx, &[ix, withdraw], &[&alice]).await.unwrap();
+    assert_eq!(
+        balance(&mut ctx, m.pc_wallet(&alice.pubkey())).await,
+        before + 20
+    );
+}
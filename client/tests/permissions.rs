@@ -0,0 +1,255 @@
+//! Keys trading for an account without being able to withdraw: a session key
+//! finalising and cancelling until it expires or is revoked, and withdrawals
+//! held to the whitelisted destinations.
+
+mod common;
+
+use common::*;
+use fermi_dex_client::decode::{self, EventKind, Settlement};
+use fermi_dex_client::{instructions, MarketAddresses, OrderType, Side};
+use solana_program::instruction::AccountMeta;
+use solana_program::pubkey::Pubkey;
+use solana_program::sysvar::clock::Clock;
+use solana_program_test::ProgramTestContext;
+use solana_sdk::signature::{Keypair, Signer};
+
+async fn now(ctx: &mut ProgramTestContext) -> u64 {
+    let clock: Clock = ctx.banks_client.get_sysvar().await.unwrap();
+    clock.unix_timestamp as u64
+}
+
+#[tokio::test]
+async fn session_key_trades_but_never_withdraws() {
+    let mut ctx = program_test().start_with_context().await;
+    create_registry(&mut ctx).await;
+
+    let coin_mint = create_mint(&mut ctx).await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive(coin_mint, pc_mint);
+    let authority = ctx.payer.pubkey();
+    send(
+        &mut ctx,
+        &[instructions::initialize_market(
+            &m,
+            authority,
+            authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let alice = create_user(&mut ctx, &m, 0, 10_000).await;
+    let bob = create_user(&mut ctx, &m, 10_000, 0).await;
+    let hot = Keypair::new();
+    let stranger = Keypair::new();
+
+    // one bid to be filled and two resting well below the market
+    for (limit_price, max_coin_qty) in [(10, 100), (2, 1), (3, 1)] {
+        let ix = instructions::new_order(
+            &m,
+            alice.pubkey(),
+            m.pc_wallet(&alice.pubkey()),
+            Side::Bid,
+            limit_price,
+            max_coin_qty,
+            limit_price * max_coin_qty,
+            OrderType::Limit,
+            false,
+        );
+        send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+    }
+    let bids = decode::bids(&account_data(&mut ctx, m.bids).await).unwrap();
+    let resting = |price| {
+        bids.sorted
+            .iter()
+            .find(|order| order.price() == price)
+            .unwrap()
+            .order_id
+    };
+    let (resting_b, resting_c) = (resting(2), resting(3));
+
+    let ix = instructions::new_order(
+        &m,
+        bob.pubkey(),
+        m.coin_wallet(&bob.pubkey()),
+        Side::Ask,
+        10,
+        100,
+        0,
+        OrderType::Limit,
+        false,
+    );
+    send(&mut ctx, &[ix], &[&bob]).await.unwrap();
+    let queue = decode::event_queue(&account_data(&mut ctx, m.event_q).await).unwrap();
+    let fills: Vec<_> = decode::events(&queue)
+        .into_iter()
+        .filter(|event| event.kind == EventKind::Fill)
+        .collect();
+    let alice_fill = fills
+        .iter()
+        .find(|event| event.owner == m.open_orders(&alice.pubkey()))
+        .unwrap();
+    let bob_fill = fills
+        .iter()
+        .find(|event| event.order_id == alice_fill.order_id_second)
+        .unwrap();
+
+    let expires_at = now(&mut ctx).await + 60;
+    let ix = instructions::set_session_key(&m, alice.pubkey(), hot.pubkey(), expires_at);
+    send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+
+    // the session key finalises alice's fill, a stranger can't
+    let finalise = |signer: &Keypair| {
+        instructions::finalise_matches_bid_for(
+            &m,
+            alice.pubkey(),
+            signer.pubkey(),
+            bob.pubkey(),
+            alice.pubkey(),
+            alice_fill.slot as u8,
+            bob_fill.slot as u8,
+        )
+    };
+    assert!(send(&mut ctx, &[finalise(&stranger)], &[&stranger])
+        .await
+        .is_err());
+    send(&mut ctx, &[finalise(&hot)], &[&hot]).await.unwrap();
+    let queue = decode::event_queue(&account_data(&mut ctx, m.event_q).await).unwrap();
+    assert_eq!(
+        decode::event(alice_fill.slot, &queue.buf[alice_fill.slot])
+            .unwrap()
+            .settlement,
+        Settlement::Finalised
+    );
+
+    // but can't withdraw what it bought
+    let mut ix = instructions::withdraw(&m, alice.pubkey(), m.coin_mint, 100);
+    for meta in ix
+        .accounts
+        .iter_mut()
+        .filter(|meta| meta.pubkey == alice.pubkey())
+    {
+        *meta = AccountMeta::new_readonly(hot.pubkey(), true);
+    }
+    assert!(send(&mut ctx, &[ix], &[&hot]).await.is_err());
+
+    // once expired the session key is refused until it is renewed
+    let slot = ctx.banks_client.get_root_slot().await.unwrap();
+    ctx.warp_to_slot(slot + 10).unwrap();
+    let mut clock: Clock = ctx.banks_client.get_sysvar().await.unwrap();
+    clock.unix_timestamp += 120;
+    ctx.set_sysvar(&clock);
+
+    let cancel =
+        |order_id| instructions::cancel_bid_for(&m, alice.pubkey(), hot.pubkey(), order_id);
+    assert!(send(&mut ctx, &[cancel(resting_b)], &[&hot]).await.is_err());
+    let expires_at = now(&mut ctx).await + 60;
+    let ix = instructions::set_session_key(&m, alice.pubkey(), hot.pubkey(), expires_at);
+    send(&mut ctx, &[ix, cancel(resting_b)], &[&alice, &hot])
+        .await
+        .unwrap();
+
+    // and alice can revoke it before then
+    let ix = instructions::set_session_key(&m, alice.pubkey(), Pubkey::default(), 0);
+    send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+    assert!(send(&mut ctx, &[cancel(resting_c)], &[&hot]).await.is_err());
+}
+
+#[tokio::test]
+async fn withdrawals_follow_the_whitelist() {
+    let mut ctx = program_test().start_with_context().await;
+    create_registry(&mut ctx).await;
+
+    let coin_mint = create_mint(&mut ctx).await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive(coin_mint, pc_mint);
+    let authority = ctx.payer.pubkey();
+    send(
+        &mut ctx,
+        &[instructions::initialize_market(
+            &m,
+            authority,
+            authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let alice = create_user(&mut ctx, &m, 0, 1_000).await;
+    let cold = create_user(&mut ctx, &m, 0, 0).await;
+    let stranger = create_user(&mut ctx, &m, 0, 0).await;
+
+    // a resting 1 @ 1 bid opens alice's account
+    let ix = instructions::new_order(
+        &m,
+        alice.pubkey(),
+        m.pc_wallet(&alice.pubkey()),
+        Side::Bid,
+        1,
+        1,
+        1,
+        OrderType::Limit,
+        false,
+    );
+    let deposit = instructions::deposit(&m, alice.pubkey(), m.pc_mint, 500);
+    send(&mut ctx, &[ix, deposit], &[&alice]).await.unwrap();
+
+    // without a whitelist only alice's own accounts receive withdrawals
+    let withdraw_to = |wallet: &Keypair, amount| {
+        instructions::withdraw_to(
+            &m,
+            alice.pubkey(),
+            m.pc_mint,
+            m.pc_wallet(&wallet.pubkey()),
+            amount,
+        )
+    };
+    assert!(send(&mut ctx, &[withdraw_to(&cold, 100)], &[&alice])
+        .await
+        .is_err());
+    send(&mut ctx, &[withdraw_to(&alice, 100)], &[&alice])
+        .await
+        .unwrap();
+
+    let whitelist =
+        |destinations| instructions::set_withdraw_whitelist(&m, alice.pubkey(), destinations);
+    assert!(
+        send(&mut ctx, &[whitelist(vec![cold.pubkey(); 5])], &[&alice])
+            .await
+            .is_err()
+    );
+    send(&mut ctx, &[whitelist(vec![cold.pubkey()])], &[&alice])
+        .await
+        .unwrap();
+
+    // with one, only the listed wallets do, alice included only if listed
+    assert!(send(&mut ctx, &[withdraw_to(&alice, 50)], &[&alice])
+        .await
+        .is_err());
+    assert!(send(&mut ctx, &[withdraw_to(&stranger, 50)], &[&alice])
+        .await
+        .is_err());
+    send(&mut ctx, &[withdraw_to(&cold, 50)], &[&alice])
+        .await
+        .unwrap();
+    assert_eq!(balance(&mut ctx, m.pc_wallet(&cold.pubkey())).await, 50);
+
+    // clearing it restores the default
+    let before = balance(&mut ctx, m.pc_wallet(&alice.pubkey())).await;
+    send(&mut ctx, &[whitelist(vec![])], &[&alice])
+        .await
+        .unwrap();
+    send(&mut ctx, &[withdraw_to(&alice, 60)], &[&alice])
+        .await
+        .unwrap();
+    assert_eq!(
+        balance(&mut ctx, m.pc_wallet(&alice.pubkey())).await,
+        before + 60
+    );
+}
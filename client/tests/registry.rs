@@ -0,0 +1,157 @@
+//! Permissionless listing through the registry: two markets of one pair told
+//! apart by their nonce, the listing fee, and delisting an empty market.
+
+mod common;
+
+use common::*;
+use fermi_dex_client::{decode, instructions, pda, MarketAddresses, OrderType, Side};
+use solana_program::system_instruction;
+use solana_program_test::ProgramTestContext;
+use solana_sdk::signature::{Keypair, Signer};
+
+const LISTING_FEE: u64 = 100_000_000;
+
+async fn list(
+    ctx: &mut ProgramTestContext,
+    m: &MarketAddresses,
+    lister: &Keypair,
+    registry_authority: &Keypair,
+    coin_lot_size: u64,
+) -> Result<(), String> {
+    let ix = instructions::initialize_market(
+        m,
+        lister.pubkey(),
+        registry_authority.pubkey(),
+        coin_lot_size,
+        PC_LOT_SIZE,
+    );
+    send(ctx, &[ix], &[lister]).await
+}
+
+#[tokio::test]
+async fn list_and_delist_markets() {
+    let mut ctx = program_test().start_with_context().await;
+
+    let admin = Keypair::new();
+    let lister = Keypair::new();
+    let payer = ctx.payer.pubkey();
+    let fund = [
+        system_instruction::transfer(&payer, &admin.pubkey(), 1_000_000_000),
+        system_instruction::transfer(&payer, &lister.pubkey(), 1_000_000_000),
+    ];
+    send(&mut ctx, &fund, &[]).await.unwrap();
+    send(
+        &mut ctx,
+        &[instructions::init_registry(admin.pubkey(), LISTING_FEE)],
+        &[&admin],
+    )
+    .await
+    .unwrap();
+
+    // anyone can list, several markets per pair, each paying the fee
+    let coin_mint = create_mint(&mut ctx).await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let first = MarketAddresses::derive_with_nonce(coin_mint, pc_mint, 0);
+    let second = MarketAddresses::derive_with_nonce(coin_mint, pc_mint, 1);
+    assert_ne!(first.market, second.market);
+
+    let admin_before = ctx.banks_client.get_balance(admin.pubkey()).await.unwrap();
+    list(&mut ctx, &first, &lister, &admin, COIN_LOT_SIZE)
+        .await
+        .unwrap();
+    list(&mut ctx, &second, &lister, &admin, 10).await.unwrap();
+    assert_eq!(
+        ctx.banks_client.get_balance(admin.pubkey()).await.unwrap(),
+        admin_before + 2 * LISTING_FEE
+    );
+    // the fee has to go to the registry's authority
+    let third = MarketAddresses::derive_with_nonce(coin_mint, pc_mint, 2);
+    assert!(list(&mut ctx, &third, &lister, &lister, 10).await.is_err());
+
+    let registry = decode::registry(&account_data(&mut ctx, pda::registry().0).await).unwrap();
+    assert_eq!(registry.markets.len(), 2);
+    let listing = registry.markets[1];
+    assert_eq!(listing.market, second.market);
+    assert_eq!((listing.coin_mint, listing.pc_mint), (coin_mint, pc_mint));
+    assert_eq!((listing.nonce, listing.coin_lot_size), (1, 10));
+    assert_eq!(listing.authority, admin.pubkey());
+    let market = decode::market(&account_data(&mut ctx, second.market).await).unwrap();
+    assert_eq!((market.nonce, market.coin_lot_size), (1, 10));
+    // listing doesn't hand the lister the market's admin powers
+    assert_eq!(market.authority, admin.pubkey());
+    let ix = instructions::set_admission_authority(&second, lister.pubkey(), lister.pubkey());
+    assert!(send(&mut ctx, &[ix], &[&lister]).await.is_err());
+
+    // only an empty market can be delisted, by the registry's authority
+    let bob = create_user(&mut ctx, &first, 100, 0).await;
+    let ask = instructions::new_order(
+        &first,
+        bob.pubkey(),
+        first.coin_wallet(&bob.pubkey()),
+        Side::Ask,
+        10,
+        100,
+        0,
+        OrderType::Limit,
+        true,
+    );
+    send(&mut ctx, &[ask], &[&bob]).await.unwrap();
+    let by_admin = instructions::delist_market(&first, admin.pubkey());
+    assert!(send(&mut ctx, &[by_admin], &[&admin]).await.is_err());
+
+    let order_id = decode::asks(&account_data(&mut ctx, first.asks).await)
+        .unwrap()
+        .sorted[0]
+        .order_id;
+    send(
+        &mut ctx,
+        &[instructions::cancel_ask(&first, bob.pubkey(), order_id)],
+        &[&bob],
+    )
+    .await
+    .unwrap();
+    let by_bob = instructions::delist_market(&first, bob.pubkey());
+    assert!(send(&mut ctx, &[by_bob], &[&bob]).await.is_err());
+    let by_lister = instructions::delist_market(&first, lister.pubkey());
+    assert!(send(&mut ctx, &[by_lister], &[&lister]).await.is_err());
+    let by_admin = instructions::delist_market(&first, admin.pubkey());
+    send(&mut ctx, &[by_admin], &[&admin]).await.unwrap();
+
+    let registry = decode::registry(&account_data(&mut ctx, pda::registry().0).await).unwrap();
+    assert_eq!(registry.markets.len(), 1);
+    assert_eq!(registry.markets[0].market, second.market);
+
+    // a delisted market takes no new orders, but balances can still leave
+    let ask = instructions::new_order(
+        &first,
+        bob.pubkey(),
+        first.coin_wallet(&bob.pubkey()),
+        Side::Ask,
+        10,
+        50,
+        0,
+        OrderType::Limit,
+        true,
+    );
+    assert!(send(&mut ctx, &[ask], &[&bob]).await.is_err());
+    send(
+        &mut ctx,
+        &[instructions::settle_funds(&first, bob.pubkey(), payer)],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        balance(&mut ctx, first.coin_wallet(&bob.pubkey())).await,
+        100
+    );
+
+    // and any other empty market
+    send(
+        &mut ctx,
+        &[instructions::delist_market(&second, admin.pubkey())],
+        &[&admin],
+    )
+    .await
+    .unwrap();
+}
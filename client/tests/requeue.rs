@@ -0,0 +1,133 @@
+//! A maker that opted into `requeue_on_default` goes back to its place in the
+//! book when the taker it matched defaults.
+
+mod common;
+
+use common::*;
+use fermi_dex_client::decode::{self, EventKind};
+use fermi_dex_client::{instructions, MarketAddresses, OrderType, Side};
+use solana_program::sysvar::clock::Clock;
+use solana_sdk::signature::Signer;
+
+#[tokio::test]
+async fn defaulting_taker_leaves_maker_in_place() {
+    let mut ctx = program_test().start_with_context().await;
+    create_registry(&mut ctx).await;
+
+    let coin_mint = create_mint(&mut ctx).await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive(coin_mint, pc_mint);
+    let authority = ctx.payer.pubkey();
+    send(
+        &mut ctx,
+        &[instructions::initialize_market(
+            &m,
+            authority,
+            authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let alice = create_user(&mut ctx, &m, 0, 10_000).await;
+    let bob = create_user(&mut ctx, &m, 10_000, 0).await;
+    let carol = create_user(&mut ctx, &m, 10_000, 0).await;
+
+    // bob rests 100 @ 10 and carol queues 50 @ 10 behind him
+    for (user, qty) in [(&bob, 100), (&carol, 50)] {
+        let ix = instructions::new_order(
+            &m,
+            user.pubkey(),
+            m.coin_wallet(&user.pubkey()),
+            Side::Ask,
+            10,
+            qty,
+            0,
+            OrderType::Limit,
+            false,
+        );
+        send(&mut ctx, &[ix], &[user]).await.unwrap();
+    }
+    let ix = instructions::set_requeue_on_default(&m, bob.pubkey(), true);
+    send(&mut ctx, &[ix], &[&bob]).await.unwrap();
+    let asks = decode::asks(&account_data(&mut ctx, m.asks).await).unwrap();
+    let (bob_order, carol_order) = (asks.sorted[0].order_id, asks.sorted[1].order_id);
+
+    // alice takes bob's 100 and never funds the fill
+    let ix = instructions::new_order(
+        &m,
+        alice.pubkey(),
+        m.pc_wallet(&alice.pubkey()),
+        Side::Bid,
+        10,
+        100,
+        1_000,
+        OrderType::Limit,
+        false,
+    );
+    send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+    let asks = decode::asks(&account_data(&mut ctx, m.asks).await).unwrap();
+    assert_eq!(asks.sorted.len(), 1);
+    assert_eq!(asks.sorted[0].order_id, carol_order);
+
+    let queue = decode::event_queue(&account_data(&mut ctx, m.event_q).await).unwrap();
+    let events = decode::events(&queue);
+    let fill_of = |owner| {
+        events
+            .iter()
+            .find(|e| e.kind == EventKind::Fill && e.owner == owner)
+            .unwrap()
+            .slot as u8
+    };
+    let alice_fill = fill_of(m.open_orders(&alice.pubkey()));
+    let bob_fill = fill_of(m.open_orders(&bob.pubkey()));
+    let head = queue.head;
+
+    let slot = ctx.banks_client.get_root_slot().await.unwrap();
+    ctx.warp_to_slot(slot + 10).unwrap();
+    let mut clock: Clock = ctx.banks_client.get_sysvar().await.unwrap();
+    clock.unix_timestamp += 120;
+    ctx.set_sysvar(&clock);
+
+    let ix = instructions::cancel_with_penalty(
+        &m,
+        alice.pubkey(),
+        bob.pubkey(),
+        Side::Bid,
+        alice_fill,
+        bob_fill,
+    );
+    send(&mut ctx, &[ix], &[]).await.unwrap();
+
+    // bob is back ahead of carol with the same order id and quantity
+    let asks = decode::asks(&account_data(&mut ctx, m.asks).await).unwrap();
+    assert_eq!(asks.sorted.len(), 2);
+    assert_eq!(asks.sorted[0].order_id, bob_order);
+    assert_eq!(asks.sorted[0].qty, 100);
+    assert_eq!(asks.sorted[1].order_id, carol_order);
+    let market = decode::market(&account_data(&mut ctx, m.market).await).unwrap();
+    assert_eq!(market.best_ask, 10);
+
+    // bob's coin margin was released once and locked again to back the order,
+    // and alice's 10 pc penalty is free to withdraw
+    let oo =
+        decode::open_orders(&account_data(&mut ctx, m.open_orders(&bob.pubkey())).await).unwrap();
+    assert!(oo.orders.contains(&bob_order));
+    assert_eq!(
+        (
+            oo.native_coin_free,
+            oo.native_coin_total,
+            oo.native_pc_free,
+            oo.native_pc_total
+        ),
+        (0, 1, 10, 10)
+    );
+
+    // nothing else was written to the queue
+    let queue = decode::event_queue(&account_data(&mut ctx, m.event_q).await).unwrap();
+    let head_after = queue.head;
+    assert_eq!(head_after, head);
+}
@@ -0,0 +1,106 @@
+//! `settle_funds` can be cranked by anyone, but only ever pays out to the
+//! owner's associated token accounts.
+
+mod common;
+
+use common::*;
+use fermi_dex_client::{decode, instructions, MarketAddresses, OrderType, Side};
+use solana_sdk::signature::Signer;
+
+#[tokio::test]
+async fn crank_settles_to_owner_only() {
+    let mut ctx = program_test().start_with_context().await;
+    create_registry(&mut ctx).await;
+
+    let coin_mint = create_mint(&mut ctx).await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive(coin_mint, pc_mint);
+    let authority = ctx.payer.pubkey();
+    send(
+        &mut ctx,
+        &[instructions::initialize_market(
+            &m,
+            authority,
+            authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+
+    let alice = create_user(&mut ctx, &m, 300, 1_000).await;
+    let crank = create_user(&mut ctx, &m, 0, 0).await;
+    let alice_coin = m.coin_wallet(&alice.pubkey());
+    let alice_pc = m.pc_wallet(&alice.pubkey());
+
+    // a cancelled fully funded bid leaves 50 pc free, and 300 coin are deposited
+    let ix = instructions::new_order(
+        &m,
+        alice.pubkey(),
+        alice_pc,
+        Side::Bid,
+        10,
+        5,
+        50,
+        OrderType::Limit,
+        true,
+    );
+    send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+    let order_id = decode::bids(&account_data(&mut ctx, m.bids).await)
+        .unwrap()
+        .sorted[0]
+        .order_id;
+    let ix = instructions::cancel_bid(&m, alice.pubkey(), order_id);
+    send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+    let ix = instructions::deposit(&m, alice.pubkey(), m.coin_mint, 300);
+    send(&mut ctx, &[ix], &[&alice]).await.unwrap();
+    assert_eq!(balance(&mut ctx, alice_coin).await, 0);
+    assert_eq!(balance(&mut ctx, alice_pc).await, 950);
+
+    // the crank can't point either payout at its own wallets
+    let settle = instructions::settle_funds(&m, alice.pubkey(), crank.pubkey());
+    for (to, from) in [
+        (m.coin_wallet(&crank.pubkey()), alice_coin),
+        (m.pc_wallet(&crank.pubkey()), alice_pc),
+    ] {
+        let mut ix = settle.clone();
+        for meta in ix.accounts.iter_mut().filter(|meta| meta.pubkey == from) {
+            meta.pubkey = to;
+        }
+        assert!(send(&mut ctx, &[ix], &[&crank]).await.is_err());
+    }
+    // nor name itself as the owner of alice's account
+    let mut ix = settle.clone();
+    for meta in ix.accounts.iter_mut() {
+        if meta.pubkey == alice.pubkey() {
+            meta.pubkey = crank.pubkey();
+        } else if meta.pubkey == alice_coin {
+            meta.pubkey = m.coin_wallet(&crank.pubkey());
+        } else if meta.pubkey == alice_pc {
+            meta.pubkey = m.pc_wallet(&crank.pubkey());
+        }
+    }
+    assert!(send(&mut ctx, &[ix], &[&crank]).await.is_err());
+
+    // sent as is, everything free goes back to alice without her signature
+    send(&mut ctx, &[settle], &[&crank]).await.unwrap();
+    assert_eq!(balance(&mut ctx, alice_coin).await, 300);
+    assert_eq!(balance(&mut ctx, alice_pc).await, 1_000);
+    assert_eq!(balance(&mut ctx, m.coin_wallet(&crank.pubkey())).await, 0);
+    assert_eq!(balance(&mut ctx, m.pc_wallet(&crank.pubkey())).await, 0);
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 0);
+    assert_eq!(balance(&mut ctx, m.pc_vault).await, 0);
+    let oo =
+        decode::open_orders(&account_data(&mut ctx, m.open_orders(&alice.pubkey())).await).unwrap();
+    assert_eq!(
+        (
+            oo.native_coin_free,
+            oo.native_coin_total,
+            oo.native_pc_free,
+            oo.native_pc_total
+        ),
+        (0, 0, 0, 0)
+    );
+}
@@ -0,0 +1,191 @@
+//! Markets over Token-2022 mints: a coin mint charging a transfer fee paired
+//! with a legacy pc mint, and a non-transferable mint the market refuses.
+
+mod common;
+
+use common::*;
+use fermi_dex_client::decode;
+use fermi_dex_client::{instructions, MarketAddresses, OrderType, Side};
+use solana_program::instruction::Instruction;
+use solana_program::pubkey::Pubkey;
+use solana_program::system_instruction;
+use solana_program_test::ProgramTestContext;
+use solana_sdk::signature::{Keypair, Signer};
+use spl_token_2022::extension::transfer_fee::instruction::initialize_transfer_fee_config;
+use spl_token_2022::extension::ExtensionType;
+
+/// 1% on every transfer, uncapped.
+const FEE_BASIS_POINTS: u16 = 100;
+
+/// A Token-2022 mint with `extensions`, set up by `init_extensions`.
+async fn create_mint_2022(
+    ctx: &mut ProgramTestContext,
+    extensions: &[ExtensionType],
+    init_extensions: impl FnOnce(&Pubkey) -> Vec<Instruction>,
+) -> Pubkey {
+    let mint = Keypair::new();
+    let rent = ctx.banks_client.get_rent().await.unwrap();
+    let payer = ctx.payer.pubkey();
+    let space = ExtensionType::get_account_len::<spl_token_2022::state::Mint>(extensions);
+    let mut ixs = vec![system_instruction::create_account(
+        &payer,
+        &mint.pubkey(),
+        rent.minimum_balance(space),
+        space as u64,
+        &spl_token_2022::id(),
+    )];
+    ixs.extend(init_extensions(&mint.pubkey()));
+    ixs.push(
+        spl_token_2022::instruction::initialize_mint(
+            &spl_token_2022::id(),
+            &mint.pubkey(),
+            &payer,
+            None,
+            0,
+        )
+        .unwrap(),
+    );
+    send(ctx, &ixs, &[&mint]).await.unwrap();
+    mint.pubkey()
+}
+
+async fn create_fee_mint(ctx: &mut ProgramTestContext) -> Pubkey {
+    let authority = ctx.payer.pubkey();
+    create_mint_2022(ctx, &[ExtensionType::TransferFeeConfig], |mint| {
+        vec![initialize_transfer_fee_config(
+            &spl_token_2022::id(),
+            mint,
+            Some(&authority),
+            Some(&authority),
+            FEE_BASIS_POINTS,
+            u64::MAX,
+        )
+        .unwrap()]
+    })
+    .await
+}
+
+async fn initialize(ctx: &mut ProgramTestContext, m: &MarketAddresses) -> Result<(), String> {
+    let authority = ctx.payer.pubkey();
+    send(
+        ctx,
+        &[instructions::initialize_market(
+            m,
+            authority,
+            authority,
+            COIN_LOT_SIZE,
+            PC_LOT_SIZE,
+        )],
+        &[],
+    )
+    .await
+}
+
+#[tokio::test]
+async fn transfer_fee_mint_market() {
+    let mut ctx = program_test().start_with_context().await;
+    create_registry(&mut ctx).await;
+
+    let coin_mint = create_fee_mint(&mut ctx).await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive_with_programs(
+        coin_mint,
+        pc_mint,
+        0,
+        spl_token_2022::id(),
+        spl_token::id(),
+    );
+    initialize(&mut ctx, &m).await.unwrap();
+
+    // each vault belongs to its mint's program
+    let coin_vault = ctx.banks_client.get_account(m.coin_vault).await.unwrap();
+    assert_eq!(coin_vault.unwrap().owner, spl_token_2022::id());
+    let pc_vault = ctx.banks_client.get_account(m.pc_vault).await.unwrap();
+    assert_eq!(pc_vault.unwrap().owner, spl_token::id());
+
+    let bob = create_user(&mut ctx, &m, 10_000, 0).await;
+    let bob_coin = m.coin_wallet(&bob.pubkey());
+
+    // a fully funded ask sends 102 so that the whole 100 lands in the vault
+    let ix = instructions::new_order(
+        &m,
+        bob.pubkey(),
+        bob_coin,
+        Side::Ask,
+        10,
+        100,
+        0,
+        OrderType::Limit,
+        true,
+    );
+    send(&mut ctx, &[ix], &[&bob]).await.unwrap();
+    assert_eq!(balance(&mut ctx, bob_coin).await, 9_898);
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 100);
+    let asks = decode::asks(&account_data(&mut ctx, m.asks).await).unwrap();
+    assert_eq!(asks.sorted[0].qty, 100);
+
+    // deposits are credited net of the fee
+    send(
+        &mut ctx,
+        &[instructions::deposit(&m, bob.pubkey(), m.coin_mint, 1_000)],
+        &[&bob],
+    )
+    .await
+    .unwrap();
+    assert_eq!(balance(&mut ctx, bob_coin).await, 8_898);
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 1_090);
+    let oo =
+        decode::open_orders(&account_data(&mut ctx, m.open_orders(&bob.pubkey())).await).unwrap();
+    assert_eq!(oo.native_coin_free, 990);
+
+    // and withdrawals pay the fee out of what the owner receives
+    send(
+        &mut ctx,
+        &[instructions::withdraw(&m, bob.pubkey(), m.coin_mint, 500)],
+        &[&bob],
+    )
+    .await
+    .unwrap();
+    assert_eq!(balance(&mut ctx, bob_coin).await, 9_393);
+    assert_eq!(balance(&mut ctx, m.coin_vault).await, 590);
+    let oo =
+        decode::open_orders(&account_data(&mut ctx, m.open_orders(&bob.pubkey())).await).unwrap();
+    assert_eq!(oo.native_coin_free, 490);
+}
+
+#[tokio::test]
+async fn non_transferable_mint_is_rejected() {
+    let mut ctx = program_test().start_with_context().await;
+    create_registry(&mut ctx).await;
+
+    let coin_mint = create_mint_2022(&mut ctx, &[ExtensionType::NonTransferable], |mint| {
+        vec![
+            spl_token_2022::instruction::initialize_non_transferable_mint(
+                &spl_token_2022::id(),
+                mint,
+            )
+            .unwrap(),
+        ]
+    })
+    .await;
+    let pc_mint = create_mint(&mut ctx).await;
+    let m = MarketAddresses::derive_with_programs(
+        coin_mint,
+        pc_mint,
+        0,
+        spl_token_2022::id(),
+        spl_token::id(),
+    );
+    assert!(initialize(&mut ctx, &m).await.is_err());
+
+    // naming the legacy program for a Token-2022 mint is refused too
+    let fee_mint = create_fee_mint(&mut ctx).await;
+    let m = MarketAddresses::derive_with_programs(
+        fee_mint,
+        pc_mint,
+        0,
+        spl_token::id(),
+        spl_token::id(),
+    );
+    assert!(initialize(&mut ctx, &m).await.is_err());
+}
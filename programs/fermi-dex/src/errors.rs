@@ -52,6 +52,7 @@ pub enum ErrorCodeCustom {
     #[msg("ThisSideAlreadyFinalised")]
     SideAlreadyFinalised,
 
+    // superseded by SettlementWindowOpen, kept so the codes after it don't shift
     #[msg("EventNotExpired")]
     FinalizeNotExpired,
 
@@ -61,9 +62,84 @@ pub enum ErrorCodeCustom {
     #[msg("WrongSideProvided")]
     WrongSide,
 
+    // no longer raised, kept so the codes after it don't shift
     #[msg("Error")]
     Error,
 
     #[msg("ApprovalFailed")]
     ApprovalFailed,
+
+    #[msg("Delegates can only trade the owner's deposited balance")]
+    DelegateCannotFund,
+
+    #[msg("Invalid quantity")]
+    InvalidQty,
+
+    #[msg("Token program doesn't own the mint")]
+    InvalidTokenProgram,
+
+    #[msg("Mint extension not supported by the market")]
+    UnsupportedMintExtension,
+
+    #[msg("Market has no native SOL side")]
+    NotNativeMarket,
+
+    #[msg("Registry is full")]
+    RegistryFull,
+    #[msg("Market is not listed")]
+    MarketNotListed,
+    #[msg("Market has been delisted")]
+    MarketDelisted,
+    #[msg("Market still has resting orders")]
+    MarketNotEmpty,
+
+    #[msg("Linked margin account or its vault not passed")]
+    MarginAccountMissing,
+    #[msg("Margin account holds too many mints")]
+    MarginAccountFull,
+    #[msg("Margin requirement not met")]
+    MarginRequirement,
+    #[msg("Open orders account still has orders")]
+    OpenOrdersNotEmpty,
+
+    #[msg("Account is not under-margined")]
+    AccountHealthy,
+    #[msg("Not every pending fill was liquidated")]
+    ObligationsRemaining,
+
+    #[msg("Open orders account still holds funds")]
+    OpenOrdersHoldsFunds,
+    #[msg("Open orders account still has fills pending settlement")]
+    PendingFills,
+
+    #[msg("Withdrawal destination is not whitelisted")]
+    DestinationNotWhitelisted,
+    #[msg("Too many whitelisted withdrawal destinations")]
+    WithdrawWhitelistFull,
+
+    #[msg("Market admits new accounts only with its admission authority's approval")]
+    NotAdmitted,
+    #[msg("Allow list is full")]
+    AllowListFull,
+    #[msg("Open orders account is frozen, only cancels are allowed")]
+    OpenOrdersFrozen,
+
+    #[msg("Open orders account belongs to another market")]
+    MarketMismatch,
+    #[msg("Events are not the two sides of one fill")]
+    EventsNotMatched,
+    #[msg("Event slot does not hold a fill")]
+    EventEvicted,
+    #[msg("Lot sizes must be non-zero")]
+    InvalidLotSize,
+    #[msg("Arithmetic overflow")]
+    MathOverflow,
+    #[msg("Counterparty can still settle, the finalise window is open")]
+    SettlementWindowOpen,
+    #[msg("Market isn't approved to pull enough from the payer's token account")]
+    DelegationInsufficient,
+    #[msg("Free balance would exceed the total")]
+    BalanceInconsistent,
+    #[msg("Price feed isn't owned by the market's oracle program")]
+    InvalidOracle,
 }
@@ -1,11 +1,13 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{Approve, Transfer};
+use token_interface::{ApproveChecked, TransferChecked, UnwrapSol, WrapSol};
 
 use enumflags2::BitFlags;
 use solana_program::clock::Clock;
 
-mod errors;
-mod state;
+pub mod errors;
+pub mod matching;
+pub mod state;
+pub mod token_interface;
 mod utils2;
 
 use crate::errors::ErrorCodeCustom;
@@ -19,11 +21,59 @@ pub mod fermi_dex {
 
     use super::*;
 
+    pub fn init_registry(ctx: Context<InitRegistry>, listing_fee: u64) -> Result<()> {
+        let registry = &mut ctx.accounts.registry;
+        registry.authority = ctx.accounts.authority.key();
+        registry.listing_fee = listing_fee;
+
+        Ok(())
+    }
+
+    pub fn set_listing_fee(ctx: Context<SetListingFee>, listing_fee: u64) -> Result<()> {
+        ctx.accounts.registry.listing_fee = listing_fee;
+
+        Ok(())
+    }
+
+    /// Lists a new market for the pair. Permissionless, the caller pays the
+    /// registry's listing fee. `nonce` tells apart markets of the same pair.
     pub fn initialize_market(
         ctx: Context<InitializeMarket>,
         coin_lot_size: u64,
         pc_lot_size: u64,
+        nonce: u64,
     ) -> Result<()> {
+        require!(
+            coin_lot_size > 0 && pc_lot_size > 0,
+            ErrorCodeCustom::InvalidLotSize
+        );
+        let accounts = &ctx.accounts;
+        token_interface::check_mint(&accounts.coin_mint)?;
+        token_interface::check_mint(&accounts.pc_mint)?;
+        // vaults are the market's associated token accounts under each mint's program
+        for (vault, mint, token_program) in [
+            (
+                &accounts.coin_vault,
+                &accounts.coin_mint,
+                &accounts.coin_token_program,
+            ),
+            (
+                &accounts.pc_vault,
+                &accounts.pc_mint,
+                &accounts.pc_token_program,
+            ),
+        ] {
+            token_interface::create_associated_account(
+                &accounts.authority,
+                vault,
+                &accounts.market.to_account_info(),
+                mint,
+                &accounts.system_program,
+                token_program,
+                &accounts.associated_token_program,
+            )?;
+        }
+
         let market = &mut ctx.accounts.market;
         market.coin_vault = ctx.accounts.coin_vault.key();
         market.pc_vault = ctx.accounts.pc_vault.key();
@@ -37,7 +87,51 @@ pub mod fermi_dex {
         market.asks = ctx.accounts.asks.key();
         market.req_q = ctx.accounts.req_q.key();
         market.event_q = ctx.accounts.event_q.key();
-        market.authority = ctx.accounts.authority.key();
+        // listing is permissionless, so the freeze, oracle and admission powers
+        // stay with the registry's authority rather than whoever listed
+        market.authority = ctx.accounts.registry.authority;
+        market.nonce = nonce;
+
+        let listing_fee = ctx.accounts.registry.listing_fee;
+        if listing_fee > 0 {
+            let fee_ix = anchor_lang::system_program::Transfer {
+                from: ctx.accounts.authority.to_account_info(),
+                to: ctx.accounts.listing_fee_receiver.to_account_info(),
+            };
+            let cpi_ctx = CpiContext::new(ctx.accounts.system_program.to_account_info(), fee_ix);
+            anchor_lang::system_program::transfer(cpi_ctx, listing_fee)?;
+        }
+
+        let listing = MarketListing {
+            market: market.key(),
+            coin_mint: market.coin_mint,
+            pc_mint: market.pc_mint,
+            nonce,
+            coin_lot_size,
+            pc_lot_size,
+            authority: market.authority,
+        };
+        ctx.accounts.registry.list(listing)?;
+
+        Ok(())
+    }
+
+    /// Takes a market without resting orders off the registry and stops it
+    /// taking new orders. Balances can still be settled and withdrawn.
+    pub fn delist_market(ctx: Context<DelistMarket>) -> Result<()> {
+        let bids = &ctx.accounts.bids;
+        let asks = &ctx.accounts.asks;
+        require!(
+            bids.sorted.is_empty()
+                && bids.pegged.is_empty()
+                && asks.sorted.is_empty()
+                && asks.pegged.is_empty(),
+            ErrorCodeCustom::MarketNotEmpty
+        );
+
+        let market = &mut ctx.accounts.market;
+        ctx.accounts.registry.delist(market.key())?;
+        market.delisted = true;
 
         Ok(())
     }
@@ -52,11 +146,10 @@ pub mod fermi_dex {
         let openorders = &mut ctx.accounts.open_orders;
         let authority = ctx.accounts.authority.key();
 
-        //check openorders owner
-
+        //check openorders owner or its delegate
         require!(
-            openorders.authority == authority,
-            ErrorCodeCustom::OrderNotFound
+            openorders.can_trade(authority),
+            ErrorCodeCustom::WrongAuthority
         );
 
         //check the order is owned by this user
@@ -80,8 +173,32 @@ pub mod fermi_dex {
 
         //order value is freed up
         let order_value = Order::price_from_order_id(order_id);
-        let marginal_deposit = order_value / 100;
-        openorders.unlock_pc(marginal_deposit);
+        let resting = order_book.bids.find(order_id).copied();
+        match resting {
+            Some(order) if order.fully_funded => {
+                let pc_lot_size = order_book.market.pc_lot_size;
+                openorders.unlock_pc(order.total_qty() * order_value * pc_lot_size)?;
+            }
+            // a fully funded order released what it didn't fill when it stopped resting
+            _ if openorders.slot_is_fully_funded(slot as u8) => {}
+            _ => {
+                let marginal_deposit = order_value / 100;
+                openorders.unlock_pc(marginal_deposit)?;
+            }
+        }
+        // what is left of a JIT order no longer weighs on the linked margin account
+        if let Some(order) = resting.filter(|order| !order.fully_funded) {
+            if openorders.margin_account != Pubkey::default() {
+                let mut margin_account =
+                    utils2::linked_margin_account(openorders, ctx.remaining_accounts)?;
+                let pc_lot_size = order_book.market.pc_lot_size;
+                margin_account.release(
+                    order_book.market.pc_mint,
+                    order.total_qty() * order_value * pc_lot_size,
+                );
+                margin_account.exit(ctx.program_id)?;
+            }
+        }
 
         order_book.cancel_order_bid(true, order_id, expected_owner)?;
 
@@ -105,10 +222,10 @@ pub mod fermi_dex {
         let openorders = &mut ctx.accounts.open_orders;
         let authority = ctx.accounts.authority.key();
 
-        //check openorders owner
+        //check openorders owner or its delegate
         require!(
-            openorders.authority == authority,
-            ErrorCodeCustom::OrderNotFound
+            openorders.can_trade(authority),
+            ErrorCodeCustom::WrongAuthority
         );
 
         //check the order is owned by this user
@@ -131,8 +248,32 @@ pub mod fermi_dex {
 
         //order value is freed up
         let order_value = Order::price_from_order_id(order_id);
-        let marginal_deposit = order_value / 100;
-        openorders.unlock_coin(marginal_deposit);
+        let resting = order_book.asks.find(order_id).copied();
+        match resting {
+            Some(order) if order.fully_funded => {
+                let coin_lot_size = order_book.market.coin_lot_size;
+                openorders.unlock_coin(order.total_qty() * coin_lot_size)?;
+            }
+            // a fully funded order released what it didn't fill when it stopped resting
+            _ if openorders.slot_is_fully_funded(slot as u8) => {}
+            _ => {
+                let marginal_deposit = order_value / 100;
+                openorders.unlock_coin(marginal_deposit)?;
+            }
+        }
+        // what is left of a JIT order no longer weighs on the linked margin account
+        if let Some(order) = resting.filter(|order| !order.fully_funded) {
+            if openorders.margin_account != Pubkey::default() {
+                let mut margin_account =
+                    utils2::linked_margin_account(openorders, ctx.remaining_accounts)?;
+                let coin_lot_size = order_book.market.coin_lot_size;
+                margin_account.release(
+                    order_book.market.coin_mint,
+                    order.total_qty() * coin_lot_size,
+                );
+                margin_account.exit(ctx.program_id)?;
+            }
+        }
 
         order_book.cancel_order_ask(false, order_id, expected_owner)?;
 
@@ -147,155 +288,220 @@ pub mod fermi_dex {
         Ok(())
     }
 
-    pub fn deposit_pc_tokens(ctx: Context<DepositTokens>, amount: u64) -> Result<()> {
-        // Construct the transfer instruction
-        msg!("Starting deposit_tokens function");
-
-        let token_program = &ctx.accounts.token_program;
-
-        let transfer_ix = Transfer {
-            from: ctx.accounts.payer.to_account_info(),
-            to: ctx.accounts.vault.to_account_info(),
-            authority: ctx.accounts.authority.to_account_info(),
-        };
-        let cpi_ctx = CpiContext::new(token_program.to_account_info(), transfer_ix);
-        msg!("Transferred tokens!");
-
-        // Execute the transfer
-        anchor_spl::token::transfer(cpi_ctx, amount).map_err(|err| match err {
-            _ => error!(ErrorCodeCustom::TransferFailed),
-        })?;
-
-        ctx.accounts.open_orders.native_pc_free = ctx
+    /// Moves `amount` of `mint`, one of the market's two, from the authority's
+    /// token account into the market's vault and credits what arrives, net of
+    /// the mint's transfer fee, to the free balance.
+    pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
+        let mint = &ctx.accounts.mint;
+        let side = ctx
             .accounts
-            .open_orders
-            .native_pc_free
-            .checked_add(amount)
-            .ok_or(ErrorCodeCustom::Error)?;
-
-        Ok(())
-    }
-
-    pub fn deposit_coin_tokens(ctx: Context<DepositTokens>, amount: u64) -> Result<()> {
-        // Construct the transfer instruction
-        let token_program = &ctx.accounts.token_program;
+            .market
+            .mint_side(mint.key())
+            .ok_or(ErrorCodeCustom::WrongPayerMint)?;
+        require!(
+            !ctx.accounts.open_orders.frozen,
+            ErrorCodeCustom::OpenOrdersFrozen
+        );
 
-        let transfer_ix = Transfer {
+        let transfer_ix = TransferChecked {
             from: ctx.accounts.payer.to_account_info(),
+            mint: mint.to_account_info(),
             to: ctx.accounts.vault.to_account_info(),
             authority: ctx.accounts.authority.to_account_info(),
         };
-        let cpi_ctx = CpiContext::new(token_program.to_account_info(), transfer_ix);
-        msg!("Transferred tokens!");
-
-        // Execute the transfer
-        anchor_spl::token::transfer(cpi_ctx, amount).map_err(|err| match err {
-            _ => error!(ErrorCodeCustom::TransferFailed),
-        })?;
-
-        // Credit the balance to openOrders
-        ctx.accounts.open_orders.native_coin_free = ctx
-            .accounts
-            .open_orders
-            .native_coin_free
-            .checked_add(amount)
-            .ok_or(ErrorCodeCustom::Error)?;
-
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), transfer_ix);
+        token_interface::transfer_checked(cpi_ctx, amount)?;
+
+        let received = token_interface::amount_received(mint, amount)?;
+        ctx.accounts.open_orders.deposit(side, received)?;
+        ctx.accounts.market.credit_deposits(side, received)?;
+
+        emit!(DepositEvent {
+            market: ctx.accounts.market.key(),
+            open_orders: ctx.accounts.open_orders.key(),
+            mint: mint.key(),
+            amount: received,
+        });
         Ok(())
     }
 
-    pub fn withdraw_coins(ctx: Context<WithdrawTokens>, amount: u64) -> Result<()> {
+    /// Pays `amount` of `mint` out of the free balance to the authority's token
+    /// account. Any transfer fee comes out of what the authority receives.
+    pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
         let program_id = ctx.program_id;
-        let open_orders = &mut ctx.accounts.open_orders;
         let market = &mut ctx.accounts.market;
-        let coin_vault = &ctx.accounts.coin_vault;
-        let payer = &ctx.accounts.payer;
+        let mint = &ctx.accounts.mint;
+        let side = market
+            .mint_side(mint.key())
+            .ok_or(ErrorCodeCustom::WrongPayerMint)?;
 
-        let authority = &ctx.accounts.authority;
-        let token_program = &ctx.accounts.token_program;
-        let coin_mint = &ctx.accounts.coin_mint;
-        let pc_mint = &ctx.accounts.pc_mint;
+        let open_orders = &mut ctx.accounts.open_orders;
+        require!(!open_orders.frozen, ErrorCodeCustom::OpenOrdersFrozen);
+        open_orders.withdraw(side, amount)?;
+        market.debit_deposits(side, amount)?;
+
+        let nonce_seed = market.nonce.to_le_bytes();
         let (_market_pda, bump_seed) = Pubkey::find_program_address(
-            &[b"market", coin_mint.key().as_ref(), pc_mint.key().as_ref()],
+            &[
+                b"market",
+                market.coin_mint.as_ref(),
+                market.pc_mint.as_ref(),
+                &nonce_seed,
+            ],
             &program_id,
         );
+        let seed_slices: [&[u8]; 5] = [
+            b"market",
+            market.coin_mint.as_ref(),
+            market.pc_mint.as_ref(),
+            &nonce_seed,
+            &[bump_seed],
+        ];
+        let seeds: &[&[&[u8]]] = &[&seed_slices];
 
-        //Validation: owner of openorders is the authority
-        require!(
-            open_orders.authority == authority.key(),
-            ErrorCodeCustom::InvalidAuthority
+        let transfer_ix = TransferChecked {
+            from: ctx.accounts.vault.to_account_info(),
+            mint: mint.to_account_info(),
+            to: ctx.accounts.destination.to_account_info(),
+            authority: market.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            transfer_ix,
+            seeds,
         );
+        token_interface::transfer_checked(cpi_ctx, amount)?;
 
-        //Validation of the user's openorders balance
-        msg!("oo coin free : {}", open_orders.native_coin_free);
-        msg!("oo owner owner {}", open_orders.authority);
-        msg!("oo owner market {}", open_orders.market);
+        emit!(WithdrawEvent {
+            market: market.key(),
+            open_orders: open_orders.key(),
+            mint: mint.key(),
+            amount,
+        });
+        Ok(())
+    }
 
-        // Signing the transaction with the market PDA and bump seed.
-        let market_seed = b"market";
+    /// Wraps `lamports` from the authority into the market's SOL vault and
+    /// credits them to its free balance.
+    pub fn deposit_native(ctx: Context<DepositNative>, lamports: u64) -> Result<()> {
+        let side = ctx
+            .accounts
+            .market
+            .native_side()
+            .ok_or(ErrorCodeCustom::NotNativeMarket)?;
+        require!(
+            !ctx.accounts.open_orders.frozen,
+            ErrorCodeCustom::OpenOrdersFrozen
+        );
 
-        let coin_mint_key = coin_mint.key();
-        let pc_mint_key = pc_mint.key();
+        let wrap_ix = WrapSol {
+            from: ctx.accounts.authority.to_account_info(),
+            vault: ctx.accounts.vault.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), wrap_ix);
+        token_interface::wrap_sol(cpi_ctx, lamports)?;
 
-        let coin_mint_seed = coin_mint_key.as_ref();
-        let pc_mint_seed = pc_mint_key.as_ref();
+        let market = &mut ctx.accounts.market;
+        let open_orders = &mut ctx.accounts.open_orders;
+        open_orders.deposit(side, lamports)?;
+        market.credit_deposits(side, lamports)?;
+
+        emit!(DepositEvent {
+            market: market.key(),
+            open_orders: open_orders.key(),
+            mint: market.payer_mint(side),
+            amount: lamports,
+        });
+        Ok(())
+    }
 
-        let bump_seed_arr: &[u8] = &[bump_seed];
+    /// Pays `lamports` of the free wrapped SOL balance back to the authority as SOL.
+    pub fn withdraw_native(ctx: Context<WithdrawNative>, lamports: u64) -> Result<()> {
+        let program_id = ctx.program_id;
+        let market = &mut ctx.accounts.market;
+        let side = market
+            .native_side()
+            .ok_or(ErrorCodeCustom::NotNativeMarket)?;
+
+        let open_orders = &mut ctx.accounts.open_orders;
+        require!(!open_orders.frozen, ErrorCodeCustom::OpenOrdersFrozen);
+        require!(
+            open_orders.may_withdraw_to(ctx.accounts.authority.key()),
+            ErrorCodeCustom::DestinationNotWhitelisted
+        );
+        open_orders.withdraw(side, lamports)?;
+        market.debit_deposits(side, lamports)?;
 
-        let seed_slices: [&[u8]; 4] = [market_seed, coin_mint_seed, pc_mint_seed, bump_seed_arr];
+        let nonce_seed = market.nonce.to_le_bytes();
+        let (_market_pda, bump_seed) = Pubkey::find_program_address(
+            &[
+                b"market",
+                market.coin_mint.as_ref(),
+                market.pc_mint.as_ref(),
+                &nonce_seed,
+            ],
+            &program_id,
+        );
+        let seed_slices: [&[u8]; 5] = [
+            b"market",
+            market.coin_mint.as_ref(),
+            market.pc_mint.as_ref(),
+            &nonce_seed,
+            &[bump_seed],
+        ];
         let seeds: &[&[&[u8]]] = &[&seed_slices];
 
-        let transfer_ix = Transfer {
-            from: coin_vault.to_account_info(),
-            to: payer.to_account_info(),
-            authority: market.to_account_info(), // Using the market PDA as the authority.
+        let open_orders_key = open_orders.key();
+        let (_wsol_pda, wsol_bump) =
+            Pubkey::find_program_address(&[b"wsol", open_orders_key.as_ref()], &program_id);
+        let wsol_seeds: &[&[u8]] = &[b"wsol", open_orders_key.as_ref(), &[wsol_bump]];
+
+        let unwrap_ix = UnwrapSol {
+            payer: ctx.accounts.authority.to_account_info(),
+            temp: ctx.accounts.wsol.to_account_info(),
+            vault: ctx.accounts.vault.to_account_info(),
+            mint: ctx.accounts.mint.to_account_info(),
+            authority: market.to_account_info(),
+            recipient: ctx.accounts.authority.to_account_info(),
+            system_program: ctx.accounts.system_program.to_account_info(),
         };
-
-        // Construct the context with the market PDA and bump seed.
-        let cpi_ctx =
-            CpiContext::new_with_signer(token_program.to_account_info(), transfer_ix, seeds);
-
-        anchor_spl::token::transfer(cpi_ctx, amount).map_err(|err| match err {
-            _ => error!(ErrorCodeCustom::TransferFailed),
-        })?;
-        msg!("tokens withdrawn");
-
-        // Reduce balance from user's OpenOrders account
-        open_orders.native_coin_free = open_orders
-            .native_coin_free
-            .checked_sub(amount)
-            .ok_or(ErrorCodeCustom::Error)?;
-
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            unwrap_ix,
+            seeds,
+        );
+        token_interface::unwrap_sol(cpi_ctx, wsol_seeds, lamports)?;
+        msg!("withdrew {} lamports", lamports);
+
+        emit!(WithdrawEvent {
+            market: market.key(),
+            open_orders: open_orders_key,
+            mint: market.payer_mint(side),
+            amount: lamports,
+        });
         Ok(())
     }
 
-    pub fn withdraw_tokens(ctx: Context<WithdrawTokens>, amount: u64) -> Result<()> {
+    /// Pays all free coin and pc of an OpenOrders account to the owner's associated token accounts,
+    /// or as lamports for a native SOL mint. Permissionless, so a crank can settle on the owner's behalf.
+    pub fn settle_funds(ctx: Context<SettleFunds>) -> Result<()> {
         let program_id = ctx.program_id;
         let open_orders = &mut ctx.accounts.open_orders;
-        let market = &mut ctx.accounts.market;
-        let pc_vault = &ctx.accounts.pc_vault;
-        let payer = &ctx.accounts.payer;
-
-        let authority = &ctx.accounts.authority;
-        let token_program = &ctx.accounts.token_program;
+        require!(!open_orders.frozen, ErrorCodeCustom::OpenOrdersFrozen);
+        let market = &ctx.accounts.market;
         let coin_mint = &ctx.accounts.coin_mint;
         let pc_mint = &ctx.accounts.pc_mint;
         let (_market_pda, bump_seed) = Pubkey::find_program_address(
-            &[b"market", coin_mint.key().as_ref(), pc_mint.key().as_ref()],
+            &[
+                b"market",
+                coin_mint.key().as_ref(),
+                pc_mint.key().as_ref(),
+                &market.nonce.to_le_bytes(),
+            ],
             &program_id,
         );
 
-        // Validation: owner of openorders is the authority
-        require!(
-            open_orders.authority == authority.key(),
-            ErrorCodeCustom::InvalidAuthority
-        );
-
-        // Validation of the user's openorders balance
-        msg!("oo owner owner {}", open_orders.authority);
-        msg!("oo owner market {}", open_orders.market);
-        msg!("oo pc free : {}", open_orders.native_pc_free);
-
         // Signing the transaction with the market PDA and bump seed.
         let market_seed = b"market";
 
@@ -305,229 +511,382 @@ pub mod fermi_dex {
         let coin_mint_seed = coin_mint_key.as_ref();
         let pc_mint_seed = pc_mint_key.as_ref();
 
+        let nonce_seed = market.nonce.to_le_bytes();
         let bump_seed_arr: &[u8] = &[bump_seed];
 
-        let seed_slices: [&[u8]; 4] = [market_seed, coin_mint_seed, pc_mint_seed, bump_seed_arr];
+        let seed_slices: [&[u8]; 5] = [
+            market_seed,
+            coin_mint_seed,
+            pc_mint_seed,
+            &nonce_seed,
+            bump_seed_arr,
+        ];
         let seeds: &[&[&[u8]]] = &[&seed_slices];
 
-        let transfer_ix = Transfer {
-            from: pc_vault.to_account_info(),
-            to: payer.to_account_info(),
-            authority: market.to_account_info(), // Using the market PDA as the authority.
-        };
+        // a native SOL side is unwrapped and paid to the owner in lamports
+        let open_orders_key = open_orders.key();
+        let (_wsol_pda, wsol_bump) =
+            Pubkey::find_program_address(&[b"wsol", open_orders_key.as_ref()], &program_id);
+        let wsol_seeds: &[&[u8]] = &[b"wsol", open_orders_key.as_ref(), &[wsol_bump]];
+
+        let coin_amount = open_orders.native_coin_free;
+        if coin_amount > 0 {
+            if token_interface::is_native_mint(&coin_mint.key()) {
+                let unwrap_ix = UnwrapSol {
+                    payer: ctx.accounts.payer.to_account_info(),
+                    temp: ctx.accounts.wsol.to_account_info(),
+                    vault: ctx.accounts.coin_vault.to_account_info(),
+                    mint: coin_mint.to_account_info(),
+                    authority: market.to_account_info(),
+                    recipient: ctx.accounts.owner.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                };
+                let cpi_ctx = CpiContext::new_with_signer(
+                    ctx.accounts.coin_token_program.to_account_info(),
+                    unwrap_ix,
+                    seeds,
+                );
+                token_interface::unwrap_sol(cpi_ctx, wsol_seeds, coin_amount)?;
+            } else {
+                let transfer_ix = TransferChecked {
+                    from: ctx.accounts.coin_vault.to_account_info(),
+                    mint: coin_mint.to_account_info(),
+                    to: ctx.accounts.owner_coin_account.to_account_info(),
+                    authority: market.to_account_info(),
+                };
+                let cpi_ctx = CpiContext::new_with_signer(
+                    ctx.accounts.coin_token_program.to_account_info(),
+                    transfer_ix,
+                    seeds,
+                );
+                token_interface::transfer_checked(cpi_ctx, coin_amount)?;
+            }
 
-        // Construct the context with the market PDA and bump seed.
-        let cpi_ctx =
-            CpiContext::new_with_signer(token_program.to_account_info(), transfer_ix, seeds);
+            open_orders.withdraw(Side::Ask, coin_amount)?;
+        }
 
-        anchor_spl::token::transfer(cpi_ctx, amount).map_err(|err| match err {
-            _ => error!(ErrorCodeCustom::TransferFailed),
-        })?;
-        msg!("tokens withdrawn");
+        let pc_amount = open_orders.native_pc_free;
+        if pc_amount > 0 {
+            if token_interface::is_native_mint(&pc_mint.key()) {
+                let unwrap_ix = UnwrapSol {
+                    payer: ctx.accounts.payer.to_account_info(),
+                    temp: ctx.accounts.wsol.to_account_info(),
+                    vault: ctx.accounts.pc_vault.to_account_info(),
+                    mint: pc_mint.to_account_info(),
+                    authority: market.to_account_info(),
+                    recipient: ctx.accounts.owner.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                };
+                let cpi_ctx = CpiContext::new_with_signer(
+                    ctx.accounts.pc_token_program.to_account_info(),
+                    unwrap_ix,
+                    seeds,
+                );
+                token_interface::unwrap_sol(cpi_ctx, wsol_seeds, pc_amount)?;
+            } else {
+                let transfer_ix = TransferChecked {
+                    from: ctx.accounts.pc_vault.to_account_info(),
+                    mint: pc_mint.to_account_info(),
+                    to: ctx.accounts.owner_pc_account.to_account_info(),
+                    authority: market.to_account_info(),
+                };
+                let cpi_ctx = CpiContext::new_with_signer(
+                    ctx.accounts.pc_token_program.to_account_info(),
+                    transfer_ix,
+                    seeds,
+                );
+                token_interface::transfer_checked(cpi_ctx, pc_amount)?;
+            }
 
-        // Reduce balance from user's OpenOrders account
-        open_orders.native_pc_free = open_orders
-            .native_pc_free
-            .checked_sub(amount)
-            .ok_or(ErrorCodeCustom::Error)?;
+            open_orders.withdraw(Side::Bid, pc_amount)?;
+        }
 
+        let market = &mut ctx.accounts.market;
+        market.debit_deposits(Side::Ask, coin_amount)?;
+        market.debit_deposits(Side::Bid, pc_amount)?;
+        msg!("settled {} coin and {} pc to owner", coin_amount, pc_amount);
         Ok(())
     }
 
-    pub fn new_order(
-        ctx: Context<NewOrder>,
+    pub fn new_order<'info>(
+        ctx: Context<'_, '_, '_, 'info, NewOrder<'info>>,
+        side: Side,
+        limit_price: u64,
+        max_coin_qty: u64,
+        max_native_pc_qty: u64,
+        order_type: OrderType,
+        fully_funded: bool,
+    ) -> Result<()> {
+        place_order(
+            ctx,
+            side,
+            limit_price,
+            max_coin_qty,
+            max_native_pc_qty,
+            order_type,
+            fully_funded,
+            None,
+            None,
+        )
+    }
+
+    /// Rests an iceberg order showing at most `display_qty` lots. Each time the
+    /// visible slice fills, the next one is drawn from the hidden reserve and
+    /// goes to the back of its price level. Depth views only show the slice.
+    pub fn new_iceberg_order<'info>(
+        ctx: Context<'_, '_, '_, 'info, NewOrder<'info>>,
         side: Side,
         limit_price: u64,
         max_coin_qty: u64,
         max_native_pc_qty: u64,
+        display_qty: u64,
         order_type: OrderType,
+        fully_funded: bool,
+    ) -> Result<()> {
+        // an IOC never rests, so there'd be nothing to hide
+        require!(
+            !matches!(order_type, OrderType::ImmediateOrCancel),
+            ErrorCodeCustom::InvalidQty
+        );
+        place_order(
+            ctx,
+            side,
+            limit_price,
+            max_coin_qty,
+            max_native_pc_qty,
+            order_type,
+            fully_funded,
+            None,
+            Some(display_qty),
+        )
+    }
+
+    /// Rests an order whose price follows `peg`, recomputed whenever a taker
+    /// meets it. `limit_price` is the worst price it may trade at. Pegged
+    /// orders only provide liquidity and don't show in the depth views.
+    pub fn new_pegged_order<'info>(
+        ctx: Context<'_, '_, '_, 'info, NewOrder<'info>>,
+        side: Side,
+        limit_price: u64,
+        max_coin_qty: u64,
+        max_native_pc_qty: u64,
+        peg: Peg,
+        fully_funded: bool,
+    ) -> Result<()> {
+        place_order(
+            ctx,
+            side,
+            limit_price,
+            max_coin_qty,
+            max_native_pc_qty,
+            OrderType::PostOnly,
+            fully_funded,
+            Some(peg),
+            None,
+        )
+    }
+
+    /// Parks a conditional order until execute_triggers finds it triggered. Its
+    /// collateral is locked now, from the free balance first, and it holds an
+    /// OpenOrders slot meanwhile.
+    pub fn place_trigger_order(
+        ctx: Context<PlaceTriggerOrder>,
+        params: TriggerParams,
     ) -> Result<()> {
         let open_orders = &mut ctx.accounts.open_orders;
+        let trigger_orders = &mut ctx.accounts.trigger_orders;
         let market = &mut ctx.accounts.market;
-        let coin_vault = &ctx.accounts.coin_vault;
-        let pc_vault = &ctx.accounts.pc_vault;
-        let payer = &ctx.accounts.payer;
-        let bids = &mut ctx.accounts.bids;
-        let asks = &mut ctx.accounts.asks;
-        let req_q = &mut ctx.accounts.req_q;
-        let event_q = &mut ctx.accounts.event_q.load_mut();
         let authority = &ctx.accounts.authority;
-        let token_program = &ctx.accounts.token_program;
-        let _coin_mint = &ctx.accounts.coin_mint;
-        let _pc_mint = &ctx.accounts.pc_mint;
 
-        if !open_orders.is_initialized {
-            open_orders.init(market.key(), authority.key())?;
-        } else {
-            require!(
-                open_orders.market.key() == market.key(),
-                ErrorCodeCustom::EmptyQueue
-            );
-            require!(
-                open_orders.authority.key() == authority.key(),
-                ErrorCodeCustom::WrongAuthority
-            );
+        require!(!market.delisted, ErrorCodeCustom::MarketDelisted);
+        require!(!open_orders.frozen, ErrorCodeCustom::OpenOrdersFrozen);
+        require!(
+            params.trigger_price > 0 && params.limit_price > 0,
+            ErrorCodeCustom::InvalidPrice
+        );
+        if trigger_orders.market == Pubkey::default() {
+            trigger_orders.market = market.key();
         }
-        let clock = Clock::get()?;
-        let current_timestamp = clock.unix_timestamp;
-        msg!("timestamp is {}", current_timestamp);
-        let deposit_amount;
-        let deposit_vault;
-        let native_pc_qty_locked;
-        match side {
-            Side::Bid => {
-                let lock_qty_native = max_native_pc_qty
-                    .checked_mul(market.pc_lot_size)
-                    .ok_or(error!(ErrorCodeCustom::InsufficientFunds))?;
-                native_pc_qty_locked = Some(lock_qty_native);
-                let free_qty_to_lock = lock_qty_native.min(open_orders.native_pc_free);
-                let total_deposit_amount = lock_qty_native - free_qty_to_lock;
-                deposit_amount = total_deposit_amount; //for test with matching, L1044
-                deposit_vault = pc_vault;
 
+        let native_qty_locked = match params.side {
+            Side::Bid => params.max_native_pc_qty.checked_mul(market.pc_lot_size),
+            Side::Ask => params.max_coin_qty.checked_mul(market.coin_lot_size),
+        }
+        .ok_or(error!(ErrorCodeCustom::InsufficientFunds))?;
+        let free = match params.side {
+            Side::Bid => open_orders.native_pc_free,
+            Side::Ask => open_orders.native_coin_free,
+        };
+        let from_free = native_qty_locked.min(free);
+        let deposit_amount = native_qty_locked - from_free;
+
+        if deposit_amount > 0 {
+            let transfer_ix = TransferChecked {
+                from: ctx.accounts.payer.to_account_info(),
+                mint: ctx.accounts.mint.to_account_info(),
+                to: ctx.accounts.vault.to_account_info(),
+                authority: authority.to_account_info(),
+            };
+            let transfer_cpi_ctx =
+                CpiContext::new(ctx.accounts.token_program.to_account_info(), transfer_ix);
+            token_interface::transfer_checked_net(transfer_cpi_ctx, deposit_amount)?;
+        }
+
+        match params.side {
+            Side::Bid => {
+                open_orders.lock_free_pc(from_free)?;
+                open_orders.credit_locked_pc(deposit_amount)?;
                 market.pc_deposits_total = market
                     .pc_deposits_total
                     .checked_add(deposit_amount)
-                    .unwrap();
+                    .ok_or(ErrorCodeCustom::MathOverflow)?;
             }
             Side::Ask => {
-                native_pc_qty_locked = None;
-                let lock_qty_native = max_coin_qty
-                    .checked_mul(market.coin_lot_size)
-                    .ok_or(error!(ErrorCodeCustom::InsufficientFunds))?;
-                let free_qty_to_lock = lock_qty_native.min(open_orders.native_coin_free);
-                let total_deposit_amount = lock_qty_native - free_qty_to_lock;
-                deposit_amount = total_deposit_amount; //for test with matching, L1044
-                deposit_vault = coin_vault;
-
+                open_orders.lock_free_coin(from_free)?;
+                open_orders.credit_locked_coin(deposit_amount)?;
                 market.coin_deposits_total = market
                     .coin_deposits_total
                     .checked_add(deposit_amount)
-                    .unwrap();
+                    .ok_or(ErrorCodeCustom::MathOverflow)?;
             }
         }
 
-        let order_id = req_q.gen_order_id(limit_price, side);
-        if open_orders.free_slot_bits == 0 {
-            open_orders.remove_order(0);
-        }
-        let owner_slot = open_orders.add_order(order_id, side)?;
-        let request = RequestView::NewOrder {
-            side,
-            order_type,
+        let order_id = ctx
+            .accounts
+            .req_q
+            .gen_order_id(params.limit_price, params.side);
+        let owner_slot = open_orders.add_order(order_id, params.side)?;
+        open_orders.set_fully_funded(owner_slot);
+        trigger_orders.insert(TriggerOrder {
+            params,
             order_id,
             owner: open_orders.key(),
             owner_slot,
-            max_coin_qty,
-            native_pc_qty_locked,
-        };
-        let jitdata: Vec<JitStruct> = vec![];
-        let mut proceeds = RequestProceeds {
-            coin_unlocked: 0,
-            native_pc_unlocked: 0,
-            coin_credit: 0,
-            native_pc_credit: 0,
-            coin_debit: 0,
-            native_pc_debit: 0,
-            jit_data: jitdata,
-        };
-        let mut order_book = OrderBook { bids, asks, market };
+            native_qty_locked,
+        })?;
 
-        // matching occurs at this stage
-        msg!("proessing request");
-        order_book.process_request(&request, &mut event_q.as_mut().unwrap(), &mut proceeds)?;
-        msg!("request processed");
+        msg!(
+            "trigger order {} at {} placed",
+            order_id,
+            params.trigger_price
+        );
+        Ok(())
+    }
 
-        {
-            let coin_lot_size = market.coin_lot_size;
-
-            let RequestProceeds {
-                coin_unlocked,
-                coin_credit,
-
-                native_pc_unlocked: _,
-                native_pc_credit: _,
-
-                coin_debit,
-                native_pc_debit: _,
-                jit_data,
-            } = proceeds;
-            let _native_coin_unlocked = coin_unlocked.checked_mul(coin_lot_size).unwrap();
-            let _native_coin_credit = coin_credit.checked_mul(coin_lot_size).unwrap();
-            let _native_coin_debit = coin_debit.checked_mul(coin_lot_size).unwrap();
-
-            let others = jit_data;
-
-            msg!("going to loop!");
-            for p in others {
-                msg!("heya {}", p.owner);
-                let owner_slot = p.owner_slot;
-                msg!("this is the way {}", owner_slot);
-                let owner_order = open_orders.orders[usize::from(owner_slot)];
-                let deposits = p.native_qty_paid;
-                msg!("owner qty {}", owner_order);
-                msg!("dep {}", deposits);
-            }
-        }
-        let _matched_amount_pc = proceeds.native_pc_credit;
-        let matched_amount_coin = proceeds.coin_credit;
+    pub fn cancel_trigger_order(ctx: Context<CancelTriggerOrder>, order_id: u128) -> Result<()> {
+        let open_orders = &mut ctx.accounts.open_orders;
+        let trigger = ctx.accounts.trigger_orders.remove(order_id)?;
+        require!(
+            trigger.owner == open_orders.key(),
+            ErrorCodeCustom::OrderNotFound
+        );
 
-        // if order is not crossed, creator is maker, and only needs to approve tokens.
+        match trigger.params.side {
+            Side::Bid => open_orders.unlock_pc(trigger.native_qty_locked)?,
+            Side::Ask => open_orders.unlock_coin(trigger.native_qty_locked)?,
+        }
+        open_orders.remove_order(trigger.owner_slot)?;
 
-        if deposit_amount > 0 {
-            msg!("approval amount {}", deposit_amount);
+        msg!("cancelled trigger order: {}", order_id);
+        Ok(())
+    }
 
-            let approve_ix = Approve {
-                to: payer.to_account_info(),
-                delegate: market.to_account_info(),
-                authority: authority.to_account_info(), // authority.to_account_info(),
-            };
-            let approve_cpi_ctx = CpiContext::new(token_program.to_account_info(), approve_ix);
-            anchor_spl::token::approve(approve_cpi_ctx, deposit_amount).map_err(|err| {
-                msg!("Failed to approve tokens: {:?}", err);
-                ErrorCodeCustom::ApprovalFailed // Use the correct error code
-            })?;
-        }
-        msg!("Approval successful for {} tokens", deposit_amount);
-
-        // Calculate 1% of the deposit_amount
-        let transfer_fraction = 0.01; // 1%
-        let transfer_amount = (deposit_amount as f64 * transfer_fraction) as u64;
-
-        // Marginal deposit to back your order (for later penalties if order fails)
-        if transfer_amount > 0 {
-            // Set up the Approve instruction
-            let transfer_ix = Transfer {
-                from: payer.to_account_info(), // This is the account holding the tokens
-                to: deposit_vault.to_account_info(), // This is who you're giving permission to
-                authority: authority.to_account_info(), // The authority of the 'to' account
+    /// Places up to `max_orders` triggered orders through the matching engine,
+    /// checking each against the prices left by the ones before it. Orders whose
+    /// owner's OpenOrders wasn't passed stay parked.
+    pub fn execute_triggers(ctx: Context<ExecuteTriggers>, max_orders: u8) -> Result<()> {
+        let program_id = ctx.program_id;
+        let market_key = ctx.accounts.market.key();
+        require!(
+            !ctx.accounts.market.delisted,
+            ErrorCodeCustom::MarketDelisted
+        );
+        let trigger_orders = &mut ctx.accounts.trigger_orders;
+        let req_q = &mut ctx.accounts.req_q;
+        let mut event_q = ctx.accounts.event_q.load_mut()?;
+        let mut order_book = OrderBook {
+            bids: &mut ctx.accounts.bids,
+            asks: &mut ctx.accounts.asks,
+            market: &mut ctx.accounts.market,
+        };
+        let current_timestamp = Clock::get()?.unix_timestamp as u64;
+
+        let mut executed = 0;
+        let mut i = 0;
+        while i < trigger_orders.orders.len() && executed < max_orders {
+            let trigger = trigger_orders.orders[i];
+            let market = &order_book.market;
+            if !trigger.is_triggered(market.last_trade_price, market.mid_price()) {
+                i += 1;
+                continue;
+            }
+            let owner_info = match ctx
+                .remaining_accounts
+                .iter()
+                .find(|account| account.key() == trigger.owner)
+            {
+                Some(owner_info) => owner_info,
+                None => {
+                    i += 1;
+                    continue;
+                }
             };
 
-            // Create the CPI context for the approve instruction
-            let transfer_cpi_ctx = CpiContext::new(token_program.to_account_info(), transfer_ix);
-            msg!(
-                "Tokens transferred as Margin later spending: {}",
-                transfer_amount
+            let mut open_orders: Account<OpenOrders> = Account::try_from(owner_info)?;
+            let slot = usize::from(trigger.owner_slot);
+            require!(
+                open_orders.market == market_key && open_orders.orders[slot] == trigger.order_id,
+                ErrorCodeCustom::OrderNotFound
             );
-
-            // Update openorders balances.
-            match side {
-                Side::Bid => {
-                    open_orders.credit_locked_pc(transfer_amount);
-                }
-                Side::Ask => {
-                    open_orders.credit_locked_coin(transfer_amount);
-                }
+            // a frozen owner's triggers wait, they can still be cancelled
+            if open_orders.frozen {
+                i += 1;
+                continue;
+            }
+            // a fresh id gives the order time priority from when it fired
+            let order_id = req_q.gen_order_id(trigger.params.limit_price, trigger.params.side);
+            open_orders.orders[slot] = order_id;
+            trigger_orders.orders.remove(i);
+
+            let request = RequestView::NewOrder {
+                side: trigger.params.side,
+                order_type: trigger.params.order_type,
+                order_id,
+                owner: trigger.owner,
+                owner_slot: trigger.owner_slot,
+                max_coin_qty: trigger.params.max_coin_qty,
+                native_pc_qty_locked: match trigger.params.side {
+                    Side::Bid => Some(trigger.native_qty_locked),
+                    Side::Ask => None,
+                },
+                fully_funded: true,
+            };
+            let mut proceeds = RequestProceeds {
+                coin_unlocked: 0,
+                native_pc_unlocked: 0,
+                coin_credit: 0,
+                native_pc_credit: 0,
+                coin_debit: 0,
+                native_pc_debit: 0,
+                jit_data: vec![],
+            };
+            order_book.process_request(&request, &mut event_q, &mut proceeds, current_timestamp)?;
+            // as in place_order, what neither filled nor rests is released again
+            match trigger.params.side {
+                Side::Bid => open_orders.unlock_pc(proceeds.native_pc_unlocked)?,
+                Side::Ask => open_orders.unlock_coin(
+                    proceeds
+                        .coin_unlocked
+                        .checked_mul(order_book.market.coin_lot_size)
+                        .ok_or(ErrorCodeCustom::MathOverflow)?,
+                )?,
             }
-            // Execute the approval (passing the amount separately)
-            anchor_spl::token::transfer(transfer_cpi_ctx, transfer_amount).map_err(|err| {
-                msg!("Failed to transfer tokens: {:?}", err);
-                ErrorCodeCustom::TransferFailed // Replace with your actual error code
-            })?;
+            open_orders.exit(program_id)?;
+            executed += 1;
+            msg!("trigger order {} placed as {}", trigger.order_id, order_id);
         }
 
-        msg!("matched amount {}", matched_amount_coin);
-
+        msg!("{} trigger orders executed", executed);
         Ok(())
     }
 
@@ -555,14 +914,19 @@ pub mod fermi_dex {
         let event1_timestamp = event1.timestamp;
         let event2_timestamp = event2.timestamp;
         require!(
-            current_timestamp > event1_timestamp + 60 && current_timestamp > event2_timestamp + 60,
-            ErrorCodeCustom::FinalizeNotExpired
+            current_timestamp > event1_timestamp + FINALISE_TIMEOUT_SECS
+                && current_timestamp > event2_timestamp + FINALISE_TIMEOUT_SECS,
+            ErrorCodeCustom::SettlementWindowOpen
         );
 
         //Verify that the events are a match.
+        require!(
+            event1.is_fill() && event2.is_fill(),
+            ErrorCodeCustom::EventEvicted
+        );
         require!(
             event1.order_id_second == event2.order_id || event2.order_id_second == event1.order_id,
-            ErrorCodeCustom::Error
+            ErrorCodeCustom::EventsNotMatched
         );
 
         // verify that the events have not already been finalized
@@ -572,10 +936,6 @@ pub mod fermi_dex {
         );
 
         // Verify openorders specified match the events.
-        msg!("event1 owner is {}", event1.owner);
-        msg!("openorders bidder is {}", open_orders_bidder.key());
-        msg!("event2 owner is {}", event2.owner);
-        msg!("openorders asker is {}", open_orders_asker.key());
         require!(
             open_orders_bidder.key() == event1.owner || open_orders_asker.key() == event1.owner,
             ErrorCodeCustom::InvalidAuthority
@@ -587,21 +947,28 @@ pub mod fermi_dex {
             ErrorCodeCustom::InvalidAuthority
         );
 
+        // the side that did not default, and whether it is the bidder account
+        let honest_event: Event;
+        let honest_is_bidder: bool;
+
         match side {
             Side::Bid => {
                 if open_orders_bidder.key() == event1.owner {
                     // this ensures that a party cannot be penalised if they've already supplied capital.
                     require!(event1.finalised == 0, ErrorCodeCustom::SideAlreadyFinalised);
 
+                    honest_event = event2;
+                    honest_is_bidder = false;
+
                     // Calculate the penalty amount (1% of deposit_amount)
                     let deposit_amount = event1.native_qty_paid;
                     let penalty_amount = deposit_amount / 100;
 
                     // Deduct the penalty from the bidder's deposit
-                    open_orders_bidder.debit_locked_pc(penalty_amount);
+                    open_orders_bidder.debit_locked_pc(penalty_amount)?;
 
                     // Add the penalty amount to the asker's open order balance
-                    open_orders_asker.credit_unlocked_pc(penalty_amount);
+                    open_orders_asker.credit_unlocked_pc(penalty_amount)?;
 
                     msg!(
                         "Penalty of {} PC Tokens transferred from bidder to asker",
@@ -611,39 +978,39 @@ pub mod fermi_dex {
                     //If asker has finalized bid, free up their tokens deposited
                     if event2.finalised == 1 {
                         let asker_deposit_amount = event2.native_qty_paid;
-                        open_orders_asker.unlock_coin(asker_deposit_amount);
+                        open_orders_asker.unlock_coin(asker_deposit_amount)?;
                     } else {
                         // free up locked funds for honest counterparty
                         let asker_marginal_deposit = event2.native_qty_paid / 100;
-                        open_orders_asker.unlock_coin(asker_marginal_deposit);
+                        open_orders_asker.unlock_coin(asker_marginal_deposit)?;
                     }
                 } else {
                     require!(event2.finalised == 0, ErrorCodeCustom::SideAlreadyFinalised);
+                    honest_event = event1;
+                    honest_is_bidder = true;
+
                     let deposit_amount = event2.native_qty_paid;
                     let penalty_amount = deposit_amount / 100;
 
                     // Deduct the penalty from the bidder's deposit
-                    open_orders_asker.debit_locked_pc(penalty_amount);
+                    open_orders_asker.debit_locked_pc(penalty_amount)?;
 
                     // Add the penalty amount to the asker's open order balance
-                    open_orders_bidder.credit_unlocked_pc(penalty_amount);
+                    open_orders_bidder.credit_unlocked_pc(penalty_amount)?;
 
                     msg!(
                         "Penalty of {} PC Tokens transferred from bidder to asker",
                         penalty_amount
                     );
 
-                    // free up locked funds for honest counterparty
-                    let asker_marginal_deposit = event1.native_qty_released;
-                    open_orders_bidder.unlock_coin(asker_marginal_deposit);
                     //if asker has finalized bid, free up their tokens deposited
                     if event1.finalised == 1 {
                         let asker_deposit_amount = event1.native_qty_released;
-                        open_orders_bidder.unlock_coin(asker_deposit_amount);
+                        open_orders_bidder.unlock_coin(asker_deposit_amount)?;
                     } else {
                         // free up margin locked funds for honest counterparty
                         let asker_marginal_deposit = event1.native_qty_released / 100;
-                        open_orders_bidder.unlock_coin(asker_marginal_deposit);
+                        open_orders_bidder.unlock_coin(asker_marginal_deposit)?;
                     }
                 }
             }
@@ -652,15 +1019,17 @@ pub mod fermi_dex {
                 // this ensures that a party cannot be penalised if they've already supplied capital
                 if open_orders_asker.key() == event2.owner {
                     require!(event2.finalised == 0, ErrorCodeCustom::SideAlreadyFinalised);
+                    honest_event = event1;
+                    honest_is_bidder = true;
 
                     let deposit_amount = event2.native_qty_paid;
                     let penalty_amount = deposit_amount / 100;
 
                     // Deduct the penalty from the asker's deposit
-                    open_orders_asker.debit_locked_coin(penalty_amount);
+                    open_orders_asker.debit_locked_coin(penalty_amount)?;
 
                     // Add the penalty amount to the bidder's open order balance
-                    open_orders_bidder.credit_unlocked_coin(penalty_amount);
+                    open_orders_bidder.credit_unlocked_coin(penalty_amount)?;
 
                     msg!(
                         "Penalty of {} coins transferred from asker to bidder",
@@ -670,23 +1039,25 @@ pub mod fermi_dex {
                     // if bidder has finalized bid, free up their tokens deposited
                     if event1.finalised == 1 {
                         let bidder_deposit_amount = event1.native_qty_paid;
-                        open_orders_bidder.unlock_pc(bidder_deposit_amount);
+                        open_orders_bidder.unlock_pc(bidder_deposit_amount)?;
                     } else {
                         // free up margin locked funds for honest counterparty
                         let bidder_marginal_deposit = event1.native_qty_paid / 100;
-                        open_orders_bidder.unlock_pc(bidder_marginal_deposit);
+                        open_orders_bidder.unlock_pc(bidder_marginal_deposit)?;
                     }
                 } else {
                     require!(event1.finalised == 0, ErrorCodeCustom::SideAlreadyFinalised);
+                    honest_event = event2;
+                    honest_is_bidder = false;
 
                     let deposit_amount = event1.native_qty_paid;
                     let penalty_amount = deposit_amount / 100;
 
                     // Deduct the penalty from the asker's deposit
-                    open_orders_bidder.debit_locked_coin(penalty_amount);
+                    open_orders_bidder.debit_locked_coin(penalty_amount)?;
 
                     // Add the penalty amount to the bidder's open order balance
-                    open_orders_asker.credit_unlocked_coin(penalty_amount);
+                    open_orders_asker.credit_unlocked_coin(penalty_amount)?;
 
                     msg!(
                         "Penalty of {} coins transferred from asker to bidder",
@@ -696,12 +1067,88 @@ pub mod fermi_dex {
                     //if bidder has finalized bid, free up their tokens deposited
                     if event2.finalised == 1 {
                         let bidder_deposit_amount = event2.native_qty_released;
-                        open_orders_asker.unlock_pc(bidder_deposit_amount);
+                        open_orders_asker.unlock_pc(bidder_deposit_amount)?;
                     } else {
                         // free up margin locked funds for honest counterparty
                         let bidder_marginal_deposit = event2.native_qty_released / 100;
-                        open_orders_asker.unlock_pc(bidder_marginal_deposit);
+                        open_orders_asker.unlock_pc(bidder_marginal_deposit)?;
+                    }
+                }
+            }
+        }
+
+        // a default by the taker should not cost the honest maker its queue position
+        let honest_open_orders = if honest_is_bidder {
+            open_orders_bidder
+        } else {
+            open_orders_asker
+        };
+        let honest_flags = BitFlags::<EventFlag>::from_bits(honest_event.event_flags)
+            .unwrap_or(BitFlags::empty());
+        if honest_open_orders.requeue_on_default
+            && honest_flags.contains(EventFlag::Fill)
+            && honest_flags.contains(EventFlag::Maker)
+        {
+            let honest_side = EventFlag::flags_to_side(honest_flags);
+            let coin_lot_size = ctx.accounts.market.coin_lot_size;
+            let native_coin_qty = match honest_side {
+                Side::Bid => honest_event.native_qty_released,
+                Side::Ask => honest_event.native_qty_paid,
+            };
+            let qty = native_coin_qty / coin_lot_size;
+
+            if qty > 0 {
+                // the re-queued quantity stays backed by its margin, so the
+                // order only goes back if that can be locked and the book has room
+                let requeue_margin = honest_event.native_qty_paid / 100;
+                let order_id = honest_event.order_id;
+                let (free, has_room) = match honest_side {
+                    Side::Bid => (
+                        honest_open_orders.native_pc_free,
+                        ctx.accounts.bids.can_requeue(order_id),
+                    ),
+                    Side::Ask => (
+                        honest_open_orders.native_coin_free,
+                        ctx.accounts.asks.can_requeue(order_id),
+                    ),
+                };
+
+                if free >= requeue_margin && has_room {
+                    match honest_side {
+                        Side::Bid => honest_open_orders.lock_free_pc(requeue_margin)?,
+                        Side::Ask => honest_open_orders.lock_free_coin(requeue_margin)?,
                     }
+
+                    let owner_slot = if honest_open_orders.orders
+                        [usize::from(honest_event.owner_slot)]
+                        == order_id
+                    {
+                        honest_event.owner_slot
+                    } else {
+                        honest_open_orders.add_order(order_id, honest_side)?
+                    };
+
+                    let mut order_book = OrderBook {
+                        bids: &mut ctx.accounts.bids,
+                        asks: &mut ctx.accounts.asks,
+                        market: &mut ctx.accounts.market,
+                    };
+                    order_book.requeue_order(
+                        honest_side,
+                        Order {
+                            order_id,
+                            qty,
+                            owner: honest_event.owner,
+                            owner_slot,
+                            fully_funded: false,
+                            display_qty: 0,
+                            reserve_qty: 0,
+                        },
+                    )?;
+                    msg!("requeued order {} with qty {}", order_id, qty);
+                } else {
+                    // the margin was released above, the order just leaves the book
+                    msg!("order {} not requeued", order_id);
                 }
             }
         }
@@ -709,47 +1156,499 @@ pub mod fermi_dex {
         //replace events with finalised = 2
         let fin: u8 = 2;
         let owner = event1.owner;
-        let bidder_fill = Event::new(EventView::Finalise {
-            side: Side::Ask,
-            maker: true,
-            native_qty_paid: event1.native_qty_paid,
-            native_qty_received: event1.native_qty_released,
-            order_id: event1.order_id,
-            owner: event1.owner,
-            owner_slot: event1.owner_slot,
-            finalised: fin,
-            cpty: owner,
-        });
+        let bidder_fill = Event::new(
+            EventView::Finalise {
+                side: Side::Ask,
+                maker: true,
+                native_qty_paid: event1.native_qty_paid,
+                native_qty_received: event1.native_qty_released,
+                order_id: event1.order_id,
+                owner: event1.owner,
+                owner_slot: event1.owner_slot,
+                finalised: fin,
+                cpty: owner,
+            },
+            current_timestamp,
+        );
         let idx = event_slot1;
         event_q.buf[idx as usize] = bidder_fill;
 
         let owner = event2.owner;
-        let asker_fill = Event::new(EventView::Finalise {
-            side: Side::Ask,
-            maker: true,
-            native_qty_paid: event2.native_qty_paid,
-            native_qty_received: event2.native_qty_released,
-            order_id: event2.order_id,
-            owner: event2.owner,
-            owner_slot: event2.owner_slot,
-            finalised: fin,
-            cpty: owner,
-        });
+        let asker_fill = Event::new(
+            EventView::Finalise {
+                side: Side::Ask,
+                maker: true,
+                native_qty_paid: event2.native_qty_paid,
+                native_qty_received: event2.native_qty_released,
+                order_id: event2.order_id,
+                owner: event2.owner,
+                owner_slot: event2.owner_slot,
+                finalised: fin,
+                cpty: owner,
+            },
+            current_timestamp,
+        );
         let idx = event_slot2;
         event_q.buf[idx as usize] = asker_fill;
 
         Ok(())
     }
 
-    pub fn finalise_matches_bid(
-        ctx: Context<NewMatch>,
+    /// Permissionless. Once an account's health is negative, cancels its resting
+    /// orders and treats all of its pending fills as defaults, as
+    /// cancel_with_penalty would without waiting for the timeout. `fills` has to
+    /// cover every one of them, with each counterparty's OpenOrders passed as a
+    /// remaining account. The liquidator is paid LIQUIDATION_BOUNTY_BPS of each.
+    pub fn liquidate(ctx: Context<Liquidate>, fills: Vec<LiquidatedFill>) -> Result<()> {
+        let program_id = ctx.program_id;
+        let market_key = ctx.accounts.market.key();
+        let open_orders = &mut ctx.accounts.open_orders;
+        let liquidator_open_orders = &mut ctx.accounts.liquidator_open_orders;
+        let event_q = &mut ctx.accounts.event_q.load_mut()?;
+        let owner = open_orders.key();
+        require!(
+            liquidator_open_orders.key() != owner,
+            ErrorCodeCustom::InvalidAuthority
+        );
+
+        let pending = event_q.pending_obligations(owner, usize::MAX);
+        let health = open_orders.health(&ctx.accounts.market, &pending);
+        require!(health < 0, ErrorCodeCustom::AccountHealthy);
+
+        // fully funded orders give their funds back, the margin of JIT orders
+        // stays locked towards the penalties
+        let mut order_book = OrderBook {
+            bids: &mut ctx.accounts.bids,
+            asks: &mut ctx.accounts.asks,
+            market: &mut ctx.accounts.market,
+        };
+        for slot in 0..open_orders.orders.len() as u8 {
+            let order_id = open_orders.orders[usize::from(slot)];
+            let order_value = Order::price_from_order_id(order_id);
+            let resting = match open_orders.slot_side(slot) {
+                Some(Side::Bid) => order_book.bids.delete(order_id).ok(),
+                Some(Side::Ask) => order_book.asks.delete(order_id).ok(),
+                None => None,
+            };
+            // slots of filled and trigger orders are left alone
+            let order = match resting {
+                Some(order) => order,
+                None => continue,
+            };
+            if order.fully_funded {
+                match open_orders.slot_side(slot) {
+                    Some(Side::Bid) => {
+                        let pc_lot_size = order_book.market.pc_lot_size;
+                        open_orders.unlock_pc(order.total_qty() * order_value * pc_lot_size)?;
+                    }
+                    _ => {
+                        let coin_lot_size = order_book.market.coin_lot_size;
+                        open_orders.unlock_coin(order.total_qty() * coin_lot_size)?;
+                    }
+                }
+            }
+            open_orders.remove_order(slot)?;
+            msg!("cancelled order {}", order_id);
+        }
+        order_book.refresh_bbo();
+
+        let mut bounty_pc = 0;
+        let mut bounty_coin = 0;
+        for fill in fills {
+            let event = event_q.buf[usize::from(fill.event_slot)];
+            let cpty_event = event_q.buf[usize::from(fill.counterparty_slot)];
+            require!(
+                event.is_fill() && cpty_event.is_fill(),
+                ErrorCodeCustom::EventEvicted
+            );
+            require!(event.owner == owner, ErrorCodeCustom::InvalidAuthority);
+            require!(
+                event.finalised == 0 && cpty_event.finalised < 2,
+                ErrorCodeCustom::EventFinalised
+            );
+            require!(
+                event.order_id_second == cpty_event.order_id
+                    || cpty_event.order_id_second == event.order_id,
+                ErrorCodeCustom::EventsNotMatched
+            );
+
+            let mut cpty_account: Option<Account<OpenOrders>> = None;
+            let cpty: &mut OpenOrders = if cpty_event.owner == liquidator_open_orders.key() {
+                &mut *liquidator_open_orders
+            } else {
+                let cpty_info = ctx
+                    .remaining_accounts
+                    .iter()
+                    .find(|account| account.key() == cpty_event.owner)
+                    .ok_or(ErrorCodeCustom::InvalidAuthority)?;
+                let account: Account<OpenOrders> = Account::try_from(cpty_info)?;
+                require!(account.market == market_key, ErrorCodeCustom::WrongMarket);
+                cpty_account.insert(account)
+            };
+
+            let deposit_amount = event.native_qty_paid;
+            let bounty_amount = deposit_amount * LIQUIDATION_BOUNTY_BPS / 10_000;
+            match event.side() {
+                Side::Bid => {
+                    let penalty_amount = open_orders.seize_pc(deposit_amount / 100);
+                    cpty.credit_unlocked_pc(penalty_amount)?;
+                    if cpty_event.finalised == 1 {
+                        cpty.unlock_coin(cpty_event.native_qty_paid)?;
+                    } else {
+                        cpty.unlock_coin(cpty_event.native_qty_paid / 100)?;
+                    }
+                    bounty_pc += open_orders.seize_pc(bounty_amount);
+                    msg!("Penalty of {} PC Tokens paid by the bidder", penalty_amount);
+                }
+                Side::Ask => {
+                    let penalty_amount = open_orders.seize_coin(deposit_amount / 100);
+                    cpty.credit_unlocked_coin(penalty_amount)?;
+                    if cpty_event.finalised == 1 {
+                        cpty.unlock_pc(cpty_event.native_qty_paid)?;
+                    } else {
+                        cpty.unlock_pc(cpty_event.native_qty_paid / 100)?;
+                    }
+                    bounty_coin += open_orders.seize_coin(bounty_amount);
+                    msg!("Penalty of {} coins paid by the asker", penalty_amount);
+                }
+            }
+            if let Some(account) = cpty_account {
+                account.exit(program_id)?;
+            }
+
+            // finalised = 2 means cancelled with penalty
+            event_q.buf[usize::from(fill.event_slot)].finalised = 2;
+            event_q.buf[usize::from(fill.counterparty_slot)].finalised = 2;
+        }
+        require!(
+            event_q.pending_obligations(owner, 1).is_empty(),
+            ErrorCodeCustom::ObligationsRemaining
+        );
+
+        liquidator_open_orders.credit_unlocked_pc(bounty_pc)?;
+        liquidator_open_orders.credit_unlocked_coin(bounty_coin)?;
+        msg!("liquidation bounty {} pc, {} coin", bounty_pc, bounty_coin);
+        Ok(())
+    }
+
+    pub fn set_requeue_on_default(ctx: Context<SetRequeueOnDefault>, enabled: bool) -> Result<()> {
+        ctx.accounts.open_orders.requeue_on_default = enabled;
+
+        msg!("requeue on default: {}", enabled);
+        Ok(())
+    }
+
+    /// Points oracle-pegged orders at a price feed owned by `owner`, see
+    /// PRICE_FEED_LEN. `Pubkey::default()` disables them.
+    pub fn set_peg_oracle(ctx: Context<SetPegOracle>, oracle: Pubkey, owner: Pubkey) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        market.peg_oracle = oracle;
+        market.peg_oracle_owner = owner;
+        market.oracle_price = 0;
+        market.oracle_ts = 0;
+
+        msg!("peg oracle: {} owned by {}", oracle, owner);
+        Ok(())
+    }
+
+    /// Makes the market permissioned: new open orders accounts need
+    /// `admission_authority` to co-sign or to have put the owner on the allow
+    /// list. `Pubkey::default()` opens the market to everyone again.
+    pub fn set_admission_authority(
+        ctx: Context<SetAdmissionAuthority>,
+        admission_authority: Pubkey,
+    ) -> Result<()> {
+        ctx.accounts.market.open_orders_admission_authority = admission_authority;
+
+        msg!("admission authority: {}", admission_authority);
+        Ok(())
+    }
+
+    /// Adds `wallet` to the market's allow list, or takes it off. Accounts
+    /// already opened are unaffected.
+    pub fn set_allowed(ctx: Context<SetAllowed>, wallet: Pubkey, allowed: bool) -> Result<()> {
+        let allow_list = &mut ctx.accounts.allow_list;
+        allow_list.market = ctx.accounts.market.key();
+        if allowed {
+            allow_list.allow(wallet)?;
+        } else {
+            allow_list.disallow(wallet);
+        }
+
+        msg!("{} allowed: {}", wallet, allowed);
+        Ok(())
+    }
+
+    /// Freezes an open orders account, leaving its owner able to cancel
+    /// orders only. Fills already matched still settle.
+    pub fn freeze_open_orders(ctx: Context<FreezeOpenOrders>, frozen: bool) -> Result<()> {
+        ctx.accounts.open_orders.frozen = frozen;
+
+        msg!("frozen: {}", frozen);
+        Ok(())
+    }
+
+    /// Lets `delegate` place, cancel and finalise orders for this account.
+    /// `Pubkey::default()` revokes it.
+    pub fn set_delegate(ctx: Context<SetDelegate>, delegate: Pubkey) -> Result<()> {
+        ctx.accounts.open_orders.delegate = delegate;
+
+        msg!("delegate: {}", delegate);
+        Ok(())
+    }
+
+    /// Lets `session_key` trade like the delegate until `expires_at`, in unix
+    /// seconds. Replaces any earlier session; `Pubkey::default()` revokes it.
+    pub fn set_session_key(
+        ctx: Context<SetSessionKey>,
+        session_key: Pubkey,
+        expires_at: u64,
+    ) -> Result<()> {
+        let open_orders = &mut ctx.accounts.open_orders;
+        open_orders.session_key = session_key;
+        open_orders.session_expires_at = expires_at;
+
+        msg!("session key {} until {}", session_key, expires_at);
+        Ok(())
+    }
+
+    /// Restricts withdrawals to token accounts of `destinations`. An empty list
+    /// allows the authority's own accounts only.
+    pub fn set_withdraw_whitelist(
+        ctx: Context<SetWithdrawWhitelist>,
+        destinations: Vec<Pubkey>,
+    ) -> Result<()> {
+        require!(
+            destinations.len() <= MAX_WITHDRAW_DESTINATIONS,
+            ErrorCodeCustom::WithdrawWhitelistFull
+        );
+        let mut whitelist = [Pubkey::default(); MAX_WITHDRAW_DESTINATIONS];
+        whitelist[..destinations.len()].copy_from_slice(&destinations);
+        ctx.accounts.open_orders.withdraw_whitelist = whitelist;
+
+        Ok(())
+    }
+
+    /// Closes an open orders account with no orders, balances or pending fills
+    /// left, returning its rent to `recipient`.
+    pub fn close_open_orders(ctx: Context<CloseOpenOrders>) -> Result<()> {
+        let open_orders = &ctx.accounts.open_orders;
+        require!(
+            open_orders.free_slot_bits == u8::MAX,
+            ErrorCodeCustom::OpenOrdersNotEmpty
+        );
+        require!(
+            open_orders.native_coin_free == 0
+                && open_orders.native_coin_total == 0
+                && open_orders.native_pc_free == 0
+                && open_orders.native_pc_total == 0,
+            ErrorCodeCustom::OpenOrdersHoldsFunds
+        );
+        let event_q = ctx.accounts.event_q.load()?;
+        require!(
+            event_q.pending_obligations(open_orders.key(), 1).is_empty(),
+            ErrorCodeCustom::PendingFills
+        );
+
+        msg!("closed open orders {}", open_orders.key());
+        Ok(())
+    }
+
+    pub fn init_margin_account(ctx: Context<InitMarginAccount>) -> Result<()> {
+        ctx.accounts.margin_account.owner = ctx.accounts.owner.key();
+
+        Ok(())
+    }
+
+    /// Adds `amount` of the mint to the margin account, creating its vault on the first deposit.
+    pub fn deposit_margin(ctx: Context<DepositMargin>, amount: u64) -> Result<()> {
+        let accounts = &ctx.accounts;
+        token_interface::check_mint(&accounts.mint)?;
+        if accounts.vault.data_is_empty() {
+            token_interface::create_associated_account(
+                &accounts.owner,
+                &accounts.vault,
+                &accounts.margin_account.to_account_info(),
+                &accounts.mint,
+                &accounts.system_program,
+                &accounts.token_program,
+                &accounts.associated_token_program,
+            )?;
+        }
+
+        let transfer_ix = TransferChecked {
+            from: accounts.payer.to_account_info(),
+            mint: accounts.mint.to_account_info(),
+            to: accounts.vault.to_account_info(),
+            authority: accounts.owner.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(accounts.token_program.to_account_info(), transfer_ix);
+        token_interface::transfer_checked(cpi_ctx, amount)?;
+
+        // only what arrives after the mint's transfer fee is credited
+        let received = token_interface::amount_received(&accounts.mint, amount)?;
+        let mint = accounts.mint.key();
+        ctx.accounts.margin_account.deposit(mint, received)?;
+
+        Ok(())
+    }
+
+    /// Withdraws collateral the margin account's reserved obligations don't need.
+    pub fn withdraw_margin(ctx: Context<WithdrawMargin>, amount: u64) -> Result<()> {
+        let program_id = ctx.program_id;
+        let owner = ctx.accounts.owner.key();
+        let mint = &ctx.accounts.mint;
+        ctx.accounts.margin_account.withdraw(mint.key(), amount)?;
+
+        let (_margin_pda, bump_seed) =
+            Pubkey::find_program_address(&[b"margin", owner.as_ref()], &program_id);
+        let bump_seed_arr: &[u8] = &[bump_seed];
+        let seed_slices: [&[u8]; 3] = [b"margin", owner.as_ref(), bump_seed_arr];
+        let seeds: &[&[&[u8]]] = &[&seed_slices];
+
+        let transfer_ix = TransferChecked {
+            from: ctx.accounts.vault.to_account_info(),
+            mint: mint.to_account_info(),
+            to: ctx.accounts.destination.to_account_info(),
+            authority: ctx.accounts.margin_account.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            transfer_ix,
+            seeds,
+        );
+        // any transfer fee comes out of what the owner receives
+        token_interface::transfer_checked(cpi_ctx, amount)?;
+
+        Ok(())
+    }
+
+    /// Backs this account's JIT orders with the owner's margin account from
+    /// now on, creating the account if needed. Only allowed while it has no
+    /// open orders.
+    pub fn link_margin_account<'info>(
+        ctx: Context<'_, '_, '_, 'info, LinkMarginAccount<'info>>,
+    ) -> Result<()> {
+        let open_orders = &mut ctx.accounts.open_orders;
+        if !open_orders.is_initialized {
+            require!(
+                utils2::is_admitted(
+                    &ctx.accounts.market,
+                    ctx.accounts.authority.key(),
+                    ctx.remaining_accounts
+                )?,
+                ErrorCodeCustom::NotAdmitted
+            );
+            open_orders.init(ctx.accounts.market.key(), ctx.accounts.authority.key())?;
+        }
+        require!(
+            open_orders.free_slot_bits == u8::MAX,
+            ErrorCodeCustom::OpenOrdersNotEmpty
+        );
+        open_orders.margin_account = ctx.accounts.margin_account.key();
+
+        msg!("margin account: {}", open_orders.margin_account);
+        Ok(())
+    }
+
+    /// Returns a borsh `Bbo`: best bid and offer with the quantity resting at each.
+    pub fn view_bbo(ctx: Context<ViewBook>) -> Result<()> {
+        let bbo = Bbo {
+            best_bid: ctx.accounts.bids.levels(1).first().copied(),
+            best_ask: ctx.accounts.asks.levels(1).first().copied(),
+        };
+        utils2::return_view(&bbo)?;
+        Ok(())
+    }
+
+    /// Returns a borsh `PriceView` of the market's last trade, BBO and TWAP.
+    pub fn view_price(ctx: Context<ViewBook>) -> Result<()> {
+        let market = &ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp as u64;
+        let price = PriceView {
+            last_trade_price: market.last_trade_price,
+            last_trade_ts: market.last_trade_ts,
+            best_bid: market.best_bid,
+            best_ask: market.best_ask,
+            price_cumulative: market.price_cumulative_at(now),
+            twap: market.twap(now),
+            timestamp: now,
+        };
+        utils2::return_view(&price)?;
+        Ok(())
+    }
+
+    /// Returns a borsh `Vec<PriceLevel>` of one side, best first. At most
+    /// MAX_VIEW_LEVELS levels fit the return data. Iceberg reserves are hidden.
+    pub fn view_depth(ctx: Context<ViewBook>, side: Side, levels: u8) -> Result<()> {
+        let levels = usize::from(levels).min(MAX_VIEW_LEVELS);
+        let depth = match side {
+            Side::Bid => ctx.accounts.bids.levels(levels),
+            Side::Ask => ctx.accounts.asks.levels(levels),
+        };
+        utils2::return_view(&depth)?;
+        Ok(())
+    }
+
+    /// Returns a borsh `Vec<OpenOrderView>` of the occupied slots of an OpenOrders account.
+    pub fn view_open_orders(ctx: Context<ViewOpenOrders>) -> Result<()> {
+        let open_orders = &ctx.accounts.open_orders;
+        let mut views = vec![];
+        for slot in 0..open_orders.orders.len() as u8 {
+            let side = match open_orders.slot_side(slot) {
+                Some(side) => side,
+                None => continue,
+            };
+            let order_id = open_orders.orders[usize::from(slot)];
+            let qty = match side {
+                Side::Bid => ctx.accounts.bids.resting_qty(order_id),
+                Side::Ask => ctx.accounts.asks.resting_qty(order_id),
+            };
+            views.push(OpenOrderView {
+                slot,
+                side,
+                order_id,
+                price: Order::price_from_order_id(order_id),
+                qty,
+                fully_funded: open_orders.slot_is_fully_funded(slot),
+            });
+        }
+        utils2::return_view(&views)?;
+        Ok(())
+    }
+
+    /// Returns a borsh `Vec<PendingObligation>`: the fills of an OpenOrders account
+    /// still waiting for its JIT funding, oldest first, at most MAX_VIEW_OBLIGATIONS.
+    pub fn view_pending_obligations(ctx: Context<ViewOpenOrders>) -> Result<()> {
+        let event_q = ctx.accounts.event_q.load()?;
+        let pending =
+            event_q.pending_obligations(ctx.accounts.open_orders.key(), MAX_VIEW_OBLIGATIONS);
+        utils2::return_view(&pending)?;
+        Ok(())
+    }
+
+    /// Returns a borsh `i128`: the account's health against its pending fills,
+    /// see `OpenOrders::health`. It can be liquidated while this is negative.
+    pub fn view_health(ctx: Context<ViewOpenOrders>) -> Result<()> {
+        let event_q = ctx.accounts.event_q.load()?;
+        let pending = event_q.pending_obligations(ctx.accounts.open_orders.key(), usize::MAX);
+        let health = ctx
+            .accounts
+            .open_orders
+            .health(&ctx.accounts.market, &pending);
+        utils2::return_view(&health)?;
+        Ok(())
+    }
+
+    pub fn finalise_matches_bid<'info>(
+        ctx: Context<'_, '_, '_, 'info, NewMatch<'info>>,
         event1_slot: u8,
         event2_slot: u8,
     ) -> Result<()> {
         let program_id = ctx.program_id;
         let open_orders_auth = &mut ctx.accounts.open_orders_owner;
         let open_orders_cpty = &mut ctx.accounts.open_orders_counterparty;
-        let market = &ctx.accounts.market;
+        let market = &mut ctx.accounts.market;
         let pc_vault = &ctx.accounts.pc_vault;
         let _req_q = &mut ctx.accounts.req_q;
         let event_q = &mut ctx.accounts.event_q.load_mut()?;
@@ -762,49 +1661,60 @@ pub mod fermi_dex {
         let event1: Event = event_q.buf[usize::from(event1_slot)];
         let event2: Event = event_q.buf[usize::from(event2_slot)];
 
-        let event1_orderid = event1.order_id;
-        let event2_orderid = event2.order_id;
-        let event1_orderidsecond = event1.order_id_second;
-        let event2_orderidsecond = event2.order_id_second;
-
-        msg!("event1 orderid is {}", event1_orderid);
-        msg!("event1 orderidsecond is {}", event1_orderidsecond);
-        msg!("event2 orderid is {}", event2_orderid);
-        msg!("event2 orderidsecond is {}", event2_orderidsecond);
-
+        require!(
+            event1.is_fill() && event2.is_fill(),
+            ErrorCodeCustom::EventEvicted
+        );
         require!(
             event1.order_id_second == event2.order_id,
-            ErrorCodeCustom::Error
+            ErrorCodeCustom::EventsNotMatched
+        );
+
+        // the open orders accounts must be the ones recorded on the events
+        require!(
+            event1.owner == open_orders_auth.key() && event2.owner == open_orders_cpty.key(),
+            ErrorCodeCustom::InvalidAuthority
         );
 
+        // only the bidder's own wallet can be pulled from, or the vault of the
+        // margin account backing their orders
+        let bidder = if event1.side() == Side::Bid {
+            &open_orders_auth
+        } else {
+            &open_orders_cpty
+        };
+        let bidder_authority = bidder.authority;
+        let mut margin = if bidder.margin_account != Pubkey::default() {
+            Some((
+                utils2::linked_margin_account(bidder, ctx.remaining_accounts)?,
+                utils2::margin_vault(bidder, pc_mint, ctx.remaining_accounts)?,
+            ))
+        } else {
+            require!(
+                token_interface::is_token_account(payerpc, &pc_mint.key(), Some(&bidder_authority)),
+                ErrorCodeCustom::InvalidAuthority
+            );
+            None
+        };
+        let (_margin_pda, margin_bump) =
+            Pubkey::find_program_address(&[b"margin", bidder_authority.as_ref()], &program_id);
+        let margin_bump_arr: &[u8] = &[margin_bump];
+        let margin_seed_slices: [&[u8]; 3] =
+            [b"margin", bidder_authority.as_ref(), margin_bump_arr];
+        let margin_seeds: &[&[&[u8]]] = &[&margin_seed_slices];
+
         let events: Vec<Event> = vec![event1, event2];
         let mut eventBidFinalised: bool = false;
         let mut eventAskFinalised: bool = false;
 
-        //validation
+        //validation, finalised = 2 means cancelled with penalty and 3 means already settled
         require!(
-            event1.finalised == 0 || event2.finalised == 0,
+            event1.finalised < 2 && event2.finalised < 2,
             ErrorCodeCustom::BothEventsFinalised
         );
 
         for (index, parsed_event) in events.iter().enumerate() {
             let sider; // u8 for side
-            match BitFlags::<EventFlag>::from_bits(parsed_event.event_flags) {
-                Ok(flags) => {
-                    let side = EventFlag::flags_to_side(flags);
-                    msg!(
-                        "The side derived from parsed_event.event_flags is: {:?}",
-                        side
-                    );
-                }
-                Err(_) => {
-                    msg!(
-                        "Error: Invalid flags detected: {:?}",
-                        parsed_event.event_flags
-                    );
-                }
-            }
-
             let flags = BitFlags::<EventFlag>::from_bits(parsed_event.event_flags)
                 .unwrap_or(BitFlags::empty());
 
@@ -814,24 +1724,28 @@ pub mod fermi_dex {
             } else {
                 sider = 2;
             }
-            msg!("side is {}", sider);
 
             if sider == 1 {
+                // fully funded bids were paid into the vault when they were placed
+                if parsed_event.finalised == 1 {
+                    eventBidFinalised = true;
+                    continue;
+                }
+
                 let qty_pc = parsed_event.native_qty_paid;
-                let qty_coin = parsed_event.native_qty_released;
-                let available_funds = open_orders_auth.native_pc_total;
-                msg!("the available funds is {}", available_funds);
-                msg!("the required funds are {}", qty_pc);
 
                 let deposit_amount = qty_pc / (market.pc_lot_size * 10);
-                msg!("Deposit amt {}", deposit_amount);
-                let cpty_deposit_amt = qty_coin;
                 let deposit_vault = pc_vault;
 
                 if deposit_amount > 0 {
                     // Derive the market's PDA and bump seed.
                     let (_market_pda, bump_seed) = Pubkey::find_program_address(
-                        &[b"market", coin_mint.key().as_ref(), pc_mint.key().as_ref()],
+                        &[
+                            b"market",
+                            coin_mint.key().as_ref(),
+                            pc_mint.key().as_ref(),
+                            &market.nonce.to_le_bytes(),
+                        ],
                         &program_id,
                     );
 
@@ -843,16 +1757,37 @@ pub mod fermi_dex {
                     let coin_mint_seed = coin_mint_key.as_ref();
                     let pc_mint_seed = pc_mint_key.as_ref();
 
+                    let nonce_seed = market.nonce.to_le_bytes();
                     let bump_seed_arr: &[u8] = &[bump_seed];
 
-                    let seed_slices: [&[u8]; 4] =
-                        [market_seed, coin_mint_seed, pc_mint_seed, bump_seed_arr];
-                    let seeds: &[&[&[u8]]] = &[&seed_slices];
+                    let seed_slices: [&[u8]; 5] = [
+                        market_seed,
+                        coin_mint_seed,
+                        pc_mint_seed,
+                        &nonce_seed,
+                        bump_seed_arr,
+                    ];
+                    let mut seeds: &[&[&[u8]]] = &[&seed_slices];
+                    let mut from = payerpc.to_account_info();
+                    let mut transfer_authority = market.to_account_info(); // Using the market PDA as the authority.
+                    if let Some((margin_account, vault)) = &margin {
+                        seeds = margin_seeds;
+                        from = vault.clone();
+                        transfer_authority = margin_account.to_account_info();
+                    } else {
+                        // pulled on the approval given when the order was placed
+                        require!(
+                            token_interface::delegated_amount(payerpc, &market.key())
+                                >= token_interface::amount_with_fee(pc_mint, deposit_amount)?,
+                            ErrorCodeCustom::DelegationInsufficient
+                        );
+                    }
 
-                    let transfer_ix = Transfer {
-                        from: payerpc.to_account_info(),
+                    let transfer_ix = TransferChecked {
+                        from,
+                        mint: pc_mint.to_account_info(),
                         to: deposit_vault.to_account_info(),
-                        authority: market.to_account_info(), // Using the market PDA as the authority.
+                        authority: transfer_authority,
                     };
 
                     // Construct the context with the market PDA and bump seed.
@@ -864,17 +1799,18 @@ pub mod fermi_dex {
                     // handle error if transfer fails by pentalty
 
                     // Execute the transfer
-                    if let Err(err) = anchor_spl::token::transfer(cpi_ctx, deposit_amount) {
+                    if let Err(err) = token_interface::transfer_checked_net(cpi_ctx, deposit_amount)
+                    {
                         // CPI errors cannot be handled in code, this arm will not be reached.
                         msg!("Failed to transfer tokens: {:?}", err);
                         msg!("handling penalty payments!");
                         let penalty_amount = deposit_amount / 100;
                         
                         // Deduct the penalty from the bidder's deposit
-                        open_orders_auth.debit_locked_pc(penalty_amount);
+                        open_orders_auth.debit_locked_pc(penalty_amount)?;
 
                         // Add the penalty amount to the asker's open order balance
-                        open_orders_cpty.credit_unlocked_pc(penalty_amount);
+                        open_orders_cpty.credit_unlocked_pc(penalty_amount)?;
 
                         msg!(
                             "Penalty of {} PC Tokens transferred from bidder to asker",
@@ -883,19 +1819,21 @@ pub mod fermi_dex {
                         // finalized = 2 means cancelled with penalty
                         let fin: u8 = 2;
                         let owner = parsed_event.owner;
-                        msg!("deposit amount {}", deposit_amount);
-                        open_orders_auth.credit_unlocked_pc(deposit_amount);
-                        let bidder_fill = Event::new(EventView::Finalise {
-                            side: Side::Ask,
-                            maker: true,
-                            native_qty_paid: parsed_event.native_qty_paid,
-                            native_qty_received: parsed_event.native_qty_released,
-                            order_id: parsed_event.order_id,
-                            owner: parsed_event.owner,
-                            owner_slot: parsed_event.owner_slot,
-                            finalised: fin,
-                            cpty: owner,
-                        });
+                        open_orders_auth.credit_unlocked_pc(deposit_amount)?;
+                        let bidder_fill = Event::new(
+                            EventView::Finalise {
+                                side: Side::Ask,
+                                maker: true,
+                                native_qty_paid: parsed_event.native_qty_paid,
+                                native_qty_received: parsed_event.native_qty_released,
+                                order_id: parsed_event.order_id,
+                                owner: parsed_event.owner,
+                                owner_slot: parsed_event.owner_slot,
+                                finalised: fin,
+                                cpty: owner,
+                            },
+                            ctx.accounts.clock.unix_timestamp as u64,
+                        );
                         let mut event_slot = 1;
                         if index == 0 {
                             event_slot = event1_slot;
@@ -907,12 +1845,15 @@ pub mod fermi_dex {
                         event_q.buf[idx as usize] = bidder_fill;
                         eventBidFinalised = false;
                     } else {
-                        msg!("Tokens transferred!");
+                        if let Some((margin_account, _vault)) = &mut margin {
+                            let pulled = token_interface::amount_with_fee(pc_mint, deposit_amount)?;
+                            margin_account.draw(pc_mint.key(), pulled)?;
+                        }
+                        market.credit_deposits(Side::Bid, deposit_amount)?;
 
                         let fin: u8 = 1;
                         let owner = parsed_event.owner;
-                        msg!("deposit amount {}", deposit_amount);
-                        open_orders_auth.credit_unlocked_pc(deposit_amount);
+                        open_orders_auth.credit_unlocked_pc(deposit_amount)?;
                         let mut event_updated = parsed_event.clone();
                         event_updated.finalised = 1;
                         let bidder_finalize = event_updated;
@@ -927,22 +1868,17 @@ pub mod fermi_dex {
                         event_q.buf[idx as usize] = bidder_finalize;
                         eventBidFinalised = true;
                     }
-                    if cpty_deposit_amt > 0 {}
-                    let remaining_funds = 0;
-                    if remaining_funds > 0 {
-                        msg!("Newly locked PC for bidder {}", qty_pc);
-                    }
                     if index == 0 {
                         open_orders_auth.native_pc_total = open_orders_auth
                             .native_pc_total
                             .checked_add(qty_pc)
-                            .unwrap();
+                            .ok_or(ErrorCodeCustom::MathOverflow)?;
                     }
                     if index == 1 {
                         open_orders_cpty.native_pc_total = open_orders_cpty
                             .native_pc_total
                             .checked_add(deposit_amount)
-                            .unwrap();
+                            .ok_or(ErrorCodeCustom::MathOverflow)?;
                     }
                 }
             }
@@ -960,74 +1896,40 @@ pub mod fermi_dex {
         //Settlement if both events are finalised
 
         if eventBidFinalised == true && eventAskFinalised == true {
-            //checked subtract pc from event1 owner
-            open_orders_auth.native_pc_total = open_orders_auth
-                .native_pc_total
-                .checked_sub(event1.native_qty_paid)
-                .unwrap();
-
-            //subtract coin from event2 owner
-            //checked sub
-            open_orders_cpty.native_coin_total = open_orders_cpty
-                .native_coin_total
-                .checked_sub(event2.native_qty_paid)
-                .unwrap();
+            //subtract locked pc from event1 owner
+            open_orders_auth.debit_locked_pc(event1.native_qty_paid)?;
+
+            //subtract locked coin from event2 owner
+            open_orders_cpty.debit_locked_coin(event2.native_qty_paid)?;
 
             //add pc to event2 owner
-            let qty_pc = event2.native_qty_released;
-            let qty_coin = event1.native_qty_released;
-
-            ctx.accounts.open_orders_owner.native_pc_free = ctx
-                .accounts
-                .open_orders_counterparty
-                .native_pc_free
-                .checked_add(qty_pc)
-                .ok_or(ErrorCodeCustom::Error)?;
-
-            ctx.accounts.open_orders_counterparty.native_coin_total = ctx
-                .accounts
-                .open_orders_counterparty
-                .native_coin_free
-                .checked_add(qty_coin)
-                .ok_or(ErrorCodeCustom::Error)?;
+            open_orders_cpty.credit_unlocked_pc(event2.native_qty_released)?;
+
             //add coin to event1 owner
+            open_orders_auth.credit_unlocked_coin(event1.native_qty_released)?;
 
-            msg!("settlement completed!");
-            msg!("balance pc added to cpty {}", qty_pc);
-            msg!("balance coin added to auth {}", qty_coin);
-            msg!(
-                "oo cpty coin bal {}",
-                ctx.accounts.open_orders_counterparty.native_coin_total
-            );
-            msg!(
-                "oo cpty owner {}",
-                ctx.accounts.open_orders_counterparty.authority
-            );
+            // finalised = 3 means settled, so the pair cannot be settled twice
+            event_q.buf[usize::from(event1_slot)].finalised = 3;
+            event_q.buf[usize::from(event2_slot)].finalised = 3;
+        }
 
-            msg!(
-                "oo owner pc bal {}",
-                ctx.accounts.open_orders_owner.native_pc_total
-            );
-            msg!(
-                "oo owner owner {}",
-                ctx.accounts.open_orders_owner.authority
-            );
-            msg!("oo owner market {}", ctx.accounts.open_orders_owner.market);
+        if let Some((margin_account, _vault)) = margin {
+            margin_account.exit(program_id)?;
         }
 
         Ok(())
     }
 
     /// just in time transfers for ask side
-    pub fn finalise_matches_ask(
-        ctx: Context<NewMatchAsk>,
+    pub fn finalise_matches_ask<'info>(
+        ctx: Context<'_, '_, '_, 'info, NewMatchAsk<'info>>,
         event1_slot: u8,
         event2_slot: u8,
     ) -> Result<()> {
         let program_id = ctx.program_id;
         let open_orders_auth = &mut ctx.accounts.open_orders_owner; //owner of event 1
         let open_orders_cpty = &mut ctx.accounts.open_orders_counterparty; // owner of event 2
-        let market = &ctx.accounts.market;
+        let market = &mut ctx.accounts.market;
         let coin_vault = &ctx.accounts.coin_vault;
         let _req_q = &mut ctx.accounts.req_q;
         let event_q = &mut ctx.accounts.event_q.load_mut()?;
@@ -1040,29 +1942,59 @@ pub mod fermi_dex {
         let event1: Event = event_q.buf[usize::from(event1_slot)];
         let event2: Event = event_q.buf[usize::from(event2_slot)];
 
-        let event1_orderid = event1.order_id;
-        let event2_orderid = event2.order_id;
-        let event1_orderidsecond = event1.order_id_second;
-        let event2_orderidsecond = event2.order_id_second;
-
-        msg!("event1 orderid is {}", event1_orderid);
-        msg!("event1 orderidsecond is {}", event1_orderidsecond);
-        msg!("event2 orderid is {}", event2_orderid);
-        msg!("event2 orderidsecond is {}", event2_orderidsecond);
-
+        require!(
+            event1.is_fill() && event2.is_fill(),
+            ErrorCodeCustom::EventEvicted
+        );
         require!(
             event1.order_id_second == event2.order_id,
-            ErrorCodeCustom::BothEventsFinalised
+            ErrorCodeCustom::EventsNotMatched
+        );
+
+        // the open orders accounts must be the ones recorded on the events
+        require!(
+            event1.owner == open_orders_auth.key() && event2.owner == open_orders_cpty.key(),
+            ErrorCodeCustom::InvalidAuthority
         );
 
+        // only the asker's own wallet can be pulled from, or the vault of the
+        // margin account backing their orders
+        let asker = if event1.side() == Side::Ask {
+            &open_orders_auth
+        } else {
+            &open_orders_cpty
+        };
+        let asker_authority = asker.authority;
+        let mut margin = if asker.margin_account != Pubkey::default() {
+            Some((
+                utils2::linked_margin_account(asker, ctx.remaining_accounts)?,
+                utils2::margin_vault(asker, coin_mint, ctx.remaining_accounts)?,
+            ))
+        } else {
+            require!(
+                token_interface::is_token_account(
+                    payercoin,
+                    &coin_mint.key(),
+                    Some(&asker_authority)
+                ),
+                ErrorCodeCustom::InvalidAuthority
+            );
+            None
+        };
+        let (_margin_pda, margin_bump) =
+            Pubkey::find_program_address(&[b"margin", asker_authority.as_ref()], &program_id);
+        let margin_bump_arr: &[u8] = &[margin_bump];
+        let margin_seed_slices: [&[u8]; 3] = [b"margin", asker_authority.as_ref(), margin_bump_arr];
+        let margin_seeds: &[&[&[u8]]] = &[&margin_seed_slices];
+
         let events: Vec<Event> = vec![event1, event2];
 
         let mut eventBidFinalised: bool = false;
         let mut eventAskFinalised: bool = false;
 
-        //validation
+        //validation, finalised = 2 means cancelled with penalty and 3 means already settled
         require!(
-            event1.finalised == 0 || event2.finalised == 0,
+            event1.finalised < 2 && event2.finalised < 2,
             ErrorCodeCustom::BothEventsFinalised
         );
 
@@ -1078,22 +2010,28 @@ pub mod fermi_dex {
             } else {
                 sider = 2;
             }
-            msg!("side is {}", sider);
 
             if sider == 2 {
+                // fully funded asks were paid into the vault when they were placed
+                if parsed_event.finalised == 1 {
+                    eventAskFinalised = true;
+                    continue;
+                }
+
                 let qty_coin = parsed_event.native_qty_paid;
-                let available_funds = open_orders_auth.native_coin_total;
-                msg!("the available funds is {}", available_funds);
-                msg!("the required funds are {}", qty_coin);
 
                 let deposit_amount = qty_coin; //decimals already multiplied
-                msg!("Deposit amt {}", deposit_amount);
                 let deposit_vault = coin_vault;
 
                 if deposit_amount > 0 {
                     // Derive the market's PDA and bump seed.
                     let (_market_pda, bump_seed) = Pubkey::find_program_address(
-                        &[b"market", coin_mint.key().as_ref(), pc_mint.key().as_ref()],
+                        &[
+                            b"market",
+                            coin_mint.key().as_ref(),
+                            pc_mint.key().as_ref(),
+                            &market.nonce.to_le_bytes(),
+                        ],
                         &program_id,
                     );
 
@@ -1105,16 +2043,37 @@ pub mod fermi_dex {
                     let coin_mint_seed = coin_mint_key.as_ref();
                     let pc_mint_seed = pc_mint_key.as_ref();
 
+                    let nonce_seed = market.nonce.to_le_bytes();
                     let bump_seed_arr: &[u8] = &[bump_seed];
 
-                    let seed_slices: [&[u8]; 4] =
-                        [market_seed, coin_mint_seed, pc_mint_seed, bump_seed_arr];
-                    let seeds: &[&[&[u8]]] = &[&seed_slices];
+                    let seed_slices: [&[u8]; 5] = [
+                        market_seed,
+                        coin_mint_seed,
+                        pc_mint_seed,
+                        &nonce_seed,
+                        bump_seed_arr,
+                    ];
+                    let mut seeds: &[&[&[u8]]] = &[&seed_slices];
+                    let mut from = payercoin.to_account_info();
+                    let mut transfer_authority = market.to_account_info(); // Using the market PDA as the authority.
+                    if let Some((margin_account, vault)) = &margin {
+                        seeds = margin_seeds;
+                        from = vault.clone();
+                        transfer_authority = margin_account.to_account_info();
+                    } else {
+                        // pulled on the approval given when the order was placed
+                        require!(
+                            token_interface::delegated_amount(payercoin, &market.key())
+                                >= token_interface::amount_with_fee(coin_mint, deposit_amount)?,
+                            ErrorCodeCustom::DelegationInsufficient
+                        );
+                    }
 
-                    let transfer_ix = Transfer {
-                        from: payercoin.to_account_info(),
+                    let transfer_ix = TransferChecked {
+                        from,
+                        mint: coin_mint.to_account_info(),
                         to: deposit_vault.to_account_info(),
-                        authority: market.to_account_info(), // Using the market PDA as the authority.
+                        authority: transfer_authority,
                     };
 
                     // Construct the context with the market PDA and bump seed.
@@ -1124,8 +2083,7 @@ pub mod fermi_dex {
                         seeds,
                     );
 
-                    msg!("attempting JIT transfers");
-                    match utils2::custom_token_transfer(cpi_ctx, deposit_amount) {
+                    match token_interface::transfer_checked_net(cpi_ctx, deposit_amount) {
                         Err(err) => {
                             // Error on CPI cannot be handled. Below arm never reached.
                             msg!("Failed to transfer tokens: {:?}", err);
@@ -1135,7 +2093,12 @@ pub mod fermi_dex {
                         //If transfer succeeds, record deal status in eventQ
                         Ok(_) => {
                             // Successful transfer
-                            msg!("Tokens transferred!");
+                            if let Some((margin_account, _vault)) = &mut margin {
+                                let pulled =
+                                    token_interface::amount_with_fee(coin_mint, deposit_amount)?;
+                                margin_account.draw(coin_mint.key(), pulled)?;
+                            }
+                            market.credit_deposits(Side::Ask, deposit_amount)?;
                             // finalized = 1 means succesfully transferred and settleable.
                             let fin: u8 = 1;
                             let owner = parsed_event.owner;
@@ -1157,47 +2120,393 @@ pub mod fermi_dex {
                                 open_orders_auth.native_coin_total = open_orders_auth
                                     .native_coin_total
                                     .checked_add(deposit_amount)
-                                    .unwrap();
+                                    .ok_or(ErrorCodeCustom::MathOverflow)?;
                             }
                             if index == 1 {
                                 open_orders_cpty.native_coin_total = open_orders_cpty
                                     .native_coin_total
                                     .checked_add(deposit_amount)
-                                    .unwrap();
+                                    .ok_or(ErrorCodeCustom::MathOverflow)?;
                             };
                             eventAskFinalised = true;
                         }
                     }
                 }
-                if sider == 1 {
-                    // check if event is finalised
-                    let eventFin = parsed_event.finalised;
-                    if eventFin == 1 {
-                        eventBidFinalised = true;
-                    } else {
-                        eventBidFinalised == false;
-                    }
+            }
+            if sider == 1 {
+                // check if event is finalised
+                let eventFin = parsed_event.finalised;
+                if eventFin == 1 {
+                    eventBidFinalised = true;
+                } else {
+                    eventBidFinalised == false;
                 }
             }
         }
         //Settle funds
         if eventBidFinalised == true && eventAskFinalised == true {
-            //checked subtract pc from event1 owner
-            open_orders_auth.native_pc_total = open_orders_auth
-                .native_pc_total
-                .checked_sub(event1.native_qty_paid)
-                .unwrap();
-
-            //subtract coin from event2 owner
-            open_orders_cpty.native_coin_total = open_orders_cpty
-                .native_coin_total
-                .checked_sub(event2.native_qty_paid)
-                .unwrap();
+            //subtract locked pc from event1 owner
+            open_orders_auth.debit_locked_pc(event1.native_qty_paid)?;
+
+            //subtract locked coin from event2 owner
+            open_orders_cpty.debit_locked_coin(event2.native_qty_paid)?;
+
             //add pc to event2 owner
-            open_orders_cpty.native_pc_free += event2.native_qty_released;
+            open_orders_cpty.credit_unlocked_pc(event2.native_qty_released)?;
+
             //add coin to event1 owner
-            open_orders_auth.native_coin_free += event1.native_qty_released;
+            open_orders_auth.credit_unlocked_coin(event1.native_qty_released)?;
+
+            // finalised = 3 means settled, so the pair cannot be settled twice
+            event_q.buf[usize::from(event1_slot)].finalised = 3;
+            event_q.buf[usize::from(event2_slot)].finalised = 3;
+        }
+
+        if let Some((margin_account, _vault)) = margin {
+            margin_account.exit(program_id)?;
         }
         Ok(())
     }
 }
+
+/// Shared by new_order and new_pegged_order. Pegged orders rest without matching.
+#[allow(clippy::too_many_arguments)]
+fn place_order<'info>(
+    ctx: Context<'_, '_, '_, 'info, NewOrder<'info>>,
+    side: Side,
+    limit_price: u64,
+    max_coin_qty: u64,
+    max_native_pc_qty: u64,
+    order_type: OrderType,
+    fully_funded: bool,
+    peg: Option<Peg>,
+    display_qty: Option<u64>,
+) -> Result<()> {
+    let open_orders = &mut ctx.accounts.open_orders;
+    let market = &mut ctx.accounts.market;
+    let coin_vault = &ctx.accounts.coin_vault;
+    let pc_vault = &ctx.accounts.pc_vault;
+    let payer = &ctx.accounts.payer;
+    let bids = &mut ctx.accounts.bids;
+    let asks = &mut ctx.accounts.asks;
+    let req_q = &mut ctx.accounts.req_q;
+    let event_q = &mut ctx.accounts.event_q.load_mut()?;
+    let authority = &ctx.accounts.authority;
+    let token_program = &ctx.accounts.token_program;
+    let coin_mint = &ctx.accounts.coin_mint;
+    let pc_mint = &ctx.accounts.pc_mint;
+
+    let owner = &ctx.accounts.owner;
+
+    require!(!market.delisted, ErrorCodeCustom::MarketDelisted);
+    // pegged and resting orders are sized by dividing by their limit price
+    require!(limit_price > 0, ErrorCodeCustom::InvalidPrice);
+
+    // paying in lamports wraps them straight into the vault, and as lamports
+    // can't be pulled just in time such orders are always fully funded
+    let wrap_native = payer.key() == authority.key();
+    let fully_funded = fully_funded || wrap_native;
+
+    if !open_orders.is_initialized {
+        require!(
+            owner.key() == authority.key(),
+            ErrorCodeCustom::WrongAuthority
+        );
+        require!(
+            utils2::is_admitted(market, owner.key(), ctx.remaining_accounts)?,
+            ErrorCodeCustom::NotAdmitted
+        );
+        open_orders.init(market.key(), owner.key())?;
+    } else {
+        require!(
+            open_orders.market.key() == market.key(),
+            ErrorCodeCustom::MarketMismatch
+        );
+        require!(
+            open_orders.can_trade(authority.key()),
+            ErrorCodeCustom::WrongAuthority
+        );
+        require!(!open_orders.frozen, ErrorCodeCustom::OpenOrdersFrozen);
+    }
+    // oracle-pegged orders are priced off the feed passed as a remaining account
+    if market.peg_oracle != Pubkey::default() {
+        if let Some(feed) = ctx
+            .remaining_accounts
+            .iter()
+            .find(|account| account.key() == market.peg_oracle)
+        {
+            require_keys_eq!(
+                *feed.owner,
+                market.peg_oracle_owner,
+                ErrorCodeCustom::InvalidOracle
+            );
+            let (price, publish_time) = utils2::read_price_feed(&feed.try_borrow_data()?)?;
+            if publish_time >= market.oracle_ts {
+                market.oracle_price = price;
+                market.oracle_ts = publish_time;
+            }
+        }
+    }
+
+    let clock = Clock::get()?;
+    let current_timestamp = clock.unix_timestamp;
+    let deposit_amount;
+    let deposit_vault;
+    let deposit_mint;
+    let native_pc_qty_locked;
+    let free_qty_locked;
+    match side {
+        Side::Bid => {
+            let lock_qty_native = max_native_pc_qty
+                .checked_mul(market.pc_lot_size)
+                .ok_or(error!(ErrorCodeCustom::InsufficientFunds))?;
+            native_pc_qty_locked = Some(lock_qty_native);
+            let free_qty_to_lock = lock_qty_native.min(open_orders.native_pc_free);
+            free_qty_locked = free_qty_to_lock;
+            let total_deposit_amount = lock_qty_native - free_qty_to_lock;
+            deposit_amount = total_deposit_amount; //for test with matching, L1044
+            deposit_vault = pc_vault;
+            deposit_mint = pc_mint;
+        }
+        Side::Ask => {
+            native_pc_qty_locked = None;
+            let lock_qty_native = max_coin_qty
+                .checked_mul(market.coin_lot_size)
+                .ok_or(error!(ErrorCodeCustom::InsufficientFunds))?;
+            let free_qty_to_lock = lock_qty_native.min(open_orders.native_coin_free);
+            free_qty_locked = free_qty_to_lock;
+            let total_deposit_amount = lock_qty_native - free_qty_to_lock;
+            deposit_amount = total_deposit_amount; //for test with matching, L1044
+            deposit_vault = coin_vault;
+            deposit_mint = coin_mint;
+        }
+    }
+
+    // a delegate trades the owner's balances but can't pull tokens in on its behalf
+    if authority.key() != open_orders.authority {
+        require!(deposit_amount == 0, ErrorCodeCustom::DelegateCannotFund);
+    }
+
+    let order_id = req_q.gen_order_id(limit_price, side);
+    let owner_slot = open_orders.add_order(order_id, side)?;
+    if fully_funded {
+        open_orders.set_fully_funded(owner_slot);
+    }
+    let request = RequestView::NewOrder {
+        side,
+        order_type,
+        order_id,
+        owner: open_orders.key(),
+        owner_slot,
+        max_coin_qty,
+        native_pc_qty_locked,
+        fully_funded,
+    };
+    let jitdata: Vec<JitStruct> = vec![];
+    let mut proceeds = RequestProceeds {
+        coin_unlocked: 0,
+        native_pc_unlocked: 0,
+        coin_credit: 0,
+        native_pc_credit: 0,
+        coin_debit: 0,
+        native_pc_debit: 0,
+        jit_data: jitdata,
+    };
+    let mut order_book = OrderBook { bids, asks, market };
+    let first_event_slot = event_q.head + 1;
+
+    match peg {
+        Some(peg) => {
+            let order = Order {
+                order_id,
+                qty: max_coin_qty,
+                owner: open_orders.key(),
+                owner_slot,
+                fully_funded,
+                display_qty: 0,
+                reserve_qty: 0,
+            };
+            order_book.post_pegged(side, order, native_pc_qty_locked, peg)?;
+            msg!("pegged order posted");
+        }
+        None => {
+            // matching occurs at this stage
+            order_book.process_request(
+                &request,
+                event_q,
+                &mut proceeds,
+                current_timestamp as u64,
+            )?;
+        }
+    }
+    // whatever rested of an iceberg is split into its visible slice and reserve
+    if let Some(display_qty) = display_qty {
+        match side {
+            Side::Bid => order_book.bids.hide_reserve(order_id, display_qty)?,
+            Side::Ask => order_book.asks.hide_reserve(order_id, display_qty)?,
+        }
+    }
+
+    // fully funded orders lock the whole order up front instead of approving the market
+    if fully_funded {
+        if deposit_amount > 0 && wrap_native {
+            let wrap_ix = WrapSol {
+                from: payer.to_account_info(),
+                vault: deposit_vault.to_account_info(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+            };
+            let wrap_cpi_ctx = CpiContext::new(token_program.to_account_info(), wrap_ix);
+            token_interface::wrap_sol(wrap_cpi_ctx, deposit_amount)?;
+        } else if deposit_amount > 0 {
+            let transfer_ix = TransferChecked {
+                from: payer.to_account_info(),
+                mint: deposit_mint.to_account_info(),
+                to: deposit_vault.to_account_info(),
+                authority: authority.to_account_info(),
+            };
+            let transfer_cpi_ctx = CpiContext::new(token_program.to_account_info(), transfer_ix);
+            token_interface::transfer_checked_net(transfer_cpi_ctx, deposit_amount)?;
+        }
+
+        match side {
+            Side::Bid => {
+                open_orders.lock_free_pc(free_qty_locked)?;
+                open_orders.credit_locked_pc(deposit_amount)?;
+            }
+            Side::Ask => {
+                open_orders.lock_free_coin(free_qty_locked)?;
+                open_orders.credit_locked_coin(deposit_amount)?;
+            }
+        }
+        market.credit_deposits(side, deposit_amount)?;
+        // the part that neither filled nor rests, and any price improvement, is free again
+        match side {
+            Side::Bid => open_orders.unlock_pc(proceeds.native_pc_unlocked)?,
+            Side::Ask => open_orders.unlock_coin(
+                proceeds
+                    .coin_unlocked
+                    .checked_mul(market.coin_lot_size)
+                    .ok_or(ErrorCodeCustom::MathOverflow)?,
+            )?,
+        }
+        msg!("Fully funded order, {} tokens deposited", deposit_amount);
+
+        // fills against fully funded makers are paid for on both sides already
+        utils2::settle_fully_funded_fills(
+            event_q,
+            first_event_slot,
+            open_orders,
+            ctx.remaining_accounts,
+            ctx.program_id,
+        )?;
+    }
+
+    // linked accounts reserve JIT obligations on their margin account, which
+    // pays the margin below out of its vault, instead of approving the market
+    let margin_funded =
+        !fully_funded && deposit_amount > 0 && open_orders.margin_account != Pubkey::default();
+    let margin = if margin_funded {
+        Some((
+            utils2::linked_margin_account(open_orders, ctx.remaining_accounts)?,
+            utils2::margin_vault(open_orders, deposit_mint, ctx.remaining_accounts)?,
+        ))
+    } else {
+        None
+    };
+    let margin_owner = open_orders.authority;
+    let margin_bump = if margin_funded {
+        Pubkey::find_program_address(&[b"margin", margin_owner.as_ref()], ctx.program_id).1
+    } else {
+        0
+    };
+    let margin_bump_arr: &[u8] = &[margin_bump];
+    let margin_seed_slices: [&[u8]; 3] = [b"margin", margin_owner.as_ref(), margin_bump_arr];
+    let margin_seeds: &[&[&[u8]]] = &[&margin_seed_slices];
+
+    // if order is not crossed, creator is maker, and only needs to approve tokens.
+
+    if deposit_amount > 0 && !fully_funded && !margin_funded {
+        let approve_ix = ApproveChecked {
+            to: payer.to_account_info(),
+            mint: deposit_mint.to_account_info(),
+            delegate: market.to_account_info(),
+            authority: authority.to_account_info(), // authority.to_account_info(),
+        };
+        let approve_cpi_ctx = CpiContext::new(token_program.to_account_info(), approve_ix);
+        // the JIT pull has to cover the mint's transfer fee on top of the deposit
+        let approve_amount = token_interface::amount_with_fee(deposit_mint, deposit_amount)?;
+        token_interface::approve_checked(approve_cpi_ctx, approve_amount)?;
+    }
+
+    // Calculate 1% of the deposit_amount
+    let transfer_fraction = 0.01; // 1%
+    let transfer_amount = if fully_funded {
+        0
+    } else {
+        (deposit_amount as f64 * transfer_fraction) as u64
+    };
+
+    // Marginal deposit to back your order (for later penalties if order fails)
+    if transfer_amount > 0 {
+        // Set up the Approve instruction
+        let (from, transfer_authority, signer_seeds) = match &margin {
+            Some((margin_account, vault)) => (
+                vault.clone(),
+                margin_account.to_account_info(),
+                margin_seeds,
+            ),
+            None => (
+                payer.to_account_info(),
+                authority.to_account_info(),
+                &[][..],
+            ),
+        };
+        let transfer_ix = TransferChecked {
+            from, // This is the account holding the tokens
+            mint: deposit_mint.to_account_info(),
+            to: deposit_vault.to_account_info(), // This is who you're giving permission to
+            authority: transfer_authority,       // The authority of the 'to' account
+        };
+
+        // Create the CPI context for the approve instruction
+        let transfer_cpi_ctx =
+            CpiContext::new_with_signer(token_program.to_account_info(), transfer_ix, signer_seeds);
+
+        // Update openorders balances.
+        match side {
+            Side::Bid => {
+                open_orders.credit_locked_pc(transfer_amount)?;
+            }
+            Side::Ask => {
+                open_orders.credit_locked_coin(transfer_amount)?;
+            }
+        }
+        // the rest of a JIT order only reaches the vault when it is finalised
+        market.credit_deposits(side, transfer_amount)?;
+        // Execute the approval (passing the amount separately)
+        token_interface::transfer_checked_net(transfer_cpi_ctx, transfer_amount)?;
+    }
+
+    if let Some((mut margin_account, _vault)) = margin {
+        let mint = deposit_mint.key();
+        margin_account.reserve(mint, deposit_amount)?;
+        // the margin paid in above already counts towards the obligation
+        let transfer_gross = token_interface::amount_with_fee(deposit_mint, transfer_amount)?;
+        margin_account.draw(mint, transfer_gross)?;
+        require!(
+            margin_account.is_healthy(),
+            ErrorCodeCustom::MarginRequirement
+        );
+        margin_account.exit(ctx.program_id)?;
+        msg!("{} reserved on margin account", deposit_amount);
+    }
+
+    msg!(
+        "order {} matched {} coin lots for {} native pc",
+        order_id,
+        proceeds.coin_credit,
+        proceeds.native_pc_debit
+    );
+
+    Ok(())
+}
@@ -0,0 +1,963 @@
+//! Order book matching and event construction. Free of sysvars and account wrappers so it
+//! also runs natively; callers pass the unix timestamp in.
+
+use anchor_lang::prelude::*;
+use enumflags2::BitFlags;
+
+use crate::errors::ErrorCodeCustom;
+use crate::state::*;
+
+impl EventView {
+    pub fn side(&self) -> Side {
+        match self {
+            &EventView::Fill { side, .. }
+            | &EventView::Out { side, .. }
+            | &EventView::Finalise { side, .. } => side,
+        }
+    }
+}
+
+impl EventFlag {
+    #[inline]
+    pub fn from_side(side: Side) -> BitFlags<Self> {
+        match side {
+            Side::Bid => EventFlag::Bid.into(),
+            Side::Ask => BitFlags::empty(),
+        }
+    }
+
+    #[inline]
+    pub fn flags_to_side(flags: BitFlags<Self>) -> Side {
+        if flags.contains(EventFlag::Bid) {
+            Side::Bid
+        } else {
+            Side::Ask
+        }
+    }
+}
+
+impl Peg {
+    /// Price of a pegged order with limit price `limit`, or None while its
+    /// reference is unavailable or the peg would price a bid at zero.
+    pub fn price(&self, side: Side, limit: u64, references: &PegReferences) -> Option<u64> {
+        let reference = match self.reference {
+            PegReference::BestBid => references.best_bid,
+            PegReference::BestAsk => references.best_ask,
+            PegReference::LastTrade => references.last_trade,
+            PegReference::Twap => references.twap,
+            PegReference::Oracle => references.oracle,
+        };
+        if reference == 0 {
+            return None;
+        }
+
+        let pegged = i128::from(reference) * (10_000 + i128::from(self.offset_bps)) / 10_000
+            + i128::from(self.offset_ticks);
+        let pegged = u64::try_from(pegged.max(0)).unwrap_or(u64::MAX);
+        match side {
+            Side::Bid if pegged == 0 => None,
+            Side::Bid => Some(pegged.min(limit)),
+            Side::Ask => Some(pegged.max(limit)),
+        }
+    }
+}
+
+impl TriggerOrder {
+    pub const MAX_SIZE: usize = (1 + 1 + 8 + 1 + 1 + 8 + 8 + 8) + 16 + 32 + 1 + 8;
+
+    /// Stop-losses fire when the price moves through the trigger against the
+    /// position they close, take-profits when it moves in its favour: asks at
+    /// or below (stop) or above (take-profit) the trigger, bids the reverse.
+    pub fn is_triggered(&self, last_trade: u64, mid: u64) -> bool {
+        let price = match self.params.reference {
+            TriggerReference::LastTrade => last_trade,
+            TriggerReference::Mid => mid,
+        };
+        if price == 0 {
+            return false;
+        }
+        let trigger_price = self.params.trigger_price;
+        match (self.params.kind, self.params.side) {
+            (TriggerKind::StopLoss, Side::Ask) | (TriggerKind::TakeProfit, Side::Bid) => {
+                price <= trigger_price
+            }
+            (TriggerKind::StopLoss, Side::Bid) | (TriggerKind::TakeProfit, Side::Ask) => {
+                price >= trigger_price
+            }
+        }
+    }
+}
+
+impl TriggerOrders {
+    pub const MAX_SIZE: usize = 32 + 4 + MAX_TRIGGER_ORDERS * TriggerOrder::MAX_SIZE;
+
+    pub fn insert(&mut self, order: TriggerOrder) -> Result<()> {
+        require!(
+            self.orders.len() < MAX_TRIGGER_ORDERS,
+            ErrorCodeCustom::OrdersAlreadyFull
+        );
+        let idx = self
+            .orders
+            .iter()
+            .position(|o| o.params.trigger_price > order.params.trigger_price)
+            .unwrap_or(self.orders.len());
+        self.orders.insert(idx, order);
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, order_id: u128) -> Result<TriggerOrder> {
+        let idx = self
+            .orders
+            .iter()
+            .position(|o| o.order_id == order_id)
+            .ok_or(ErrorCodeCustom::OrderNotFound)?;
+
+        Ok(self.orders.remove(idx))
+    }
+}
+
+impl Event {
+    pub const MAX_SIZE: usize = 1 + 1 + 8 + 8 + 16 + 32 + 1 + 32;
+
+    #[inline]
+    pub fn side(&self) -> Side {
+        let flags = BitFlags::<EventFlag>::from_bits(self.event_flags).unwrap_or(BitFlags::empty());
+        EventFlag::flags_to_side(flags)
+    }
+
+    /// Whether this is a fill, finalised or not. Slots past the head and out
+    /// events aren't.
+    #[inline]
+    pub fn is_fill(&self) -> bool {
+        BitFlags::<EventFlag>::from_bits(self.event_flags)
+            .map_or(false, |flags| flags.contains(EventFlag::Fill))
+    }
+
+    #[inline]
+    pub fn is_maker(&self) -> bool {
+        BitFlags::<EventFlag>::from_bits(self.event_flags)
+            .map_or(false, |flags| flags.contains(EventFlag::Maker))
+    }
+
+    #[inline(always)]
+    pub fn new(view: EventView, current_timestamp: u64) -> Self {
+        match view {
+            EventView::Fill {
+                side,
+                maker,
+                native_qty_paid,
+                native_qty_received,
+                order_id,
+                owner,
+                owner_slot,
+                finalised,
+                cpty: _,
+                order_id_second,
+            } => {
+                let mut flags = EventFlag::from_side(side) | EventFlag::Fill;
+                if maker {
+                    flags |= EventFlag::Maker;
+                }
+                Event {
+                    event_flags: flags.bits(),
+                    owner_slot,
+                    native_qty_released: native_qty_received,
+                    native_qty_paid,
+                    order_id,
+                    owner,
+                    finalised,
+                    order_id_second,
+                    timestamp: current_timestamp,
+                    //cpty,
+                }
+            }
+
+            EventView::Out {
+                side,
+                release_funds,
+                native_qty_unlocked,
+                native_qty_still_locked,
+                order_id,
+                owner,
+                owner_slot,
+                finalised: _,
+            } => {
+                let mut flags = EventFlag::from_side(side) | EventFlag::Out;
+                if release_funds {
+                    flags |= EventFlag::ReleaseFunds;
+                }
+                let finalised: u8 = 0;
+                let _cpty: Pubkey = owner;
+                Event {
+                    event_flags: flags.bits(),
+                    owner_slot,
+                    //finalised: finalised,
+                    native_qty_released: native_qty_unlocked,
+                    native_qty_paid: native_qty_still_locked,
+                    order_id,
+                    owner,
+                    finalised,
+                    order_id_second: 0,
+                    timestamp: current_timestamp,
+                    //cpty
+                }
+            }
+
+            EventView::Finalise {
+                side,
+                maker,
+                native_qty_paid,
+                native_qty_received,
+                order_id,
+                owner,
+                owner_slot,
+                finalised,
+                cpty: _,
+            } => {
+                let mut flags = EventFlag::from_side(side) | EventFlag::Fill;
+                if maker {
+                    flags |= EventFlag::Maker;
+                }
+                Event {
+                    event_flags: flags.bits(),
+                    owner_slot,
+                    //finalised: finalised,
+                    native_qty_released: native_qty_received,
+                    native_qty_paid,
+                    order_id,
+                    owner,
+                    finalised,
+                    order_id_second: 0,
+                    timestamp: current_timestamp,
+                    //cpty,
+                }
+            }
+        }
+    }
+}
+
+impl EventQueue {
+    /// Unfunded fills of `owner` (an OpenOrders key) in slot order, at most `max`.
+    pub fn pending_obligations(&self, owner: Pubkey, max: usize) -> Vec<PendingObligation> {
+        let mut pending = vec![];
+        let last = (self.head as usize).min(self.buf.len() - 1);
+        for slot in 1..=last {
+            let event = self.buf[slot];
+            let flags =
+                BitFlags::<EventFlag>::from_bits(event.event_flags).unwrap_or(BitFlags::empty());
+            if event.owner != owner || event.finalised != 0 || !flags.contains(EventFlag::Fill) {
+                continue;
+            }
+            if pending.len() == max {
+                break;
+            }
+            pending.push(PendingObligation {
+                event_slot: slot as u8,
+                side: EventFlag::flags_to_side(flags),
+                maker: flags.contains(EventFlag::Maker),
+                order_id: event.order_id,
+                native_qty_paid: event.native_qty_paid,
+                native_qty_released: event.native_qty_released,
+                expires_at: event.timestamp + FINALISE_TIMEOUT_SECS,
+            });
+        }
+        pending
+    }
+}
+
+impl<'a> OrderBook<'a> {
+    pub fn find_bbo(&self, side: Side) -> Result<&Order> {
+        match side {
+            Side::Bid => self.bids.find_bbo(),
+            Side::Ask => self.asks.find_bbo(),
+        }
+    }
+
+    pub fn find_bbo_mut(&mut self, side: Side) -> Result<&mut Order> {
+        match side {
+            Side::Bid => self.bids.find_bbo_mut(),
+            Side::Ask => self.asks.find_bbo_mut(),
+        }
+    }
+
+    pub fn peg_references(&self, timestamp: u64) -> PegReferences {
+        PegReferences {
+            best_bid: self.market.best_bid,
+            best_ask: self.market.best_ask,
+            last_trade: self.market.last_trade_price,
+            twap: self.market.twap(timestamp),
+            oracle: self.market.oracle_price_at(timestamp),
+        }
+    }
+
+    /// Rests a pegged order without matching it. Bids hold as many lots as the
+    /// locked pc buys at the limit price.
+    pub fn post_pegged(
+        &mut self,
+        side: Side,
+        order: Order,
+        native_pc_qty_locked: Option<u64>,
+        peg: Peg,
+    ) -> Result<()> {
+        let qty = match side {
+            Side::Bid => {
+                let locked = native_pc_qty_locked.ok_or(ErrorCodeCustom::InvalidLocked)?;
+                order
+                    .qty
+                    .min(locked / self.market.pc_lot_size / order.price())
+            }
+            Side::Ask => order.qty,
+        };
+        require!(qty > 0, ErrorCodeCustom::InvalidLocked);
+
+        let order = Order { qty, ..order };
+        match side {
+            Side::Bid => self.bids.insert_pegged(order, peg),
+            Side::Ask => self.asks.insert_pegged(order, peg),
+        }
+    }
+
+    /// Mirrors the top of both books onto the market's price fields.
+    pub fn refresh_bbo(&mut self) {
+        self.market.best_bid = self.bids.levels(1).first().map_or(0, |level| level.price);
+        self.market.best_ask = self.asks.levels(1).first().map_or(0, |level| level.price);
+    }
+
+    pub fn process_request(
+        &mut self,
+        request: &RequestView,
+        event_q: &mut EventQueue,
+        proceeds: &mut RequestProceeds,
+        timestamp: u64,
+    ) -> Result<Option<RequestView>> {
+        Ok(match *request {
+            RequestView::NewOrder {
+                side,
+                order_type,
+                order_id,
+                max_coin_qty,
+                native_pc_qty_locked,
+                owner_slot,
+                owner,
+                fully_funded,
+            } => self
+                .new_order(
+                    NewOrderParams {
+                        side,
+                        order_type,
+                        order_id,
+                        max_coin_qty,
+                        native_pc_qty_locked,
+                        owner_slot,
+                        owner,
+                        fully_funded,
+                    },
+                    event_q,
+                    proceeds,
+                    timestamp,
+                )?
+                .map(|remaining| RequestView::NewOrder {
+                    side,
+                    order_type,
+                    order_id,
+                    max_coin_qty: remaining.coin_qty_remaining,
+                    native_pc_qty_locked: remaining.native_pc_qty_remaining,
+                    owner_slot,
+                    owner,
+                    fully_funded,
+                }),
+            RequestView::CancelOrder {
+                side,
+                order_id,
+                cancel_id: _,
+                expected_owner,
+                expected_owner_slot,
+            } => {
+                self.cancel_order(
+                    CancelOrderParams {
+                        side,
+                        order_id,
+                        expected_owner,
+                        expected_owner_slot,
+                    },
+                    event_q,
+                )?;
+                None
+            }
+        })
+    }
+}
+
+impl<'a> OrderBook<'a> {
+    pub fn new_order(
+        &mut self,
+        params: NewOrderParams,
+        event_q: &mut EventQueue,
+        proceeds: &mut RequestProceeds,
+        timestamp: u64,
+    ) -> Result<Option<OrderRemaining>> {
+        let NewOrderParams {
+            side,
+            order_type,
+            order_id,
+            owner,
+            owner_slot,
+            mut max_coin_qty,
+            mut native_pc_qty_locked,
+            fully_funded,
+        } = params;
+        let (mut post_only, mut post_allowed) = match order_type {
+            OrderType::Limit => (false, true),
+            OrderType::ImmediateOrCancel => (false, false),
+            OrderType::PostOnly => (true, true),
+        };
+        //check Order impls for sourcing payer acc.
+        let limit_price = Order::price_from_order_id(order_id);
+        let mut limit = 10;
+        loop {
+            if limit == 0 {
+                // Stop matching and release funds if we're out of cycles
+                post_only = true;
+                post_allowed = true;
+            }
+
+            let remaining_order = match side {
+                Side::Bid => self.new_bid(
+                    NewBidParams {
+                        max_coin_qty,
+                        native_pc_qty_locked: native_pc_qty_locked
+                            .ok_or(ErrorCodeCustom::InvalidLocked)?,
+                        limit_price: Some(limit_price),
+                        order_id,
+                        owner,
+                        owner_slot,
+                        post_only,
+                        post_allowed,
+                        fully_funded,
+                        timestamp,
+                    },
+                    event_q,
+                    proceeds,
+                ),
+                Side::Ask => self.new_ask(
+                    NewAskParams {
+                        max_qty: max_coin_qty,
+                        limit_price,
+                        order_id,
+                        owner,
+                        owner_slot,
+                        post_only,
+                        post_allowed,
+                        fully_funded,
+                        timestamp,
+                    },
+                    event_q,
+                    proceeds,
+                ),
+            }?;
+            if limit == 0 {
+                return Ok(remaining_order);
+            }
+            limit -= 1;
+            match remaining_order {
+                Some(remaining_order) => {
+                    max_coin_qty = remaining_order.coin_qty_remaining;
+                    native_pc_qty_locked = remaining_order.native_pc_qty_remaining;
+                }
+                None => return Ok(None),
+            };
+        }
+    }
+}
+
+impl<'a> OrderBook<'a> {
+    pub fn new_bid(
+        &mut self,
+        params: NewBidParams,
+        event_q: &mut EventQueue,
+        to_release: &mut RequestProceeds,
+    ) -> Result<Option<OrderRemaining>> {
+        let NewBidParams {
+            max_coin_qty,
+            native_pc_qty_locked,
+            limit_price,
+            order_id,
+            owner,
+            owner_slot,
+            post_only,
+            post_allowed,
+            fully_funded,
+            timestamp,
+        } = params;
+        if post_allowed {
+            require!(limit_price.is_some(), ErrorCodeCustom::InvalidPrice);
+        }
+
+        let coin_lot_size = self.market.coin_lot_size;
+        let pc_lot_size = self.market.pc_lot_size;
+
+        let max_pc_qty = native_pc_qty_locked / pc_lot_size;
+
+        let mut coin_qty_remaining = max_coin_qty;
+        let mut pc_qty_remaining = max_pc_qty;
+        let jit_data = vec![];
+
+        let crossed;
+        let references = self.peg_references(timestamp);
+        let done = loop {
+            let (best_offer, trade_price) = match self.asks.best_match_mut(&references) {
+                Err(_) => {
+                    crossed = false;
+                    break true;
+                }
+                Ok(o) => o,
+            };
+
+            crossed = limit_price
+                .map(|limit_price| limit_price >= trade_price)
+                .unwrap_or(true);
+            // testing
+
+            if !crossed || post_only {
+                break true;
+            }
+            let offer_size = best_offer.qty;
+            let trade_qty = offer_size
+                .min(coin_qty_remaining)
+                .min(pc_qty_remaining / trade_price);
+
+            if trade_qty == 0 {
+                break true;
+            }
+
+            let native_maker_pc_qty = trade_qty * trade_price * pc_lot_size;
+
+            let maker_fill = Event::new(
+                EventView::Fill {
+                    side: Side::Ask,
+                    maker: true,
+                    native_qty_paid: trade_qty * coin_lot_size,
+                    native_qty_received: native_maker_pc_qty,
+                    order_id: best_offer.order_id,
+                    owner: best_offer.owner,
+                    owner_slot: best_offer.owner_slot,
+                    finalised: u8::from(best_offer.fully_funded),
+                    cpty: owner,
+                    order_id_second: order_id,
+                },
+                timestamp,
+            );
+
+            //write maker side event to eventQ
+            event_q.push(maker_fill)?;
+
+            best_offer.qty -= trade_qty;
+            coin_qty_remaining -= trade_qty;
+            pc_qty_remaining -= trade_qty * trade_price;
+
+            //if order is filled, refill an iceberg or delete (ask) order.
+            if best_offer.qty == 0 && best_offer.refill() {
+                // the next slice queues behind its price level
+                let best_offer_id = best_offer.order_id;
+                self.asks.move_to_back(best_offer_id)?;
+            } else if best_offer.qty == 0 {
+                let best_offer_id = best_offer.order_id;
+
+                let event_out = Event::new(
+                    EventView::Out {
+                        side: Side::Ask,
+                        release_funds: true,
+                        native_qty_unlocked: 0,
+                        native_qty_still_locked: 0,
+                        order_id: best_offer_id,
+                        owner: best_offer.owner,
+                        owner_slot: best_offer.owner_slot,
+                        finalised: 0,
+                    },
+                    timestamp,
+                );
+                event_q.push(event_out)?;
+
+                // filled makers leave the book
+                self.asks.delete(best_offer_id)?;
+            }
+
+            self.market.record_trade(trade_price, timestamp)?;
+            self.refresh_bbo();
+            break false;
+        };
+
+        let native_accum_fill_price = (max_pc_qty - pc_qty_remaining) * pc_lot_size;
+        let native_pc_qty_remaining = native_pc_qty_locked - native_accum_fill_price;
+
+        {
+            let coin_lots_received = max_coin_qty - coin_qty_remaining;
+            let native_pc_paid = native_accum_fill_price;
+
+            to_release.credit_coin(coin_lots_received)?;
+            to_release.debit_native_pc(native_pc_paid)?;
+            to_release.jit_data = jit_data;
+
+            let taker_fill = Event::new(
+                EventView::Fill {
+                    side: Side::Bid,
+                    maker: false,
+                    native_qty_paid: native_pc_paid,
+                    native_qty_received: coin_lots_received * coin_lot_size,
+                    order_id,
+                    owner,
+                    owner_slot,
+                    finalised: u8::from(fully_funded),
+                    cpty: owner,
+                    order_id_second: 0,
+                },
+                timestamp,
+            );
+            event_q.push(taker_fill)?;
+        }
+
+        if !done {
+            if coin_qty_remaining > 0 && native_pc_qty_remaining > 0 {
+                return Ok(Some(OrderRemaining {
+                    coin_qty_remaining,
+                    native_pc_qty_remaining: Some(native_pc_qty_remaining),
+                }));
+            }
+        }
+
+        let (coin_qty_to_post, pc_qty_to_keep_locked) = match limit_price {
+            Some(price) if post_allowed && !crossed => {
+                let coin_qty_to_post =
+                    coin_qty_remaining.min(native_pc_qty_remaining / pc_lot_size / price);
+                (coin_qty_to_post, coin_qty_to_post * price)
+            }
+            _ => (0, 0),
+        };
+
+        if coin_qty_to_post > 0 {
+            let insert_result = self.bids.insert(Order {
+                order_id,
+                qty: coin_qty_to_post,
+                owner,
+                owner_slot,
+                fully_funded,
+                display_qty: 0,
+                reserve_qty: 0,
+            });
+            if let Err(err) = insert_result {
+                if err == error!(ErrorCodeCustom::OrdersAlreadyFull) {
+                    // boot out the least aggressive bid
+                    msg!("bids full! booting...");
+                    let order = self.bids.delete_worst()?;
+                    let out = Event::new(
+                        EventView::Out {
+                            side: Side::Bid,
+                            release_funds: true,
+                            native_qty_unlocked: order.total_qty() * order.price() * pc_lot_size,
+                            native_qty_still_locked: 0,
+                            order_id: order.order_id,
+                            owner: order.owner,
+                            owner_slot: order.owner_slot,
+                            finalised: 0,
+                        },
+                        timestamp,
+                    );
+                    event_q.push(out)?;
+
+                    self.bids.insert(Order {
+                        order_id,
+                        qty: coin_qty_to_post,
+                        owner,
+                        owner_slot,
+                        fully_funded,
+                        display_qty: 0,
+                        reserve_qty: 0,
+                    })?;
+                }
+            }
+            self.refresh_bbo();
+        }
+
+        let _out = {
+            let native_qty_still_locked = pc_qty_to_keep_locked * pc_lot_size;
+            let native_qty_unlocked = native_pc_qty_remaining - native_qty_still_locked;
+            to_release.unlock_native_pc(native_qty_unlocked)?;
+
+            let outer = Event::new(
+                EventView::Out {
+                    side: Side::Bid,
+                    release_funds: false,
+                    native_qty_unlocked,
+                    native_qty_still_locked,
+                    order_id,
+                    owner,
+                    owner_slot,
+                    finalised: 0,
+                },
+                timestamp,
+            );
+            event_q.push(outer)?;
+        };
+
+        Ok(None)
+    }
+}
+
+impl<'a> OrderBook<'a> {
+    pub fn new_ask(
+        &mut self,
+        params: NewAskParams,
+        event_q: &mut EventQueue,
+        to_release: &mut RequestProceeds,
+    ) -> Result<Option<OrderRemaining>> {
+        let NewAskParams {
+            max_qty,
+            limit_price,
+            order_id,
+            owner,
+            owner_slot,
+            post_only,
+            post_allowed,
+            fully_funded,
+            timestamp,
+        } = params;
+        let mut unfilled_qty = max_qty;
+        let mut accum_fill_price = 0;
+
+        let pc_lot_size = self.market.pc_lot_size;
+        let coin_lot_size = self.market.coin_lot_size;
+        let mut jit_data = vec![];
+
+        //begin matching
+        let crossed;
+
+        let references = self.peg_references(timestamp);
+        let done = loop {
+            let (best_bid, trade_price) = match self.bids.best_match_mut(&references) {
+                Err(_) => {
+                    crossed = false;
+                    break true;
+                }
+                Ok(o) => o,
+            };
+
+            crossed = limit_price <= trade_price;
+
+            if !crossed || post_only {
+                break true;
+            }
+
+            let bid_size = best_bid.qty;
+            let trade_qty = bid_size.min(unfilled_qty);
+
+            if trade_qty == 0 {
+                break true;
+            }
+
+            let native_maker_pc_qty = trade_qty * trade_price * pc_lot_size;
+            let jit_struct = JitStruct {
+                side: Side::Bid,
+                maker: true,
+                native_qty_paid: native_maker_pc_qty,
+                native_qty_received: trade_qty * coin_lot_size,
+                order_id: best_bid.order_id,
+                owner: best_bid.owner,
+                owner_slot: best_bid.owner_slot,
+            };
+            jit_data.push(jit_struct);
+
+            let maker_fill = Event::new(
+                EventView::Fill {
+                    side: Side::Bid,
+                    maker: true,
+                    native_qty_paid: native_maker_pc_qty,
+                    native_qty_received: trade_qty * coin_lot_size,
+                    order_id: best_bid.order_id,
+                    owner: best_bid.owner,
+                    owner_slot: best_bid.owner_slot,
+                    finalised: u8::from(best_bid.fully_funded),
+                    cpty: owner,
+                    order_id_second: order_id,
+                },
+                timestamp,
+            );
+            event_q.push(maker_fill)?;
+
+            best_bid.qty -= trade_qty;
+            unfilled_qty -= trade_qty;
+            accum_fill_price += trade_qty * trade_price;
+
+            if best_bid.qty == 0 && best_bid.refill() {
+                // the next slice queues behind its price level
+                let best_bid_id = best_bid.order_id;
+                self.bids.move_to_back(best_bid_id)?;
+            } else if best_bid.qty == 0 {
+                let best_bid_id = best_bid.order_id;
+                let out = Event::new(
+                    EventView::Out {
+                        side: Side::Bid,
+                        release_funds: true,
+                        native_qty_unlocked: 0,
+                        native_qty_still_locked: 0,
+                        order_id: best_bid_id,
+                        owner: best_bid.owner,
+                        owner_slot: best_bid.owner_slot,
+                        finalised: 0,
+                    },
+                    timestamp,
+                );
+                event_q.push(out)?;
+
+                // filled makers leave the book
+                self.bids.delete(best_bid_id)?;
+            }
+
+            self.market.record_trade(trade_price, timestamp)?;
+            self.refresh_bbo();
+            break false;
+        };
+
+        let native_taker_pc_qty = accum_fill_price * pc_lot_size;
+
+        {
+            let net_taker_pc_qty = native_taker_pc_qty;
+            let coin_lots_traded = max_qty - unfilled_qty;
+
+            to_release.credit_native_pc(net_taker_pc_qty)?;
+            to_release.debit_coin(coin_lots_traded)?;
+            to_release.jit_data = jit_data;
+            if native_taker_pc_qty > 0 {
+                let taker_fill = Event::new(
+                    EventView::Fill {
+                        side: Side::Ask,
+                        maker: false,
+                        native_qty_paid: coin_lots_traded * coin_lot_size,
+                        native_qty_received: net_taker_pc_qty,
+                        order_id,
+                        owner,
+                        owner_slot,
+                        finalised: u8::from(fully_funded),
+                        cpty: owner,
+                        order_id_second: 0,
+                    },
+                    timestamp,
+                );
+                event_q.push(taker_fill)?;
+            }
+        }
+
+        if !done {
+            if unfilled_qty > 0 {
+                return Ok(Some(OrderRemaining {
+                    coin_qty_remaining: unfilled_qty,
+                    native_pc_qty_remaining: None,
+                }));
+            }
+        }
+
+        if post_allowed && !crossed && unfilled_qty > 0 {
+            let insert_result = self.asks.insert(Order {
+                order_id,
+                qty: unfilled_qty,
+                owner,
+                owner_slot,
+                fully_funded,
+                display_qty: 0,
+                reserve_qty: 0,
+            });
+            if let Err(err) = insert_result {
+                if err == error!(ErrorCodeCustom::OrdersAlreadyFull) {
+                    // boot out the least aggressive offer
+                    msg!("offers full! booting...");
+                    let order = self.asks.delete_worst()?;
+                    let out = Event::new(
+                        EventView::Out {
+                            side: Side::Ask,
+                            release_funds: true,
+                            native_qty_unlocked: order.total_qty() * coin_lot_size,
+                            native_qty_still_locked: 0,
+                            order_id: order.order_id,
+                            owner: order.owner,
+                            owner_slot: order.owner_slot,
+                            finalised: 0,
+                        },
+                        timestamp,
+                    );
+                    event_q.push(out)?;
+
+                    self.asks.insert(Order {
+                        order_id,
+                        qty: unfilled_qty,
+                        owner,
+                        owner_slot,
+                        fully_funded,
+                        display_qty: 0,
+                        reserve_qty: 0,
+                    })?;
+                }
+            }
+            self.refresh_bbo();
+        } else {
+            to_release.unlock_coin(unfilled_qty)?;
+            let out = Event::new(
+                EventView::Out {
+                    side: Side::Ask,
+                    release_funds: false,
+                    native_qty_unlocked: unfilled_qty * coin_lot_size,
+                    native_qty_still_locked: 0,
+                    order_id,
+                    owner,
+                    owner_slot,
+                    finalised: 0,
+                },
+                timestamp,
+            );
+            event_q.push(out)?;
+        }
+
+        Ok(None)
+    }
+}
+
+impl<'a> OrderBook<'a> {
+    pub fn cancel_order(
+        &mut self,
+        params: CancelOrderParams,
+        _event_q: &mut EventQueue,
+    ) -> Result<()> {
+        let CancelOrderParams {
+            side: _,
+            order_id: _,
+            expected_owner: _,
+            expected_owner_slot: _,
+        } = params;
+        Ok(())
+    }
+
+    pub fn cancel_order_bid(&mut self, _side: bool, order_id: u128, _owner: Pubkey) -> Result<()> {
+        // the owner's slot outlives the book entry once the order is filled
+        let _ = self.bids.delete(order_id);
+        self.refresh_bbo();
+
+        Ok(())
+    }
+
+    pub fn cancel_order_ask(&mut self, _side: bool, order_id: u128, _owner: Pubkey) -> Result<()> {
+        // the owner's slot outlives the book entry once the order is filled
+        let _ = self.asks.delete(order_id);
+        self.refresh_bbo();
+
+        Ok(())
+    }
+
+    /// Re-inserts the unfilled quantity of a maker fill whose counterparty defaulted.
+    pub fn requeue_order(&mut self, side: Side, order: Order) -> Result<()> {
+        match side {
+            Side::Bid => self.bids.requeue(order)?,
+            Side::Ask => self.asks.requeue(order)?,
+        }
+        self.refresh_bbo();
+        Ok(())
+    }
+}
@@ -8,6 +8,7 @@ use enumflags2::bitflags;
 
 
 use crate::errors::ErrorCodeCustom;
+use crate::token_interface;
 
 #[account]
 #[derive(Default)]
@@ -31,8 +32,66 @@ pub struct Market {
     pub event_q: Pubkey,
 
     pub authority: Pubkey,
+
+    // price oracle, updated by the matching engine. Prices are in pc lots per coin lot, 0 if unset.
+    pub last_trade_price: u64,
+    pub last_trade_ts: u64,
+    pub first_trade_ts: u64,
+    pub best_bid: u64,
+    pub best_ask: u64,
+    // sum of last_trade_price * seconds held, up to last_trade_ts
+    pub price_cumulative: u128,
+
+    // external reference for oracle-pegged orders, Pubkey::default() if none,
+    // and the program that has to own it
+    pub peg_oracle: Pubkey,
+    pub peg_oracle_owner: Pubkey,
+    pub oracle_price: u64,
+    pub oracle_ts: u64,
+
+    // tells apart markets of the same pair, part of the market's seeds
+    pub nonce: u64,
+    // set once the market is taken off the registry, blocks new orders
+    pub delisted: bool,
+
+    // if set, new OpenOrders need its signature or a place on the market's allow list
+    pub open_orders_admission_authority: Pubkey,
+}
+
+/// A market as listed in the registry.
+#[derive(Copy, Clone, Default, AnchorSerialize, AnchorDeserialize)]
+pub struct MarketListing {
+    pub market: Pubkey,
+    pub coin_mint: Pubkey,
+    pub pc_mint: Pubkey,
+    pub nonce: u64,
+    pub coin_lot_size: u64,
+    pub pc_lot_size: u64,
+    pub authority: Pubkey,
+}
+
+/// Every listed market. Anyone can list one by paying `listing_fee` lamports
+/// to the registry's authority.
+#[account]
+#[derive(Default)]
+pub struct Registry {
+    pub authority: Pubkey,
+    pub listing_fee: u64,
+    pub markets: Vec<MarketListing>,
+}
+
+pub const MAX_LISTED_MARKETS: usize = 64;
+
+/// Wallets the admission authority lets open accounts on a permissioned market.
+#[account]
+#[derive(Default)]
+pub struct AllowList {
+    pub market: Pubkey,
+    pub wallets: Vec<Pubkey>,
 }
 
+pub const MAX_ALLOWED_WALLETS: usize = 64;
+
 #[bitflags]
 #[repr(u8)]
 #[derive(Copy, Clone, AnchorSerialize, AnchorDeserialize)]
@@ -93,7 +152,7 @@ impl RequestQueue {
 
 #[bitflags]
 #[repr(u8)]
-#[derive(Copy, Clone, AnchorSerialize, AnchorDeserialize)]
+#[derive(Copy, Clone, Debug, AnchorSerialize, AnchorDeserialize)]
 pub enum EventFlag {
     Fill = 0x1,
     Out = 0x2,
@@ -150,8 +209,56 @@ pub struct Order {
     pub qty: u64,
     pub owner: Pubkey,
     pub owner_slot: u8,
+    pub fully_funded: bool, // collateral sits in the vault, no JIT transfer on fill
+    // icebergs show at most display_qty and refill from the hidden reserve
+    pub display_qty: u64,
+    pub reserve_qty: u64,
+}
+
+/// What a pegged order's price follows.
+#[derive(Copy, Clone, Debug, PartialEq, AnchorSerialize, AnchorDeserialize)]
+pub enum PegReference {
+    BestBid,
+    BestAsk,
+    LastTrade,
+    Twap,
+    Oracle,
+}
+
+/// Effective price is `reference * (10_000 + offset_bps) / 10_000 + offset_ticks`,
+/// capped by the order's limit price for bids and floored by it for asks.
+#[derive(Copy, Clone, Debug, PartialEq, AnchorSerialize, AnchorDeserialize)]
+pub struct Peg {
+    pub reference: PegReference,
+    pub offset_ticks: i64,
+    pub offset_bps: i16,
+}
+
+/// A resting order whose id carries its limit price rather than the price it trades at.
+#[derive(Copy, Clone, AnchorSerialize, AnchorDeserialize)]
+pub struct PeggedOrder {
+    pub order: Order,
+    pub peg: Peg,
+}
+
+/// Reference prices pegged orders are priced from while matching, 0 where unavailable.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PegReferences {
+    pub best_bid: u64,
+    pub best_ask: u64,
+    pub last_trade: u64,
+    pub twap: u64,
+    pub oracle: u64,
 }
 
+pub const MAX_ORDERS: usize = 32;
+pub const MAX_PEGGED_ORDERS: usize = 8;
+
+// A peg oracle account holds a little-endian u64 price in pc lots per coin lot
+// followed by its i64 unix publish time. Prices older than MAX_ORACLE_AGE_SECS are ignored.
+pub const PRICE_FEED_LEN: usize = 16;
+pub const MAX_ORACLE_AGE_SECS: u64 = 60;
+
 #[repr(packed)]
 #[zero_copy]
 pub struct EventQueueHeader {
@@ -172,6 +279,8 @@ pub struct EventQueue {
 #[derive(Default)]
 pub struct Orders<const T: bool> {
     pub sorted: Vec<Order>,
+    // priced at match time, so they sit outside the sorted book
+    pub pegged: Vec<PeggedOrder>,
 }
 
 pub type Bids = Orders<true>;
@@ -198,6 +307,7 @@ pub enum RequestView {
         native_pc_qty_locked: Option<u64>,
         owner_slot: u8,
         owner: Pubkey,
+        fully_funded: bool,
     },
     CancelOrder {
         side: Side,
@@ -211,7 +321,7 @@ pub enum RequestView {
 pub struct OrderBook<'a> {
     pub bids: &'a mut Bids,
     pub asks: &'a mut Asks,
-    pub market: &'a Market,
+    pub market: &'a mut Market,
 }
 
 pub struct NewOrderParams {
@@ -222,6 +332,7 @@ pub struct NewOrderParams {
     pub native_pc_qty_locked: Option<u64>,
     pub owner: Pubkey,
     pub owner_slot: u8,
+    pub fully_funded: bool,
 }
 
 pub struct OrderRemaining {
@@ -229,6 +340,96 @@ pub struct OrderRemaining {
     pub native_pc_qty_remaining: Option<u64>,
 }
 
+// Return data of the view instructions, borsh encoded.
+
+/// Quantity in coin lots resting at one price, and how much of it fully
+/// funded makers have already paid into the vault.
+#[derive(Copy, Clone, Debug, PartialEq, AnchorSerialize, AnchorDeserialize)]
+pub struct PriceLevel {
+    pub price: u64,
+    pub qty: u64,
+    pub fully_funded_qty: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, AnchorSerialize, AnchorDeserialize)]
+pub struct Bbo {
+    pub best_bid: Option<PriceLevel>,
+    pub best_ask: Option<PriceLevel>,
+}
+
+/// An order held in an OpenOrders slot. `qty` is what still rests on the book,
+/// zero once the order has been matched in full.
+#[derive(Copy, Clone, Debug, PartialEq, AnchorSerialize, AnchorDeserialize)]
+pub struct OpenOrderView {
+    pub slot: u8,
+    pub side: Side,
+    pub order_id: u128,
+    pub price: u64,
+    pub qty: u64,
+    pub fully_funded: bool,
+}
+
+/// A fill its owner has not funded yet. After `expires_at` the counterparty can
+/// call cancel_with_penalty.
+#[derive(Copy, Clone, Debug, PartialEq, AnchorSerialize, AnchorDeserialize)]
+pub struct PendingObligation {
+    pub event_slot: u8,
+    pub side: Side,
+    pub maker: bool,
+    pub order_id: u128,
+    pub native_qty_paid: u64,
+    pub native_qty_released: u64,
+    pub expires_at: u64,
+}
+
+/// A pending fill of the liquidated account and the event of the
+/// counterparty it matched.
+#[derive(Copy, Clone, AnchorSerialize, AnchorDeserialize)]
+pub struct LiquidatedFill {
+    pub event_slot: u8,
+    pub counterparty_slot: u8,
+}
+
+/// The market's price oracle. `price_cumulative` is projected to `timestamp`,
+/// so TWAPs over any window follow from two observations.
+#[derive(Copy, Clone, Debug, PartialEq, AnchorSerialize, AnchorDeserialize)]
+pub struct PriceView {
+    pub last_trade_price: u64,
+    pub last_trade_ts: u64,
+    pub best_bid: u64,
+    pub best_ask: u64,
+    pub price_cumulative: u128,
+    pub twap: u64,
+    pub timestamp: u64,
+}
+
+/// Tokens credited to an open orders account's free balance, net of any transfer fee.
+#[event]
+pub struct DepositEvent {
+    pub market: Pubkey,
+    pub open_orders: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+}
+
+/// Tokens paid out of an open orders account's free balance.
+#[event]
+pub struct WithdrawEvent {
+    pub market: Pubkey,
+    pub open_orders: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+}
+
+pub const MAX_VIEW_LEVELS: usize = 32;
+pub const MAX_VIEW_OBLIGATIONS: usize = 16;
+pub const FINALISE_TIMEOUT_SECS: u64 = 60;
+
+/// Locked margin an account has to keep against its pending fills, in basis points.
+pub const MAINTENANCE_MARGIN_BPS: u64 = 100;
+/// Paid to the liquidator out of each liquidated obligation, in basis points.
+pub const LIQUIDATION_BOUNTY_BPS: u64 = 50;
+
 #[repr(packed)]
 #[zero_copy]
 pub struct Event {
@@ -279,6 +480,19 @@ impl EventQueue {
         return self.head;
     }
 
+    /// Writes `event` to the slot after the head. The queue doesn't wrap, so
+    /// once the last slot is written it fails with `QueueAlreadyFull`.
+    pub fn push(&mut self, event: Event) -> Result<()> {
+        let idx = self.head + 1;
+        require!(
+            (idx as usize) < self.buf.len(),
+            ErrorCodeCustom::QueueAlreadyFull
+        );
+        self.buf[idx as usize] = event;
+        self.head = idx;
+        Ok(())
+    }
+
     #[inline]
     pub fn full(&self) -> bool {
         self.header.count() as usize == self.buf.len()
@@ -338,7 +552,7 @@ impl EventQueue {
 }
 
 impl Order {
-    pub const MAX_SIZE: usize = 16;
+    pub const MAX_SIZE: usize = 16 + 8 + 32 + 1 + 1 + 8 + 8;
 
     pub fn price_from_order_id(order_id: u128) -> u64 {
         (order_id >> 64) as u64
@@ -347,10 +561,32 @@ impl Order {
     pub fn price(&self) -> u64 {
         Order::price_from_order_id(self.order_id)
     }
+
+    /// Visible and hidden quantity together.
+    pub fn total_qty(&self) -> u64 {
+        self.qty + self.reserve_qty
+    }
+
+    /// Tops the visible quantity of an iceberg back up to `display_qty` from
+    /// its reserve. Returns false once the reserve is exhausted.
+    pub fn refill(&mut self) -> bool {
+        let refill_qty = self
+            .display_qty
+            .saturating_sub(self.qty)
+            .min(self.reserve_qty);
+        self.qty += refill_qty;
+        self.reserve_qty -= refill_qty;
+        refill_qty > 0
+    }
+}
+
+impl PeggedOrder {
+    pub const MAX_SIZE: usize = Order::MAX_SIZE + 1 + 8 + 2;
 }
 
 impl<const T: bool> Orders<T> {
-    pub const MAX_SIZE: usize = 8 + 4 + 32 * Order::MAX_SIZE;
+    pub const MAX_SIZE: usize =
+        8 + 4 + MAX_ORDERS * Order::MAX_SIZE + 4 + MAX_PEGGED_ORDERS * PeggedOrder::MAX_SIZE;
 
     pub fn find_bbo(&self) -> Result<&Order> {
         require!(self.sorted.len() > 0, ErrorCodeCustom::EmptyOrders);
@@ -362,55 +598,200 @@ impl<const T: bool> Orders<T> {
         Ok(&mut self.sorted[0])
     }
 
-    pub fn insert(&mut self, order: Order) -> Result<()> {
-        self.sorted.push(order.clone());
-        let mut is_found = false;
-        for i in 0..(self.sorted.len() - 1) {
-            if T {
-                if self.sorted[i].price() < order.price() {
-                    self.sorted[i] = order;
-                    is_found = true;
-                }
-            } else {
-                if self.sorted[i].price() > order.price() {
-                    self.sorted[i] = order;
-                    is_found = true;
+    /// Index at which `order_id` ranks. Bid ids carry !seq_num and ask ids
+    /// seq_num in the low bits, so ordering by order id gives price-time
+    /// priority on both sides.
+    fn rank(&self, order_id: u128) -> usize {
+        self.sorted
+            .iter()
+            .position(|o| {
+                if T {
+                    o.order_id < order_id
+                } else {
+                    o.order_id > order_id
                 }
-            }
-            if is_found {
-                self.sorted[i + 1] = self.sorted[i];
-            }
+            })
+            .unwrap_or(self.sorted.len())
+    }
+
+    pub fn insert(&mut self, order: Order) -> Result<()> {
+        require!(
+            self.sorted.len() < MAX_ORDERS,
+            ErrorCodeCustom::OrdersAlreadyFull
+        );
+        let idx = self.rank(order.order_id);
+        self.sorted.insert(idx, order);
+
+        Ok(())
+    }
+
+    pub fn delete(&mut self, order_id: u128) -> Result<Order> {
+        if let Some(idx) = self.sorted.iter().position(|o| o.order_id == order_id) {
+            return Ok(self.sorted.remove(idx));
         }
+        let idx = self
+            .pegged
+            .iter()
+            .position(|p| p.order.order_id == order_id)
+            .ok_or(ErrorCodeCustom::OrderNotFound)?;
+
+        Ok(self.pegged.remove(idx).order)
+    }
+
+    pub fn find(&self, order_id: u128) -> Option<&Order> {
+        self.sorted
+            .iter()
+            .chain(self.pegged.iter().map(|p| &p.order))
+            .find(|o| o.order_id == order_id)
+    }
+
+    pub fn insert_pegged(&mut self, order: Order, peg: Peg) -> Result<()> {
+        require!(
+            self.pegged.len() < MAX_PEGGED_ORDERS,
+            ErrorCodeCustom::OrdersAlreadyFull
+        );
+        self.pegged.push(PeggedOrder { order, peg });
 
         Ok(())
     }
 
-    pub fn delete(&mut self, order_id: u128) -> Result<()> {
-        let mut is_found = false;
-        for i in 0..(self.sorted.len() - 1) {
-            if self.sorted[i].order_id == order_id {
-                is_found = true;
+    /// The order a taker meets next and the price it trades at: the top of the
+    /// sorted book, unless a pegged order currently prices strictly better.
+    pub fn best_match_mut(&mut self, references: &PegReferences) -> Result<(&mut Order, u64)> {
+        let side = if T { Side::Bid } else { Side::Ask };
+        let better = |a: u64, b: u64| if T { a > b } else { a < b };
+
+        let mut best_pegged: Option<(usize, u64)> = None;
+        for (i, pegged) in self.pegged.iter().enumerate() {
+            let price = match pegged.peg.price(side, pegged.order.price(), references) {
+                Some(price) => price,
+                None => continue,
+            };
+            match best_pegged {
+                Some((_, best)) if !better(price, best) => {}
+                _ => best_pegged = Some((i, price)),
             }
-            if is_found {
-                self.sorted[i] = self.sorted[i + 1];
+        }
+
+        let top = self.sorted.first().map(|o| o.price());
+        match (top, best_pegged) {
+            (Some(top), Some((i, price))) if better(price, top) => {
+                Ok((&mut self.pegged[i].order, price))
             }
+            (None, Some((i, price))) => Ok((&mut self.pegged[i].order, price)),
+            (Some(top), _) => Ok((&mut self.sorted[0], top)),
+            (None, None) => Err(error!(ErrorCodeCustom::EmptyOrders)),
+        }
+    }
+
+    /// Whether `requeue` has room for `order_id`: it is still resting, or the
+    /// sorted book isn't full.
+    pub fn can_requeue(&self, order_id: u128) -> bool {
+        self.sorted.len() < MAX_ORDERS || self.sorted.iter().any(|o| o.order_id == order_id)
+    }
+
+    /// Puts an order back at the position its order id sorts to, so it keeps
+    /// its original price-time priority. Quantity is added to the order if it
+    /// is still (partially) resting on the book.
+    pub fn requeue(&mut self, order: Order) -> Result<()> {
+        if let Some(resting) = self
+            .sorted
+            .iter_mut()
+            .find(|o| o.order_id == order.order_id)
+        {
+            resting.qty = resting
+                .qty
+                .checked_add(order.qty)
+                .ok_or(ErrorCodeCustom::MathOverflow)?;
+            return Ok(());
+        }
+
+        self.insert(order)
+    }
+
+    /// Moves an order behind every other order resting at its price, as if it
+    /// had just been placed. Orders inserted later still queue behind it.
+    pub fn move_to_back(&mut self, order_id: u128) -> Result<()> {
+        if let Some(idx) = self.sorted.iter().position(|o| o.order_id == order_id) {
+            let order = self.sorted.remove(idx);
+            let end = self.sorted[idx..]
+                .iter()
+                .position(|o| o.price() != order.price())
+                .map_or(self.sorted.len(), |n| idx + n);
+            self.sorted.insert(end, order);
+            return Ok(());
         }
-        self.sorted.pop();
+        let idx = self
+            .pegged
+            .iter()
+            .position(|p| p.order.order_id == order_id)
+            .ok_or(ErrorCodeCustom::OrderNotFound)?;
+        let pegged = self.pegged.remove(idx);
+        self.pegged.push(pegged);
+
+        Ok(())
+    }
+
+    /// Turns a resting order into an iceberg showing at most `display_qty`,
+    /// with the rest held back in its reserve.
+    pub fn hide_reserve(&mut self, order_id: u128, display_qty: u64) -> Result<()> {
+        require!(display_qty > 0, ErrorCodeCustom::InvalidQty);
+        let order = match self.sorted.iter_mut().find(|o| o.order_id == order_id) {
+            Some(order) => order,
+            // nothing rested
+            None => return Ok(()),
+        };
+        let total_qty = order.total_qty();
+        order.display_qty = display_qty;
+        order.qty = total_qty.min(display_qty);
+        order.reserve_qty = total_qty - order.qty;
 
         Ok(())
     }
 
     pub fn delete_worst(&mut self) -> Result<Order> {
-        require!(!self.sorted.is_empty(), ErrorCodeCustom::EmptyOrders);
-        Ok(self.sorted.pop().unwrap())
+        self.sorted
+            .pop()
+            .ok_or_else(|| error!(ErrorCodeCustom::EmptyOrders))
+    }
+
+    /// Aggregated visible quantity per price, best price first, at most `n`
+    /// levels. Iceberg reserves aren't shown.
+    pub fn levels(&self, n: usize) -> Vec<PriceLevel> {
+        let mut levels: Vec<PriceLevel> = vec![];
+        for order in self.sorted.iter() {
+            let fully_funded_qty = if order.fully_funded { order.qty } else { 0 };
+            let full = levels.len() == n;
+            match levels.last_mut() {
+                Some(level) if level.price == order.price() => {
+                    level.qty += order.qty;
+                    level.fully_funded_qty += fully_funded_qty;
+                }
+                _ if full => break,
+                _ => levels.push(PriceLevel {
+                    price: order.price(),
+                    qty: order.qty,
+                    fully_funded_qty,
+                }),
+            }
+        }
+        levels
+    }
+
+    pub fn resting_qty(&self, order_id: u128) -> u64 {
+        self.find(order_id).map_or(0, |o| o.total_qty())
     }
 }
 
 macro_rules! impl_incr_method {
     ($method:ident, $var:ident) => {
         #[allow(unused)]
-        pub fn $method(&mut self, $var: u64) {
-            self.$var = self.$var.checked_add($var).unwrap();
+        pub fn $method(&mut self, $var: u64) -> Result<()> {
+            self.$var = self
+                .$var
+                .checked_add($var)
+                .ok_or(ErrorCodeCustom::MathOverflow)?;
+            Ok(())
         }
     };
 }
@@ -424,82 +805,6 @@ impl RequestProceeds {
     impl_incr_method!(debit_native_pc, native_pc_debit);
 }
 
-impl<'a> OrderBook<'a> {
-    pub fn new_order(
-        &mut self,
-        params: NewOrderParams,
-        event_q: &mut EventQueue,
-        proceeds: &mut RequestProceeds,
-    ) -> Result<Option<OrderRemaining>> {
-        let NewOrderParams {
-            side,
-            order_type,
-            order_id,
-            owner,
-            owner_slot,
-            mut max_coin_qty,
-            mut native_pc_qty_locked,
-        } = params;
-        let (mut post_only, mut post_allowed) = match order_type {
-            OrderType::Limit => (false, true),
-            OrderType::ImmediateOrCancel => (false, false),
-            OrderType::PostOnly => (true, true),
-        };
-        msg!("New order being processed");
-        //check Order impls for sourcing payer acc.
-        let limit_price = Order::price_from_order_id(order_id);
-        let mut limit = 10;
-        loop {
-            if limit == 0 {
-                // Stop matching and release funds if we're out of cycles
-                post_only = true;
-                post_allowed = true;
-            }
-
-            let remaining_order = match side {
-                Side::Bid => self.new_bid(
-                    NewBidParams {
-                        max_coin_qty,
-                        native_pc_qty_locked: native_pc_qty_locked.unwrap(),
-                        limit_price: Some(limit_price),
-                        order_id,
-                        owner,
-                        owner_slot,
-                        post_only,
-                        post_allowed,
-                    },
-                    event_q,
-                    proceeds,
-                ),
-                Side::Ask => self.new_ask(
-                    NewAskParams {
-                        max_qty: max_coin_qty,
-                        limit_price,
-                        order_id,
-                        owner,
-                        owner_slot,
-                        post_only,
-                        post_allowed,
-                    },
-                    event_q,
-                    proceeds,
-                ),
-            }?;
-            if limit == 0 {
-                return Ok(remaining_order);
-            }
-            limit -= 1;
-            match remaining_order {
-                Some(remaining_order) => {
-                    max_coin_qty = remaining_order.coin_qty_remaining;
-                    native_pc_qty_locked = remaining_order.native_pc_qty_remaining;
-                }
-                None => return Ok(None),
-            };
-        }
-    }
-}
-
 pub struct NewBidParams {
     pub max_coin_qty: u64,
     pub native_pc_qty_locked: u64,
@@ -509,6 +814,8 @@ pub struct NewBidParams {
     pub owner_slot: u8,
     pub post_only: bool,
     pub post_allowed: bool,
+    pub fully_funded: bool,
+    pub timestamp: u64,
 }
 
 pub struct NewAskParams {
@@ -519,6 +826,8 @@ pub struct NewAskParams {
     pub owner_slot: u8,
     pub post_only: bool,
     pub post_allowed: bool,
+    pub fully_funded: bool,
+    pub timestamp: u64,
 }
 
 pub struct CancelOrderParams {
@@ -529,33 +838,34 @@ pub struct CancelOrderParams {
 }
 
 #[derive(Accounts)]
+#[instruction(coin_lot_size: u64, pc_lot_size: u64, nonce: u64)]
 pub struct InitializeMarket<'info> {
     #[account(
         init,
         payer = authority,
         space = 8 + Market::MAX_SIZE,
-        seeds = [b"market".as_ref(), coin_mint.key().as_ref(), pc_mint.key().as_ref()],
+        seeds = [b"market".as_ref(), coin_mint.key().as_ref(), pc_mint.key().as_ref(), nonce.to_le_bytes().as_ref()],
         bump,
     )]
     pub market: Box<Account<'info, Market>>,
 
+    /// CHECK: Created in the handler as the market's associated token account.
     #[account(
-        init,
-        payer = authority,
-        associated_token::mint = coin_mint,
-        associated_token::authority = market,
+        mut,
+        address = token_interface::associated_address(&market.key(), &coin_mint.key(), coin_mint.owner),
     )]
-    pub coin_vault: Box<Account<'info, TokenAccount>>,
+    pub coin_vault: UncheckedAccount<'info>,
+    /// CHECK: Created in the handler as the market's associated token account.
     #[account(
-        init,
-        payer = authority,
-        associated_token::mint = pc_mint,
-        associated_token::authority = market,
+        mut,
+        address = token_interface::associated_address(&market.key(), &pc_mint.key(), pc_mint.owner),
     )]
-    pub pc_vault: Box<Account<'info, TokenAccount>>,
+    pub pc_vault: UncheckedAccount<'info>,
 
-    pub coin_mint: Account<'info, Mint>,
-    pub pc_mint: Account<'info, Mint>,
+    /// CHECK: A legacy or Token-2022 mint, validated by token_interface::check_mint.
+    pub coin_mint: UncheckedAccount<'info>,
+    /// CHECK: A legacy or Token-2022 mint, validated by token_interface::check_mint.
+    pub pc_mint: UncheckedAccount<'info>,
 
     #[account(
         init,
@@ -592,16 +902,81 @@ pub struct InitializeMarket<'info> {
     )]
     pub event_q: AccountLoader<'info, EventQueue>,
 
+    #[account(
+        mut,
+        seeds = [b"registry".as_ref()],
+        bump,
+    )]
+    pub registry: Box<Account<'info, Registry>>,
+    /// CHECK: The registry's authority, by address, paid the listing fee.
+    #[account(
+        mut,
+        address = registry.authority,
+    )]
+    pub listing_fee_receiver: UncheckedAccount<'info>,
+
+    /// Lists the market and pays for it, the market's authority is the registry's.
     #[account(mut)]
     pub authority: Signer<'info>,
 
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+    /// CHECK: The program owning coin_mint, checked when the vault is created.
+    pub coin_token_program: UncheckedAccount<'info>,
+    /// CHECK: The program owning pc_mint, checked when the vault is created.
+    pub pc_token_program: UncheckedAccount<'info>,
     pub associated_token_program: Program<'info, AssociatedToken>,
 
     pub rent: Sysvar<'info, Rent>,
 }
 
+#[derive(Accounts)]
+pub struct InitRegistry<'info> {
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Registry::MAX_SIZE,
+        seeds = [b"registry".as_ref()],
+        bump,
+    )]
+    pub registry: Box<Account<'info, Registry>>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetListingFee<'info> {
+    #[account(
+        mut,
+        seeds = [b"registry".as_ref()],
+        bump,
+        has_one = authority,
+    )]
+    pub registry: Box<Account<'info, Registry>>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DelistMarket<'info> {
+    #[account(
+        mut,
+        seeds = [b"registry".as_ref()],
+        bump,
+    )]
+    pub registry: Box<Account<'info, Registry>>,
+    #[account(mut)]
+    pub market: Box<Account<'info, Market>>,
+    #[account(address = market.bids @ ErrorCodeCustom::WrongMarket)]
+    pub bids: Box<Account<'info, Bids>>,
+    #[account(address = market.asks @ ErrorCodeCustom::WrongMarket)]
+    pub asks: Box<Account<'info, Asks>>,
+    /// The market's authority or the registry's.
+    #[account(
+        constraint = authority.key() == market.authority || authority.key() == registry.authority @ ErrorCodeCustom::WrongAuthority,
+    )]
+    pub authority: Signer<'info>,
+}
+
 #[derive(Copy, Debug, Clone, PartialEq, AnchorSerialize, AnchorDeserialize)]
 pub enum Side {
     Bid = 0,
@@ -631,9 +1006,101 @@ pub struct OpenOrders {
 
     pub free_slot_bits: u8,
     pub is_bid_bits: u8,
+    // slots of fully funded orders, which lock the whole order rather than a margin
+    pub fully_funded_bits: u8,
     pub orders: [u128; 8],
+
+    // re-insert this account's maker orders into the book if the counterparty defaults
+    pub requeue_on_default: bool,
+
+    // may place, cancel and finalise orders against the free balances, but never withdraw
+    pub delegate: Pubkey,
+
+    // JIT orders are backed by this margin account instead of the owner's wallet
+    pub margin_account: Pubkey,
+
+    // trades like the delegate until session_expires_at, in unix seconds
+    pub session_key: Pubkey,
+    pub session_expires_at: u64,
+    // wallets withdrawals may be paid to; while empty, only the authority's own
+    pub withdraw_whitelist: [Pubkey; MAX_WITHDRAW_DESTINATIONS],
+
+    // set by the market authority, leaves the owner able to cancel and nothing else
+    pub frozen: bool,
+}
+
+pub const MAX_WITHDRAW_DESTINATIONS: usize = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, AnchorSerialize, AnchorDeserialize)]
+pub enum TriggerKind {
+    StopLoss,
+    TakeProfit,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, AnchorSerialize, AnchorDeserialize)]
+pub enum TriggerReference {
+    LastTrade,
+    Mid,
+}
+
+/// Arguments of place_trigger_order. Once triggered the order is placed like
+/// a fully funded new_order with these parameters.
+#[derive(Copy, Clone, AnchorSerialize, AnchorDeserialize)]
+pub struct TriggerParams {
+    pub kind: TriggerKind,
+    pub reference: TriggerReference,
+    pub trigger_price: u64,
+    pub side: Side,
+    pub order_type: OrderType,
+    pub limit_price: u64,
+    pub max_coin_qty: u64,
+    pub max_native_pc_qty: u64,
+}
+
+#[derive(Copy, Clone, AnchorSerialize, AnchorDeserialize)]
+pub struct TriggerOrder {
+    pub params: TriggerParams,
+    // held in the owner's OpenOrders slot until the order is triggered
+    pub order_id: u128,
+    pub owner: Pubkey,
+    pub owner_slot: u8,
+    pub native_qty_locked: u64,
+}
+
+/// Conditional orders of one market, sorted by trigger price.
+#[account]
+#[derive(Default)]
+pub struct TriggerOrders {
+    pub market: Pubkey,
+    pub orders: Vec<TriggerOrder>,
+}
+
+pub const MAX_TRIGGER_ORDERS: usize = 16;
+
+/// One mint's collateral in a margin account.
+#[derive(Copy, Clone, Default, AnchorSerialize, AnchorDeserialize)]
+pub struct Collateral {
+    pub mint: Pubkey,
+    // held in the margin account's vault for the mint, net of transfer fees
+    pub deposited: u64,
+    // JIT obligations of open orders, across every linked market
+    pub reserved: u64,
+}
+
+/// A user's collateral shared by all of their linked OpenOrders accounts, so
+/// makers quoting several markets don't have to split capital between them.
+#[account]
+#[derive(Default)]
+pub struct MarginAccount {
+    pub owner: Pubkey,
+    pub collateral: Vec<Collateral>,
 }
 
+pub const MAX_MARGIN_MINTS: usize = 8;
+
+/// Collateral required against reserved obligations, in basis points.
+pub const MARGIN_REQUIREMENT_BPS: u64 = 2_000;
+
 #[derive(Accounts)]
 
 pub struct FinaliseMatch<'info> {
@@ -644,13 +1111,13 @@ pub struct FinaliseMatch<'info> {
     pub open_orders_owner: Box<Account<'info, OpenOrders>>,
 
     #[account(
-        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority_second.key().as_ref()],
         bump,
     )]
     pub open_orders_cpty: Box<Account<'info, OpenOrders>>,
 
     #[account(
-        seeds = [b"market".as_ref(), coin_mint.key().as_ref(), pc_mint.key().as_ref()],
+        seeds = [b"market".as_ref(), coin_mint.key().as_ref(), pc_mint.key().as_ref(), market.nonce.to_le_bytes().as_ref()],
         bump,
     )]
     pub market: Box<Account<'info, Market>>,
@@ -696,6 +1163,9 @@ pub struct FinaliseMatch<'info> {
     #[account(mut)]
     pub authority: Signer<'info>,
 
+    /// CHECK: This account is only used for its public key in seeds and is not used for signing.
+    pub authority_second: AccountInfo<'info>,
+
     pub system_program: Program<'info, System>,
     pub token_program: Program<'info, Token>,
     pub associated_token_program: Program<'info, AssociatedToken>,
@@ -709,56 +1179,78 @@ pub struct NewOrder<'info> {
     #[account(
         init_if_needed,
         space = 8 + OpenOrders::MAX_SIZE,
-        payer = authority,
-        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        payer = rent_payer,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), owner.key().as_ref()],
         bump,
     )]
     pub open_orders: Box<Account<'info, OpenOrders>>,
 
     #[account(
-        seeds = [b"market".as_ref(), coin_mint.key().as_ref(), pc_mint.key().as_ref()],
+        mut,
+        seeds = [b"market".as_ref(), coin_mint.key().as_ref(), pc_mint.key().as_ref(), market.nonce.to_le_bytes().as_ref()],
         bump,
     )]
     pub market: Box<Account<'info, Market>>,
 
+    /// CHECK: The market's vault, by address.
     #[account(
         mut,
-        associated_token::mint = coin_mint,
-        associated_token::authority = market,
+        address = market.coin_vault,
     )]
-    pub coin_vault: Account<'info, TokenAccount>,
+    pub coin_vault: UncheckedAccount<'info>,
+    /// CHECK: The market's vault, by address.
     #[account(
         mut,
-        associated_token::mint = pc_mint,
-        associated_token::authority = market,
+        address = market.pc_vault,
     )]
-    pub pc_vault: Account<'info, TokenAccount>,
+    pub pc_vault: UncheckedAccount<'info>,
 
-    pub coin_mint: Account<'info, Mint>,
-    pub pc_mint: Account<'info, Mint>,
+    /// CHECK: Bound to the market by its seeds.
+    pub coin_mint: UncheckedAccount<'info>,
+    /// CHECK: Bound to the market by its seeds.
+    pub pc_mint: UncheckedAccount<'info>,
 
+    /// CHECK: A token account of the mint this side pays with, owned by `authority`.
+    /// On the native side of a SOL market, `authority` itself to pay in lamports.
     #[account(
         mut,
-        constraint = market.check_payer_mint(payer.mint, side) @ ErrorCodeCustom::WrongPayerMint,
-        token::authority = authority,
+        constraint = token_interface::is_token_account(&payer, &market.payer_mint(side), Some(&authority.key()))
+            || (payer.key() == authority.key() && market.native_side() == Some(side)) @ ErrorCodeCustom::WrongPayerMint,
     )]
-    pub payer: Account<'info, TokenAccount>,
+    pub payer: UncheckedAccount<'info>,
 
-    #[account(mut)]
+    #[account(
+        mut,
+        address = market.bids @ ErrorCodeCustom::WrongMarket,
+    )]
     pub bids: Box<Account<'info, Bids>>,
-    #[account(mut)]
+    #[account(
+        mut,
+        address = market.asks @ ErrorCodeCustom::WrongMarket,
+    )]
     pub asks: Box<Account<'info, Asks>>,
 
-    #[account(mut)]
+    #[account(
+        mut,
+        address = market.req_q @ ErrorCodeCustom::WrongMarket,
+    )]
     pub req_q: Box<Account<'info, RequestQueue>>,
-    #[account(mut)]
+    #[account(
+        mut,
+        address = market.event_q @ ErrorCodeCustom::WrongMarket,
+    )]
     pub event_q: AccountLoader<'info, EventQueue>,
 
-    #[account(mut)]
+    /// CHECK: Only used for its public key in seeds. Either it or its delegate signs as `authority`.
+    pub owner: AccountInfo<'info>,
     pub authority: Signer<'info>,
+    // kept apart from the owner so PDAs, which can't fund account creation, can trade via CPI
+    #[account(mut)]
+    pub rent_payer: Signer<'info>,
 
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+    /// CHECK: Must own the payer's mint, checked on transfer.
+    pub token_program: UncheckedAccount<'info>,
     pub associated_token_program: Program<'info, AssociatedToken>,
 
     pub rent: Sysvar<'info, Rent>,
@@ -767,38 +1259,69 @@ pub struct NewOrder<'info> {
 #[derive(Accounts)]
 
 pub struct NewMatch<'info> {
-    #[account(mut)]
-    pub open_orders_owner: Box<Account<'info, OpenOrders>>,
-
-    #[account(mut)]
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), owner.key().as_ref()],
+        bump,
+        has_one = market,
+        constraint = open_orders_owner.can_trade(authority.key()) @ ErrorCodeCustom::InvalidAuthority,
+    )]
+    pub open_orders_owner: Box<Account<'info, OpenOrders>>,
+
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority_second.key().as_ref()],
+        bump,
+        has_one = market,
+    )]
     pub open_orders_counterparty: Box<Account<'info, OpenOrders>>,
 
     #[account(
-      seeds = [b"market".as_ref(), coin_mint.key().as_ref(), pc_mint.key().as_ref()],
+      mut,
+      seeds = [b"market".as_ref(), coin_mint.key().as_ref(), pc_mint.key().as_ref(), market.nonce.to_le_bytes().as_ref()],
       bump,
     )]
     pub market: Box<Account<'info, Market>>,
 
-    #[account(mut)]
-    pub pc_vault: Account<'info, TokenAccount>,
+    /// CHECK: The market's vault, by address.
+    #[account(
+        mut,
+        address = market.pc_vault,
+    )]
+    pub pc_vault: UncheckedAccount<'info>,
 
-    pub coin_mint: Account<'info, Mint>,
-    pub pc_mint: Account<'info, Mint>,
+    /// CHECK: Bound to the market by its seeds.
+    pub coin_mint: UncheckedAccount<'info>,
+    /// CHECK: Bound to the market by its seeds.
+    pub pc_mint: UncheckedAccount<'info>,
 
-    #[account(mut)]
+    #[account(
+        mut,
+        address = market.req_q,
+    )]
     pub req_q: Box<Account<'info, RequestQueue>>,
-    #[account(mut)]
+    #[account(
+        mut,
+        address = market.event_q,
+    )]
     pub event_q: AccountLoader<'info, EventQueue>,
+    /// CHECK: Only used for its public key in seeds. Either it or a key trading for it signs as `authority`.
+    pub owner: AccountInfo<'info>,
     pub authority: Signer<'info>,
 
     /// CHECK: This account is only used for its public key in seeds and is not used for signing.
     pub authority_second: AccountInfo<'info>,
 
-    #[account(mut)]
-    pub pcpayer: Account<'info, TokenAccount>,
+    /// CHECK: The maker's pc account the market was approved to pull from.
+    #[account(
+        mut,
+        constraint = token_interface::is_token_account(&pcpayer, &pc_mint.key(), None) @ ErrorCodeCustom::WrongPayerMint,
+    )]
+    pub pcpayer: UncheckedAccount<'info>,
 
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+    /// CHECK: Must own pc_mint, checked on transfer.
+    pub token_program: UncheckedAccount<'info>,
     pub associated_token_program: Program<'info, AssociatedToken>,
 
     pub rent: Sysvar<'info, Rent>,
@@ -807,118 +1330,751 @@ pub struct NewMatch<'info> {
 
 #[derive(Accounts)]
 pub struct NewMatchAsk<'info> {
-    #[account(mut)]
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), owner.key().as_ref()],
+        bump,
+        has_one = market,
+        constraint = open_orders_owner.can_trade(authority.key()) @ ErrorCodeCustom::InvalidAuthority,
+    )]
     pub open_orders_owner: Box<Account<'info, OpenOrders>>,
 
-    #[account(mut)]
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority_second.key().as_ref()],
+        bump,
+        has_one = market,
+    )]
     pub open_orders_counterparty: Box<Account<'info, OpenOrders>>,
 
     #[account(
-        seeds = [b"market".as_ref(), coin_mint.key().as_ref(), pc_mint.key().as_ref()],
+        mut,
+        seeds = [b"market".as_ref(), coin_mint.key().as_ref(), pc_mint.key().as_ref(), market.nonce.to_le_bytes().as_ref()],
         bump,
     )]
     pub market: Box<Account<'info, Market>>,
 
+    /// CHECK: The market's vault, by address.
     #[account(
         mut,
-        associated_token::mint = coin_mint,
-        associated_token::authority = market,
+        address = market.coin_vault,
     )]
-    pub coin_vault: Account<'info, TokenAccount>,
+    pub coin_vault: UncheckedAccount<'info>,
 
-    pub coin_mint: Account<'info, Mint>,
-    pub pc_mint: Account<'info, Mint>,
+    /// CHECK: Bound to the market by its seeds.
+    pub coin_mint: UncheckedAccount<'info>,
+    /// CHECK: Bound to the market by its seeds.
+    pub pc_mint: UncheckedAccount<'info>,
 
-    #[account(mut)]
+    #[account(
+        mut,
+        address = market.req_q,
+    )]
     pub req_q: Box<Account<'info, RequestQueue>>,
-    #[account(mut)]
+    #[account(
+        mut,
+        address = market.event_q,
+    )]
     pub event_q: AccountLoader<'info, EventQueue>,
+    /// CHECK: Only used for its public key in seeds. Either it or a key trading for it signs as `authority`.
+    pub owner: AccountInfo<'info>,
     pub authority: Signer<'info>,
 
     /// CHECK: This account is only used for its public key in seeds and is not used for signing.
     pub authority_second: AccountInfo<'info>,
 
-    #[account(mut)]
-    pub coinpayer: Account<'info, TokenAccount>,
+    /// CHECK: The maker's coin account the market was approved to pull from.
+    #[account(
+        mut,
+        constraint = token_interface::is_token_account(&coinpayer, &coin_mint.key(), None) @ ErrorCodeCustom::WrongPayerMint,
+    )]
+    pub coinpayer: UncheckedAccount<'info>,
 
     pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+    /// CHECK: Must own coin_mint, checked on transfer.
+    pub token_program: UncheckedAccount<'info>,
     pub associated_token_program: Program<'info, AssociatedToken>,
 
     pub rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
-pub struct DepositTokens<'info> {
+pub struct Deposit<'info> {
     #[account(mut)]
     pub market: Box<Account<'info, Market>>,
+    /// CHECK: One of the market's mints, by address.
+    #[account(
+        constraint = market.mint_side(mint.key()).is_some() @ ErrorCodeCustom::WrongPayerMint,
+    )]
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: The market's vault for `mint`, by address.
+    #[account(
+        mut,
+        constraint = market.mint_side(mint.key()).map(|side| market.payer_vault(side)) == Some(vault.key()) @ ErrorCodeCustom::WrongMarket,
+    )]
+    pub vault: UncheckedAccount<'info>,
+    /// CHECK: A token account of `mint` owned by `authority`.
+    #[account(
+        mut,
+        constraint = token_interface::is_token_account(&payer, &mint.key(), Some(&authority.key())) @ ErrorCodeCustom::WrongPayerMint,
+    )]
+    pub payer: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        bump,
+        has_one = market,
+        has_one = authority,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+    pub authority: Signer<'info>,
+
+    /// CHECK: Must own `mint`, checked on transfer.
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelOrder<'info> {
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), owner.key().as_ref()],
+        bump,
+        has_one = market,
+        constraint = open_orders.can_trade(authority.key()) @ ErrorCodeCustom::InvalidAuthority,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+
     #[account(mut)]
-    pub payer: Account<'info, TokenAccount>,
-    #[account(mut)]
-    pub vault: Account<'info, TokenAccount>,
+    pub market: Box<Account<'info, Market>>,
+    #[account(
+        mut,
+        address = market.bids,
+    )]
+    pub bids: Box<Account<'info, Bids>>,
+    #[account(
+        mut,
+        address = market.asks,
+    )]
+    pub asks: Box<Account<'info, Asks>>,
+    #[account(
+        mut,
+        address = market.event_q,
+    )]
+    pub event_q: AccountLoader<'info, EventQueue>,
+    /// CHECK: Only used for its public key in seeds. Either it or its delegate signs as `authority`.
+    pub owner: AccountInfo<'info>,
+    pub authority: Signer<'info>,
+}
 
+#[derive(Accounts)]
+pub struct Withdraw<'info> {
     #[account(mut)]
+    pub market: Box<Account<'info, Market>>,
+    /// CHECK: One of the market's mints, by address.
+    #[account(
+        constraint = market.mint_side(mint.key()).is_some() @ ErrorCodeCustom::WrongPayerMint,
+    )]
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: The market's vault for `mint`, by address.
+    #[account(
+        mut,
+        constraint = market.mint_side(mint.key()).map(|side| market.payer_vault(side)) == Some(vault.key()) @ ErrorCodeCustom::WrongMarket,
+    )]
+    pub vault: UncheckedAccount<'info>,
+    /// CHECK: A token account of `mint` whose owner the account may withdraw to.
+    #[account(
+        mut,
+        constraint = token_interface::token_account_owner(&destination, &mint.key()).map_or(false, |owner| open_orders.may_withdraw_to(owner)) @ ErrorCodeCustom::DestinationNotWhitelisted,
+    )]
+    pub destination: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        bump,
+        has_one = market,
+        has_one = authority,
+    )]
     pub open_orders: Box<Account<'info, OpenOrders>>,
     pub authority: Signer<'info>,
 
-    pub token_program: Program<'info, Token>,
+    /// CHECK: Must own `mint`, checked on transfer.
+    pub token_program: UncheckedAccount<'info>,
+}
 
-    pub associated_token_program: Program<'info, AssociatedToken>,
+#[derive(Accounts)]
+pub struct DepositNative<'info> {
+    #[account(mut)]
+    pub market: Box<Account<'info, Market>>,
+    /// CHECK: The market's wrapped SOL vault, by address.
+    #[account(
+        mut,
+        constraint = market.native_side().map(|side| market.payer_vault(side)) == Some(vault.key()) @ ErrorCodeCustom::NotNativeMarket,
+    )]
+    pub vault: UncheckedAccount<'info>,
 
-    pub rent: Sysvar<'info, Rent>,
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        bump,
+        has_one = market,
+        has_one = authority,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+    /// CHECK: Must own the vault, checked on sync.
+    pub token_program: UncheckedAccount<'info>,
 }
 
 #[derive(Accounts)]
-pub struct CancelOrder<'info> {
+pub struct WithdrawNative<'info> {
     #[account(mut)]
-    pub open_orders: Box<Account<'info, OpenOrders>>,
+    pub market: Box<Account<'info, Market>>,
+    /// CHECK: The market's wrapped SOL mint, by address.
+    #[account(
+        constraint = market.native_side().map(|side| market.payer_mint(side)) == Some(mint.key()) @ ErrorCodeCustom::NotNativeMarket,
+    )]
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: The market's wrapped SOL vault, by address.
+    #[account(
+        mut,
+        constraint = market.native_side().map(|side| market.payer_vault(side)) == Some(vault.key()) @ ErrorCodeCustom::NotNativeMarket,
+    )]
+    pub vault: UncheckedAccount<'info>,
 
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        bump,
+        has_one = market,
+        has_one = authority,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+    /// CHECK: Temporary wrapped SOL account, created and closed by the instruction.
+    #[account(
+        mut,
+        seeds = [b"wsol".as_ref(), open_orders.key().as_ref()],
+        bump,
+    )]
+    pub wsol: UncheckedAccount<'info>,
     #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+    /// CHECK: Must own the mint, checked on transfer.
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SettleFunds<'info> {
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), owner.key().as_ref()],
+        bump,
+        has_one = market,
+        constraint = open_orders.authority == owner.key() @ ErrorCodeCustom::InvalidAuthority,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+
+    #[account(
+        mut,
+        seeds = [b"market".as_ref(), coin_mint.key().as_ref(), pc_mint.key().as_ref(), market.nonce.to_le_bytes().as_ref()],
+        bump,
+    )]
     pub market: Box<Account<'info, Market>>,
+
+    /// CHECK: Bound to the market by its seeds.
+    pub coin_mint: UncheckedAccount<'info>,
+    /// CHECK: Bound to the market by its seeds.
+    pub pc_mint: UncheckedAccount<'info>,
+
+    /// CHECK: The market's vault, by address.
+    #[account(
+        mut,
+        address = market.coin_vault,
+    )]
+    pub coin_vault: UncheckedAccount<'info>,
+    /// CHECK: The market's vault, by address.
+    #[account(
+        mut,
+        address = market.pc_vault,
+    )]
+    pub pc_vault: UncheckedAccount<'info>,
+
+    /// CHECK: Not a signer, settlement can be cranked by anyone. Used to derive the destination
+    /// ATAs, and paid directly in lamports for a native SOL mint.
     #[account(mut)]
-    pub bids: Box<Account<'info, Bids>>,
+    pub owner: AccountInfo<'info>,
+
+    /// CHECK: The owner's associated token account, by address.
+    #[account(
+        mut,
+        address = token_interface::associated_address(&owner.key(), &coin_mint.key(), coin_mint.owner),
+    )]
+    pub owner_coin_account: UncheckedAccount<'info>,
+    /// CHECK: The owner's associated token account, by address.
+    #[account(
+        mut,
+        address = token_interface::associated_address(&owner.key(), &pc_mint.key(), pc_mint.owner),
+    )]
+    pub owner_pc_account: UncheckedAccount<'info>,
+
+    /// CHECK: Temporary wrapped SOL account, created and closed when a native mint is settled.
+    #[account(
+        mut,
+        seeds = [b"wsol".as_ref(), open_orders.key().as_ref()],
+        bump,
+    )]
+    pub wsol: UncheckedAccount<'info>,
+    /// Fronts the rent of `wsol`, which is refunded in the same instruction.
     #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: Must own coin_mint, checked on transfer.
+    pub coin_token_program: UncheckedAccount<'info>,
+    /// CHECK: Must own pc_mint, checked on transfer.
+    pub pc_token_program: UncheckedAccount<'info>,
+    pub system_program: Program<'info, System>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+}
+
+#[derive(Accounts)]
+pub struct CancelWithPenalty<'info> {
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), open_orders_bidder.authority.as_ref()],
+        bump,
+        has_one = market,
+    )]
+    pub open_orders_bidder: Box<Account<'info, OpenOrders>>,
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), open_orders_asker.authority.as_ref()],
+        bump,
+        has_one = market,
+    )]
+    pub open_orders_asker: Box<Account<'info, OpenOrders>>,
+    #[account(
+        mut,
+        address = market.event_q,
+    )]
+    pub event_q: AccountLoader<'info, EventQueue>,
+
+    #[account(mut)]
+    pub market: Box<Account<'info, Market>>,
+    #[account(
+        mut,
+        address = market.bids,
+    )]
+    pub bids: Box<Account<'info, Bids>>,
+    #[account(
+        mut,
+        address = market.asks,
+    )]
     pub asks: Box<Account<'info, Asks>>,
+}
+
+#[derive(Accounts)]
+pub struct Liquidate<'info> {
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), open_orders.authority.as_ref()],
+        bump,
+        has_one = market,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), liquidator.key().as_ref()],
+        bump,
+        has_one = market,
+    )]
+    pub liquidator_open_orders: Box<Account<'info, OpenOrders>>,
+
     #[account(mut)]
+    pub market: Box<Account<'info, Market>>,
+    #[account(
+        mut,
+        address = market.bids,
+    )]
+    pub bids: Box<Account<'info, Bids>>,
+    #[account(
+        mut,
+        address = market.asks,
+    )]
+    pub asks: Box<Account<'info, Asks>>,
+    #[account(
+        mut,
+        address = market.event_q,
+    )]
     pub event_q: AccountLoader<'info, EventQueue>,
+    pub liquidator: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetRequeueOnDefault<'info> {
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        bump,
+        has_one = market,
+        has_one = authority,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+
+    pub market: Box<Account<'info, Market>>,
     pub authority: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct WithdrawTokens<'info> {
+pub struct SetPegOracle<'info> {
+    #[account(
+        mut,
+        has_one = authority,
+    )]
+    pub market: Box<Account<'info, Market>>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetAdmissionAuthority<'info> {
+    #[account(
+        mut,
+        has_one = authority,
+    )]
+    pub market: Box<Account<'info, Market>>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetAllowed<'info> {
+    #[account(
+        init_if_needed,
+        space = 8 + AllowList::MAX_SIZE,
+        payer = admission_authority,
+        seeds = [b"allow-list".as_ref(), market.key().as_ref()],
+        bump,
+    )]
+    pub allow_list: Box<Account<'info, AllowList>>,
+    #[account(
+        constraint = market.open_orders_admission_authority == admission_authority.key() @ ErrorCodeCustom::WrongAuthority,
+    )]
+    pub market: Box<Account<'info, Market>>,
+    #[account(mut)]
+    pub admission_authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct FreezeOpenOrders<'info> {
+    #[account(
+        mut,
+        has_one = market,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+    #[account(has_one = authority)]
+    pub market: Box<Account<'info, Market>>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(params: TriggerParams)]
+pub struct PlaceTriggerOrder<'info> {
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        bump,
+        has_one = market,
+        has_one = authority,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+
+    #[account(
+        init_if_needed,
+        space = 8 + TriggerOrders::MAX_SIZE,
+        payer = authority,
+        seeds = [b"trigger-orders".as_ref(), market.key().as_ref()],
+        bump,
+    )]
+    pub trigger_orders: Box<Account<'info, TriggerOrders>>,
+
     #[account(mut)]
     pub market: Box<Account<'info, Market>>,
+    /// CHECK: The vault of the mint this side pays with, by address.
+    #[account(
+        mut,
+        address = market.payer_vault(params.side) @ ErrorCodeCustom::WrongMarket,
+    )]
+    pub vault: UncheckedAccount<'info>,
+    /// CHECK: The mint this side pays with, by address.
+    #[account(address = market.payer_mint(params.side) @ ErrorCodeCustom::WrongPayerMint)]
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: A token account of `mint` owned by `authority`.
+    #[account(
+        mut,
+        constraint = token_interface::is_token_account(&payer, &mint.key(), Some(&authority.key())) @ ErrorCodeCustom::WrongPayerMint,
+    )]
+    pub payer: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        address = market.req_q,
+    )]
+    pub req_q: Box<Account<'info, RequestQueue>>,
+
     #[account(mut)]
-    pub payer: Account<'info, TokenAccount>,
-    pub coin_mint: Account<'info, Mint>,
-    pub pc_mint: Account<'info, Mint>,
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+    /// CHECK: Must own `mint`, checked on transfer.
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelTriggerOrder<'info> {
     #[account(
         mut,
-        associated_token::mint = coin_mint,
-        associated_token::authority = market,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        bump,
+        has_one = market,
+        has_one = authority,
     )]
-    pub coin_vault: Account<'info, TokenAccount>,
+    pub open_orders: Box<Account<'info, OpenOrders>>,
     #[account(
         mut,
-        associated_token::mint = pc_mint,
-        associated_token::authority = market,
+        seeds = [b"trigger-orders".as_ref(), market.key().as_ref()],
+        bump,
+        has_one = market,
+    )]
+    pub trigger_orders: Box<Account<'info, TriggerOrders>>,
+
+    pub market: Box<Account<'info, Market>>,
+    pub authority: Signer<'info>,
+}
+
+/// Permissionless. The OpenOrders of every owner whose order should fire go
+/// in the remaining accounts, writable.
+#[derive(Accounts)]
+pub struct ExecuteTriggers<'info> {
+    #[account(
+        mut,
+        seeds = [b"trigger-orders".as_ref(), market.key().as_ref()],
+        bump,
+        has_one = market,
     )]
-    pub pc_vault: Box<Account<'info, TokenAccount>>,
+    pub trigger_orders: Box<Account<'info, TriggerOrders>>,
+
     #[account(mut)]
+    pub market: Box<Account<'info, Market>>,
+    #[account(
+        mut,
+        address = market.bids,
+    )]
+    pub bids: Box<Account<'info, Bids>>,
+    #[account(
+        mut,
+        address = market.asks,
+    )]
+    pub asks: Box<Account<'info, Asks>>,
+    #[account(
+        mut,
+        address = market.req_q,
+    )]
+    pub req_q: Box<Account<'info, RequestQueue>>,
+    #[account(
+        mut,
+        address = market.event_q,
+    )]
+    pub event_q: AccountLoader<'info, EventQueue>,
+}
+
+#[derive(Accounts)]
+pub struct SetDelegate<'info> {
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        bump,
+        has_one = market,
+        has_one = authority,
+    )]
     pub open_orders: Box<Account<'info, OpenOrders>>,
+
+    pub market: Box<Account<'info, Market>>,
     pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetSessionKey<'info> {
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        bump,
+        has_one = market,
+        has_one = authority,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+
+    pub market: Box<Account<'info, Market>>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetWithdrawWhitelist<'info> {
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        bump,
+        has_one = market,
+        has_one = authority,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+
+    pub market: Box<Account<'info, Market>>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseOpenOrders<'info> {
+    #[account(
+        mut,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        bump,
+        has_one = market,
+        has_one = authority,
+        close = recipient,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+
+    pub market: Box<Account<'info, Market>>,
+    #[account(address = market.event_q)]
+    pub event_q: AccountLoader<'info, EventQueue>,
+    pub authority: Signer<'info>,
+    /// CHECK: Only receives the account's lamports.
+    #[account(mut)]
+    pub recipient: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitMarginAccount<'info> {
+    #[account(
+        init,
+        space = 8 + MarginAccount::MAX_SIZE,
+        payer = owner,
+        seeds = [b"margin".as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub margin_account: Box<Account<'info, MarginAccount>>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
     pub system_program: Program<'info, System>,
+}
 
-    pub token_program: Program<'info, Token>,
+#[derive(Accounts)]
+pub struct DepositMargin<'info> {
+    #[account(
+        mut,
+        seeds = [b"margin".as_ref(), owner.key().as_ref()],
+        bump,
+        has_one = owner,
+    )]
+    pub margin_account: Box<Account<'info, MarginAccount>>,
+    /// CHECK: The margin account's associated token account for `mint`, created on the first deposit.
+    #[account(
+        mut,
+        address = token_interface::associated_address(&margin_account.key(), &mint.key(), &token_program.key()),
+    )]
+    pub vault: UncheckedAccount<'info>,
+    /// CHECK: Checked against the token program when the vault is created and on transfer.
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: A token account of `mint` owned by `owner`.
+    #[account(
+        mut,
+        constraint = token_interface::is_token_account(&payer, &mint.key(), Some(&owner.key())) @ ErrorCodeCustom::WrongPayerMint,
+    )]
+    pub payer: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+    /// CHECK: Must own `mint`, checked on transfer.
+    pub token_program: UncheckedAccount<'info>,
     pub associated_token_program: Program<'info, AssociatedToken>,
 }
 
 #[derive(Accounts)]
-pub struct CancelWithPenalty<'info> {
-    #[account(mut)]
-    pub open_orders_bidder: Box<Account<'info, OpenOrders>>,
+pub struct WithdrawMargin<'info> {
+    #[account(
+        mut,
+        seeds = [b"margin".as_ref(), owner.key().as_ref()],
+        bump,
+        has_one = owner,
+    )]
+    pub margin_account: Box<Account<'info, MarginAccount>>,
+    /// CHECK: The margin account's associated token account for `mint`.
+    #[account(
+        mut,
+        address = token_interface::associated_address(&margin_account.key(), &mint.key(), &token_program.key()),
+    )]
+    pub vault: UncheckedAccount<'info>,
+    /// CHECK: Checked against the token program on transfer.
+    pub mint: UncheckedAccount<'info>,
+    /// CHECK: The destination, any token account of `mint`.
     #[account(mut)]
-    pub open_orders_asker: Box<Account<'info, OpenOrders>>,
+    pub destination: UncheckedAccount<'info>,
+    pub owner: Signer<'info>,
+
+    /// CHECK: Must own `mint`, checked on transfer.
+    pub token_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct LinkMarginAccount<'info> {
+    #[account(
+        init_if_needed,
+        space = 8 + OpenOrders::MAX_SIZE,
+        payer = authority,
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), authority.key().as_ref()],
+        bump,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+
+    pub market: Box<Account<'info, Market>>,
+    #[account(
+        seeds = [b"margin".as_ref(), authority.key().as_ref()],
+        bump,
+    )]
+    pub margin_account: Box<Account<'info, MarginAccount>>,
     #[account(mut)]
+    pub authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ViewBook<'info> {
+    pub market: Box<Account<'info, Market>>,
+    #[account(address = market.bids)]
+    pub bids: Box<Account<'info, Bids>>,
+    #[account(address = market.asks)]
+    pub asks: Box<Account<'info, Asks>>,
+}
+
+#[derive(Accounts)]
+pub struct ViewOpenOrders<'info> {
+    pub market: Box<Account<'info, Market>>,
+    #[account(
+        seeds = [b"open-orders".as_ref(), market.key().as_ref(), open_orders.authority.as_ref()],
+        bump,
+        has_one = market,
+    )]
+    pub open_orders: Box<Account<'info, OpenOrders>>,
+    #[account(address = market.bids)]
+    pub bids: Box<Account<'info, Bids>>,
+    #[account(address = market.asks)]
+    pub asks: Box<Account<'info, Asks>>,
+    #[account(address = market.event_q)]
     pub event_q: AccountLoader<'info, EventQueue>,
 }
@@ -0,0 +1,468 @@
+//! Token transfers that work for both the legacy token program and Token-2022.
+//! `anchor_spl::token` is hard-wired to the former, so mints, token accounts
+//! and the token program come in as unchecked accounts and are validated here.
+
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::instruction::{AccountMeta, Instruction};
+use anchor_lang::solana_program::program::{invoke, invoke_signed};
+use anchor_lang::solana_program::program_option::COption;
+use anchor_lang::solana_program::program_pack::Pack;
+use anchor_lang::solana_program::system_instruction;
+use spl_token_2022::extension::default_account_state::DefaultAccountState;
+use spl_token_2022::extension::transfer_fee::TransferFeeConfig;
+use spl_token_2022::extension::{ExtensionType, StateWithExtensions};
+use spl_token_2022::state::{Account, AccountState, Mint};
+
+use crate::errors::ErrorCodeCustom;
+
+const MAX_FEE_BASIS_POINTS: u128 = 10_000;
+
+pub fn is_token_program(key: &Pubkey) -> bool {
+    *key == spl_token::ID || *key == spl_token_2022::ID
+}
+
+/// Wrapped SOL under either token program.
+pub fn is_native_mint(key: &Pubkey) -> bool {
+    *key == spl_token::native_mint::ID || *key == spl_token_2022::native_mint::ID
+}
+
+/// The associated token account of `wallet`, which for Token-2022 mints
+/// differs from the legacy address.
+pub fn associated_address(wallet: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[wallet.as_ref(), token_program.as_ref(), mint.as_ref()],
+        &anchor_spl::associated_token::ID,
+    )
+    .0
+}
+
+/// Whether `info` is a token account of `mint`, and of `owner` if given.
+pub fn is_token_account(info: &AccountInfo, mint: &Pubkey, owner: Option<&Pubkey>) -> bool {
+    token_account_owner(info, mint).map_or(false, |account_owner| {
+        owner.map_or(true, |owner| account_owner == *owner)
+    })
+}
+
+/// The owner of `info` if it is a token account of `mint`.
+pub fn token_account_owner(info: &AccountInfo, mint: &Pubkey) -> Option<Pubkey> {
+    if !is_token_program(info.owner) {
+        return None;
+    }
+    let data = info.try_borrow_data().ok()?;
+    let account = StateWithExtensions::<Account>::unpack(&data).ok()?;
+    if account.base.mint != *mint {
+        return None;
+    }
+    Some(account.base.owner)
+}
+
+/// What `delegate` may still pull from the token account `info`, 0 unless it
+/// is the account's delegate.
+pub fn delegated_amount(info: &AccountInfo, delegate: &Pubkey) -> u64 {
+    if !is_token_program(info.owner) {
+        return 0;
+    }
+    info.try_borrow_data()
+        .ok()
+        .and_then(|data| {
+            StateWithExtensions::<Account>::unpack(&data)
+                .ok()
+                .map(|account| account.base)
+        })
+        .filter(|account| account.delegate == COption::Some(*delegate))
+        .map_or(0, |account| account.delegated_amount)
+}
+
+/// Rejects mints the market can't custody, or can't pull from a maker's
+/// wallet through the delegation JIT settlement relies on.
+pub fn check_mint(mint: &AccountInfo) -> Result<()> {
+    require!(
+        is_token_program(mint.owner),
+        ErrorCodeCustom::InvalidTokenProgram
+    );
+    let data = mint.try_borrow_data()?;
+    let mint = StateWithExtensions::<Mint>::unpack(&data)?;
+    for extension in mint.get_extension_types()? {
+        match extension {
+            // can't leave the owner's wallet, or moves outside the public
+            // balance the market is approved to pull from
+            ExtensionType::NonTransferable | ExtensionType::ConfidentialTransferMint => {
+                return Err(error!(ErrorCodeCustom::UnsupportedMintExtension));
+            }
+            // the vaults would start out frozen
+            ExtensionType::DefaultAccountState => {
+                let default_state = mint.get_extension::<DefaultAccountState>()?;
+                require!(
+                    default_state.state != AccountState::Frozen as u8,
+                    ErrorCodeCustom::UnsupportedMintExtension
+                );
+            }
+            // interest-bearing mints only scale the UI amount, and transfer
+            // fees are grossed up or netted out wherever tokens move
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub fn decimals(mint: &AccountInfo) -> Result<u8> {
+    let data = mint.try_borrow_data()?;
+    Ok(StateWithExtensions::<Mint>::unpack(&data)?.base.decimals)
+}
+
+/// The current epoch's (basis points, maximum fee), if the mint charges one.
+fn fee_schedule(mint: &AccountInfo) -> Result<Option<(u128, u64)>> {
+    let data = mint.try_borrow_data()?;
+    let mint = StateWithExtensions::<Mint>::unpack(&data)?;
+    let fee_config = match mint.get_extension::<TransferFeeConfig>() {
+        Ok(fee_config) => fee_config,
+        Err(_) => return Ok(None),
+    };
+    let fee = fee_config.get_epoch_fee(Clock::get()?.epoch);
+    let basis_points = u16::from(fee.transfer_fee_basis_points);
+    if basis_points == 0 {
+        return Ok(None);
+    }
+    Ok(Some((u128::from(basis_points), u64::from(fee.maximum_fee))))
+}
+
+/// The fee the mint withholds from a transfer of `amount`.
+pub fn transfer_fee(mint: &AccountInfo, amount: u64) -> Result<u64> {
+    Ok(match fee_schedule(mint)? {
+        Some((basis_points, maximum_fee)) => {
+            let fee = (u128::from(amount) * basis_points + MAX_FEE_BASIS_POINTS - 1)
+                / MAX_FEE_BASIS_POINTS;
+            fee.min(u128::from(maximum_fee)) as u64
+        }
+        None => 0,
+    })
+}
+
+/// What arrives when `amount` is sent.
+pub fn amount_received(mint: &AccountInfo, amount: u64) -> Result<u64> {
+    Ok(amount - transfer_fee(mint, amount)?)
+}
+
+/// What to send so that at least `amount` arrives after the transfer fee.
+pub fn amount_with_fee(mint: &AccountInfo, amount: u64) -> Result<u64> {
+    Ok(match fee_schedule(mint)? {
+        Some((basis_points, maximum_fee)) => {
+            require!(
+                basis_points < MAX_FEE_BASIS_POINTS,
+                ErrorCodeCustom::UnsupportedMintExtension
+            );
+            let net_share = MAX_FEE_BASIS_POINTS - basis_points;
+            let gross = (u128::from(amount) * MAX_FEE_BASIS_POINTS + net_share - 1) / net_share;
+            let capped = u128::from(amount) + u128::from(maximum_fee);
+            u64::try_from(gross.min(capped)).map_err(|_| ErrorCodeCustom::InsufficientFunds)?
+        }
+        None => amount,
+    })
+}
+
+#[derive(Accounts)]
+pub struct TransferChecked<'info> {
+    /// CHECK: validated by the token program
+    pub from: AccountInfo<'info>,
+    /// CHECK: validated by the token program
+    pub mint: AccountInfo<'info>,
+    /// CHECK: validated by the token program
+    pub to: AccountInfo<'info>,
+    /// CHECK: validated by the token program
+    pub authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ApproveChecked<'info> {
+    /// CHECK: validated by the token program
+    pub to: AccountInfo<'info>,
+    /// CHECK: validated by the token program
+    pub mint: AccountInfo<'info>,
+    /// CHECK: validated by the token program
+    pub delegate: AccountInfo<'info>,
+    /// CHECK: validated by the token program
+    pub authority: AccountInfo<'info>,
+}
+
+// Error handling is currently impossible for solana CPI's: https://solana.stackexchange.com/questions/4277/how-to-handle-error-of-invoked-signed-calls
+/// `transfer_checked` through the token program owning the mint, with the
+/// decimals read from the mint.
+pub fn transfer_checked<'info>(
+    ctx: CpiContext<'_, '_, '_, 'info, TransferChecked<'info>>,
+    amount: u64,
+) -> Result<()> {
+    let TransferChecked {
+        from,
+        mint,
+        to,
+        authority,
+    } = ctx.accounts;
+    require!(
+        ctx.program.key == mint.owner,
+        ErrorCodeCustom::InvalidTokenProgram
+    );
+    let ix = spl_token_2022::instruction::transfer_checked(
+        ctx.program.key,
+        from.key,
+        mint.key,
+        to.key,
+        authority.key,
+        &[],
+        amount,
+        decimals(&mint)?,
+    )?;
+
+    invoke_signed(
+        &ix,
+        &[from, mint, to, authority, ctx.program],
+        ctx.signer_seeds,
+    )
+    .map_err(|e| {
+        msg!("Transfer failed: {:?}", e);
+        error!(ErrorCodeCustom::TransferFailed)
+    })
+}
+
+/// Sends enough that at least `amount` arrives after the mint's transfer fee.
+/// Rounding can leave a few native units more at the destination.
+pub fn transfer_checked_net<'info>(
+    ctx: CpiContext<'_, '_, '_, 'info, TransferChecked<'info>>,
+    amount: u64,
+) -> Result<()> {
+    let gross = amount_with_fee(&ctx.accounts.mint, amount)?;
+    transfer_checked(ctx, gross)
+}
+
+pub fn approve_checked<'info>(
+    ctx: CpiContext<'_, '_, '_, 'info, ApproveChecked<'info>>,
+    amount: u64,
+) -> Result<()> {
+    let ApproveChecked {
+        to,
+        mint,
+        delegate,
+        authority,
+    } = ctx.accounts;
+    require!(
+        ctx.program.key == mint.owner,
+        ErrorCodeCustom::InvalidTokenProgram
+    );
+    let ix = spl_token_2022::instruction::approve_checked(
+        ctx.program.key,
+        to.key,
+        mint.key,
+        delegate.key,
+        authority.key,
+        &[],
+        amount,
+        decimals(&mint)?,
+    )?;
+
+    invoke_signed(
+        &ix,
+        &[to, mint, delegate, authority, ctx.program],
+        ctx.signer_seeds,
+    )
+    .map_err(|e| {
+        msg!("Approval failed: {:?}", e);
+        error!(ErrorCodeCustom::ApprovalFailed)
+    })
+}
+
+/// Creates the associated token account of `wallet` for `mint` under the
+/// mint's token program.
+pub fn create_associated_account<'info>(
+    payer: &AccountInfo<'info>,
+    account: &AccountInfo<'info>,
+    wallet: &AccountInfo<'info>,
+    mint: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    associated_token_program: &AccountInfo<'info>,
+) -> Result<()> {
+    require!(
+        token_program.key == mint.owner,
+        ErrorCodeCustom::InvalidTokenProgram
+    );
+    let ix = Instruction {
+        program_id: anchor_spl::associated_token::ID,
+        accounts: vec![
+            AccountMeta::new(*payer.key, true),
+            AccountMeta::new(*account.key, false),
+            AccountMeta::new_readonly(*wallet.key, false),
+            AccountMeta::new_readonly(*mint.key, false),
+            AccountMeta::new_readonly(*system_program.key, false),
+            AccountMeta::new_readonly(*token_program.key, false),
+        ],
+        data: vec![],
+    };
+
+    invoke_signed(
+        &ix,
+        &[
+            payer.clone(),
+            account.clone(),
+            wallet.clone(),
+            mint.clone(),
+            system_program.clone(),
+            token_program.clone(),
+            associated_token_program.clone(),
+        ],
+        &[],
+    )
+    .map_err(Into::into)
+}
+
+#[derive(Accounts)]
+pub struct WrapSol<'info> {
+    /// CHECK: validated by the system program
+    pub from: AccountInfo<'info>,
+    /// CHECK: validated by the token program
+    pub vault: AccountInfo<'info>,
+    /// CHECK: validated by the runtime
+    pub system_program: AccountInfo<'info>,
+}
+
+/// Wraps `lamports` from the system account `from` straight into `vault`, a
+/// wrapped SOL account, and syncs its token balance.
+pub fn wrap_sol<'info>(
+    ctx: CpiContext<'_, '_, '_, 'info, WrapSol<'info>>,
+    lamports: u64,
+) -> Result<()> {
+    let WrapSol {
+        from,
+        vault,
+        system_program,
+    } = ctx.accounts;
+    require!(
+        ctx.program.key == vault.owner,
+        ErrorCodeCustom::InvalidTokenProgram
+    );
+    invoke(
+        &system_instruction::transfer(from.key, vault.key, lamports),
+        &[from, vault.clone(), system_program],
+    )?;
+    invoke(
+        &spl_token_2022::instruction::sync_native(ctx.program.key, vault.key)?,
+        &[vault, ctx.program],
+    )
+    .map_err(Into::into)
+}
+
+#[derive(Accounts)]
+pub struct UnwrapSol<'info> {
+    /// CHECK: fronts the temporary account's rent, validated by the system program
+    pub payer: AccountInfo<'info>,
+    /// CHECK: a program address, created and closed here
+    pub temp: AccountInfo<'info>,
+    /// CHECK: validated by the token program
+    pub vault: AccountInfo<'info>,
+    /// CHECK: validated by the token program
+    pub mint: AccountInfo<'info>,
+    /// CHECK: validated by the token program
+    pub authority: AccountInfo<'info>,
+    /// CHECK: any writable account
+    pub recipient: AccountInfo<'info>,
+    /// CHECK: validated by the runtime
+    pub system_program: AccountInfo<'info>,
+}
+
+/// Pays `amount` of wrapped SOL out of `vault` to `recipient` as lamports.
+/// Lamports only leave a token account when it is closed, so the wSOL moves
+/// through `temp`, created at the program address `temp_seeds` sign for and
+/// closed again. `payer` fronts its rent and gets it back, along with any
+/// lamports sent to `temp` beforehand.
+pub fn unwrap_sol<'info>(
+    ctx: CpiContext<'_, '_, '_, 'info, UnwrapSol<'info>>,
+    temp_seeds: &[&[u8]],
+    amount: u64,
+) -> Result<()> {
+    let UnwrapSol {
+        payer,
+        temp,
+        vault,
+        mint,
+        authority,
+        recipient,
+        system_program,
+    } = ctx.accounts;
+    let token_program = ctx.program;
+    require!(
+        token_program.key == mint.owner,
+        ErrorCodeCustom::InvalidTokenProgram
+    );
+    let space = Account::LEN;
+    let rent = Rent::get()?.minimum_balance(space);
+    if temp.lamports() == 0 {
+        invoke_signed(
+            &system_instruction::create_account(
+                payer.key,
+                temp.key,
+                rent,
+                space as u64,
+                token_program.key,
+            ),
+            &[payer.clone(), temp.clone(), system_program.clone()],
+            &[temp_seeds],
+        )?;
+    } else {
+        // anyone can send lamports to the address, which makes create_account
+        // fail, so top it up to rent exemption and allocate and assign it instead
+        let top_up = rent.saturating_sub(temp.lamports());
+        if top_up > 0 {
+            invoke(
+                &system_instruction::transfer(payer.key, temp.key, top_up),
+                &[payer.clone(), temp.clone(), system_program.clone()],
+            )?;
+        }
+        invoke_signed(
+            &system_instruction::allocate(temp.key, space as u64),
+            &[temp.clone(), system_program.clone()],
+            &[temp_seeds],
+        )?;
+        invoke_signed(
+            &system_instruction::assign(temp.key, token_program.key),
+            &[temp.clone(), system_program.clone()],
+            &[temp_seeds],
+        )?;
+    }
+    invoke(
+        &spl_token_2022::instruction::initialize_account3(
+            token_program.key,
+            temp.key,
+            mint.key,
+            authority.key,
+        )?,
+        &[temp.clone(), mint.clone(), token_program.clone()],
+    )?;
+
+    let transfer_ix = TransferChecked {
+        from: vault,
+        mint,
+        to: temp.clone(),
+        authority: authority.clone(),
+    };
+    transfer_checked(
+        CpiContext::new_with_signer(token_program.clone(), transfer_ix, ctx.signer_seeds),
+        amount,
+    )?;
+    invoke_signed(
+        &spl_token_2022::instruction::close_account(
+            token_program.key,
+            temp.key,
+            payer.key,
+            authority.key,
+            &[],
+        )?,
+        &[temp, payer.clone(), authority, token_program],
+        ctx.signer_seeds,
+    )?;
+
+    if payer.key != recipient.key {
+        invoke(
+            &system_instruction::transfer(payer.key, recipient.key, amount),
+            &[payer, recipient, system_program],
+        )?;
+    }
+    Ok(())
+}
@@ -1,15 +1,8 @@
 use anchor_lang::{prelude::*};
-use anchor_spl::{
-    token::{Transfer},
-};
-
-use enumflags2::BitFlags;
-use solana_program::clock::Clock;
-
 
 use anchor_lang::solana_program::pubkey::Pubkey;
-use anchor_lang::context::CpiContext;
-use anchor_lang::{solana_program, Result};
+use anchor_lang::solana_program::program::set_return_data;
+use anchor_lang::Result;
 
 pub use spl_token;
 pub use spl_token::ID;
@@ -17,9 +10,13 @@ pub use spl_token::ID;
 use crate::state::*;
 
 use crate::errors::ErrorCodeCustom;
+use crate::token_interface;
 
 impl Market {
-    pub const MAX_SIZE: usize = 32 + 32 + 32 + 32 + 8 + 8 + 8 + 8 + 32 + 32 + 32 + 32 + 32;
+    // keys and lot sizes, then the trade price oracle, the peg oracle, the listing
+    // and the admission authority
+    pub const MAX_SIZE: usize =
+        32 * 4 + 8 * 4 + 32 * 5 + (8 * 5 + 16) + (32 * 2 + 8 + 8) + (8 + 1) + 32;
 
     #[inline]
     pub fn check_payer_mint(&self, payer_mint: Pubkey, side: Side) -> bool {
@@ -38,869 +35,401 @@ impl Market {
             }
         }
     }
-}
 
-impl EventView {
-    pub fn side(&self) -> Side {
-        match self {
-            &EventView::Fill { side, .. }
-            | &EventView::Out { side, .. }
-            | &EventView::Finalise { side, .. } => side,
+    /// The mint orders on `side` lock: pc for bids, coin for asks.
+    pub fn payer_mint(&self, side: Side) -> Pubkey {
+        match side {
+            Side::Bid => self.pc_mint,
+            Side::Ask => self.coin_mint,
         }
     }
-}
 
-impl EventFlag {
-    #[inline]
-    pub fn from_side(side: Side) -> BitFlags<Self> {
-        match side {
-            Side::Bid => EventFlag::Bid.into(),
-            Side::Ask => BitFlags::empty(),
+    /// The side whose orders lock `mint`, if it is one of the market's.
+    pub fn mint_side(&self, mint: Pubkey) -> Option<Side> {
+        if mint == self.pc_mint {
+            Some(Side::Bid)
+        } else if mint == self.coin_mint {
+            Some(Side::Ask)
+        } else {
+            None
         }
     }
 
-    #[inline]
-    pub fn flags_to_side(flags: BitFlags<Self>) -> Side {
-        if flags.contains(EventFlag::Bid) {
-            Side::Bid
-        } else {
-            Side::Ask
+    pub fn payer_vault(&self, side: Side) -> Pubkey {
+        match side {
+            Side::Bid => self.pc_vault,
+            Side::Ask => self.coin_vault,
         }
     }
-}
 
-impl Event {
-    pub const MAX_SIZE: usize = 1 + 1 + 8 + 8 + 16 + 32 + 1 + 32;
-
-    #[inline(always)]
-    pub fn new(view: EventView) -> Self {
-        let clock = match Clock::get() {
-            Ok(clock) => clock,
-            Err(program_error) => {
-                // Handle the error, for example, print an error message
-                msg!("Error getting clock data: {:?}", program_error);
-                // Return a default Clock or handle the error in another way
-                Clock::default() // You might need to implement the Default trait for Clock
-            }
+    /// Accounts for tokens entering the vault of `side` outside of an order.
+    pub fn credit_deposits(&mut self, side: Side, native_amount: u64) -> Result<()> {
+        let total = match side {
+            Side::Bid => &mut self.pc_deposits_total,
+            Side::Ask => &mut self.coin_deposits_total,
         };
-        let current_timestamp: u64 = clock.unix_timestamp as u64;
-
-        //let current_timestamp= clock.unix_timestamp as u64;
-        match view {
-            EventView::Fill {
-                side,
-                maker,
-                native_qty_paid,
-                native_qty_received,
-                order_id,
-                owner,
-                owner_slot,
-                finalised: _,
-                cpty: _,
-                order_id_second,
-            } => {
-                let mut flags = EventFlag::from_side(side) | EventFlag::Fill;
-                if maker {
-                    flags |= EventFlag::Maker;
-                }
-                let finalised: u8 = 0;
-                Event {
-                    event_flags: flags.bits(),
-                    owner_slot,
-                    native_qty_released: native_qty_received,
-                    native_qty_paid,
-                    order_id,
-                    owner,
-                    finalised,
-                    order_id_second,
-                    timestamp: current_timestamp,
-                    //cpty,
-                }
-            }
-
-            EventView::Out {
-                side,
-                release_funds,
-                native_qty_unlocked,
-                native_qty_still_locked,
-                order_id,
-                owner,
-                owner_slot,
-                finalised: _,
-            } => {
-                let mut flags = EventFlag::from_side(side) | EventFlag::Out;
-                if release_funds {
-                    flags |= EventFlag::ReleaseFunds;
-                }
-                let finalised: u8 = 0;
-                let _cpty: Pubkey = owner;
-                Event {
-                    event_flags: flags.bits(),
-                    owner_slot,
-                    //finalised: finalised,
-                    native_qty_released: native_qty_unlocked,
-                    native_qty_paid: native_qty_still_locked,
-                    order_id,
-                    owner,
-                    finalised,
-                    order_id_second: 0,
-                    timestamp: current_timestamp,
-                    //cpty
-                }
-            }
+        *total = total
+            .checked_add(native_amount)
+            .ok_or(ErrorCodeCustom::MathOverflow)?;
+        Ok(())
+    }
 
-            EventView::Finalise {
-                side,
-                maker,
-                native_qty_paid,
-                native_qty_received,
-                order_id,
-                owner,
-                owner_slot,
-                finalised,
-                cpty: _,
-            } => {
-                let mut flags = EventFlag::from_side(side) | EventFlag::Fill;
-                if maker {
-                    flags |= EventFlag::Maker;
-                }
-                Event {
-                    event_flags: flags.bits(),
-                    owner_slot,
-                    //finalised: finalised,
-                    native_qty_released: native_qty_received,
-                    native_qty_paid,
-                    order_id,
-                    owner,
-                    finalised,
-                    order_id_second: 0,
-                    timestamp: current_timestamp,
-                    //cpty,
-                }
-            }
-        }
+    /// Accounts for tokens leaving the vault of `side`.
+    pub fn debit_deposits(&mut self, side: Side, native_amount: u64) -> Result<()> {
+        let total = match side {
+            Side::Bid => &mut self.pc_deposits_total,
+            Side::Ask => &mut self.coin_deposits_total,
+        };
+        *total = total
+            .checked_sub(native_amount)
+            .ok_or(ErrorCodeCustom::MathOverflow)?;
+        Ok(())
     }
-}
 
-impl<'a> OrderBook<'a> {
-    pub fn find_bbo(&self, side: Side) -> Result<&Order> {
-        match side {
-            Side::Bid => self.bids.find_bbo(),
-            Side::Ask => self.asks.find_bbo(),
+    /// The side whose orders lock wrapped SOL, if either mint is native.
+    pub fn native_side(&self) -> Option<Side> {
+        if token_interface::is_native_mint(&self.pc_mint) {
+            Some(Side::Bid)
+        } else if token_interface::is_native_mint(&self.coin_mint) {
+            Some(Side::Ask)
+        } else {
+            None
         }
     }
 
-    pub fn find_bbo_mut(&mut self, side: Side) -> Result<&mut Order> {
-        match side {
-            Side::Bid => self.bids.find_bbo_mut(),
-            Side::Ask => self.asks.find_bbo_mut(),
+    /// Folds the time the previous price was held into the accumulator and
+    /// makes `price` the last trade price.
+    pub fn record_trade(&mut self, price: u64, timestamp: u64) -> Result<()> {
+        if self.last_trade_price == 0 {
+            self.first_trade_ts = timestamp;
+        } else {
+            let elapsed = timestamp.saturating_sub(self.last_trade_ts);
+            self.price_cumulative = self
+                .price_cumulative
+                .checked_add(u128::from(self.last_trade_price) * u128::from(elapsed))
+                .ok_or(ErrorCodeCustom::MathOverflow)?;
         }
+        self.last_trade_price = price;
+        self.last_trade_ts = self.last_trade_ts.max(timestamp);
+        Ok(())
     }
 
-    pub fn process_request(
-        &mut self,
-        request: &RequestView,
-        event_q: &mut EventQueue,
-        proceeds: &mut RequestProceeds,
-    ) -> Result<Option<RequestView>> {
-        Ok(match *request {
-            RequestView::NewOrder {
-                side,
-                order_type,
-                order_id,
-                max_coin_qty,
-                native_pc_qty_locked,
-                owner_slot,
-                owner,
-            } => self
-                .new_order(
-                    NewOrderParams {
-                        side,
-                        order_type,
-                        order_id,
-                        max_coin_qty,
-                        native_pc_qty_locked,
-                        owner_slot,
-                        owner,
-                    },
-                    event_q,
-                    proceeds,
-                )?
-                .map(|remaining| RequestView::NewOrder {
-                    side,
-                    order_type,
-                    order_id,
-                    max_coin_qty: remaining.coin_qty_remaining,
-                    native_pc_qty_locked: remaining.native_pc_qty_remaining,
-                    owner_slot,
-                    owner,
-                }),
-            RequestView::CancelOrder {
-                side,
-                order_id,
-                cancel_id: _,
-                expected_owner,
-                expected_owner_slot,
-            } => {
-                self.cancel_order(
-                    CancelOrderParams {
-                        side,
-                        order_id,
-                        expected_owner,
-                        expected_owner_slot,
-                    },
-                    event_q,
-                )?;
-                None
-            }
-        })
+    /// The accumulator as of `now`, counting the last price as held until then.
+    /// The TWAP between two observations is the difference over the elapsed time.
+    pub fn price_cumulative_at(&self, now: u64) -> u128 {
+        let elapsed = now.saturating_sub(self.last_trade_ts);
+        self.price_cumulative + u128::from(self.last_trade_price) * u128::from(elapsed)
     }
-}
 
-impl<'a> OrderBook<'a> {
-    pub fn new_bid(
-        &mut self,
-        params: NewBidParams,
-        event_q: &mut EventQueue,
-        to_release: &mut RequestProceeds,
-    ) -> Result<Option<OrderRemaining>> {
-        let NewBidParams {
-            max_coin_qty,
-            native_pc_qty_locked,
-            limit_price,
-            order_id,
-            owner,
-            owner_slot,
-            post_only,
-            post_allowed,
-        } = params;
-        if post_allowed {
-            require!(limit_price.is_some(), ErrorCodeCustom::InvalidPrice);
+    /// Time-weighted average price since the first trade, or the last trade
+    /// price if no time has passed.
+    pub fn twap(&self, now: u64) -> u64 {
+        let window = now.saturating_sub(self.first_trade_ts);
+        if window == 0 {
+            return self.last_trade_price;
         }
+        (self.price_cumulative_at(now) / u128::from(window)) as u64
+    }
 
-        let coin_lot_size = self.market.coin_lot_size;
-        let pc_lot_size = self.market.pc_lot_size;
-
-        msg!("[OrderBook.new_bid] coin_lot_size: {}", coin_lot_size);
-        msg!("[OrderBook.new_bid] pc_lot_size: {}", pc_lot_size);
+    /// Midpoint of the BBO, or 0 unless both sides have orders.
+    pub fn mid_price(&self) -> u64 {
+        if self.best_bid == 0 || self.best_ask == 0 {
+            return 0;
+        }
+        (self.best_bid + self.best_ask) / 2
+    }
 
-        let max_pc_qty = native_pc_qty_locked / pc_lot_size;
+    /// `native_coin_qty` in native pc at the last trade price.
+    pub fn coin_value(&self, native_coin_qty: u64) -> u128 {
+        u128::from(native_coin_qty)
+            * u128::from(self.last_trade_price)
+            * u128::from(self.pc_lot_size)
+            / u128::from(self.coin_lot_size)
+    }
 
-        msg!("[OrderBook.new_bid] max_coin_qty: {}", max_coin_qty);
-        msg!(
-            "[OrderBook.new_bid] native_pc_qty_locked: {}",
-            native_pc_qty_locked
-        );
-        msg!("[OrderBook.new_bid] limit_price: {}", limit_price.unwrap());
-        msg!("[OrderBook.new_bid] order_id: {}", order_id);
-        msg!("[OrderBook.new_bid] post_only: {}", post_only);
-        msg!("[OrderBook.new_bid] post_allowed: {}", post_allowed);
-
-        let mut coin_qty_remaining = max_coin_qty;
-        let mut pc_qty_remaining = max_pc_qty;
-        let jit_data = vec![];
-
-        msg!("bid inserted");
-        let insert_result = self.bids.insert(Order {
-            order_id,
-            qty: max_coin_qty,
-            owner,
-            owner_slot,
-        });
-        if let Err(err) = insert_result {
-            if err == error!(ErrorCodeCustom::OrdersAlreadyFull) {
-                // boot out the least aggressive bid
-                msg!("bids full! booting...");
-                let order = self.bids.delete_worst()?;
-                let out = Event::new(EventView::Out {
-                    side: Side::Bid,
-                    release_funds: true,
-                    native_qty_unlocked: order.qty * order.price() * pc_lot_size,
-                    native_qty_still_locked: 0,
-                    order_id: order.order_id,
-                    owner: order.owner,
-                    owner_slot: order.owner_slot,
-                    finalised: 0,
-                });
-                let idx = event_q.head + 1;
-                msg!("event id is {}", idx);
-
-                event_q.buf[idx as usize] = out;
-                event_q.head += 1;
-
-                msg!("event.idx: {}", idx);
-                msg!("event.side: {}", "Bid");
-                msg!("event.release_funds: {}", "true");
-                msg!(
-                    "event.native_qty_unlocked: {}",
-                    order.qty * order.price() * pc_lot_size
-                );
-                msg!("event.order_id: {}", order.order_id);
-                msg!("event.order_id_second: {}", 0);
-                msg!("event.order: {}", order.owner);
-                msg!("event.owner_slot: {}", order.owner_slot);
-                msg!("event.finalised: {}", "0");
-
-                self.bids.insert(Order {
-                    order_id,
-                    qty: max_coin_qty,
-                    owner,
-                    owner_slot,
-                })?;
-            }
+    /// The last price read from `peg_oracle`, or 0 if there is none or it is stale.
+    pub fn oracle_price_at(&self, now: u64) -> u64 {
+        if now.saturating_sub(self.oracle_ts) > MAX_ORACLE_AGE_SECS {
+            return 0;
         }
-        let crossed;
-        msg!("checking bid  for matches");
-        let done = loop {
-            let best_offer = match self.find_bbo_mut(Side::Ask) {
-                Err(_) => {
-                    crossed = false;
-                    break true;
-                }
-                Ok(o) => o,
-            };
-
-            let trade_price = best_offer.price();
-            crossed = limit_price
-                .map(|limit_price| limit_price >= trade_price)
-                .unwrap_or(true);
-            // testing
+        self.oracle_price
+    }
+}
 
-            if !crossed || post_only {
-                msg!("not crossed!");
-                break true;
-            }
-            msg!("crossed!");
-            let offer_size = best_offer.qty;
-            let trade_qty = offer_size
-                .min(coin_qty_remaining)
-                .min(pc_qty_remaining / trade_price);
-
-            if trade_qty == 0 {
-                break true;
-            }
 
-            let native_maker_pc_qty = trade_qty * trade_price * pc_lot_size;
-
-            let idx = event_q.head + 1;
-            let maker_fill = Event::new(EventView::Fill {
-                side: Side::Ask,
-                maker: true,
-                native_qty_paid: trade_qty * coin_lot_size,
-                native_qty_received: native_maker_pc_qty,
-                order_id: best_offer.order_id,
-                owner: best_offer.owner,
-                owner_slot: best_offer.owner_slot,
-                finalised: 0,
-                cpty: owner,
-                order_id_second: order_id,
-            });
-
-            //write maker side event to eventQ
-            event_q.buf[idx as usize] = maker_fill;
-            event_q.head += 1;
-
-            msg!("event.idx: {}", idx);
-            msg!("event.side: {}", "Ask");
-            msg!("event.maker: {}", "true");
-            msg!("event.native_qty_paid: {}", trade_qty * coin_lot_size);
-            msg!("event.native_qty_received: {}", native_maker_pc_qty);
-            msg!("event.order_id: {}", best_offer.order_id);
-            msg!("event.order_id_second: {}", order_id);
-            msg!("event.owner: {}", best_offer.owner);
-            msg!("owner_slot: {}", best_offer.owner_slot);
-            msg!("event.finalised: {}", "0");
-            msg!("event.cpty_orderid: {}", order_id);
-
-            best_offer.qty -= trade_qty;
-            coin_qty_remaining -= trade_qty;
-            pc_qty_remaining -= trade_qty * trade_price;
-
-            //if order is filled, delete (ask) order.
-            if best_offer.qty == 0 {
-                let best_offer_id = best_offer.order_id;
-
-                let event_out = Event::new(EventView::Out {
-                    side: Side::Ask,
-                    release_funds: true,
-                    native_qty_unlocked: 0,
-                    native_qty_still_locked: 0,
-                    order_id: best_offer_id,
-                    owner: best_offer.owner,
-                    owner_slot: best_offer.owner_slot,
-                    finalised: 0,
-                });
-                let idx = event_q.head + 1;
-                msg!("event id is {}", idx);
-                event_q.buf[idx as usize] = event_out;
-                event_q.head += 1;
-
-                msg!("event.idx: {}", idx);
-                msg!("event.side: {}", "Ask");
-                msg!("event.release_funds: {}", "true");
-                msg!("event.native_qty_unlocked: {}", "0");
-                msg!("event.native_qty_still_locked: {}", "0");
-                msg!("event.order_id: {}", best_offer_id);
-                msg!("event.order_id_second: {}", 0);
-                msg!("event.owner: {}", best_offer.owner);
-                msg!("event.owner_slot: {}", best_offer.owner_slot);
-                msg!("event.finalised: {}", "0");
-            }
+impl MarketListing {
+    pub const MAX_SIZE: usize = 32 * 3 + 8 * 3 + 32;
+}
 
-            break false;
-        };
+impl Registry {
+    pub const MAX_SIZE: usize = 32 + 8 + 4 + MAX_LISTED_MARKETS * MarketListing::MAX_SIZE;
 
-        msg!("[OrderBook.new_bid] crossed: {}", crossed);
-        msg!("[OrderBook.new_bid] done: {}", done);
-        msg!("[OrderBook.new_bid] countrerparty: {}", done);
-        msg!(
-            "[OrderBook.new_bid] coin_qty_remaining: {}",
-            coin_qty_remaining
+    pub fn list(&mut self, listing: MarketListing) -> Result<()> {
+        require!(
+            self.markets.len() < MAX_LISTED_MARKETS,
+            ErrorCodeCustom::RegistryFull
         );
-        msg!("[OrderBook.new_bid] pc_qty_remaining: {}", pc_qty_remaining);
-
-        let native_accum_fill_price = (max_pc_qty - pc_qty_remaining) * pc_lot_size;
-        let native_pc_qty_remaining = native_pc_qty_locked - native_accum_fill_price;
+        self.markets.push(listing);
+        Ok(())
+    }
 
-        msg!(
-            "[OrderBook.new_bid] native_accum_fill_price: {}",
-            native_accum_fill_price
-        );
-        msg!(
-            "[OrderBook.new_bid] native_pc_qty_remaining: {}",
-            native_pc_qty_remaining
-        );
+    pub fn delist(&mut self, market: Pubkey) -> Result<MarketListing> {
+        let idx = self
+            .markets
+            .iter()
+            .position(|listing| listing.market == market)
+            .ok_or(ErrorCodeCustom::MarketNotListed)?;
+        Ok(self.markets.remove(idx))
+    }
+}
 
-        {
-            let coin_lots_received = max_coin_qty - coin_qty_remaining;
-            let native_pc_paid = native_accum_fill_price;
-
-            to_release.credit_coin(coin_lots_received);
-            to_release.debit_native_pc(native_pc_paid);
-            to_release.jit_data = jit_data;
-
-            let taker_fill = Event::new(EventView::Fill {
-                side: Side::Bid,
-                maker: false,
-                native_qty_paid: native_pc_paid,
-                native_qty_received: coin_lots_received * coin_lot_size,
-                order_id,
-                owner,
-                owner_slot,
-                finalised: 0,
-                cpty: owner,
-                order_id_second: 0,
-            });
-            let idx = event_q.head + 1;
-            msg!("event id is {}", idx);
-
-            event_q.buf[idx as usize] = taker_fill;
-            event_q.head += 1;
-
-            msg!("event.idx: {}", idx);
-            msg!("event.side: {}", "Bid");
-            msg!("event.maker: {}", "false");
-            msg!("event.native_qty_paid: {}", native_pc_paid);
-            msg!(
-                "event.native_qty_received: {}",
-                coin_lots_received * coin_lot_size
-            );
-            msg!("event.order_id: {}", order_id);
-            msg!("event.order_id_second: {}", 0);
-            msg!("event.owner: {}", owner);
-            msg!("event.owner_slot: {}", owner_slot);
-            msg!("event.finalised: {}", "0");
-        }
+impl AllowList {
+    pub const MAX_SIZE: usize = 32 + 4 + 32 * MAX_ALLOWED_WALLETS;
 
-        if !done {
-            if coin_qty_remaining > 0 && native_pc_qty_remaining > 0 {
-                return Ok(Some(OrderRemaining {
-                    coin_qty_remaining,
-                    native_pc_qty_remaining: Some(native_pc_qty_remaining),
-                }));
-            }
+    pub fn allow(&mut self, wallet: Pubkey) -> Result<()> {
+        if self.wallets.contains(&wallet) {
+            return Ok(());
         }
+        require!(
+            self.wallets.len() < MAX_ALLOWED_WALLETS,
+            ErrorCodeCustom::AllowListFull
+        );
+        self.wallets.push(wallet);
+        Ok(())
+    }
 
-        let (coin_qty_to_post, pc_qty_to_keep_locked) = match limit_price {
-            Some(price) if post_allowed && !crossed => {
-                let coin_qty_to_post =
-                    coin_qty_remaining.min(native_pc_qty_remaining / pc_lot_size / price);
-                (coin_qty_to_post, coin_qty_to_post * price)
-            }
-            _ => (0, 0),
-        };
+    pub fn disallow(&mut self, wallet: Pubkey) {
+        self.wallets.retain(|allowed| *allowed != wallet);
+    }
+}
 
-        msg!("[OrderBook.new_bid] coin_qty_to_post: {}", coin_qty_to_post);
-        msg!(
-            "[OrderBook.new_bid] pc_qty_to_keep_locked: {}",
-            pc_qty_to_keep_locked
-        );
+impl MarginAccount {
+    pub const MAX_SIZE: usize = 32 + 4 + MAX_MARGIN_MINTS * (32 + 8 + 8);
 
-        let _out = {
-            let native_qty_still_locked = pc_qty_to_keep_locked * pc_lot_size;
-            let native_qty_unlocked = native_pc_qty_remaining - native_qty_still_locked;
-            to_release.unlock_native_pc(native_qty_unlocked);
-
-            let outer = Event::new(EventView::Out {
-                side: Side::Bid,
-                release_funds: false,
-                native_qty_unlocked,
-                native_qty_still_locked,
-                order_id,
-                owner,
-                owner_slot,
-                finalised: 0,
-            });
-            let idx = event_q.head + 1;
-            msg!("event id is {}", idx);
-            event_q.buf[idx as usize] = outer;
-            event_q.head += 1;
+    fn collateral_mut(&mut self, mint: Pubkey) -> Result<&mut Collateral> {
+        let idx = match self.collateral.iter().position(|c| c.mint == mint) {
+            Some(idx) => idx,
+            None => {
+                require!(
+                    self.collateral.len() < MAX_MARGIN_MINTS,
+                    ErrorCodeCustom::MarginAccountFull
+                );
+                self.collateral.push(Collateral {
+                    mint,
+                    ..Default::default()
+                });
+                self.collateral.len() - 1
+            }
         };
-        let idx = event_q.head;
+        Ok(&mut self.collateral[idx])
+    }
 
-        let native_qty_still_locked = pc_qty_to_keep_locked * pc_lot_size;
-        let native_qty_unlocked = native_pc_qty_remaining - native_qty_still_locked;
-        to_release.unlock_native_pc(native_qty_unlocked);
+    /// Whether every mint's deposit covers the requirement on what is reserved against it.
+    pub fn is_healthy(&self) -> bool {
+        self.collateral.iter().all(|c| {
+            u128::from(c.deposited) * 10_000
+                >= u128::from(c.reserved) * u128::from(MARGIN_REQUIREMENT_BPS)
+        })
+    }
 
-        msg!("event.idx: {}", idx);
-        msg!("event.side: {}", "Ask");
-        msg!("event.release_funds: {}", "false");
-        msg!("event.native_qty_unlocked: {}", native_qty_unlocked);
-        msg!("event.native_qty_still_locked: {}", native_qty_still_locked);
-        msg!("event.order_id: {}", order_id);
-        msg!("event.order_id_second: {}", order_id);
+    pub fn deposit(&mut self, mint: Pubkey, amount: u64) -> Result<()> {
+        let collateral = self.collateral_mut(mint)?;
+        collateral.deposited = collateral
+            .deposited
+            .checked_add(amount)
+            .ok_or(ErrorCodeCustom::MathOverflow)?;
+        Ok(())
+    }
 
-        msg!("event.owner: {}", owner);
-        msg!("owner_slot: {}", owner_slot);
-        msg!("event.finalised: {}", "0");
+    /// Takes `amount` out of the deposit, as long as what is left stays healthy.
+    pub fn withdraw(&mut self, mint: Pubkey, amount: u64) -> Result<()> {
+        let collateral = self.collateral_mut(mint)?;
+        collateral.deposited = collateral
+            .deposited
+            .checked_sub(amount)
+            .ok_or(ErrorCodeCustom::InsufficientFunds)?;
+        require!(self.is_healthy(), ErrorCodeCustom::MarginRequirement);
+        Ok(())
+    }
 
-        Ok(None)
+    /// Reserves a new JIT obligation, checked against all the others.
+    pub fn reserve(&mut self, mint: Pubkey, amount: u64) -> Result<()> {
+        let collateral = self.collateral_mut(mint)?;
+        collateral.reserved = collateral
+            .reserved
+            .checked_add(amount)
+            .ok_or(ErrorCodeCustom::MathOverflow)?;
+        require!(self.is_healthy(), ErrorCodeCustom::MarginRequirement);
+        Ok(())
     }
-}
 
-impl<'a> OrderBook<'a> {
-    pub fn new_ask(
-        &mut self,
-        params: NewAskParams,
-        event_q: &mut EventQueue,
-        to_release: &mut RequestProceeds,
-    ) -> Result<Option<OrderRemaining>> {
-        msg!("new ask");
-        let NewAskParams {
-            max_qty,
-            limit_price,
-            order_id,
-            owner,
-            owner_slot,
-            post_only,
-            post_allowed,
-        } = params;
-        let mut unfilled_qty = max_qty;
-        let mut accum_fill_price = 0;
-
-        let pc_lot_size = self.market.pc_lot_size;
-        let coin_lot_size = self.market.coin_lot_size;
-        let mut jit_data = vec![];
-
-        //begin matching
-        let crossed;
-
-        let insert_result = self.asks.insert(Order {
-            order_id,
-            qty: unfilled_qty,
-            owner,
-            owner_slot,
-        });
-        if let Err(err) = insert_result {
-            if err == error!(ErrorCodeCustom::OrdersAlreadyFull) {
-                // boot out the least aggressive offer
-                msg!("offers full! booting...");
-                let order = self.asks.delete_worst()?;
-                let out = Event::new(EventView::Out {
-                    side: Side::Ask,
-                    release_funds: true,
-                    native_qty_unlocked: order.qty * coin_lot_size,
-                    native_qty_still_locked: 0,
-                    order_id: order.order_id,
-                    owner: order.owner,
-                    owner_slot: order.owner_slot,
-                    finalised: 0,
-                });
-                let idx = event_q.head + 1;
-                msg!("idx is {}", idx);
-                event_q.buf[idx as usize] = out;
-                event_q.head += 1;
-
-                msg!("event.idx: {}", idx);
-                msg!("event.side: {}", "Ask");
-                msg!("event.release_funds: {}", true);
-                msg!("event.native_qty_unlocked: {}", order.qty * coin_lot_size);
-                msg!("event.native_qty_still_locked: {}", "0");
-                msg!("event.order_id: {}", order.order_id);
-                msg!("event.owner: {}", order.owner);
-                msg!("event.owner_slot: {}", order.owner_slot);
-                msg!("event.finalised: {}", "0");
-
-                self.asks.insert(Order {
-                    order_id,
-                    qty: unfilled_qty,
-                    owner,
-                    owner_slot,
-                })?;
-            }
+    /// Drops a reservation. Orders leaving the book without their margin
+    /// account passed keep theirs, so this never goes below zero.
+    pub fn release(&mut self, mint: Pubkey, amount: u64) {
+        if let Some(collateral) = self.collateral.iter_mut().find(|c| c.mint == mint) {
+            collateral.reserved = collateral.reserved.saturating_sub(amount);
         }
-        msg!("loop2");
-        let done = loop {
-            let best_bid = match self.find_bbo_mut(Side::Bid) {
-                Err(_) => {
-                    crossed = false;
-                    msg!("not crossed!");
-                    break true;
-                }
-                Ok(o) => o,
-            };
-
-            let trade_price = best_bid.price();
-            crossed = limit_price <= trade_price;
-
-            if !crossed || post_only {
-                msg!("not crossed!");
-                break true;
-            }
-            msg!("crossed!");
+    }
 
-            let bid_size = best_bid.qty;
-            let trade_qty = bid_size.min(unfilled_qty);
+    /// Pays `amount` of a reserved obligation out of the deposit.
+    pub fn draw(&mut self, mint: Pubkey, amount: u64) -> Result<()> {
+        let collateral = self.collateral_mut(mint)?;
+        collateral.deposited = collateral
+            .deposited
+            .checked_sub(amount)
+            .ok_or(ErrorCodeCustom::InsufficientFunds)?;
+        collateral.reserved = collateral.reserved.saturating_sub(amount);
+        Ok(())
+    }
+}
 
-            if trade_qty == 0 {
-                break true;
-            }
+/// The margin account linked to `open_orders`, looked up among the remaining accounts.
+pub fn linked_margin_account<'info>(
+    open_orders: &OpenOrders,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<Account<'info, MarginAccount>> {
+    let info = remaining_accounts
+        .iter()
+        .find(|account| account.key() == open_orders.margin_account)
+        .ok_or(ErrorCodeCustom::MarginAccountMissing)?;
+    Account::try_from(info)
+}
 
-            let native_maker_pc_qty = trade_qty * trade_price * pc_lot_size;
-            let jit_struct = JitStruct {
-                side: Side::Bid,
-                maker: true,
-                native_qty_paid: native_maker_pc_qty,
-                native_qty_received: trade_qty * coin_lot_size,
-                order_id: best_bid.order_id,
-                owner: best_bid.owner,
-                owner_slot: best_bid.owner_slot,
-            };
-            jit_data.push(jit_struct);
-            msg!("data pushed to jitstruct");
-
-            let maker_fill = Event::new(EventView::Fill {
-                side: Side::Bid,
-                maker: true,
-                native_qty_paid: native_maker_pc_qty,
-                native_qty_received: trade_qty * coin_lot_size,
-                order_id: best_bid.order_id,
-                owner: best_bid.owner,
-                owner_slot: best_bid.owner_slot,
-                finalised: 0,
-                cpty: owner,
-                order_id_second: order_id,
-            });
-            let idx = event_q.head + 1;
-            event_q.buf[idx as usize] = maker_fill;
-            event_q.head += 1;
-            msg!("event.idx: {}", idx);
-            msg!("event.side: {}", "Ask");
-            msg!("event.maker: {}", "true");
-            msg!("event.native_qty_paid: {}", trade_qty * coin_lot_size);
-            msg!("event.native_qty_received: {}", trade_qty * coin_lot_size);
-            msg!("event.order_id: {}", best_bid.order_id);
-            msg!("event.order_id_second: {}", order_id);
-            msg!("event.owner: {}", best_bid.owner);
-            msg!("event.owner_slot: {}", best_bid.owner_slot);
-            msg!("event.finalised: {}", "0");
-            msg!("event.cpty_orderid: {}", order_id);
-
-            best_bid.qty -= trade_qty;
-            unfilled_qty -= trade_qty;
-            accum_fill_price += trade_qty * trade_price;
-
-            if best_bid.qty == 0 {
-                let best_bid_id = best_bid.order_id;
-                let out = Event::new(EventView::Out {
-                    side: Side::Bid,
-                    release_funds: true,
-                    native_qty_unlocked: 0,
-                    native_qty_still_locked: 0,
-                    order_id: best_bid_id,
-                    owner: best_bid.owner,
-                    owner_slot: best_bid.owner_slot,
-                    finalised: 0,
-                });
-                let idx = event_q.head + 1;
-                event_q.buf[idx as usize] = out;
-                event_q.head += 1;
-
-                msg!("event.idx: {}", idx);
-                msg!("event.side: {}", "Bid");
-                msg!("event.release_funds: {}", "true");
-                msg!("event.native_qty_unlocked: {}", "0");
-                msg!("event.native_qty_locked: {}", "0");
-                msg!("event.order_id: {}", order_id);
-                msg!("event.order_id_second: {}", 0);
-                msg!("event.owner: {}", best_bid.owner);
-                msg!("event.owner_slot: {}", best_bid.owner_slot);
-                msg!("event.finalised: {}", "0");
-            }
+/// Whether `owner` may open an account on `market`. Always on an open market,
+/// otherwise the admission authority must co-sign or the market's allow list,
+/// passed among the remaining accounts, must have them.
+pub fn is_admitted<'info>(
+    market: &Account<'info, Market>,
+    owner: Pubkey,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<bool> {
+    let admission_authority = market.open_orders_admission_authority;
+    if admission_authority == Pubkey::default() {
+        return Ok(true);
+    }
+    if remaining_accounts
+        .iter()
+        .any(|account| account.key() == admission_authority && account.is_signer)
+    {
+        return Ok(true);
+    }
+    let (allow_list, _bump) =
+        Pubkey::find_program_address(&[b"allow-list", market.key().as_ref()], &crate::id());
+    match remaining_accounts
+        .iter()
+        .find(|account| account.key() == allow_list)
+    {
+        Some(info) => Ok(Account::<AllowList>::try_from(info)?
+            .wallets
+            .contains(&owner)),
+        None => Ok(false),
+    }
+}
 
-            break false;
-        };
+/// The linked margin account's vault for `mint`, looked up among the remaining accounts.
+pub fn margin_vault<'info>(
+    open_orders: &OpenOrders,
+    mint: &AccountInfo,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<AccountInfo<'info>> {
+    let vault =
+        token_interface::associated_address(&open_orders.margin_account, mint.key, mint.owner);
+    remaining_accounts
+        .iter()
+        .find(|account| account.key() == vault)
+        .cloned()
+        .ok_or_else(|| error!(ErrorCodeCustom::MarginAccountMissing))
+}
 
-        let native_taker_pc_qty = accum_fill_price * pc_lot_size;
-
-        {
-            let net_taker_pc_qty = native_taker_pc_qty;
-            let coin_lots_traded = max_qty - unfilled_qty;
-
-            to_release.credit_native_pc(net_taker_pc_qty);
-            to_release.debit_coin(coin_lots_traded);
-            to_release.jit_data = jit_data;
-            if native_taker_pc_qty > 0 {
-                let taker_fill = Event::new(EventView::Fill {
-                    side: Side::Ask,
-                    maker: false,
-                    native_qty_paid: coin_lots_traded * coin_lot_size,
-                    native_qty_received: net_taker_pc_qty,
-                    order_id,
-                    owner,
-                    owner_slot,
-                    finalised: 0,
-                    cpty: owner,
-                    order_id_second: 0,
-                });
-                let idx = event_q.head + 1;
-                event_q.buf[idx as usize] = taker_fill;
-                event_q.head += 1;
-
-                msg!("event.idx: {}", idx);
-                msg!("event.side: {}", "Ask");
-                msg!("event.maker: {}", "false");
-                msg!(
-                    "event.native_qty_paid: {}",
-                    coin_lots_traded * coin_lot_size
-                );
-                msg!("event.native_qty_received: {}", net_taker_pc_qty);
-                msg!("event.order_id: {}", order_id);
-                msg!("event.order_id_second: {}", 0);
-                msg!("event.owner: {}", owner);
-                msg!("event.owner_slot: {}", owner_slot);
-                msg!("event.finalised: {}", "0");
-            }
+/// Settles the fills from `first_slot` on whose maker and taker are both
+/// fully funded, so they skip finalise_matches_*. Makers' OpenOrders are
+/// looked up among the remaining accounts. Fills against a maker that isn't
+/// passed stay finalised = 1 and settle through finalise.
+pub fn settle_fully_funded_fills<'info>(
+    event_q: &mut EventQueue,
+    first_slot: u64,
+    taker: &mut Account<'info, OpenOrders>,
+    remaining_accounts: &[AccountInfo<'info>],
+    program_id: &Pubkey,
+) -> Result<()> {
+    let head = event_q.head;
+    for maker_slot in first_slot..=head {
+        let maker_fill = event_q.buf[maker_slot as usize];
+        if !maker_fill.is_fill() || !maker_fill.is_maker() || maker_fill.finalised != 1 {
+            continue;
         }
-
-        if !done {
-            if unfilled_qty > 0 {
-                return Ok(Some(OrderRemaining {
-                    coin_qty_remaining: unfilled_qty,
-                    native_pc_qty_remaining: None,
-                }));
-            }
+        let taker_slot = match (maker_slot + 1..=head).find(|slot| {
+            let event = event_q.buf[*slot as usize];
+            event.is_fill() && !event.is_maker() && event.order_id == maker_fill.order_id_second
+        }) {
+            Some(slot) => slot,
+            None => continue,
+        };
+        let taker_fill = event_q.buf[taker_slot as usize];
+        if taker_fill.finalised != 1 || taker_fill.owner != taker.key() {
+            continue;
         }
 
-        if post_allowed && !crossed && unfilled_qty > 0 {
+        if maker_fill.owner == taker.key() {
+            taker.settle_fill(&maker_fill)?;
         } else {
-            to_release.unlock_coin(unfilled_qty);
-            let out = Event::new(EventView::Out {
-                side: Side::Ask,
-                release_funds: false,
-                native_qty_unlocked: unfilled_qty * coin_lot_size,
-                native_qty_still_locked: 0,
-                order_id,
-                owner,
-                owner_slot,
-                finalised: 0,
-            });
-            let idx = event_q.head + 1;
-            event_q.buf[idx as usize] = out;
-            event_q.head += 1;
-
-            msg!("event.idx: {}", idx);
-            msg!("event.side: {}", "Ask");
-            msg!("event.release_funds: {}", false);
-            msg!(
-                "event.native_qty_unlocked: {}",
-                unfilled_qty * coin_lot_size
-            );
-            msg!("event.native_qty_still_locked: {}", "0");
-            msg!("event.order_id: {}", order_id);
-            msg!("event.order_id_second: {}", 0);
-            msg!("event.owner: {}", owner);
-            msg!("event.owner.slot: {}", owner_slot);
-            msg!("event.finalised: {}", "0");
+            let info = match remaining_accounts
+                .iter()
+                .find(|account| account.key() == maker_fill.owner)
+            {
+                Some(info) => info,
+                None => continue,
+            };
+            let mut maker = Account::<OpenOrders>::try_from(info)?;
+            require_keys_eq!(maker.market, taker.market, ErrorCodeCustom::MarketMismatch);
+            maker.settle_fill(&maker_fill)?;
+            maker.exit(program_id)?;
         }
+        taker.settle_fill(&taker_fill)?;
 
-        Ok(None)
+        // finalised = 3 means settled
+        event_q.buf[maker_slot as usize].finalised = 3;
+        event_q.buf[taker_slot as usize].finalised = 3;
     }
+    Ok(())
 }
 
-// Error handling is currently impossible for solana CPI's: https://solana.stackexchange.com/questions/4277/how-to-handle-error-of-invoked-signed-calls
-pub fn custom_token_transfer<'info>(
-    cpi_ctx: CpiContext<'_, '_, '_, 'info, Transfer<'info>>,
-    amount: u64,
-) -> Result<()> {
-    let ix = spl_token::instruction::transfer(
-        &spl_token::ID,
-        cpi_ctx.accounts.from.key,
-        cpi_ctx.accounts.to.key,
-        cpi_ctx.accounts.authority.key,
-        &[],
-        amount,
-    )?;
-
-    match solana_program::program::invoke_signed(
-        &ix,
-        &[
-            cpi_ctx.accounts.from,
-            cpi_ctx.accounts.to,
-            cpi_ctx.accounts.authority,
-        ],
-        cpi_ctx.signer_seeds,
-    ) {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            msg!("Transfer failed: {:?}", e);
-            Err(e.into())
-        }
-    }
+/// Borsh encodes the result of a view instruction into the return data.
+pub fn return_view<T: AnchorSerialize>(view: &T) -> Result<()> {
+    let data = view
+        .try_to_vec()
+        .map_err(|_| anchor_lang::error::ErrorCode::AccountDidNotSerialize)?;
+    set_return_data(&data);
+    Ok(())
 }
 
-impl<'a> OrderBook<'a> {
-    pub fn cancel_order(
-        &mut self,
-        params: CancelOrderParams,
-        _event_q: &mut EventQueue,
-    ) -> Result<()> {
-        let CancelOrderParams {
-            side: _,
-            order_id: _,
-            expected_owner: _,
-            expected_owner_slot: _,
-        } = params;
-        Ok(())
-    }
-
-    pub fn cancel_order_bid(&mut self, _side: bool, order_id: u128, _owner: Pubkey) -> Result<()> {
-        let orders = &mut *self.bids;
-        orders.delete(order_id);
-
-        Ok(())
-    }
-
-    pub fn cancel_order_ask(&mut self, _side: bool, order_id: u128, _owner: Pubkey) -> Result<()> {
-        let orders = &mut *self.asks;
-        orders.delete(order_id);
+/// Reads `(price, publish_time)` from a peg oracle account, see PRICE_FEED_LEN.
+pub fn read_price_feed(data: &[u8]) -> Result<(u64, u64)> {
+    require!(data.len() >= PRICE_FEED_LEN, ErrorCodeCustom::InvalidPrice);
+    let price = u64::from_le_bytes(data[0..8].try_into().unwrap());
+    let publish_time = i64::from_le_bytes(data[8..16].try_into().unwrap());
 
-        Ok(())
-    }
+    Ok((price, publish_time.max(0) as u64))
 }
 
 impl OpenOrders {
-    pub const MAX_SIZE: usize = 1 + 32 + 32 + 8 + 8 + 8 + 8 + 1 + 1 + 8 * 16;
+    pub const MAX_SIZE: usize = 1
+        + 32
+        + 32
+        + 8
+        + 8
+        + 8
+        + 8
+        + 1
+        + 1
+        + 1
+        + 8 * 16
+        + 1
+        + 32
+        + 32
+        + (32 + 8)
+        + 32 * MAX_WITHDRAW_DESTINATIONS
+        + 1;
 
     pub fn init(&mut self, market: Pubkey, authority: Pubkey) -> Result<()> {
         require!(!self.is_initialized, ErrorCodeCustom::AlreadyInitialized);
@@ -913,75 +442,199 @@ impl OpenOrders {
         Ok(())
     }
 
-    pub fn credit_unlocked_coin(&mut self, native_coin_amount: u64) {
+    /// The owner or, if one is set, its delegate or unexpired session key.
+    pub fn can_trade(&self, key: Pubkey) -> bool {
+        key == self.authority
+            || (self.delegate != Pubkey::default() && key == self.delegate)
+            || self.is_session_key(key)
+    }
+
+    pub fn is_session_key(&self, key: Pubkey) -> bool {
+        self.session_key != Pubkey::default()
+            && key == self.session_key
+            && Clock::get().map_or(false, |clock| {
+                (clock.unix_timestamp as u64) < self.session_expires_at
+            })
+    }
+
+    /// Whether withdrawals may be paid to accounts of `wallet`.
+    pub fn may_withdraw_to(&self, wallet: Pubkey) -> bool {
+        if self.withdraw_whitelist == [Pubkey::default(); MAX_WITHDRAW_DESTINATIONS] {
+            wallet == self.authority
+        } else {
+            self.withdraw_whitelist.contains(&wallet)
+        }
+    }
+
+    /// Locked balances less the margin required on `obligations`, in native pc
+    /// with coin valued at the last trade price. Negative when liquidatable.
+    pub fn health(&self, market: &Market, obligations: &[PendingObligation]) -> i128 {
+        let locked_pc = self.native_pc_total.saturating_sub(self.native_pc_free);
+        let locked_coin = self.native_coin_total.saturating_sub(self.native_coin_free);
+        let collateral = u128::from(locked_pc) + market.coin_value(locked_coin);
+        let owed: u128 = obligations
+            .iter()
+            .map(|obligation| match obligation.side {
+                Side::Bid => u128::from(obligation.native_qty_paid),
+                Side::Ask => market.coin_value(obligation.native_qty_paid),
+            })
+            .sum();
+        let required = owed * u128::from(MAINTENANCE_MARGIN_BPS) / 10_000;
+        collateral as i128 - required as i128
+    }
+
+    /// Credits a deposit on `side` to the free balance.
+    pub fn deposit(&mut self, side: Side, native_amount: u64) -> Result<()> {
+        match side {
+            Side::Bid => self.credit_unlocked_pc(native_amount),
+            Side::Ask => self.credit_unlocked_coin(native_amount),
+        }
+    }
+
+    /// Debits a withdrawal on `side` from the free balance. Locked funds,
+    /// including the margin of JIT orders, can't be withdrawn.
+    pub fn withdraw(&mut self, side: Side, native_amount: u64) -> Result<()> {
+        let (free, total) = match side {
+            Side::Bid => (&mut self.native_pc_free, &mut self.native_pc_total),
+            Side::Ask => (&mut self.native_coin_free, &mut self.native_coin_total),
+        };
+        *free = free
+            .checked_sub(native_amount)
+            .ok_or(ErrorCodeCustom::InsufficientFunds)?;
+        *total = total
+            .checked_sub(native_amount)
+            .ok_or(ErrorCodeCustom::BalanceInconsistent)?;
+        Ok(())
+    }
+
+    /// Takes up to `native_pc_amount`, locked balance first, and returns what it got.
+    pub fn seize_pc(&mut self, native_pc_amount: u64) -> u64 {
+        let locked = self.native_pc_total.saturating_sub(self.native_pc_free);
+        let from_locked = native_pc_amount.min(locked);
+        let from_free = (native_pc_amount - from_locked).min(self.native_pc_free);
+        self.native_pc_free -= from_free;
+        self.native_pc_total = self.native_pc_total.saturating_sub(from_locked + from_free);
+        from_locked + from_free
+    }
+
+    /// Takes up to `native_coin_amount`, locked balance first, and returns what it got.
+    pub fn seize_coin(&mut self, native_coin_amount: u64) -> u64 {
+        let locked = self.native_coin_total.saturating_sub(self.native_coin_free);
+        let from_locked = native_coin_amount.min(locked);
+        let from_free = (native_coin_amount - from_locked).min(self.native_coin_free);
+        self.native_coin_free -= from_free;
+        self.native_coin_total = self
+            .native_coin_total
+            .saturating_sub(from_locked + from_free);
+        from_locked + from_free
+    }
+
+    pub fn credit_unlocked_coin(&mut self, native_coin_amount: u64) -> Result<()> {
         self.native_coin_total = self
             .native_coin_total
             .checked_add(native_coin_amount)
-            .unwrap();
+            .ok_or(ErrorCodeCustom::MathOverflow)?;
         self.native_coin_free = self
             .native_coin_free
             .checked_add(native_coin_amount)
-            .unwrap();
+            .ok_or(ErrorCodeCustom::MathOverflow)?;
+        Ok(())
     }
 
-    pub fn debit_locked_coin(&mut self, native_coin_amount: u64) {
-        self.native_coin_total = self
+    pub fn debit_locked_coin(&mut self, native_coin_amount: u64) -> Result<()> {
+        let locked = self
             .native_coin_total
-            .checked_sub(native_coin_amount)
-            .unwrap();
-    }
-
-    pub fn debit_locked_pc2(&mut self, native_pc_amount: u64) {
-        self.native_pc_total = self.native_pc_total.checked_sub(native_pc_amount).unwrap();
+            .checked_sub(self.native_coin_free)
+            .ok_or(ErrorCodeCustom::BalanceInconsistent)?;
+        require!(
+            native_coin_amount <= locked,
+            ErrorCodeCustom::InsufficientFunds
+        );
+        self.native_coin_total -= native_coin_amount;
+        Ok(())
     }
 
-    pub fn debit_locked_pc(&mut self, native_pc_amount: u64) {
-        if let Some(new_total) = self.native_pc_total.checked_sub(native_pc_amount) {
-            self.native_pc_total = new_total;
-        } else {
-            msg!("current native_pc_total: {}", self.native_pc_total);
-            msg!("debit amount: {}", native_pc_amount);
-        }
+    pub fn debit_locked_pc(&mut self, native_pc_amount: u64) -> Result<()> {
+        let locked = self
+            .native_pc_total
+            .checked_sub(self.native_pc_free)
+            .ok_or(ErrorCodeCustom::BalanceInconsistent)?;
+        require!(
+            native_pc_amount <= locked,
+            ErrorCodeCustom::InsufficientFunds
+        );
+        self.native_pc_total -= native_pc_amount;
+        Ok(())
     }
 
-    pub fn credit_locked_coin(&mut self, native_coin_amount: u64) {
+    pub fn credit_locked_coin(&mut self, native_coin_amount: u64) -> Result<()> {
         self.native_coin_total = self
             .native_coin_total
             .checked_add(native_coin_amount)
-            .unwrap();
+            .ok_or(ErrorCodeCustom::MathOverflow)?;
+        Ok(())
     }
 
-    pub fn credit_unlocked_pc(&mut self, native_pc_amount: u64) {
-        self.native_pc_total = self.native_pc_total.checked_add(native_pc_amount).unwrap();
-        self.native_pc_free = self.native_pc_free.checked_add(native_pc_amount).unwrap();
+    pub fn credit_unlocked_pc(&mut self, native_pc_amount: u64) -> Result<()> {
+        self.native_pc_total = self
+            .native_pc_total
+            .checked_add(native_pc_amount)
+            .ok_or(ErrorCodeCustom::MathOverflow)?;
+        self.native_pc_free = self
+            .native_pc_free
+            .checked_add(native_pc_amount)
+            .ok_or(ErrorCodeCustom::MathOverflow)?;
+        Ok(())
     }
 
-    pub fn credit_locked_pc(&mut self, native_pc_amount: u64) {
-        self.native_pc_total = self.native_pc_total.checked_add(native_pc_amount).unwrap();
+    pub fn credit_locked_pc(&mut self, native_pc_amount: u64) -> Result<()> {
+        self.native_pc_total = self
+            .native_pc_total
+            .checked_add(native_pc_amount)
+            .ok_or(ErrorCodeCustom::MathOverflow)?;
+        Ok(())
     }
 
-    pub fn lock_free_coin(&mut self, native_coin_amount: u64) {
+    pub fn lock_free_coin(&mut self, native_coin_amount: u64) -> Result<()> {
         self.native_coin_free = self
             .native_coin_free
             .checked_sub(native_coin_amount)
-            .unwrap();
+            .ok_or(ErrorCodeCustom::InsufficientFunds)?;
+        Ok(())
     }
 
-    pub fn lock_free_pc(&mut self, native_pc_amount: u64) {
-        self.native_pc_free = self.native_pc_free.checked_sub(native_pc_amount).unwrap();
+    pub fn lock_free_pc(&mut self, native_pc_amount: u64) -> Result<()> {
+        self.native_pc_free = self
+            .native_pc_free
+            .checked_sub(native_pc_amount)
+            .ok_or(ErrorCodeCustom::InsufficientFunds)?;
+        Ok(())
     }
 
-    pub fn unlock_coin(&mut self, native_coin_amount: u64) {
+    /// Fails with `BalanceInconsistent` rather than let free exceed total.
+    pub fn unlock_coin(&mut self, native_coin_amount: u64) -> Result<()> {
         self.native_coin_free = self
             .native_coin_free
             .checked_add(native_coin_amount)
-            .unwrap();
-        assert!(self.native_coin_free <= self.native_coin_total);
+            .ok_or(ErrorCodeCustom::MathOverflow)?;
+        require!(
+            self.native_coin_free <= self.native_coin_total,
+            ErrorCodeCustom::BalanceInconsistent
+        );
+        Ok(())
     }
 
-    pub fn unlock_pc(&mut self, native_pc_amount: u64) {
-        self.native_pc_free = self.native_pc_free.checked_add(native_pc_amount).unwrap();
-        assert!(self.native_pc_free <= self.native_pc_total);
+    /// Fails with `BalanceInconsistent` rather than let free exceed total.
+    pub fn unlock_pc(&mut self, native_pc_amount: u64) -> Result<()> {
+        self.native_pc_free = self
+            .native_pc_free
+            .checked_add(native_pc_amount)
+            .ok_or(ErrorCodeCustom::MathOverflow)?;
+        require!(
+            self.native_pc_free <= self.native_pc_total,
+            ErrorCodeCustom::BalanceInconsistent
+        );
+        Ok(())
     }
 
     pub fn slot_is_free(&self, slot: u8) -> bool {
@@ -1000,11 +653,36 @@ impl OpenOrders {
         }
     }
 
+    /// Pays a fill out of the locked balance and credits what it bought to
+    /// the free balance.
+    pub fn settle_fill(&mut self, fill: &Event) -> Result<()> {
+        match fill.side() {
+            Side::Bid => {
+                self.debit_locked_pc(fill.native_qty_paid)?;
+                self.credit_unlocked_coin(fill.native_qty_released)
+            }
+            Side::Ask => {
+                self.debit_locked_coin(fill.native_qty_paid)?;
+                self.credit_unlocked_pc(fill.native_qty_released)
+            }
+        }
+    }
+
+    pub fn slot_is_fully_funded(&self, slot: u8) -> bool {
+        let slot_mask = 1u8 << slot;
+        self.fully_funded_bits & slot_mask != 0
+    }
+
+    pub fn set_fully_funded(&mut self, slot: u8) {
+        self.fully_funded_bits |= 1u8 << slot;
+    }
+
     pub fn remove_order(&mut self, slot: u8) -> Result<()> {
         let slot_mask = 1u8 << slot;
         self.orders[slot as usize] = 0;
         self.free_slot_bits |= slot_mask;
         self.is_bid_bits &= !slot_mask;
+        self.fully_funded_bits &= !slot_mask;
 
         Ok(())
     }
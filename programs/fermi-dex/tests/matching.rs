@@ -0,0 +1,973 @@
+//! Property tests for the book, the matching engine and the `OpenOrders`
+//! balance helpers. The engine is checked differentially against `Model`, a
+//! straightforward price-time priority book written from the spec.
+
+use std::collections::HashMap;
+
+use anchor_lang::prelude::Pubkey;
+use bytemuck::Zeroable;
+use enumflags2::BitFlags;
+use fermi_dex::state::*;
+use proptest::prelude::*;
+
+fn owner(i: u8) -> Pubkey {
+    Pubkey::new_from_array([i + 1; 32])
+}
+
+fn order_type(i: u8) -> OrderType {
+    match i % 3 {
+        0 => OrderType::Limit,
+        1 => OrderType::ImmediateOrCancel,
+        _ => OrderType::PostOnly,
+    }
+}
+
+fn assert_sorted<const T: bool>(orders: &Orders<T>) {
+    for pair in orders.sorted.windows(2) {
+        let (a, b) = (&pair[0], &pair[1]);
+        if T {
+            assert!(a.price() > b.price() || (a.price() == b.price() && a.order_id > b.order_id));
+        } else {
+            assert!(a.price() < b.price() || (a.price() == b.price() && a.order_id < b.order_id));
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum BookOp {
+    Insert {
+        price: u64,
+        qty: u64,
+        fully_funded: bool,
+    },
+    Delete {
+        pick: usize,
+    },
+}
+
+fn book_op() -> impl Strategy<Value = BookOp> {
+    prop_oneof![
+        3 => (1u64..50, 1u64..100, any::<bool>()).prop_map(|(price, qty, fully_funded)| {
+            BookOp::Insert { price, qty, fully_funded }
+        }),
+        1 => any::<usize>().prop_map(|pick| BookOp::Delete { pick }),
+    ]
+}
+
+fn run_book_ops<const T: bool>(side: Side, ops: &[BookOp]) {
+    let mut req_q = RequestQueue::default();
+    let mut orders = Orders::<T>::default();
+    let mut expected: HashMap<u128, u64> = HashMap::new();
+
+    for op in ops {
+        match *op {
+            BookOp::Insert {
+                price,
+                qty,
+                fully_funded,
+            } => {
+                let order_id = req_q.gen_order_id(price, side);
+                let inserted = orders.insert(Order {
+                    order_id,
+                    qty,
+                    owner: owner(0),
+                    owner_slot: 0,
+                    fully_funded,
+                    display_qty: 0,
+                    reserve_qty: 0,
+                });
+                // a full book turns further orders away
+                if expected.len() == MAX_ORDERS {
+                    assert!(inserted.is_err());
+                } else {
+                    inserted.unwrap();
+                    expected.insert(order_id, qty);
+                }
+            }
+            BookOp::Delete { pick } => {
+                if expected.is_empty() {
+                    assert!(orders.delete(pick as u128).is_err());
+                    continue;
+                }
+                let mut ids: Vec<u128> = expected.keys().copied().collect();
+                ids.sort_unstable();
+                let order_id = ids[pick % ids.len()];
+                let deleted = orders.delete(order_id).unwrap();
+                assert_eq!(deleted.order_id, order_id);
+                expected.remove(&order_id);
+                assert!(orders.delete(order_id).is_err());
+            }
+        }
+
+        assert_sorted(&orders);
+        assert_eq!(orders.sorted.len(), expected.len());
+        for order in &orders.sorted {
+            assert_eq!(expected.get(&order.order_id), Some(&order.qty));
+        }
+
+        // depth adds up to the book and shows what fully funded makers back
+        let levels = orders.levels(usize::MAX);
+        assert_eq!(
+            levels.iter().map(|l| l.qty).sum::<u64>(),
+            orders.sorted.iter().map(|o| o.qty).sum::<u64>()
+        );
+        assert_eq!(
+            levels.iter().map(|l| l.fully_funded_qty).sum::<u64>(),
+            orders
+                .sorted
+                .iter()
+                .filter(|o| o.fully_funded)
+                .map(|o| o.qty)
+                .sum::<u64>()
+        );
+    }
+}
+
+proptest! {
+    #[test]
+    fn bids_stay_sorted_and_complete(ops in prop::collection::vec(book_op(), 1..80)) {
+        run_book_ops::<true>(Side::Bid, &ops);
+    }
+
+    #[test]
+    fn asks_stay_sorted_and_complete(ops in prop::collection::vec(book_op(), 1..80)) {
+        run_book_ops::<false>(Side::Ask, &ops);
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Request {
+    side: Side,
+    price: u64,
+    qty: u64,
+    extra_pc: u64,
+    order_type: u8,
+    owner: u8,
+}
+
+fn request() -> impl Strategy<Value = Request> {
+    (any::<bool>(), 1u64..20, 1u64..50, 0u64..30, 0u8..3, 0u8..4).prop_map(
+        |(is_bid, price, qty, extra_pc, order_type, owner)| Request {
+            side: if is_bid { Side::Bid } else { Side::Ask },
+            price,
+            qty,
+            extra_pc,
+            order_type,
+            owner,
+        },
+    )
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Proceeds {
+    coin_unlocked: u64,
+    native_pc_unlocked: u64,
+    coin_credit: u64,
+    native_pc_credit: u64,
+    coin_debit: u64,
+    native_pc_debit: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Resting {
+    order_id: u128,
+    price: u64,
+    seq: u64,
+    qty: u64,
+    owner: Pubkey,
+}
+
+/// Reference book. Matches one maker per cycle for at most ten cycles, then
+/// stops matching and only posts if the order no longer crosses. Posting to a
+/// full side boots its least aggressive order.
+struct Model {
+    pc_lot_size: u64,
+    bids: Vec<Resting>,
+    asks: Vec<Resting>,
+    last_trade_price: u64,
+}
+
+impl Model {
+    fn sort(&mut self) {
+        self.bids
+            .sort_by_key(|o| (std::cmp::Reverse(o.price), o.seq));
+        self.asks.sort_by_key(|o| (o.price, o.seq));
+    }
+
+    fn place(
+        &mut self,
+        taker: Resting,
+        side: Side,
+        native_pc_locked: u64,
+        order_type: OrderType,
+    ) -> Proceeds {
+        let (mut post_only, mut post_allowed) = match order_type {
+            OrderType::Limit => (false, true),
+            OrderType::ImmediateOrCancel => (false, false),
+            OrderType::PostOnly => (true, true),
+        };
+        let mut proceeds = Proceeds::default();
+        let mut coin_remaining = taker.qty;
+        let mut native_pc_remaining = native_pc_locked;
+
+        for limit in (0..=10).rev() {
+            if limit == 0 {
+                post_only = true;
+                post_allowed = true;
+            }
+            let book = match side {
+                Side::Bid => &mut self.asks,
+                Side::Ask => &mut self.bids,
+            };
+            let crossed = match (side, book.first()) {
+                (Side::Bid, Some(best)) => taker.price >= best.price,
+                (Side::Ask, Some(best)) => taker.price <= best.price,
+                (_, None) => false,
+            };
+            if !crossed || post_only {
+                self.finish(
+                    taker,
+                    side,
+                    coin_remaining,
+                    native_pc_remaining,
+                    post_allowed && !crossed,
+                    &mut proceeds,
+                );
+                return proceeds;
+            }
+
+            let best = book[0];
+            let trade_qty = match side {
+                Side::Bid => best
+                    .qty
+                    .min(coin_remaining)
+                    .min(native_pc_remaining / self.pc_lot_size / best.price),
+                Side::Ask => best.qty.min(coin_remaining),
+            };
+            if trade_qty == 0 {
+                self.finish(
+                    taker,
+                    side,
+                    coin_remaining,
+                    native_pc_remaining,
+                    false,
+                    &mut proceeds,
+                );
+                return proceeds;
+            }
+
+            let native_pc = trade_qty * best.price * self.pc_lot_size;
+            self.last_trade_price = best.price;
+            book[0].qty -= trade_qty;
+            if book[0].qty == 0 {
+                book.remove(0);
+            }
+            coin_remaining -= trade_qty;
+            match side {
+                Side::Bid => {
+                    native_pc_remaining -= native_pc;
+                    proceeds.coin_credit += trade_qty;
+                    proceeds.native_pc_debit += native_pc;
+                }
+                Side::Ask => {
+                    proceeds.native_pc_credit += native_pc;
+                    proceeds.coin_debit += trade_qty;
+                }
+            }
+
+            let more = match side {
+                Side::Bid => coin_remaining > 0 && native_pc_remaining > 0,
+                Side::Ask => coin_remaining > 0,
+            };
+            if !more {
+                self.finish(
+                    taker,
+                    side,
+                    coin_remaining,
+                    native_pc_remaining,
+                    false,
+                    &mut proceeds,
+                );
+                return proceeds;
+            }
+        }
+        unreachable!("the last cycle is post only");
+    }
+
+    fn finish(
+        &mut self,
+        taker: Resting,
+        side: Side,
+        coin_remaining: u64,
+        native_pc_remaining: u64,
+        post: bool,
+        proceeds: &mut Proceeds,
+    ) {
+        match side {
+            Side::Bid => {
+                let qty = if post {
+                    coin_remaining.min(native_pc_remaining / self.pc_lot_size / taker.price)
+                } else {
+                    0
+                };
+                if qty > 0 {
+                    if self.bids.len() == MAX_ORDERS {
+                        self.bids.pop();
+                    }
+                    self.bids.push(Resting { qty, ..taker });
+                }
+                proceeds.native_pc_unlocked +=
+                    native_pc_remaining - qty * taker.price * self.pc_lot_size;
+            }
+            Side::Ask => {
+                if post && coin_remaining > 0 {
+                    if self.asks.len() == MAX_ORDERS {
+                        self.asks.pop();
+                    }
+                    self.asks.push(Resting {
+                        qty: coin_remaining,
+                        ..taker
+                    });
+                } else {
+                    proceeds.coin_unlocked += coin_remaining;
+                }
+            }
+        }
+        self.sort();
+    }
+}
+
+fn book_view<const T: bool>(orders: &Orders<T>) -> Vec<(u128, u64, Pubkey)> {
+    orders
+        .sorted
+        .iter()
+        .map(|o| (o.order_id, o.qty, o.owner))
+        .collect()
+}
+
+fn model_view(orders: &[Resting]) -> Vec<(u128, u64, Pubkey)> {
+    orders
+        .iter()
+        .map(|o| (o.order_id, o.qty, o.owner))
+        .collect()
+}
+
+fn run_matching(coin_lot_size: u64, pc_lot_size: u64, requests: &[Request]) {
+    let mut market = Market {
+        coin_lot_size,
+        pc_lot_size,
+        ..Market::default()
+    };
+    let mut bids = Bids::default();
+    let mut asks = Asks::default();
+    let mut req_q = RequestQueue::default();
+    let mut model = Model {
+        pc_lot_size,
+        bids: vec![],
+        asks: vec![],
+        last_trade_price: 0,
+    };
+    let mut placed_qty: HashMap<u128, u64> = HashMap::new();
+    let mut filled_qty: HashMap<u128, u64> = HashMap::new();
+
+    for (seq, r) in requests.iter().enumerate() {
+        let order_id = req_q.gen_order_id(r.price, r.side);
+        let native_pc_locked = match r.side {
+            Side::Bid => Some(r.qty * r.price * pc_lot_size + r.extra_pc),
+            Side::Ask => None,
+        };
+        placed_qty.insert(order_id, r.qty);
+
+        let mut event_q = EventQueue::zeroed();
+        let mut proceeds = RequestProceeds {
+            coin_unlocked: 0,
+            native_pc_unlocked: 0,
+            coin_credit: 0,
+            native_pc_credit: 0,
+            coin_debit: 0,
+            native_pc_debit: 0,
+            jit_data: vec![],
+        };
+        let remaining = OrderBook {
+            bids: &mut bids,
+            asks: &mut asks,
+            market: &mut market,
+        }
+        .new_order(
+            NewOrderParams {
+                side: r.side,
+                order_type: order_type(r.order_type),
+                order_id,
+                max_coin_qty: r.qty,
+                native_pc_qty_locked: native_pc_locked,
+                owner: owner(r.owner),
+                owner_slot: 0,
+                fully_funded: false,
+            },
+            &mut event_q,
+            &mut proceeds,
+            seq as u64,
+        )
+        .unwrap();
+        assert!(remaining.is_none());
+
+        let expected = model.place(
+            Resting {
+                order_id,
+                price: r.price,
+                seq: seq as u64,
+                qty: r.qty,
+                owner: owner(r.owner),
+            },
+            r.side,
+            native_pc_locked.unwrap_or(0),
+            order_type(r.order_type),
+        );
+        let actual = Proceeds {
+            coin_unlocked: proceeds.coin_unlocked,
+            native_pc_unlocked: proceeds.native_pc_unlocked,
+            coin_credit: proceeds.coin_credit,
+            native_pc_credit: proceeds.native_pc_credit,
+            coin_debit: proceeds.coin_debit,
+            native_pc_debit: proceeds.native_pc_debit,
+        };
+        assert_eq!(actual, expected);
+        assert_eq!(book_view(&bids), model_view(&model.bids));
+        assert_eq!(book_view(&asks), model_view(&model.asks));
+        assert_sorted(&bids);
+        assert_sorted(&asks);
+
+        // the oracle follows the fills and the top of the book
+        assert_eq!(market.last_trade_price, model.last_trade_price);
+        assert_eq!(market.best_bid, model.bids.first().map_or(0, |o| o.price));
+        assert_eq!(market.best_ask, model.asks.first().map_or(0, |o| o.price));
+
+        // what the taker locked is either spent, released or still resting
+        let resting = |order_id| {
+            bids.sorted
+                .iter()
+                .chain(asks.sorted.iter())
+                .find(|o| o.order_id == order_id)
+                .map_or(0, |o| o.qty)
+        };
+        match r.side {
+            Side::Bid => assert_eq!(
+                proceeds.native_pc_debit
+                    + proceeds.native_pc_unlocked
+                    + resting(order_id) * r.price * pc_lot_size,
+                native_pc_locked.unwrap()
+            ),
+            Side::Ask => assert_eq!(
+                proceeds.coin_debit + proceeds.coin_unlocked + resting(order_id),
+                r.qty
+            ),
+        }
+
+        let head = event_q.head as usize;
+        let buf = event_q.buf;
+        for event in &buf[1..=head] {
+            let flags = BitFlags::<EventFlag>::from_bits(event.event_flags).unwrap();
+            if !flags.contains(EventFlag::Fill | EventFlag::Maker) {
+                continue;
+            }
+            let native_coin = if flags.contains(EventFlag::Bid) {
+                event.native_qty_released
+            } else {
+                event.native_qty_paid
+            };
+            assert_eq!(native_coin % coin_lot_size, 0);
+            *filled_qty.entry(event.order_id).or_default() += native_coin / coin_lot_size;
+        }
+
+        // no maker is overfilled and no maker quantity disappears
+        for (order_id, filled) in &filled_qty {
+            assert!(*filled + resting(*order_id) <= placed_qty[order_id]);
+        }
+        for order in bids.sorted.iter().chain(asks.sorted.iter()) {
+            let filled = filled_qty.get(&order.order_id).copied().unwrap_or(0);
+            assert!(filled + order.qty <= placed_qty[&order.order_id]);
+        }
+    }
+
+    // the book never rests crossed
+    if let (Some(bid), Some(ask)) = (bids.sorted.first(), asks.sorted.first()) {
+        assert!(bid.price() < ask.price());
+    }
+}
+
+proptest! {
+    #[test]
+    fn matching_agrees_with_model(
+        coin_lot_size in 1u64..10,
+        pc_lot_size in 1u64..10,
+        requests in prop::collection::vec(request(), 1..60),
+    ) {
+        run_matching(coin_lot_size, pc_lot_size, &requests);
+    }
+}
+
+fn run_full_book(side: Side, prices: &[u64], price: u64) {
+    let mut book = Book::new();
+    for (timestamp, &resting) in prices.iter().enumerate() {
+        book.limit(side, resting, 1, timestamp as u64);
+    }
+    let worst = match side {
+        Side::Bid => book.bids.sorted.last().unwrap().order_id,
+        Side::Ask => book.asks.sorted.last().unwrap().order_id,
+    };
+
+    // nothing on the other side, so the order rests and the worst one leaves
+    let order_id = book.req_q.gen_order_id(price, side);
+    let mut event_q = EventQueue::zeroed();
+    let mut proceeds = RequestProceeds {
+        coin_unlocked: 0,
+        native_pc_unlocked: 0,
+        coin_credit: 0,
+        native_pc_credit: 0,
+        coin_debit: 0,
+        native_pc_debit: 0,
+        jit_data: vec![],
+    };
+    book.order_book()
+        .new_order(
+            NewOrderParams {
+                side,
+                order_type: OrderType::Limit,
+                order_id,
+                max_coin_qty: 1,
+                native_pc_qty_locked: match side {
+                    Side::Bid => Some(price),
+                    Side::Ask => None,
+                },
+                owner: owner(1),
+                owner_slot: 0,
+                fully_funded: false,
+            },
+            &mut event_q,
+            &mut proceeds,
+            prices.len() as u64,
+        )
+        .unwrap();
+
+    let sorted = match side {
+        Side::Bid => {
+            assert_sorted(&book.bids);
+            &book.bids.sorted
+        }
+        Side::Ask => {
+            assert_sorted(&book.asks);
+            &book.asks.sorted
+        }
+    };
+    assert_eq!(sorted.len(), MAX_ORDERS);
+    assert!(sorted.iter().any(|o| o.order_id == order_id));
+    assert!(sorted.iter().all(|o| o.order_id != worst));
+
+    let head = event_q.head as usize;
+    let buf = event_q.buf;
+    assert!(buf[1..=head].iter().any(|event| {
+        let flags = BitFlags::<EventFlag>::from_bits(event.event_flags).unwrap();
+        flags.contains(EventFlag::Out | EventFlag::ReleaseFunds) && event.order_id == worst
+    }));
+}
+
+proptest! {
+    #[test]
+    fn full_book_boots_its_least_aggressive_order(
+        is_bid in any::<bool>(),
+        prices in prop::collection::vec(1u64..20, MAX_ORDERS),
+        price in 1u64..20,
+    ) {
+        let side = if is_bid { Side::Bid } else { Side::Ask };
+        run_full_book(side, &prices, price);
+    }
+}
+
+#[test]
+fn twap_weights_prices_by_time_held() {
+    let mut market = Market::default();
+    assert_eq!(market.twap(100), 0);
+
+    market.record_trade(10, 100).unwrap();
+    assert_eq!(market.twap(100), 10);
+    market.record_trade(20, 130).unwrap();
+    market.record_trade(40, 160).unwrap();
+    assert_eq!(market.price_cumulative, 10 * 30 + 20 * 30);
+
+    // 10 for 30s, 20 for 30s, 40 for 60s
+    assert_eq!(market.price_cumulative_at(220), 10 * 30 + 20 * 30 + 40 * 60);
+    assert_eq!(market.twap(220), 27);
+    assert_eq!(market.last_trade_price, 40);
+
+    // a window between two observations
+    let start = market.price_cumulative_at(190);
+    assert_eq!((market.price_cumulative_at(220) - start) / 30, 40);
+}
+
+#[test]
+fn event_queue_fails_once_full() {
+    let mut event_q = EventQueue::zeroed();
+    let event = event_q.buf[0];
+    // slot 0 is never written
+    for _ in 1..event_q.buf.len() {
+        event_q.push(event).unwrap();
+    }
+    assert_eq!(event_q.head as usize, event_q.buf.len() - 1);
+    assert!(event_q.push(event).is_err());
+    assert_eq!(event_q.head as usize, event_q.buf.len() - 1);
+}
+
+struct Book {
+    market: Market,
+    bids: Bids,
+    asks: Asks,
+    req_q: RequestQueue,
+}
+
+impl Book {
+    fn new() -> Self {
+        Book {
+            market: Market {
+                coin_lot_size: 1,
+                pc_lot_size: 1,
+                ..Market::default()
+            },
+            bids: Bids::default(),
+            asks: Asks::default(),
+            req_q: RequestQueue::default(),
+        }
+    }
+
+    fn order_book(&mut self) -> OrderBook {
+        OrderBook {
+            bids: &mut self.bids,
+            asks: &mut self.asks,
+            market: &mut self.market,
+        }
+    }
+
+    /// A limit order, returns (coin lots bought or sold, native pc paid or received).
+    fn limit(&mut self, side: Side, price: u64, qty: u64, timestamp: u64) -> (u64, u64) {
+        let order_id = self.req_q.gen_order_id(price, side);
+        let mut event_q = EventQueue::zeroed();
+        let mut proceeds = RequestProceeds {
+            coin_unlocked: 0,
+            native_pc_unlocked: 0,
+            coin_credit: 0,
+            native_pc_credit: 0,
+            coin_debit: 0,
+            native_pc_debit: 0,
+            jit_data: vec![],
+        };
+        self.order_book()
+            .new_order(
+                NewOrderParams {
+                    side,
+                    order_type: OrderType::Limit,
+                    order_id,
+                    max_coin_qty: qty,
+                    native_pc_qty_locked: match side {
+                        Side::Bid => Some(qty * price),
+                        Side::Ask => None,
+                    },
+                    owner: owner(0),
+                    owner_slot: 0,
+                    fully_funded: false,
+                },
+                &mut event_q,
+                &mut proceeds,
+                timestamp,
+            )
+            .unwrap();
+        match side {
+            Side::Bid => (proceeds.coin_credit, proceeds.native_pc_debit),
+            Side::Ask => (proceeds.coin_debit, proceeds.native_pc_credit),
+        }
+    }
+
+    fn pegged(&mut self, side: Side, limit: u64, qty: u64, peg: Peg) -> u128 {
+        let order_id = self.req_q.gen_order_id(limit, side);
+        let order = Order {
+            order_id,
+            qty,
+            owner: owner(1),
+            owner_slot: 0,
+            fully_funded: false,
+            display_qty: 0,
+            reserve_qty: 0,
+        };
+        self.order_book()
+            .post_pegged(side, order, Some(qty * limit), peg)
+            .unwrap();
+        order_id
+    }
+
+    fn iceberg(&mut self, side: Side, price: u64, qty: u64, display_qty: u64) -> u128 {
+        let order_id = self.req_q.gen_order_id(price, side);
+        let order = Order {
+            order_id,
+            qty,
+            owner: owner(1),
+            owner_slot: 0,
+            fully_funded: false,
+            display_qty: 0,
+            reserve_qty: 0,
+        };
+        match side {
+            Side::Bid => {
+                self.bids.insert(order).unwrap();
+                self.bids.hide_reserve(order_id, display_qty).unwrap();
+            }
+            Side::Ask => {
+                self.asks.insert(order).unwrap();
+                self.asks.hide_reserve(order_id, display_qty).unwrap();
+            }
+        }
+        order_id
+    }
+}
+
+fn peg(reference: PegReference, offset_ticks: i64, offset_bps: i16) -> Peg {
+    Peg {
+        reference,
+        offset_ticks,
+        offset_bps,
+    }
+}
+
+#[test]
+fn pegged_orders_trade_at_their_effective_price() {
+    let mut book = Book::new();
+    book.limit(Side::Bid, 10, 5, 1);
+    book.limit(Side::Ask, 20, 5, 2);
+
+    // best ask minus 2 ticks, never below 12
+    let id = book.pegged(Side::Ask, 12, 3, peg(PegReference::BestAsk, -2, 0));
+    assert_eq!(book.limit(Side::Bid, 19, 2, 3), (2, 36));
+    assert_eq!(book.asks.resting_qty(id), 1);
+    assert_eq!(book.market.last_trade_price, 18);
+    assert_eq!(book.limit(Side::Bid, 19, 1, 4), (1, 18));
+    assert!(book.asks.find(id).is_none());
+
+    // best bid, floored at the limit of 15
+    book.pegged(Side::Ask, 15, 2, peg(PegReference::BestBid, 0, 0));
+    assert_eq!(book.limit(Side::Bid, 14, 1, 5), (0, 0));
+    assert_eq!(book.market.best_bid, 14);
+    assert_eq!(book.limit(Side::Bid, 16, 1, 6), (1, 15));
+}
+
+#[test]
+fn oracle_pegged_orders_need_a_fresh_price() {
+    let mut book = Book::new();
+
+    // oracle minus 1%, capped at 98
+    book.pegged(Side::Bid, 98, 3, peg(PegReference::Oracle, 0, -100));
+    assert_eq!(book.limit(Side::Ask, 95, 1, 100), (0, 0));
+
+    book.market.oracle_price = 100;
+    book.market.oracle_ts = 100;
+    assert_eq!(book.limit(Side::Ask, 97, 1, 110), (1, 98));
+
+    book.market.oracle_price = 90;
+    assert_eq!(book.limit(Side::Ask, 89, 1, 120), (1, 89));
+
+    // stale after MAX_ORACLE_AGE_SECS
+    book.market.oracle_price = 100;
+    let stale = 100 + MAX_ORACLE_AGE_SECS + 1;
+    assert_eq!(book.limit(Side::Ask, 50, 1, stale), (0, 0));
+}
+
+#[test]
+fn icebergs_refill_behind_their_price_level() {
+    let mut book = Book::new();
+    let id = book.iceberg(Side::Ask, 10, 5, 2);
+    book.limit(Side::Ask, 10, 1, 1);
+
+    let level = book.asks.levels(1)[0];
+    assert_eq!((level.price, level.qty), (10, 3));
+    assert_eq!(book.asks.resting_qty(id), 5);
+
+    // the first slice fills, the refill queues behind the plain ask
+    assert_eq!(book.limit(Side::Bid, 10, 2, 2), (2, 20));
+    let iceberg = *book.asks.find(id).unwrap();
+    assert_eq!((iceberg.qty, iceberg.reserve_qty), (2, 1));
+    assert_eq!(book.asks.sorted.last().unwrap().order_id, id);
+    assert_eq!(book.limit(Side::Bid, 10, 1, 3), (1, 10));
+    assert_eq!(book.asks.sorted.len(), 1);
+
+    // later orders queue behind it until its next refill
+    book.limit(Side::Ask, 10, 4, 4);
+    assert_eq!(book.asks.sorted[0].order_id, id);
+    assert_eq!(book.limit(Side::Bid, 10, 4, 5), (4, 40));
+    let iceberg = *book.asks.find(id).unwrap();
+    assert_eq!((iceberg.qty, iceberg.reserve_qty), (1, 0));
+    assert_eq!(book.asks.sorted.last().unwrap().order_id, id);
+
+    // and it leaves the book once the reserve is gone
+    assert_eq!(book.limit(Side::Bid, 10, 3, 6), (3, 30));
+    assert!(book.asks.find(id).is_none());
+    assert!(book.asks.sorted.is_empty());
+}
+
+fn trigger(kind: TriggerKind, side: Side, trigger_price: u64) -> TriggerOrder {
+    TriggerOrder {
+        params: TriggerParams {
+            kind,
+            reference: TriggerReference::LastTrade,
+            trigger_price,
+            side,
+            order_type: OrderType::ImmediateOrCancel,
+            limit_price: trigger_price,
+            max_coin_qty: 1,
+            max_native_pc_qty: trigger_price,
+        },
+        order_id: 0,
+        owner: owner(0),
+        owner_slot: 0,
+        native_qty_locked: 0,
+    }
+}
+
+#[test]
+fn triggers_fire_when_the_price_crosses() {
+    let stop_ask = trigger(TriggerKind::StopLoss, Side::Ask, 90);
+    let stop_bid = trigger(TriggerKind::StopLoss, Side::Bid, 110);
+    let take_ask = trigger(TriggerKind::TakeProfit, Side::Ask, 110);
+    let take_bid = trigger(TriggerKind::TakeProfit, Side::Bid, 90);
+
+    for (price, fired) in [
+        (0, [false; 4]),
+        (89, [true, false, false, true]),
+        (100, [false; 4]),
+        (110, [false, true, true, false]),
+    ] {
+        let got = [&stop_ask, &stop_bid, &take_ask, &take_bid].map(|t| t.is_triggered(price, 0));
+        assert_eq!(got, fired, "last trade {}", price);
+    }
+
+    let mut mid = trigger(TriggerKind::StopLoss, Side::Ask, 90);
+    mid.params.reference = TriggerReference::Mid;
+    assert!(!mid.is_triggered(80, 100));
+    assert!(mid.is_triggered(100, 85));
+
+    let mut triggers = TriggerOrders {
+        market: Pubkey::default(),
+        orders: vec![],
+    };
+    let placed = [stop_bid, stop_ask, take_ask, take_bid];
+    for (i, mut t) in placed.into_iter().enumerate() {
+        t.order_id = i as u128;
+        triggers.insert(t).unwrap();
+    }
+    let prices: Vec<_> = triggers
+        .orders
+        .iter()
+        .map(|t| t.params.trigger_price)
+        .collect();
+    assert_eq!(prices, [90, 90, 110, 110]);
+    assert_eq!(triggers.remove(0).unwrap().params.trigger_price, 110);
+    assert!(triggers.remove(0).is_err());
+    assert_eq!(triggers.orders.len(), 3);
+}
+
+#[derive(Clone, Debug)]
+enum BalanceOp {
+    Deposit(u64),
+    Lock(u64),
+    Unlock(u64),
+    CreditLocked(u64),
+    DebitLocked(u64),
+}
+
+fn balance_op() -> impl Strategy<Value = BalanceOp> {
+    (0u8..5, 0u64..1_000).prop_map(|(op, amount)| match op {
+        0 => BalanceOp::Deposit(amount),
+        1 => BalanceOp::Lock(amount),
+        2 => BalanceOp::Unlock(amount),
+        3 => BalanceOp::CreditLocked(amount),
+        _ => BalanceOp::DebitLocked(amount),
+    })
+}
+
+proptest! {
+    #[test]
+    fn open_orders_balances_are_conserved(ops in prop::collection::vec(balance_op(), 1..100)) {
+        let mut oo = OpenOrders::default();
+        oo.init(owner(0), owner(1)).unwrap();
+
+        for op in ops {
+            let (coin_total, coin_free) = (oo.native_coin_total, oo.native_coin_free);
+            let (pc_total, pc_free) = (oo.native_pc_total, oo.native_pc_free);
+            let coin_locked = coin_total - coin_free;
+            let pc_locked = pc_total - pc_free;
+
+            match op {
+                BalanceOp::Deposit(amount) => {
+                    oo.credit_unlocked_coin(amount).unwrap();
+                    oo.credit_unlocked_pc(amount).unwrap();
+                    prop_assert_eq!(oo.native_coin_free, coin_free + amount);
+                    prop_assert_eq!(oo.native_pc_free, pc_free + amount);
+                    prop_assert_eq!(oo.native_coin_total - oo.native_coin_free, coin_locked);
+                }
+                BalanceOp::Lock(amount) => {
+                    let (coin, pc) = (amount.min(coin_free), amount.min(pc_free));
+                    oo.lock_free_coin(coin).unwrap();
+                    oo.lock_free_pc(pc).unwrap();
+                    prop_assert_eq!(oo.native_coin_total, coin_total);
+                    prop_assert_eq!(oo.native_pc_total, pc_total);
+                    prop_assert_eq!(oo.native_coin_free, coin_free - coin);
+                    prop_assert_eq!(oo.native_pc_free, pc_free - pc);
+                }
+                BalanceOp::Unlock(amount) => {
+                    let (coin, pc) = (amount.min(coin_locked), amount.min(pc_locked));
+                    oo.unlock_coin(coin).unwrap();
+                    oo.unlock_pc(pc).unwrap();
+                    prop_assert_eq!(oo.native_coin_total, coin_total);
+                    prop_assert_eq!(oo.native_pc_total, pc_total);
+                    prop_assert_eq!(oo.native_coin_free, coin_free + coin);
+                    prop_assert_eq!(oo.native_pc_free, pc_free + pc);
+                }
+                BalanceOp::CreditLocked(amount) => {
+                    oo.credit_locked_coin(amount).unwrap();
+                    oo.credit_locked_pc(amount).unwrap();
+                    prop_assert_eq!(oo.native_coin_free, coin_free);
+                    prop_assert_eq!(oo.native_pc_free, pc_free);
+                    prop_assert_eq!(oo.native_coin_total - oo.native_coin_free, coin_locked + amount);
+                    prop_assert_eq!(oo.native_pc_total - oo.native_pc_free, pc_locked + amount);
+                }
+                BalanceOp::DebitLocked(amount) => {
+                    // nothing beyond the locked balance can be debited
+                    prop_assert!(oo.debit_locked_coin(coin_locked + 1).is_err());
+                    prop_assert!(oo.debit_locked_pc(pc_locked + 1).is_err());
+                    let (coin, pc) = (amount.min(coin_locked), amount.min(pc_locked));
+                    oo.debit_locked_coin(coin).unwrap();
+                    oo.debit_locked_pc(pc).unwrap();
+                    prop_assert_eq!(oo.native_coin_free, coin_free);
+                    prop_assert_eq!(oo.native_pc_free, pc_free);
+                    prop_assert_eq!(oo.native_coin_total - oo.native_coin_free, coin_locked - coin);
+                    prop_assert_eq!(oo.native_pc_total - oo.native_pc_free, pc_locked - pc);
+                }
+            }
+
+            prop_assert!(oo.native_coin_free <= oo.native_coin_total);
+            prop_assert!(oo.native_pc_free <= oo.native_pc_total);
+        }
+    }
+
+    #[test]
+    fn open_orders_slots_track_orders(sides in prop::collection::vec(any::<bool>(), 1..20)) {
+        let mut oo = OpenOrders::default();
+        oo.init(owner(0), owner(1)).unwrap();
+
+        for (i, is_bid) in sides.into_iter().enumerate() {
+            let side = if is_bid { Side::Bid } else { Side::Ask };
+            let order_id = i as u128 + 1;
+            let slot = oo.add_order(order_id, side).unwrap();
+            prop_assert_eq!(oo.orders[slot as usize], order_id);
+            prop_assert_eq!(oo.slot_side(slot), Some(side));
+
+            let used = oo.orders.iter().filter(|id| **id != 0).count();
+            prop_assert_eq!(used as u32, oo.free_slot_bits.count_zeros());
+        }
+    }
+}
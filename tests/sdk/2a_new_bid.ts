@@ -151,6 +151,7 @@ describe('#new_order', async () => {
             new anchor.BN(1),
             new anchor.BN(34),
             { limit: {} },
+            false,
           )
           .accounts({
             openOrders: openOrdersPda,
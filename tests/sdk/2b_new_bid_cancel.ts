@@ -152,6 +152,7 @@ describe('#new_order cancel', async () => {
             new anchor.BN(1),
             new anchor.BN(30),
             { limit: {} },
+            false,
           )
           .accounts({
             openOrders: openOrdersPda,
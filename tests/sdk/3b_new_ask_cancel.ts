@@ -160,6 +160,7 @@ describe('#new_order cancel', async () => {
             new anchor.BN(1),
             new anchor.BN(40),
             { limit: {} },
+            false,
           )
           .accounts({
             openOrders: openOrdersPda,
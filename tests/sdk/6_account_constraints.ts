@@ -0,0 +1,219 @@
+import * as anchor from '@project-serum/anchor';
+import * as spl from '@solana/spl-token';
+import { assert } from 'chai';
+import {
+  LocalMarket,
+  initLocalMarket,
+  openOrdersPdaFor,
+  fundedAta,
+  expectRejected,
+} from "./utils/localMarket";
+
+// Run against a local test validator: `anchor test` with cluster = "localnet".
+describe('fermi-dex-account-constraints', () => {
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+  const program = anchor.workspace.FermiDex as anchor.Program;
+  const authority = provider.wallet.publicKey;
+
+  let marketA: LocalMarket;
+  let marketB: LocalMarket;
+  let openOrdersA: anchor.web3.PublicKey;
+  let pcAccount: anchor.web3.PublicKey;
+  let coinAccount: anchor.web3.PublicKey;
+  let orderId: anchor.BN;
+
+  before(async () => {
+    // both markets quote in the same pc mint so vaults are interchangeable by mint
+    marketA = await initLocalMarket(program, provider);
+    marketB = await initLocalMarket(program, provider, marketA.pcMint);
+    pcAccount = await fundedAta(provider, marketA.pcMint, BigInt(1_000_000_000_000));
+    coinAccount = await fundedAta(provider, marketA.coinMint, BigInt(1_000_000_000_000));
+    openOrdersA = await openOrdersPdaFor(program, marketA.marketPda, authority);
+
+    await program.methods
+      .newOrder({ bid: {} }, new anchor.BN(25), new anchor.BN(1), new anchor.BN(25), { limit: {} }, false)
+      .accounts({
+        openOrders: openOrdersA,
+        market: marketA.marketPda,
+        coinVault: marketA.coinVault,
+        pcVault: marketA.pcVault,
+        coinMint: marketA.coinMint,
+        pcMint: marketA.pcMint,
+        payer: pcAccount,
+        bids: marketA.bidsPda,
+        asks: marketA.asksPda,
+        reqQ: marketA.reqQPda,
+        eventQ: marketA.eventQPda,
+        authority,
+        systemProgram: anchor.web3.SystemProgram.programId,
+        tokenProgram: spl.TOKEN_PROGRAM_ID,
+        associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      })
+      .rpc();
+
+    const openOrders = await program.account.openOrders.fetch(openOrdersA);
+    orderId = (openOrders.orders as anchor.BN[]).find((id) => !id.isZero());
+    assert(orderId, 'bid was not recorded in open orders');
+  });
+
+  const cancelAccounts = (overrides: object) => ({
+    openOrders: openOrdersA,
+    market: marketA.marketPda,
+    bids: marketA.bidsPda,
+    asks: marketA.asksPda,
+    eventQ: marketA.eventQPda,
+    authority,
+    ...overrides,
+  });
+
+  describe('#cancel_bid', async () => {
+    it('rejects bids of another market', async () => {
+      await expectRejected(
+        program.methods.cancelBid(orderId, authority).accounts(cancelAccounts({ bids: marketB.bidsPda })).rpc(),
+        'bids of market B with market A',
+      );
+    });
+
+    it('rejects asks and event queue of another market', async () => {
+      await expectRejected(
+        program.methods.cancelBid(orderId, authority).accounts(cancelAccounts({ asks: marketB.asksPda })).rpc(),
+        'asks of market B with market A',
+      );
+      await expectRejected(
+        program.methods.cancelBid(orderId, authority).accounts(cancelAccounts({ eventQ: marketB.eventQPda })).rpc(),
+        'event queue of market B with market A',
+      );
+    });
+
+    it('rejects open orders of another market', async () => {
+      await expectRejected(
+        program.methods
+          .cancelBid(orderId, authority)
+          .accounts(cancelAccounts({
+            market: marketB.marketPda,
+            bids: marketB.bidsPda,
+            asks: marketB.asksPda,
+            eventQ: marketB.eventQPda,
+          }))
+          .rpc(),
+        'open orders of market A with market B',
+      );
+    });
+
+    it('rejects a signer that does not own the open orders', async () => {
+      const stranger = anchor.web3.Keypair.generate();
+      await expectRejected(
+        program.methods
+          .cancelBid(orderId, authority)
+          .accounts(cancelAccounts({ authority: stranger.publicKey }))
+          .signers([stranger])
+          .rpc(),
+        'foreign authority',
+      );
+    });
+  });
+
+  describe('#deposit_tokens', async () => {
+    const depositAccounts = (overrides: object) => ({
+      market: marketA.marketPda,
+      payer: pcAccount,
+      vault: marketA.pcVault,
+      openOrders: openOrdersA,
+      authority,
+      tokenProgram: spl.TOKEN_PROGRAM_ID,
+      associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+      rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      ...overrides,
+    });
+
+    it('rejects the pc vault of another market', async () => {
+      await expectRejected(
+        program.methods
+          .depositPcTokens(new anchor.BN(1000))
+          .accounts(depositAccounts({ vault: marketB.pcVault }))
+          .rpc(),
+        'pc vault of market B with market A',
+      );
+    });
+
+    it('rejects a coin vault for a pc deposit', async () => {
+      await expectRejected(
+        program.methods
+          .depositPcTokens(new anchor.BN(1000))
+          .accounts(depositAccounts({ payer: coinAccount, vault: marketA.coinVault }))
+          .rpc(),
+        'coin vault passed to deposit_pc_tokens',
+      );
+    });
+
+    it('rejects an arbitrary token account as vault', async () => {
+      await expectRejected(
+        program.methods
+          .depositCoinTokens(new anchor.BN(1000))
+          .accounts(depositAccounts({ payer: coinAccount, vault: coinAccount }))
+          .rpc(),
+        'user token account as vault',
+      );
+    });
+
+    it('accepts the market vault', async () => {
+      await program.methods
+        .depositPcTokens(new anchor.BN(1000))
+        .accounts(depositAccounts({}))
+        .rpc();
+      const openOrders = await program.account.openOrders.fetch(openOrdersA);
+      assert(openOrders.nativePcFree.gte(new anchor.BN(1000)));
+    });
+  });
+
+  describe('#cancel_with_penalty', async () => {
+    const penaltyAccounts = (overrides: object) => ({
+      openOrdersBidder: openOrdersA,
+      openOrdersAsker: openOrdersA,
+      eventQ: marketA.eventQPda,
+      market: marketA.marketPda,
+      bids: marketA.bidsPda,
+      asks: marketA.asksPda,
+      ...overrides,
+    });
+
+    it('rejects the event queue of another market', async () => {
+      const err = await expectRejected(
+        program.methods
+          .cancelWithPenalty({ bid: {} }, 1, 2)
+          .accounts(penaltyAccounts({ eventQ: marketB.eventQPda }))
+          .rpc(),
+        'event queue of market B with market A',
+      );
+      assert.include(`${err}`, 'ConstraintAddress');
+    });
+
+    it('rejects open orders of another market', async () => {
+      const err = await expectRejected(
+        program.methods
+          .cancelWithPenalty({ bid: {} }, 1, 2)
+          .accounts(penaltyAccounts({
+            market: marketB.marketPda,
+            eventQ: marketB.eventQPda,
+            bids: marketB.bidsPda,
+            asks: marketB.asksPda,
+          }))
+          .rpc(),
+        'open orders of market A with market B',
+      );
+      assert.match(`${err}`, /ConstraintHasOne|ConstraintSeeds/);
+    });
+
+    it('rejects an account that is not an open orders PDA', async () => {
+      await expectRejected(
+        program.methods
+          .cancelWithPenalty({ bid: {} }, 1, 2)
+          .accounts(penaltyAccounts({ openOrdersAsker: marketA.marketPda }))
+          .rpc(),
+        'market account passed as open orders',
+      );
+    });
+  });
+});
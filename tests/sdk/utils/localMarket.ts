@@ -0,0 +1,108 @@
+import * as anchor from '@project-serum/anchor';
+import * as spl from '@solana/spl-token';
+import {createMint, createAssociatedTokenAccount, mintTo} from "./utils"
+
+// Everything needed to talk to a market created on a local test validator.
+export type LocalMarket = {
+  coinMint: anchor.web3.PublicKey;
+  pcMint: anchor.web3.PublicKey;
+  marketPda: anchor.web3.PublicKey;
+  coinVault: anchor.web3.PublicKey;
+  pcVault: anchor.web3.PublicKey;
+  bidsPda: anchor.web3.PublicKey;
+  asksPda: anchor.web3.PublicKey;
+  reqQPda: anchor.web3.PublicKey;
+  eventQPda: anchor.web3.PublicKey;
+};
+
+export const findPda = async (
+  seeds: Buffer[],
+  programId: anchor.web3.PublicKey,
+) => {
+  const [pda] = await anchor.web3.PublicKey.findProgramAddress(seeds, programId);
+  return pda;
+};
+
+export const openOrdersPdaFor = async (
+  program: anchor.Program,
+  market: anchor.web3.PublicKey,
+  owner: anchor.web3.PublicKey,
+) =>
+  findPda(
+    [Buffer.from('open-orders', 'utf-8'), market.toBuffer(), owner.toBuffer()],
+    program.programId,
+  );
+
+// Creates fresh mints (or reuses the given pc mint) and initializes a market owned by the provider wallet.
+export const initLocalMarket = async (
+  program: anchor.Program,
+  provider: anchor.AnchorProvider,
+  pcMint?: anchor.web3.PublicKey,
+): Promise<LocalMarket> => {
+  const coinMintKp = anchor.web3.Keypair.generate();
+  await createMint(provider, coinMintKp, 9);
+  if (!pcMint) {
+    const pcMintKp = anchor.web3.Keypair.generate();
+    await createMint(provider, pcMintKp, 6);
+    pcMint = pcMintKp.publicKey;
+  }
+  const coinMint = coinMintKp.publicKey;
+
+  const marketPda = await findPda(
+    [Buffer.from('market', 'utf-8'), coinMint.toBuffer(), pcMint.toBuffer()],
+    program.programId,
+  );
+  const bidsPda = await findPda([Buffer.from('bids', 'utf-8'), marketPda.toBuffer()], program.programId);
+  const asksPda = await findPda([Buffer.from('asks', 'utf-8'), marketPda.toBuffer()], program.programId);
+  const reqQPda = await findPda([Buffer.from('req-q', 'utf-8'), marketPda.toBuffer()], program.programId);
+  const eventQPda = await findPda([Buffer.from('event-q', 'utf-8'), marketPda.toBuffer()], program.programId);
+  const coinVault = await spl.getAssociatedTokenAddress(coinMint, marketPda, true);
+  const pcVault = await spl.getAssociatedTokenAddress(pcMint, marketPda, true);
+
+  await program.methods
+    .initializeMarket(new anchor.BN('1000000000'), new anchor.BN('1000000'))
+    .accounts({
+      market: marketPda,
+      coinVault,
+      pcVault,
+      coinMint,
+      pcMint,
+      bids: bidsPda,
+      asks: asksPda,
+      reqQ: reqQPda,
+      eventQ: eventQPda,
+      authority: provider.wallet.publicKey,
+      systemProgram: anchor.web3.SystemProgram.programId,
+      tokenProgram: spl.TOKEN_PROGRAM_ID,
+      associatedTokenProgram: spl.ASSOCIATED_TOKEN_PROGRAM_ID,
+      rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+    })
+    .rpc();
+
+  return {coinMint, pcMint, marketPda, coinVault, pcVault, bidsPda, asksPda, reqQPda, eventQPda};
+};
+
+// Creates and funds the provider wallet's ATA for a mint.
+export const fundedAta = async (
+  provider: anchor.AnchorProvider,
+  mint: anchor.web3.PublicKey,
+  amount: bigint,
+) => {
+  const ata = await spl.getAssociatedTokenAddress(mint, provider.wallet.publicKey);
+  const info = await provider.connection.getAccountInfo(ata);
+  if (!info) {
+    await createAssociatedTokenAccount(provider, mint, ata, provider.wallet.publicKey);
+  }
+  await mintTo(provider, mint, ata, amount);
+  return ata;
+};
+
+// Fails the test unless the promise rejects.
+export const expectRejected = async (promise: Promise<unknown>, reason: string) => {
+  try {
+    await promise;
+  } catch (err) {
+    return err;
+  }
+  throw new Error(`expected transaction to be rejected: ${reason}`);
+};